@@ -2,7 +2,7 @@ mod supported_countries;
 
 use anyhow::{anyhow, Context, Result};
 use futures::{io::BufReader, stream::BoxStream, AsyncBufReadExt, AsyncReadExt, Stream, StreamExt};
-use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest};
+use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest, StatusCode};
 use isahc::config::Configurable;
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
@@ -14,6 +14,14 @@ pub use supported_countries::*;
 
 pub const ANTHROPIC_API_URL: &'static str = "https://api.anthropic.com";
 
+/// The version of the API that requests are written against, sent as the
+/// `anthropic-version` header.
+pub const ANTHROPIC_API_VERSION: &str = "2023-06-01";
+
+/// The most output tokens to request from models whose limit isn't known.
+/// Anthropic requires every request to set `max_tokens`.
+pub const DEFAULT_MAX_OUTPUT_TOKENS: u32 = 4096;
+
 #[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, EnumIter)]
 pub enum Model {
@@ -29,6 +37,8 @@ pub enum Model {
     #[serde(rename = "custom")]
     Custom {
         name: String,
+        /// The name displayed in the model picker, defaulting to the model's id.
+        display_name: Option<String>,
         max_tokens: usize,
         /// Override this model with a different Anthropic model for tool calls.
         tool_override: Option<String>,
@@ -66,7 +76,21 @@ impl Model {
             Self::Claude3Opus => "Claude 3 Opus",
             Self::Claude3Sonnet => "Claude 3 Sonnet",
             Self::Claude3Haiku => "Claude 3 Haiku",
-            Self::Custom { name, .. } => name,
+            Self::Custom {
+                name, display_name, ..
+            } => display_name.as_ref().unwrap_or(name),
+        }
+    }
+
+    /// Returns the date after which Anthropic will stop serving this model, as
+    /// `YYYY-MM-DD`, if it has been deprecated.
+    pub fn deprecation_date(&self) -> Option<&'static str> {
+        match self {
+            Self::Claude3Sonnet => Some("2025-07-21"),
+            Self::Claude3_5Sonnet
+            | Self::Claude3Opus
+            | Self::Claude3Haiku
+            | Self::Custom { .. } => None,
         }
     }
 
@@ -80,6 +104,17 @@ impl Model {
         }
     }
 
+    /// The most tokens the model will generate in one response.
+    pub fn max_output_tokens(&self) -> u32 {
+        match self {
+            // Claude 3.5 Sonnet allows twice as many with the
+            // `max-tokens-3-5-sonnet-2024-07-15` beta, which every request sends.
+            Self::Claude3_5Sonnet => 8192,
+            Self::Claude3Opus | Self::Claude3Sonnet | Self::Claude3Haiku => 4096,
+            Self::Custom { .. } => DEFAULT_MAX_OUTPUT_TOKENS,
+        }
+    }
+
     pub fn tool_model_id(&self) -> &str {
         if let Self::Custom {
             tool_override: Some(tool_override),
@@ -103,8 +138,11 @@ pub async fn complete(
     let request_builder = HttpRequest::builder()
         .method(Method::POST)
         .uri(uri)
-        .header("Anthropic-Version", "2023-06-01")
-        .header("Anthropic-Beta", "tools-2024-04-04")
+        .header("Anthropic-Version", ANTHROPIC_API_VERSION)
+        .header(
+            "Anthropic-Beta",
+            "tools-2024-04-04,prompt-caching-2024-07-31,max-tokens-3-5-sonnet-2024-07-15",
+        )
         .header("X-Api-Key", api_key)
         .header("Content-Type", "application/json");
 
@@ -137,11 +175,7 @@ pub async fn complete(
             .context("failed to read response body")?;
         let body_str =
             std::str::from_utf8(&body).context("failed to parse response body as UTF-8")?;
-        Err(AnthropicError::Other(anyhow!(
-            "Failed to connect to API: {} {}",
-            response.status(),
-            body_str
-        )))
+        Err(error_from_response(response.status(), body_str))
     }
 }
 
@@ -160,8 +194,11 @@ pub async fn stream_completion(
     let mut request_builder = HttpRequest::builder()
         .method(Method::POST)
         .uri(uri)
-        .header("Anthropic-Version", "2023-06-01")
-        .header("Anthropic-Beta", "tools-2024-04-04")
+        .header("Anthropic-Version", ANTHROPIC_API_VERSION)
+        .header(
+            "Anthropic-Beta",
+            "tools-2024-04-04,prompt-caching-2024-07-31,max-tokens-3-5-sonnet-2024-07-15",
+        )
         .header("X-Api-Key", api_key)
         .header("Content-Type", "application/json");
     if let Some(low_speed_timeout) = low_speed_timeout {
@@ -204,18 +241,22 @@ pub async fn stream_completion(
 
         let body_str =
             std::str::from_utf8(&body).context("failed to parse response body as UTF-8")?;
+        Err(error_from_response(response.status(), body_str))
+    }
+}
 
-        match serde_json::from_str::<Event>(body_str) {
-            Ok(Event::Error { error }) => Err(AnthropicError::ApiError(error)),
-            Ok(_) => Err(AnthropicError::Other(anyhow!(
-                "Unexpected success response while expecting an error: '{body_str}'",
-            ))),
-            Err(_) => Err(AnthropicError::Other(anyhow!(
-                "Failed to connect to API: {} {}",
-                response.status(),
-                body_str,
-            ))),
-        }
+fn error_from_response(status: StatusCode, body: &str) -> AnthropicError {
+    match serde_json::from_str::<Event>(body) {
+        Ok(Event::Error { error }) => AnthropicError::ApiError(error),
+        Ok(_) => AnthropicError::Other(anyhow!(
+            "Unexpected success response while expecting an error: '{body}'",
+        )),
+        // Overloaded responses don't always come with a body.
+        Err(_) if status.as_u16() == 529 => AnthropicError::ApiError(ApiError {
+            error_type: "overloaded_error".into(),
+            message: "Overloaded".into(),
+        }),
+        Err(_) => AnthropicError::Other(anyhow!("Failed to connect to API: {status} {body}")),
     }
 }
 
@@ -226,7 +267,7 @@ pub fn extract_text_from_events(
         match response {
             Ok(response) => match response {
                 Event::ContentBlockStart { content_block, .. } => match content_block {
-                    Content::Text { text } => Some(Ok(text)),
+                    Content::Text { text, .. } => Some(Ok(text)),
                     _ => None,
                 },
                 Event::ContentBlockDelta { delta, .. } => match delta {
@@ -241,26 +282,36 @@ pub fn extract_text_from_events(
     })
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message {
     pub role: Role,
     pub content: Vec<Content>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
     User,
     Assistant,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Content {
     #[serde(rename = "text")]
-    Text { text: String },
+    Text {
+        text: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
     #[serde(rename = "image")]
     Image { source: ImageSource },
+    #[serde(rename = "document")]
+    Document {
+        source: DocumentSource,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
     #[serde(rename = "tool_use")]
     ToolUse {
         id: String,
@@ -274,7 +325,24 @@ pub enum Content {
     },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Marks the end of a prefix of the request that Anthropic should cache, so
+/// that later requests sharing that prefix are cheaper and faster.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheControl {
+    #[serde(rename = "type")]
+    pub cache_type: CacheControlType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheControlType {
+    Ephemeral,
+}
+
+/// The most cache breakpoints Anthropic accepts in a single request.
+pub const MAX_CACHE_BREAKPOINTS: usize = 4;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ImageSource {
     #[serde(rename = "type")]
     pub source_type: String,
@@ -282,14 +350,22 @@ pub struct ImageSource {
     pub data: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocumentSource {
+    #[serde(rename = "type")]
+    pub source_type: String,
+    pub media_type: String,
+    pub data: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Tool {
     pub name: String,
     pub description: String,
     pub input_schema: serde_json::Value,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ToolChoice {
     Auto,
@@ -297,7 +373,7 @@ pub enum ToolChoice {
     Tool { name: String },
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Request {
     pub model: String,
     pub max_tokens: u32,
@@ -318,6 +394,29 @@ pub struct Request {
     pub top_k: Option<u32>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub top_p: Option<f32>,
+    /// Top-level parameters that aren't modeled above, such as experimental
+    /// ones, sent as they are.
+    #[serde(flatten)]
+    pub extra_body: serde_json::Map<String, serde_json::Value>,
+}
+
+impl Request {
+    /// The top-level keys of a serialized request, including `stream`, which
+    /// [`Self::extra_body`] must not contain.
+    pub const RESERVED_KEYS: &'static [&'static str] = &[
+        "model",
+        "max_tokens",
+        "messages",
+        "tools",
+        "tool_choice",
+        "system",
+        "metadata",
+        "stop_sequences",
+        "temperature",
+        "top_k",
+        "top_p",
+        "stream",
+    ];
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -327,7 +426,7 @@ struct StreamingRequest {
     pub stream: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Metadata {
     pub user_id: Option<String>,
 }
@@ -443,4 +542,8 @@ impl ApiError {
             _ => false,
         }
     }
+
+    pub fn is_overloaded_error(&self) -> bool {
+        self.code() == Some(ApiErrorCode::OverloadedError)
+    }
 }
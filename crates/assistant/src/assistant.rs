@@ -57,7 +57,10 @@ actions!(
         DeployPromptLibrary,
         ConfirmCommand,
         ToggleModelSelector,
-        DebugWorkflowSteps
+        DebugWorkflowSteps,
+        DebugTranscripts,
+        RetryLastAssist,
+        CancelAllCompletions
     ]
 );
 
@@ -222,6 +225,11 @@ pub fn init(fs: Arc<dyn Fs>, client: Arc<Client>, cx: &mut AppContext) -> Arc<Pr
     );
     IndexedDocsRegistry::init_global(cx);
 
+    cx.on_action(|_: &CancelAllCompletions, cx| {
+        LanguageModelRegistry::global(cx)
+            .update(cx, |registry, cx| registry.cancel_all_completions(cx));
+    });
+
     CommandPaletteFilter::update_global(cx, |filter, _cx| {
         filter.hide_namespace(Assistant::NAMESPACE);
     });
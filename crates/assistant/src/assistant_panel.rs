@@ -12,10 +12,11 @@ use crate::{
     },
     terminal_inline_assistant::TerminalInlineAssistant,
     Assist, ConfirmCommand, Context, ContextEvent, ContextId, ContextStore, CycleMessageRole,
-    DebugWorkflowSteps, DeployHistory, DeployPromptLibrary, InlineAssist, InlineAssistId,
-    InlineAssistant, InsertIntoEditor, MessageStatus, ModelSelector, PendingSlashCommand,
-    PendingSlashCommandStatus, QuoteSelection, RemoteContextMetadata, ResolvedWorkflowStep,
-    SavedContextMetadata, Split, ToggleFocus, ToggleModelSelector,
+    DebugTranscripts, DebugWorkflowSteps, DeployHistory, DeployPromptLibrary, InlineAssist,
+    InlineAssistId, InlineAssistant, InsertIntoEditor, MessageAnchor, MessageStatus, ModelSelector,
+    PendingSlashCommand, PendingSlashCommandStatus, QuoteSelection, RemoteContextMetadata,
+    ResolvedWorkflowStep, RetryLastAssist, SavedContextMetadata, Split, ToggleFocus,
+    ToggleModelSelector,
 };
 use crate::{ContextStoreEvent, ShowConfiguration};
 use anyhow::{anyhow, Result};
@@ -46,8 +47,8 @@ use language::{
     language_settings::SoftWrap, Capability, LanguageRegistry, LspAdapterDelegate, Point, ToOffset,
 };
 use language_model::{
-    provider::cloud::PROVIDER_ID, LanguageModelProvider, LanguageModelProviderId,
-    LanguageModelRegistry, Role,
+    provider::cloud::PROVIDER_ID, settings::AllLanguageModelSettings, LanguageModelProvider,
+    LanguageModelProviderId, LanguageModelRegistry, Role, TranscriptLog,
 };
 use multi_buffer::MultiBufferRow;
 use picker::{Picker, PickerDelegate};
@@ -100,7 +101,8 @@ pub fn init(cx: &mut AppContext) {
                 .register_action(AssistantPanel::inline_assist)
                 .register_action(ContextEditor::quote_selection)
                 .register_action(ContextEditor::insert_selection)
-                .register_action(AssistantPanel::show_configuration);
+                .register_action(AssistantPanel::show_configuration)
+                .register_action(AssistantPanel::debug_transcripts);
         },
     )
     .detach();
@@ -495,6 +497,7 @@ impl AssistantPanel {
                     language_model::Event::AddedProvider(_)
                     | language_model::Event::RemovedProvider(_) => {
                         this.ensure_authenticated(cx);
+                        cx.notify()
                     }
                 },
             ),
@@ -644,8 +647,9 @@ impl AssistantPanel {
         let active_provider = LanguageModelRegistry::read_global(cx).active_provider();
 
         // If we're signed out and don't have a provider configured, or we're signed-out AND Zed.dev is
-        // the provider, we want to show a nudge to sign in.
+        // the provider, we want to show a nudge to sign in, unless the user has turned Zed.dev off.
         let show_zed_ai_notice = client_status.is_signed_out()
+            && AllLanguageModelSettings::get_global(cx).zed_dot_dev.enabled
             && active_provider.map_or(true, |provider| provider.id().0 == PROVIDER_ID);
 
         self.show_zed_ai_notice = show_zed_ai_notice;
@@ -1034,6 +1038,25 @@ impl AssistantPanel {
         })
     }
 
+    fn debug_transcripts(
+        workspace: &mut Workspace,
+        _: &DebugTranscripts,
+        cx: &mut ViewContext<Workspace>,
+    ) {
+        let output = if AllLanguageModelSettings::get_global(cx).record_transcripts {
+            TranscriptLog::global(cx).to_markdown()
+        } else {
+            "Enable `language_models.record_transcripts` to record transcripts.\n".to_string()
+        };
+
+        let editor = Editor::new_in_workspace(workspace, cx);
+        cx.spawn(|_, mut cx| async move {
+            let editor = editor.await?;
+            editor.update(&mut cx, |editor, cx| editor.set_text(output, cx))
+        })
+        .detach_and_notify_err(cx);
+    }
+
     fn show_configuration_tab(&mut self, cx: &mut ViewContext<Self>) {
         let configuration_item_ix = self
             .pane
@@ -1055,12 +1078,19 @@ impl AssistantPanel {
                             .active_provider()
                             .map_or(true, |p| p.id() != provider.id())
                         {
-                            if let Some(model) = provider.provided_models(cx).first().cloned() {
-                                update_settings_file::<AssistantSettings>(
-                                    this.fs.clone(),
-                                    cx,
-                                    move |settings, _| settings.set_model(model),
-                                );
+                            match LanguageModelRegistry::read_global(cx)
+                                .provider_models(provider.as_ref(), cx)
+                            {
+                                Ok(models) => {
+                                    if let Some(model) = models.first().cloned() {
+                                        update_settings_file::<AssistantSettings>(
+                                            this.fs.clone(),
+                                            cx,
+                                            move |settings, _| settings.set_model(model),
+                                        );
+                                    }
+                                }
+                                Err(error) => log::warn!("{error}"),
                             }
                         }
 
@@ -1226,6 +1256,65 @@ impl AssistantPanel {
                 |provider| provider.authenticate(cx),
             )
     }
+
+    fn is_showing_configuration(&self, cx: &AppContext) -> bool {
+        self.pane
+            .read(cx)
+            .active_item()
+            .map_or(false, |item| item.downcast::<ConfigurationView>().is_some())
+    }
+
+    fn render_onboarding(&self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        let providers = LanguageModelRegistry::read_global(cx).providers();
+
+        v_flex()
+            .id("assistant-onboarding")
+            .size_full()
+            .p(Spacing::XXLarge.rems(cx))
+            .gap_6()
+            .bg(cx.theme().colors().editor_background)
+            .overflow_y_scroll()
+            .child(
+                v_flex()
+                    .gap_1()
+                    .child(Headline::new("Welcome to the Assistant").size(HeadlineSize::Medium))
+                    .child(
+                        Label::new(
+                            "Set up one of these LLM providers to start using the Assistant.",
+                        )
+                        .color(Color::Muted),
+                    ),
+            )
+            .child(
+                v_flex()
+                    .gap_2()
+                    .children(providers.into_iter().map(|provider| {
+                        h_flex()
+                            .p_2()
+                            .gap_2()
+                            .justify_between()
+                            .border_1()
+                            .border_color(cx.theme().colors().border_variant)
+                            .rounded_md()
+                            .child(
+                                h_flex()
+                                    .gap_2()
+                                    .child(Icon::new(provider.icon()).color(Color::Muted))
+                                    .child(Label::new(provider.name().0)),
+                            )
+                            .child(
+                                Button::new(
+                                    SharedString::from(format!("set-up-{}", provider.id().0)),
+                                    "Set up",
+                                )
+                                .style(ButtonStyle::Filled)
+                                .on_click(
+                                    cx.listener(|this, _, cx| this.show_configuration_tab(cx)),
+                                ),
+                            )
+                    })),
+            )
+    }
 }
 
 impl Render for AssistantPanel {
@@ -1256,7 +1345,13 @@ impl Render for AssistantPanel {
             .on_action(cx.listener(AssistantPanel::deploy_history))
             .on_action(cx.listener(AssistantPanel::deploy_prompt_library))
             .on_action(cx.listener(AssistantPanel::toggle_model_selector))
-            .child(registrar.size_full().child(self.pane.clone()))
+            .map(|this| {
+                if needs_onboarding(cx) && !self.is_showing_configuration(cx) {
+                    this.child(self.render_onboarding(cx))
+                } else {
+                    this.child(registrar.size_full().child(self.pane.clone()))
+                }
+            })
             .into_any_element()
     }
 }
@@ -1937,8 +2032,26 @@ impl ContextEditor {
         }
     }
 
+    fn retry_last_assist(&mut self, _: &RetryLastAssist, cx: &mut ViewContext<Self>) {
+        self.error_message = None;
+        let user_message = self
+            .context
+            .update(cx, |context, cx| context.retry_last(cx));
+        self.select_queued_user_message(user_message, cx);
+        cx.notify();
+    }
+
     fn send_to_model(&mut self, cx: &mut ViewContext<Self>) {
-        if let Some(user_message) = self.context.update(cx, |context, cx| context.assist(cx)) {
+        let user_message = self.context.update(cx, |context, cx| context.assist(cx));
+        self.select_queued_user_message(user_message, cx);
+    }
+
+    fn select_queued_user_message(
+        &mut self,
+        user_message: Option<MessageAnchor>,
+        cx: &mut ViewContext<Self>,
+    ) {
+        if let Some(user_message) = user_message {
             let new_selection = {
                 let cursor = user_message
                     .start
@@ -2153,6 +2266,9 @@ impl ContextEditor {
                     context.save(Some(Duration::from_millis(500)), self.fs.clone(), cx);
                 });
             }
+            ContextEvent::ModelLoadingChanged => {
+                self.update_message_headers(cx);
+            }
             ContextEvent::WorkflowStepsRemoved(removed) => {
                 self.remove_workflow_steps(removed, cx);
                 cx.notify();
@@ -3004,6 +3120,13 @@ impl ContextEditor {
                                 .relative()
                                 .gap_1()
                                 .child(sender)
+                                .children(context.read(cx).is_model_loading_for(message_id).then(
+                                    || {
+                                        Label::new("Loading model…")
+                                            .size(LabelSize::Small)
+                                            .color(Color::Muted)
+                                    },
+                                ))
                                 .children(
                                     if let MessageStatus::Error(error) = message.status.clone() {
                                         Some(
@@ -3360,6 +3483,25 @@ impl ContextEditor {
                     )
                     .into_any_element(),
             )
+        } else if let Some(usage_warning) = LanguageModelRegistry::read_global(cx)
+            .active_provider()
+            .and_then(|provider| provider.usage_warning(cx))
+        {
+            Some(
+                h_flex()
+                    .p_3()
+                    .gap_3()
+                    .border_b_1()
+                    .border_color(cx.theme().colors().border_variant)
+                    .bg(cx.theme().colors().editor_background)
+                    .child(
+                        Icon::new(IconName::ExclamationTriangle)
+                            .size(IconSize::Small)
+                            .color(Color::Warning),
+                    )
+                    .child(Label::new(usage_warning))
+                    .into_any_element(),
+            )
         } else {
             None
         }
@@ -3424,6 +3566,22 @@ impl ContextEditor {
             })
     }
 
+    fn render_retry_button(&self, cx: &mut ViewContext<Self>) -> Option<impl IntoElement> {
+        let error_message = self.error_message.clone()?;
+        self.context.read(cx).last_request()?;
+        let focus_handle = self.focus_handle(cx).clone();
+        Some(
+            ButtonLike::new("retry_button")
+                .style(ButtonStyle::Tinted(TintColor::Negative))
+                .tooltip(move |cx| Tooltip::text(error_message.clone(), cx))
+                .layer(ElevationIndex::ModalSurface)
+                .child(Label::new("Retry"))
+                .on_click(move |_event, cx| {
+                    focus_handle.dispatch_action(&RetryLastAssist, cx);
+                }),
+        )
+    }
+
     fn active_workflow_step_for_cursor(&self, cx: &AppContext) -> Option<ActiveWorkflowStep> {
         let newest_cursor = self.editor.read(cx).selections.newest::<usize>(cx).head();
         let context = self.context.read(cx);
@@ -3474,6 +3632,7 @@ impl Render for ContextEditor {
             .capture_action(cx.listener(ContextEditor::cycle_message_role))
             .capture_action(cx.listener(ContextEditor::confirm_command))
             .on_action(cx.listener(ContextEditor::assist))
+            .on_action(cx.listener(ContextEditor::retry_last_assist))
             .on_action(cx.listener(ContextEditor::split))
             .on_action(cx.listener(ContextEditor::debug_workflow_steps))
             .size_full()
@@ -3507,6 +3666,8 @@ impl Render for ContextEditor {
                         .right_4()
                         .bottom_2()
                         .justify_end()
+                        .gap_1()
+                        .children(self.render_retry_button(cx))
                         .child(self.render_send_button(cx)),
                 ),
             )
@@ -3961,6 +4122,17 @@ impl Render for ContextEditorToolbarItem {
                                                     .color(Color::Muted),
                                             )
                                             .into_any_element(),
+                                        (Some(provider), None) => Label::new(
+                                            LanguageModelRegistry::read_global(cx)
+                                                .provider_models(provider.as_ref(), cx)
+                                                .err()
+                                                .map_or("No model selected".into(), |error| {
+                                                    error.to_string()
+                                                }),
+                                        )
+                                        .size(LabelSize::Small)
+                                        .color(Color::Muted)
+                                        .into_any_element(),
                                         _ => Label::new("No model selected")
                                             .size(LabelSize::Small)
                                             .color(Color::Muted)
@@ -4191,6 +4363,16 @@ impl ConfigurationView {
                         )
                     }),
             )
+            .children(provider.diagnostics(cx).into_iter().map(|diagnostic| {
+                h_flex()
+                    .gap_2()
+                    .child(
+                        Icon::new(IconName::ExclamationTriangle)
+                            .size(IconSize::Small)
+                            .color(Color::Warning),
+                    )
+                    .child(Label::new(diagnostic.message).size(LabelSize::Small))
+            }))
             .child(
                 div()
                     .p(Spacing::Large.rems(cx))
@@ -4523,3 +4705,29 @@ fn configuration_error(cx: &AppContext) -> Option<ConfigurationError> {
 
     None
 }
+
+/// Whether none of the LLM providers are authenticated, in which case the
+/// Assistant can't be used until one of them is set up.
+fn needs_onboarding(cx: &AppContext) -> bool {
+    LanguageModelRegistry::read_global(cx)
+        .providers()
+        .iter()
+        .all(|provider| !provider.is_authenticated(cx))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[gpui::test]
+    fn test_onboarding_without_authenticated_providers(cx: &mut AppContext) {
+        let provider = LanguageModelRegistry::test(cx);
+        assert!(!needs_onboarding(cx));
+
+        provider.set_authenticated(false);
+        assert!(needs_onboarding(cx));
+
+        provider.set_authenticated(true);
+        assert!(!needs_onboarding(cx));
+    }
+}
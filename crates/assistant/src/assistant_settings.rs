@@ -115,7 +115,8 @@ impl AssistantSettingsContent {
                                                 language_model::settings::AnthropicSettingsContentV1 {
                                                     api_url,
                                                     low_speed_timeout_in_seconds,
-                                                    available_models: None
+                                                    available_models: None,
+                                                    disabled_models: None,
                                                 }
                                             )
                                         ));
@@ -153,8 +154,8 @@ impl AssistantSettingsContent {
                                         models
                                             .into_iter()
                                             .filter_map(|model| match model {
-                                                open_ai::Model::Custom { name, max_tokens } => {
-                                                    Some(language_model::provider::open_ai::AvailableModel { name, max_tokens })
+                                                open_ai::Model::Custom { name, display_name, max_tokens } => {
+                                                    Some(language_model::provider::open_ai::AvailableModel { name, display_name, max_tokens })
                                                 }
                                                 _ => None,
                                             })
@@ -166,7 +167,9 @@ impl AssistantSettingsContent {
                                                 language_model::settings::OpenAiSettingsContentV1 {
                                                     api_url,
                                                     low_speed_timeout_in_seconds,
-                                                    available_models
+                                                    available_models,
+                                                    disabled_models: None,
+                                                    include_stream_usage: None,
                                                 }
                                             )
                                         ));
@@ -12,6 +12,7 @@ use collections::{HashMap, HashSet};
 use editor::Editor;
 use fs::{Fs, RemoveOptions};
 use futures::{
+    channel::mpsc,
     future::{self, Shared},
     FutureExt, StreamExt,
 };
@@ -24,8 +25,8 @@ use language::{
     Point, ToOffset,
 };
 use language_model::{
-    LanguageModelRegistry, LanguageModelRequest, LanguageModelRequestMessage, LanguageModelTool,
-    Role,
+    EmptyAssistantMessages, LanguageModelRegistry, LanguageModelRequest,
+    LanguageModelRequestMessage, LanguageModelTool, RequestPriority, Role,
 };
 use open_ai::Model as OpenAiModel;
 use paths::contexts_dir;
@@ -287,6 +288,8 @@ pub enum ContextEvent {
     WorkflowStepsRemoved(Vec<Range<language::Anchor>>),
     WorkflowStepUpdated(Range<language::Anchor>),
     StreamedCompletion,
+    /// The model started or stopped loading before responding to a message.
+    ModelLoadingChanged,
     PendingSlashCommandsUpdated {
         removed: Vec<Range<language::Anchor>>,
         updated: Vec<PendingSlashCommand>,
@@ -334,6 +337,8 @@ impl Message {
         LanguageModelRequestMessage {
             role: self.role,
             content: buffer.text_for_range(self.offset_range.clone()).collect(),
+            attachments: Vec::new(),
+            cache: false,
         }
     }
 }
@@ -610,6 +615,8 @@ pub struct Context {
     pending_summary: Task<Option<()>>,
     completion_count: usize,
     pending_completions: Vec<PendingCompletion>,
+    model_loading_for: Option<MessageId>,
+    last_request: Option<LanguageModelRequest>,
     token_count: Option<usize>,
     pending_token_count: Task<Option<()>>,
     pending_save: Task<Result<()>>,
@@ -686,6 +693,8 @@ impl Context {
             pending_summary: Task::ready(None),
             completion_count: Default::default(),
             pending_completions: Default::default(),
+            model_loading_for: None,
+            last_request: None,
             token_count: None,
             pending_token_count: Task::ready(None),
             _subscriptions: vec![cx.subscribe(&buffer, Self::handle_buffer_event)],
@@ -1019,6 +1028,23 @@ impl Context {
         self.summary.as_ref()
     }
 
+    /// Whether the model is still loading before it can respond to the
+    /// given assistant message, as local models may take a while to.
+    pub fn is_model_loading_for(&self, message_id: MessageId) -> bool {
+        self.model_loading_for == Some(message_id)
+    }
+
+    fn set_model_loading_for(
+        &mut self,
+        message_id: Option<MessageId>,
+        cx: &mut ModelContext<Self>,
+    ) {
+        if self.model_loading_for != message_id {
+            self.model_loading_for = message_id;
+            cx.emit(ContextEvent::ModelLoadingChanged);
+        }
+    }
+
     pub fn workflow_steps(&self) -> &[WorkflowStep] {
         &self.workflow_steps
     }
@@ -1337,6 +1363,8 @@ impl Context {
                         request.messages.push(LanguageModelRequestMessage {
                             role: Role::User,
                             content: prompt,
+                            attachments: Vec::new(),
+                            cache: false,
                         });
 
                         // Invoke the model to get its edit suggestions for this workflow step.
@@ -1615,8 +1643,36 @@ impl Context {
     }
 
     pub fn assist(&mut self, cx: &mut ModelContext<Self>) -> Option<MessageAnchor> {
+        let request = self.to_completion_request(cx);
+        self.assist_with_request(request, cx)
+    }
+
+    /// Sends the request that was made by the last call to [`Self::assist`]
+    /// again, without rebuilding it from the messages in the context, e.g.
+    /// after the completion failed.
+    pub fn retry_last(&mut self, cx: &mut ModelContext<Self>) -> Option<MessageAnchor> {
+        let request = self.last_request.clone()?;
+        self.assist_with_request(request, cx)
+    }
+
+    pub fn last_request(&self) -> Option<&LanguageModelRequest> {
+        self.last_request.as_ref()
+    }
+
+    fn assist_with_request(
+        &mut self,
+        request: LanguageModelRequest,
+        cx: &mut ModelContext<Self>,
+    ) -> Option<MessageAnchor> {
         let provider = LanguageModelRegistry::read_global(cx).active_provider()?;
-        let model = LanguageModelRegistry::read_global(cx).active_model()?;
+        let Some(model) = LanguageModelRegistry::read_global(cx).active_model() else {
+            if let Err(error) =
+                LanguageModelRegistry::read_global(cx).provider_models(provider.as_ref(), cx)
+            {
+                cx.emit(ContextEvent::AssistError(error.to_string()));
+            }
+            return None;
+        };
         let last_message_id = self.message_anchors.iter().rev().find_map(|message| {
             message
                 .start
@@ -1629,7 +1685,7 @@ impl Context {
             return None;
         }
 
-        let request = self.to_completion_request(cx);
+        self.last_request = Some(request.clone());
         let assistant_message = self
             .insert_message_after(last_message_id, Role::Assistant, MessageStatus::Pending, cx)
             .unwrap();
@@ -1641,8 +1697,29 @@ impl Context {
 
         let task = cx.spawn({
             |this, mut cx| async move {
-                let stream = model.stream_completion(request, &cx);
+                let (model_loading_tx, mut model_loading_rx) = mpsc::unbounded();
+                let stream = model.stream_completion_text_reporting_loading(
+                    request,
+                    Arc::new(move || {
+                        model_loading_tx.unbounded_send(()).ok();
+                    }),
+                    &cx,
+                );
                 let assistant_message_id = assistant_message.id;
+                // The channel closes once the stream is dropped, so this ends
+                // with the completion.
+                let show_model_loading = {
+                    let this = this.clone();
+                    let mut cx = cx.clone();
+                    async move {
+                        while model_loading_rx.next().await.is_some() {
+                            this.update(&mut cx, |this, cx| {
+                                this.set_model_loading_for(Some(assistant_message_id), cx)
+                            })
+                            .ok();
+                        }
+                    }
+                };
                 let mut response_latency = None;
                 let stream_completion = async {
                     let request_start = Instant::now();
@@ -1655,6 +1732,7 @@ impl Context {
                         let chunk = chunk?;
 
                         this.update(&mut cx, |this, cx| {
+                            this.set_model_loading_for(None, cx);
                             let message_ix = this
                                 .message_anchors
                                 .iter()
@@ -1697,9 +1775,10 @@ impl Context {
                     anyhow::Ok(())
                 };
 
-                let result = stream_completion.await;
+                let (result, ()) = future::join(stream_completion, show_model_loading).await;
 
                 this.update(&mut cx, |this, cx| {
+                    this.set_model_loading_for(None, cx);
                     let error_message = result
                         .err()
                         .map(|error| error.to_string().trim().to_string());
@@ -1749,6 +1828,19 @@ impl Context {
             messages: messages.collect(),
             stop: vec![],
             temperature: 1.0,
+            top_k: None,
+            logit_bias: None,
+            max_messages: None,
+            stop_regex: None,
+            conversation_id: Some(self.id.to_proto()),
+            experiment: None,
+            pin_model_version: false,
+            priority: RequestPriority::Normal,
+            tool_choice: None,
+            response_format: None,
+            empty_assistant_messages: EmptyAssistantMessages::default(),
+            extra_body: None,
+            tags: Default::default(),
         }
     }
 
@@ -2005,16 +2097,31 @@ impl Context {
                 .chain(Some(LanguageModelRequestMessage {
                     role: Role::User,
                     content: "Summarize the context into a short title without punctuation.".into(),
+                    attachments: Vec::new(),
+                    cache: false,
                 }));
             let request = LanguageModelRequest {
                 messages: messages.collect(),
                 stop: vec![],
                 temperature: 1.0,
+                top_k: None,
+                logit_bias: None,
+                max_messages: None,
+                stop_regex: None,
+                conversation_id: Some(self.id.to_proto()),
+                experiment: None,
+                pin_model_version: false,
+                priority: RequestPriority::Low,
+                tool_choice: None,
+                response_format: None,
+                empty_assistant_messages: EmptyAssistantMessages::default(),
+                extra_body: None,
+                tags: Default::default(),
             };
 
             self.pending_summary = cx.spawn(|this, mut cx| {
                 async move {
-                    let stream = model.stream_completion(request, &cx);
+                    let stream = model.stream_completion_text(request, &cx);
                     let mut messages = stream.await?;
 
                     let mut replaced = !replace_old;
@@ -3179,6 +3286,48 @@ mod tests {
         }
     }
 
+    #[gpui::test]
+    async fn test_retry_last(cx: &mut TestAppContext) {
+        let settings_store = cx.update(SettingsStore::test);
+        cx.set_global(settings_store);
+        cx.update(LanguageModelRegistry::test);
+        cx.update(assistant_panel::init);
+        let model = cx.read(|cx| {
+            LanguageModelRegistry::read_global(cx)
+                .active_model()
+                .unwrap()
+        });
+        let registry = Arc::new(LanguageRegistry::test(cx.executor()));
+        let prompt_builder = Arc::new(PromptBuilder::new(None).unwrap());
+        let context = cx.new_model(|cx| Context::local(registry, None, None, prompt_builder, cx));
+        let buffer = context.read_with(cx, |context, _| context.buffer.clone());
+
+        buffer.update(cx, |buffer, cx| buffer.edit([(0..0, "Hello")], None, cx));
+        context
+            .update(cx, |context, cx| context.assist(cx))
+            .unwrap();
+        cx.run_until_parked();
+        let request = context.read_with(cx, |context, _| context.last_request().cloned().unwrap());
+        assert_eq!(model.as_fake().pending_completions(), [request.clone()]);
+
+        // The context changes after the completion is abandoned, but retrying
+        // sends the request exactly as it was first made.
+        context.update(cx, |context, _| context.cancel_last_assist());
+        buffer.update(cx, |buffer, cx| buffer.edit([(5..5, ", world")], None, cx));
+        assert_ne!(
+            context.read_with(cx, |context, cx| context.to_completion_request(cx)),
+            request
+        );
+        context
+            .update(cx, |context, cx| context.retry_last(cx))
+            .unwrap();
+        cx.run_until_parked();
+        assert_eq!(
+            model.as_fake().pending_completions(),
+            [request.clone(), request]
+        );
+    }
+
     #[gpui::test]
     async fn test_serialization(cx: &mut TestAppContext) {
         let settings_store = cx.update(SettingsStore::test);
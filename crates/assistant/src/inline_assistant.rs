@@ -29,7 +29,8 @@ use gpui::{
 };
 use language::{Buffer, IndentKind, Point, Selection, TransactionId};
 use language_model::{
-    LanguageModelRegistry, LanguageModelRequest, LanguageModelRequestMessage, Role,
+    EmptyAssistantMessages, LanguageModelRegistry, LanguageModelRequest,
+    LanguageModelRequestMessage, RequestPriority, Role,
 };
 use multi_buffer::MultiBufferRow;
 use parking_lot::Mutex;
@@ -1419,7 +1420,9 @@ impl Render for PromptEditor {
                                         format!(
                                             "Using {}",
                                             LanguageModelRegistry::read_global(cx)
-                                                .active_model()
+                                                .default_model(cx)
+                                                .ok()
+                                                .flatten()
                                                 .map(|model| model.name().0)
                                                 .unwrap_or_else(|| "No model selected".into()),
                                         ),
@@ -1780,7 +1783,9 @@ impl PromptEditor {
     }
 
     fn render_token_count(&self, cx: &mut ViewContext<Self>) -> Option<impl IntoElement> {
-        let model = LanguageModelRegistry::read_global(cx).active_model()?;
+        let model = LanguageModelRegistry::read_global(cx)
+            .default_model(cx)
+            .ok()??;
         let token_count = self.token_count?;
         let max_token_count = model.max_token_count();
 
@@ -2217,14 +2222,17 @@ impl Codegen {
         assistant_panel_context: Option<LanguageModelRequest>,
         cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>> {
-        if let Some(model) = LanguageModelRegistry::read_global(cx).active_model() {
-            let request = self.build_request(user_prompt, assistant_panel_context, edit_range, cx);
-            match request {
-                Ok(request) => model.count_tokens(request, cx),
-                Err(error) => futures::future::ready(Err(error)).boxed(),
+        match LanguageModelRegistry::read_global(cx).default_model(cx) {
+            Ok(Some(model)) => {
+                let request =
+                    self.build_request(user_prompt, assistant_panel_context, edit_range, cx);
+                match request {
+                    Ok(request) => model.count_tokens(request, cx),
+                    Err(error) => futures::future::ready(Err(error)).boxed(),
+                }
             }
-        } else {
-            future::ready(Err(anyhow!("no active model"))).boxed()
+            Ok(None) => future::ready(Err(anyhow!("no active model"))).boxed(),
+            Err(error) => future::ready(Err(error.into())).boxed(),
         }
     }
 
@@ -2236,7 +2244,7 @@ impl Codegen {
         cx: &mut ModelContext<Self>,
     ) -> Result<()> {
         let model = LanguageModelRegistry::read_global(cx)
-            .active_model()
+            .default_model(cx)?
             .context("no active model")?;
 
         if let Some(transformation_transaction_id) = self.transformation_transaction_id.take() {
@@ -2259,7 +2267,7 @@ impl Codegen {
                 self.build_request(user_prompt, assistant_panel_context, edit_range.clone(), cx)?;
 
             let chunks =
-                cx.spawn(|_, cx| async move { model.stream_completion(request, &cx).await });
+                cx.spawn(|_, cx| async move { model.stream_completion_text(request, &cx).await });
             async move { Ok(chunks.await?.boxed()) }.boxed_local()
         };
         self.handle_stream(telemetry_id, edit_range, chunks, cx);
@@ -2325,12 +2333,27 @@ impl Codegen {
         messages.push(LanguageModelRequestMessage {
             role: Role::User,
             content: prompt,
+            attachments: Vec::new(),
+            cache: false,
         });
 
         Ok(LanguageModelRequest {
             messages,
             stop: vec!["|END|>".to_string()],
             temperature,
+            top_k: None,
+            logit_bias: None,
+            max_messages: None,
+            stop_regex: None,
+            conversation_id: None,
+            experiment: None,
+            pin_model_version: false,
+            priority: RequestPriority::Normal,
+            tool_choice: None,
+            response_format: None,
+            empty_assistant_messages: EmptyAssistantMessages::default(),
+            extra_body: None,
+            tags: Default::default(),
         })
     }
 
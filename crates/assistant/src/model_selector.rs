@@ -1,6 +1,9 @@
 use feature_flags::ZedPro;
 use gpui::DismissEvent;
-use language_model::{LanguageModel, LanguageModelAvailability, LanguageModelRegistry};
+use language_model::{
+    LanguageModel, LanguageModelAvailability, LanguageModelId, LanguageModelName,
+    LanguageModelProviderName, LanguageModelRegistry,
+};
 use proto::Plan;
 
 use std::sync::Arc;
@@ -30,12 +33,14 @@ pub struct ModelPickerDelegate {
     fs: Arc<dyn Fs>,
     all_models: Vec<ModelInfo>,
     filtered_models: Vec<ModelInfo>,
+    providers_without_models: Vec<SharedString>,
     selected_index: usize,
 }
 
 #[derive(Clone)]
 struct ModelInfo {
     model: Arc<dyn LanguageModel>,
+    label: SharedString,
     provider_icon: IconName,
     availability: LanguageModelAvailability,
     is_selected: bool,
@@ -82,6 +87,18 @@ impl PickerDelegate for ModelPickerDelegate {
         "Select a model...".into()
     }
 
+    fn no_matches_text(&self, _cx: &mut WindowContext) -> SharedString {
+        if self.all_models.is_empty() && !self.providers_without_models.is_empty() {
+            format!(
+                "No models available for {}",
+                self.providers_without_models.join(", ")
+            )
+            .into()
+        } else {
+            "No matches".into()
+        }
+    }
+
     fn update_matches(&mut self, query: String, cx: &mut ViewContext<Picker<Self>>) -> Task<()> {
         let all_models = self.all_models.clone();
         cx.spawn(|this, mut cx| async move {
@@ -95,9 +112,7 @@ impl PickerDelegate for ModelPickerDelegate {
                             .into_iter()
                             .filter(|model_info| {
                                 model_info
-                                    .model
-                                    .name()
-                                    .0
+                                    .label
                                     .to_lowercase()
                                     .contains(&query.to_lowercase())
                             })
@@ -170,7 +185,7 @@ impl PickerDelegate for ModelPickerDelegate {
                         .child(
                             h_flex()
                                 .gap_2()
-                                .child(Label::new(model_info.model.name().0.clone()))
+                                .child(Label::new(model_info.label.clone()))
                                 .children(match model_info.availability {
                                     LanguageModelAvailability::Public => None,
                                     LanguageModelAvailability::RequiresPlan(Plan::Free) => None,
@@ -181,6 +196,29 @@ impl PickerDelegate for ModelPickerDelegate {
                                                 .color(Color::Muted)
                                         })
                                     }
+                                })
+                                .when(model_info.model.is_deprecated(), |this| {
+                                    this.child(
+                                        h_flex()
+                                            .gap_0p5()
+                                            .child(
+                                                Icon::new(IconName::ExclamationTriangle)
+                                                    .color(Color::Warning)
+                                                    .size(IconSize::XSmall),
+                                            )
+                                            .child(
+                                                Label::new(
+                                                    match model_info.model.deprecation_date() {
+                                                        Some(date) => {
+                                                            format!("Deprecated ({date})")
+                                                        }
+                                                        None => "Deprecated".to_string(),
+                                                    },
+                                                )
+                                                .size(LabelSize::XSmall)
+                                                .color(Color::Warning),
+                                            ),
+                                    )
                                 }),
                         )
                         .child(div().when(model_info.is_selected, |this| {
@@ -255,34 +293,51 @@ impl<T: PopoverTrigger> RenderOnce for ModelSelector<T> {
             .active_model()
             .map(|m| m.id());
 
-        let all_models = LanguageModelRegistry::global(cx)
-            .read(cx)
-            .providers()
-            .iter()
-            .flat_map(|provider| {
-                let provider_id = provider.id();
-                let provider_icon = provider.icon();
-                let selected_model = selected_model.clone();
-                let selected_provider = selected_provider.clone();
-
-                provider.provided_models(cx).into_iter().map(move |model| {
-                    let model = model.clone();
-
-                    ModelInfo {
-                        model: model.clone(),
-                        provider_icon,
-                        availability: model.availability(),
-                        is_selected: selected_model.as_ref() == Some(&model.id())
-                            && selected_provider.as_ref() == Some(&provider_id),
-                    }
+        let registry = LanguageModelRegistry::read_global(cx);
+        let mut providers_without_models = Vec::new();
+        let mut all_models = Vec::new();
+        for provider in registry.providers() {
+            let provider_id = provider.id();
+            let provider_icon = provider.icon();
+            let models = match registry.provider_models(provider.as_ref(), cx) {
+                Ok(models) => models,
+                Err(error) => {
+                    providers_without_models.push(error.provider.0);
+                    continue;
+                }
+            };
+
+            all_models.extend(models.into_iter().map(|model| ModelInfo {
+                availability: model.availability(),
+                is_selected: selected_model.as_ref() == Some(&model.id())
+                    && selected_provider.as_ref() == Some(&provider_id),
+                label: model.name().0,
+                model,
+                provider_icon,
+            }));
+        }
+
+        let labels = model_labels(
+            &all_models
+                .iter()
+                .map(|model_info| {
+                    (
+                        model_info.model.provider_name(),
+                        model_info.model.id(),
+                        model_info.model.name(),
+                    )
                 })
-            })
-            .collect::<Vec<_>>();
+                .collect::<Vec<_>>(),
+        );
+        for (model_info, label) in all_models.iter_mut().zip(labels) {
+            model_info.label = label;
+        }
 
         let delegate = ModelPickerDelegate {
             fs: self.fs.clone(),
             all_models: all_models.clone(),
             filtered_models: all_models,
+            providers_without_models,
             selected_index: 0,
         };
 
@@ -297,3 +352,59 @@ impl<T: PopoverTrigger> RenderOnce for ModelSelector<T> {
             .attach(gpui::AnchorCorner::BottomLeft)
     }
 }
+
+/// Labels each model with its name, prefixed with its provider's name when
+/// another provider offers a model with the same id or name, so that the
+/// picker's entries can be told apart.
+fn model_labels(
+    models: &[(
+        LanguageModelProviderName,
+        LanguageModelId,
+        LanguageModelName,
+    )],
+) -> Vec<SharedString> {
+    models
+        .iter()
+        .map(|(provider, id, name)| {
+            let is_ambiguous = models.iter().any(|(other_provider, other_id, other_name)| {
+                other_provider != provider && (other_id == id || other_name == name)
+            });
+            if is_ambiguous {
+                format!("{}: {}", provider.0, name.0).into()
+            } else {
+                name.0.clone()
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_labels_disambiguate_providers() {
+        let model = |provider: &str, id: &str, name: &str| {
+            (
+                LanguageModelProviderName::from(provider.to_string()),
+                LanguageModelId::from(id.to_string()),
+                LanguageModelName::from(name.to_string()),
+            )
+        };
+        let labels = model_labels(&[
+            model("Zed", "claude-3-5-sonnet", "Claude 3.5 Sonnet"),
+            model("Zed", "gpt-4o", "GPT-4o"),
+            model("Anthropic", "claude-3-5-sonnet", "Claude 3.5 Sonnet"),
+            model("Ollama", "llama3.1:latest", "llama3.1"),
+        ]);
+        assert_eq!(
+            labels.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "Zed: Claude 3.5 Sonnet",
+                "GPT-4o",
+                "Anthropic: Claude 3.5 Sonnet",
+                "llama3.1",
+            ]
+        );
+    }
+}
@@ -21,7 +21,8 @@ use heed::{
 };
 use language::{language_settings::SoftWrap, Buffer, LanguageRegistry};
 use language_model::{
-    LanguageModelRegistry, LanguageModelRequest, LanguageModelRequestMessage, Role,
+    EmptyAssistantMessages, LanguageModelRegistry, LanguageModelRequest,
+    LanguageModelRequestMessage, RequestPriority, Role,
 };
 use parking_lot::RwLock;
 use picker::{Picker, PickerDelegate};
@@ -776,9 +777,24 @@ impl PromptLibrary {
                                     messages: vec![LanguageModelRequestMessage {
                                         role: Role::System,
                                         content: body.to_string(),
+                                        attachments: Vec::new(),
+                                        cache: false,
                                     }],
                                     stop: Vec::new(),
                                     temperature: 1.,
+                                    top_k: None,
+                                    logit_bias: None,
+                                    max_messages: None,
+                                    stop_regex: None,
+                                    conversation_id: None,
+                                    experiment: None,
+                                    pin_model_version: false,
+                                    priority: RequestPriority::Low,
+                                    tool_choice: None,
+                                    response_format: None,
+                                    empty_assistant_messages: EmptyAssistantMessages::default(),
+                                    extra_body: None,
+                                    tags: Default::default(),
                                 },
                                 cx,
                             )
@@ -17,7 +17,8 @@ use gpui::{
 };
 use language::Buffer;
 use language_model::{
-    LanguageModelRegistry, LanguageModelRequest, LanguageModelRequestMessage, Role,
+    EmptyAssistantMessages, LanguageModelRegistry, LanguageModelRequest,
+    LanguageModelRequestMessage, RequestPriority, Role,
 };
 use settings::Settings;
 use std::{
@@ -277,12 +278,27 @@ impl TerminalInlineAssistant {
         messages.push(LanguageModelRequestMessage {
             role: Role::User,
             content: prompt,
+            attachments: Vec::new(),
+            cache: false,
         });
 
         Ok(LanguageModelRequest {
             messages,
             stop: Vec::new(),
             temperature: 1.0,
+            top_k: None,
+            logit_bias: None,
+            max_messages: None,
+            stop_regex: None,
+            conversation_id: None,
+            experiment: None,
+            pin_model_version: false,
+            priority: RequestPriority::Normal,
+            tool_choice: None,
+            response_format: None,
+            empty_assistant_messages: EmptyAssistantMessages::default(),
+            extra_body: None,
+            tags: Default::default(),
         })
     }
 
@@ -572,7 +588,9 @@ impl Render for PromptEditor {
                                     format!(
                                         "Using {}",
                                         LanguageModelRegistry::read_global(cx)
-                                            .active_model()
+                                            .default_model(cx)
+                                            .ok()
+                                            .flatten()
                                             .map(|model| model.name().0)
                                             .unwrap_or_else(|| "No model selected".into()),
                                     ),
@@ -715,7 +733,11 @@ impl PromptEditor {
 
     fn count_tokens(&mut self, cx: &mut ViewContext<Self>) {
         let assist_id = self.id;
-        let Some(model) = LanguageModelRegistry::read_global(cx).active_model() else {
+        let Some(model) = LanguageModelRegistry::read_global(cx)
+            .default_model(cx)
+            .ok()
+            .flatten()
+        else {
             return;
         };
         self.pending_token_count = cx.spawn(|this, mut cx| async move {
@@ -854,7 +876,9 @@ impl PromptEditor {
     }
 
     fn render_token_count(&self, cx: &mut ViewContext<Self>) -> Option<impl IntoElement> {
-        let model = LanguageModelRegistry::read_global(cx).active_model()?;
+        let model = LanguageModelRegistry::read_global(cx)
+            .default_model(cx)
+            .ok()??;
         let token_count = self.token_count?;
         let max_token_count = model.max_token_count();
 
@@ -996,8 +1020,15 @@ impl Codegen {
     }
 
     pub fn start(&mut self, prompt: LanguageModelRequest, cx: &mut ModelContext<Self>) {
-        let Some(model) = LanguageModelRegistry::read_global(cx).active_model() else {
-            return;
+        let model = match LanguageModelRegistry::read_global(cx).default_model(cx) {
+            Ok(Some(model)) => model,
+            Ok(None) => return,
+            Err(error) => {
+                self.status = CodegenStatus::Error(error.into());
+                cx.emit(CodegenEvent::Finished);
+                cx.notify();
+                return;
+            }
         };
 
         let telemetry = self.telemetry.clone();
@@ -1005,7 +1036,7 @@ impl Codegen {
         self.transaction = Some(TerminalTransaction::start(self.terminal.clone()));
         self.generation = cx.spawn(|this, mut cx| async move {
             let model_telemetry_id = model.telemetry_id();
-            let response = model.stream_completion(prompt, &cx).await;
+            let response = model.stream_completion_text(prompt, &cx).await;
             let generate = async {
                 let (mut hunks_tx, mut hunks_rx) = mpsc::channel(1);
 
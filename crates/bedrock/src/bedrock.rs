@@ -0,0 +1,460 @@
+use anyhow::{anyhow, bail, Result};
+use futures::{stream::BoxStream, AsyncRead, AsyncReadExt, Stream, StreamExt};
+use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest};
+use isahc::config::Configurable;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// Returns the Bedrock runtime endpoint for the given AWS region.
+pub fn runtime_api_url(region: &str) -> String {
+    format!("https://bedrock-runtime.{region}.amazonaws.com")
+}
+
+/// Streams a completion through the Converse API, which accepts the same
+/// request shape for every model on Bedrock, such as Nova, Titan and Llama.
+///
+/// Authenticates with a Bedrock API key.
+pub async fn stream_converse(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    request: Request,
+    low_speed_timeout: Option<Duration>,
+) -> Result<BoxStream<'static, Result<StreamEvent>>> {
+    let uri = format!(
+        "{api_url}/model/{model}/converse-stream",
+        model = request.model
+    );
+    let mut request_builder = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Authorization", format!("Bearer {api_key}"))
+        .header("Content-Type", "application/json")
+        .header("Accept", "application/vnd.amazon.eventstream");
+    if let Some(low_speed_timeout) = low_speed_timeout {
+        request_builder = request_builder.low_speed_timeout(100, low_speed_timeout);
+    }
+
+    let request = request_builder.body(AsyncBody::from(serde_json::to_string(&request)?))?;
+    let mut response = client.send(request).await?;
+    if response.status().is_success() {
+        Ok(parse_event_stream(response.into_body()).boxed())
+    } else {
+        let mut text = String::new();
+        response.body_mut().read_to_string(&mut text).await?;
+        Err(anyhow!(
+            "error during ConverseStream, status code: {:?}, body: {}",
+            response.status(),
+            text
+        ))
+    }
+}
+
+#[cfg_attr(feature = "schemars", derive(schemars::JsonSchema))]
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, strum::EnumIter)]
+pub enum Model {
+    #[serde(rename = "amazon.nova-micro-v1:0")]
+    NovaMicro,
+    #[default]
+    #[serde(rename = "amazon.nova-lite-v1:0")]
+    NovaLite,
+    #[serde(rename = "amazon.nova-pro-v1:0")]
+    NovaPro,
+    #[serde(rename = "custom")]
+    Custom {
+        /// The model id or inference profile, such as
+        /// `us.meta.llama3-2-90b-instruct-v1:0`.
+        name: String,
+        /// The name displayed in the model picker, defaulting to the model's id.
+        display_name: Option<String>,
+        max_tokens: usize,
+        max_output_tokens: Option<u32>,
+    },
+}
+
+impl Model {
+    pub fn id(&self) -> &str {
+        match self {
+            Model::NovaMicro => "amazon.nova-micro-v1:0",
+            Model::NovaLite => "amazon.nova-lite-v1:0",
+            Model::NovaPro => "amazon.nova-pro-v1:0",
+            Model::Custom { name, .. } => name,
+        }
+    }
+
+    pub fn display_name(&self) -> &str {
+        match self {
+            Model::NovaMicro => "Nova Micro",
+            Model::NovaLite => "Nova Lite",
+            Model::NovaPro => "Nova Pro",
+            Model::Custom {
+                name, display_name, ..
+            } => display_name.as_ref().unwrap_or(name),
+        }
+    }
+
+    pub fn max_token_count(&self) -> usize {
+        match self {
+            Model::NovaMicro => 128_000,
+            Model::NovaLite | Model::NovaPro => 300_000,
+            Model::Custom { max_tokens, .. } => *max_tokens,
+        }
+    }
+
+    /// The most tokens the model will generate in one response, or `None` to
+    /// leave the limit to Bedrock.
+    pub fn max_output_tokens(&self) -> Option<u32> {
+        match self {
+            Model::NovaMicro | Model::NovaLite | Model::NovaPro => Some(5_000),
+            Model::Custom {
+                max_output_tokens, ..
+            } => *max_output_tokens,
+        }
+    }
+}
+
+pub fn extract_text_from_events(
+    events: impl Stream<Item = Result<StreamEvent>>,
+) -> impl Stream<Item = Result<String>> {
+    events.filter_map(|event| async move {
+        match event {
+            Ok(StreamEvent::ContentBlockDelta {
+                delta: ContentBlockDelta::Text(text),
+                ..
+            }) => Some(Ok(text)),
+            Ok(_) => None,
+            Err(error) => Some(Err(error)),
+        }
+    })
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Request {
+    /// The model id or inference profile to send the request to, which is
+    /// part of the URL rather than the body.
+    #[serde(skip)]
+    pub model: String,
+    pub messages: Vec<Message>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub system: Vec<ContentBlock>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inference_config: Option<InferenceConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Message {
+    pub role: Role,
+    pub content: Vec<ContentBlock>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContentBlock {
+    Text(String),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InferenceConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop_sequences: Vec<String>,
+}
+
+/// An event in a `ConverseStream` response, keyed by the message's
+/// `:event-type` header.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum StreamEvent {
+    MessageStart {
+        role: Role,
+    },
+    ContentBlockStart {
+        content_block_index: usize,
+        start: serde_json::Value,
+    },
+    ContentBlockDelta {
+        content_block_index: usize,
+        delta: ContentBlockDelta,
+    },
+    ContentBlockStop {
+        content_block_index: usize,
+    },
+    MessageStop {
+        stop_reason: String,
+    },
+    Metadata {
+        usage: Usage,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ContentBlockDelta {
+    Text(String),
+    ToolUse { input: String },
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Deserialize)]
+struct ExceptionPayload {
+    message: String,
+}
+
+/// The length of the prelude at the start of each message, which holds the
+/// message's total length, the length of its headers, and a checksum.
+const PRELUDE_LEN: usize = 12;
+/// The length of the checksum at the end of each message.
+const MESSAGE_CHECKSUM_LEN: usize = 4;
+const MAX_MESSAGE_LEN: usize = 16 * 1024 * 1024;
+const STRING_HEADER_TYPE: u8 = 7;
+
+/// Parses a response in the binary `application/vnd.amazon.eventstream`
+/// framing that `ConverseStream` uses in place of server-sent events.
+///
+/// Checksums aren't verified, since the response is already protected by TLS.
+pub fn parse_event_stream(
+    reader: impl AsyncRead + Unpin + Send + 'static,
+) -> impl Stream<Item = Result<StreamEvent>> {
+    futures::stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        match read_message(&mut reader).await {
+            Ok(Some((headers, payload))) => Some((decode_event(&headers, &payload), Some(reader))),
+            Ok(None) => None,
+            Err(error) => Some((Err(error), None)),
+        }
+    })
+}
+
+async fn read_message(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<(HashMap<String, String>, Vec<u8>)>> {
+    let mut prelude = [0; PRELUDE_LEN];
+    let mut prelude_read = 0;
+    while prelude_read < PRELUDE_LEN {
+        let read = reader.read(&mut prelude[prelude_read..]).await?;
+        if read == 0 {
+            // Running out of input between messages is how the stream ends.
+            if prelude_read == 0 {
+                return Ok(None);
+            }
+            bail!("event stream ended in the middle of a message");
+        }
+        prelude_read += read;
+    }
+
+    let total_len = u32::from_be_bytes(prelude[0..4].try_into()?) as usize;
+    let headers_len = u32::from_be_bytes(prelude[4..8].try_into()?) as usize;
+    if total_len > MAX_MESSAGE_LEN || total_len < PRELUDE_LEN + headers_len + MESSAGE_CHECKSUM_LEN {
+        bail!("invalid event stream message length {total_len}");
+    }
+
+    let mut message = vec![0; total_len - PRELUDE_LEN];
+    reader.read_exact(&mut message).await?;
+    let headers = parse_headers(&message[..headers_len])?;
+    let payload = message[headers_len..message.len() - MESSAGE_CHECKSUM_LEN].to_vec();
+    Ok(Some((headers, payload)))
+}
+
+/// Parses the headers of an event stream message, keeping only those with
+/// string values, which are the only ones Bedrock sends.
+fn parse_headers(mut bytes: &[u8]) -> Result<HashMap<String, String>> {
+    let mut headers = HashMap::new();
+    while !bytes.is_empty() {
+        let name_len = take(&mut bytes, 1)?[0] as usize;
+        let name = std::str::from_utf8(take(&mut bytes, name_len)?)?.to_string();
+        let value_type = take(&mut bytes, 1)?[0];
+        let value_len = match value_type {
+            0 | 1 => 0,
+            2 => 1,
+            3 => 2,
+            4 => 4,
+            5 | 8 => 8,
+            6 | STRING_HEADER_TYPE => u16::from_be_bytes(take(&mut bytes, 2)?.try_into()?) as usize,
+            9 => 16,
+            _ => bail!("unknown event stream header type {value_type}"),
+        };
+        let value = take(&mut bytes, value_len)?;
+        if value_type == STRING_HEADER_TYPE {
+            headers.insert(name, std::str::from_utf8(value)?.to_string());
+        }
+    }
+    Ok(headers)
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        bail!("truncated event stream headers");
+    }
+    let (taken, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn decode_event(headers: &HashMap<String, String>, payload: &[u8]) -> Result<StreamEvent> {
+    match headers.get(":message-type").map(String::as_str) {
+        Some("event") => {
+            let event_type = headers
+                .get(":event-type")
+                .ok_or_else(|| anyhow!("event stream message is missing its event type"))?;
+            let mut event = serde_json::Map::new();
+            event.insert(event_type.clone(), serde_json::from_slice(payload)?);
+            Ok(serde_json::from_value(serde_json::Value::Object(event))?)
+        }
+        Some("exception") => {
+            let exception_type = headers
+                .get(":exception-type")
+                .map_or("UnknownException", String::as_str);
+            let message = serde_json::from_slice::<ExceptionPayload>(payload)
+                .map(|exception| exception.message)
+                .unwrap_or_else(|_| String::from_utf8_lossy(payload).into_owned());
+            Err(anyhow!("{exception_type}: {message}"))
+        }
+        message_type => Err(anyhow!(
+            "unexpected event stream message type {message_type:?}"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+
+    #[test]
+    fn test_parse_converse_stream() {
+        // Shaped like a ConverseStream response from Nova Lite, including the
+        // padding field Bedrock adds to each payload. The checksums are left
+        // zeroed, since they aren't verified.
+        let event = |event_type: &str, payload: &str| {
+            encode_message(
+                &[
+                    (":event-type", event_type),
+                    (":content-type", "application/json"),
+                    (":message-type", "event"),
+                ],
+                payload,
+            )
+        };
+        let response = [
+            event("messageStart", r#"{"p":"abcdefghij","role":"assistant"}"#),
+            event(
+                "contentBlockDelta",
+                r#"{"contentBlockIndex":0,"delta":{"text":"Hello"},"p":"abcd"}"#,
+            ),
+            event(
+                "contentBlockDelta",
+                r#"{"contentBlockIndex":0,"delta":{"text":" there!"},"p":"abcdefgh"}"#,
+            ),
+            event("contentBlockStop", r#"{"contentBlockIndex":0,"p":"ab"}"#),
+            event("messageStop", r#"{"p":"abcdef","stopReason":"end_turn"}"#),
+            event(
+                "metadata",
+                r#"{"metrics":{"latencyMs":312},"p":"abc","usage":{"inputTokens":9,"outputTokens":3,"totalTokens":12}}"#,
+            ),
+        ]
+        .concat();
+
+        let events = block_on(
+            parse_event_stream(Cursor::new(response.clone()))
+                .map(Result::unwrap)
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(
+            events,
+            [
+                StreamEvent::MessageStart {
+                    role: Role::Assistant
+                },
+                StreamEvent::ContentBlockDelta {
+                    content_block_index: 0,
+                    delta: ContentBlockDelta::Text("Hello".into())
+                },
+                StreamEvent::ContentBlockDelta {
+                    content_block_index: 0,
+                    delta: ContentBlockDelta::Text(" there!".into())
+                },
+                StreamEvent::ContentBlockStop {
+                    content_block_index: 0
+                },
+                StreamEvent::MessageStop {
+                    stop_reason: "end_turn".into()
+                },
+                StreamEvent::Metadata {
+                    usage: Usage {
+                        input_tokens: 9,
+                        output_tokens: 3,
+                        total_tokens: 12
+                    }
+                },
+            ]
+        );
+
+        let text = block_on(
+            extract_text_from_events(parse_event_stream(Cursor::new(response.clone())))
+                .map(Result::unwrap)
+                .collect::<String>(),
+        );
+        assert_eq!(text, "Hello there!");
+
+        // Responses that are cut off mid-message report an error.
+        let truncated = response[..response.len() - 10].to_vec();
+        let events = block_on(parse_event_stream(Cursor::new(truncated)).collect::<Vec<_>>());
+        assert_eq!(events.len(), 6);
+        assert!(events.last().unwrap().is_err());
+
+        // Exceptions are reported as errors.
+        let exception = encode_message(
+            &[
+                (":exception-type", "throttlingException"),
+                (":content-type", "application/json"),
+                (":message-type", "exception"),
+            ],
+            r#"{"message":"Too many requests, please wait before trying again."}"#,
+        );
+        let events = block_on(parse_event_stream(Cursor::new(exception)).collect::<Vec<_>>());
+        assert_eq!(
+            events[0].as_ref().unwrap_err().to_string(),
+            "throttlingException: Too many requests, please wait before trying again."
+        );
+    }
+
+    fn encode_message(headers: &[(&str, &str)], payload: &str) -> Vec<u8> {
+        let mut encoded_headers = Vec::new();
+        for (name, value) in headers {
+            encoded_headers.push(name.len() as u8);
+            encoded_headers.extend_from_slice(name.as_bytes());
+            encoded_headers.push(STRING_HEADER_TYPE);
+            encoded_headers.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            encoded_headers.extend_from_slice(value.as_bytes());
+        }
+
+        let total_len = PRELUDE_LEN + encoded_headers.len() + payload.len() + MESSAGE_CHECKSUM_LEN;
+        let mut message = Vec::new();
+        message.extend_from_slice(&(total_len as u32).to_be_bytes());
+        message.extend_from_slice(&(encoded_headers.len() as u32).to_be_bytes());
+        message.extend_from_slice(&[0; 4]);
+        message.extend_from_slice(&encoded_headers);
+        message.extend_from_slice(payload.as_bytes());
+        message.extend_from_slice(&[0; MESSAGE_CHECKSUM_LEN]);
+        message
+    }
+}
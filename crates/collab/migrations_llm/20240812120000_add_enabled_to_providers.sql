@@ -0,0 +1,2 @@
+ALTER TABLE providers
+    ADD COLUMN enabled boolean NOT NULL DEFAULT true;
@@ -0,0 +1,4 @@
+ALTER TABLE models
+    ADD COLUMN supports_tools boolean,
+    ADD COLUMN supports_images boolean,
+    ADD COLUMN max_output_tokens integer;
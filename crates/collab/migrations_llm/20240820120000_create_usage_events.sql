@@ -0,0 +1,14 @@
+create table if not exists usage_events (
+    id serial primary key,
+    user_id integer not null,
+    model_id integer not null references models (id) on delete cascade,
+    timestamp timestamp without time zone not null,
+    input_token_count bigint not null,
+    output_token_count bigint not null,
+    cached_input_token_count bigint not null,
+    conversation_id text,
+    experiment text,
+    api_key_name text
+);
+
+create index ix_usage_events_on_timestamp on usage_events (timestamp);
@@ -0,0 +1,2 @@
+alter table usage_events
+add column tags jsonb not null default '{}';
@@ -155,6 +155,22 @@ pub struct Config {
     pub llm_database_max_connections: Option<u32>,
     pub llm_database_migrations_path: Option<PathBuf>,
     pub llm_api_secret: Option<String>,
+    pub llm_upstream_connect_timeout_secs: Option<u64>,
+    pub llm_upstream_read_timeout_secs: Option<u64>,
+    pub llm_heartbeat_interval_secs: Option<u64>,
+    /// How long to keep reading a completion from the upstream provider after
+    /// the client disconnects, so that the tokens it generates, which are
+    /// billed either way, are recorded.
+    pub llm_disconnect_grace_period_secs: Option<u64>,
+    /// How long the count of active users, which limits are divided between,
+    /// is cached for.
+    pub llm_active_user_count_cache_secs: Option<u64>,
+    pub llm_usage_queue_max_wait_ms: Option<u64>,
+    pub llm_prompt_templates_path: Option<PathBuf>,
+    /// Routes requests in an experiment to a different model from the same
+    /// provider, as a list of `provider:experiment=model` entries, e.g.
+    /// `anthropic:bucket-b=claude-3-opus-20240229`.
+    pub llm_experiment_models: Option<Vec<String>>,
     pub rust_log: Option<String>,
     pub log_json: Option<bool>,
     pub blob_store_url: Option<String>,
@@ -166,6 +182,16 @@ pub struct Config {
     pub openai_api_key: Option<Arc<str>>,
     pub google_ai_api_key: Option<Arc<str>>,
     pub anthropic_api_key: Option<Arc<str>>,
+    /// API keys that requests to OpenAI are spread across, as a list of
+    /// `name:weight:key` entries. Overrides `openai_api_key` when set.
+    pub openai_api_keys: Option<Vec<String>>,
+    /// API keys that requests to Google AI are spread across.
+    pub google_ai_api_keys: Option<Vec<String>>,
+    /// API keys that requests to Anthropic are spread across.
+    pub anthropic_api_keys: Option<Vec<String>>,
+    pub openai_api_url: Option<Arc<str>>,
+    pub google_ai_api_url: Option<Arc<str>>,
+    pub anthropic_api_url: Option<Arc<str>>,
     pub qwen2_7b_api_key: Option<Arc<str>>,
     pub qwen2_7b_api_url: Option<Arc<str>>,
     pub zed_client_checksum_seed: Option<String>,
@@ -190,6 +216,47 @@ impl Config {
         }
     }
 
+    /// Returns the base URL of the OpenAI API, which can be overridden to point
+    /// at a mock upstream or a regional endpoint.
+    pub fn openai_api_url(&self) -> &str {
+        self.openai_api_url
+            .as_deref()
+            .unwrap_or(open_ai::OPEN_AI_API_URL)
+    }
+
+    /// Returns the base URL of the Google AI API.
+    pub fn google_ai_api_url(&self) -> &str {
+        self.google_ai_api_url
+            .as_deref()
+            .unwrap_or(google_ai::API_URL)
+    }
+
+    /// Returns the base URL of the Anthropic API.
+    pub fn anthropic_api_url(&self) -> &str {
+        self.anthropic_api_url
+            .as_deref()
+            .unwrap_or(anthropic::ANTHROPIC_API_URL)
+    }
+
+    /// Returns the model that the provider's requests in the given experiment
+    /// should be sent to in place of the one they asked for, if there is one.
+    pub fn experiment_model(
+        &self,
+        provider: rpc::LanguageModelProvider,
+        experiment: &str,
+    ) -> Option<&str> {
+        self.llm_experiment_models
+            .iter()
+            .flatten()
+            .find_map(|entry| {
+                let (key, model) = entry.split_once('=')?;
+                let (entry_provider, name) = key.split_once(':')?;
+                let entry_provider = entry_provider.trim().parse().ok();
+                (entry_provider == Some(provider) && name.trim() == experiment)
+                    .then(|| model.trim())
+            })
+    }
+
     #[cfg(test)]
     pub fn test() -> Self {
         Self {
@@ -205,6 +272,14 @@ impl Config {
             llm_database_max_connections: None,
             llm_database_migrations_path: None,
             llm_api_secret: None,
+            llm_upstream_connect_timeout_secs: None,
+            llm_upstream_read_timeout_secs: None,
+            llm_heartbeat_interval_secs: None,
+            llm_disconnect_grace_period_secs: None,
+            llm_active_user_count_cache_secs: None,
+            llm_usage_queue_max_wait_ms: None,
+            llm_prompt_templates_path: None,
+            llm_experiment_models: None,
             rust_log: None,
             log_json: None,
             zed_environment: "test".into(),
@@ -216,6 +291,12 @@ impl Config {
             openai_api_key: None,
             google_ai_api_key: None,
             anthropic_api_key: None,
+            openai_api_keys: None,
+            google_ai_api_keys: None,
+            anthropic_api_keys: None,
+            openai_api_url: None,
+            google_ai_api_url: None,
+            anthropic_api_url: None,
             clickhouse_url: None,
             clickhouse_user: None,
             clickhouse_password: None,
@@ -1,36 +1,53 @@
 mod authorization;
 pub mod db;
+mod normalized_events;
+mod prompt_templates;
+mod provider_health;
 mod telemetry;
 mod token;
+mod upstream_api_keys;
+mod usage_queue;
 
 use crate::{
     api::CloudflareIpCountryHeader, build_clickhouse_client, executor::Executor, Config, Error,
     Result,
 };
 use anyhow::{anyhow, Context as _};
+use async_compression::futures::bufread::GzipDecoder;
 use authorization::authorize_access_to_language_model;
 use axum::{
-    body::Body,
-    http::{self, HeaderName, HeaderValue, Request, StatusCode},
+    body::{Body, Bytes},
+    extract::Query,
+    http::{self, HeaderMap, HeaderName, HeaderValue, Request, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{get, post},
     Extension, Json, Router, TypedHeader,
 };
 use chrono::{DateTime, Duration, Utc};
-use db::{ActiveUserCount, LlmDatabase};
-use futures::{Stream, StreamExt as _};
+use db::{usage_measure::UsageMeasure, ActiveUserCount, LlmDatabase, Usage, UsageEvent};
+use futures::{future::Either, stream::BoxStream, AsyncReadExt as _, Stream, StreamExt as _};
 use http_client::IsahcHttpClient;
+use isahc::config::Configurable;
+use prompt_templates::PromptTemplates;
+use provider_health::{ProviderHealth, ProviderHealthReport, UpstreamHealthClient};
 use rpc::{
-    proto::Plan, LanguageModelProvider, PerformCompletionParams, EXPIRED_LLM_TOKEN_HEADER_NAME,
+    proto::Plan, CompletionEvent, CompletionTruncated, GetLanguageModelsResponse,
+    LanguageModelProvider, PerformCompletionParams, RateLimitExceeded, RateLimitScope,
+    UsageLimitWarning, EXPIRED_LLM_TOKEN_HEADER_NAME, USAGE_LIMIT_WARNING_HEADER_NAME,
 };
+use serde::{Deserialize, Serialize};
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
 };
 use telemetry::{report_llm_usage, LlmUsageEventRow};
 use tokio::sync::RwLock;
+use upstream_api_keys::UpstreamApiKeys;
+use usage_queue::{rate_limit_exceeded, UsageCheck, UsageQueue};
 use util::ResultExt;
 
 pub use token::*;
@@ -41,10 +58,35 @@ pub struct LlmState {
     pub db: Arc<LlmDatabase>,
     pub http_client: IsahcHttpClient,
     pub clickhouse_client: Option<clickhouse::Client>,
+    usage_queue: Option<UsageQueue>,
+    provider_health: ProviderHealth,
+    prompt_templates: PromptTemplates,
+    upstream_api_keys: UpstreamApiKeys,
     active_user_count: RwLock<Option<(DateTime<Utc>, ActiveUserCount)>>,
 }
 
-const ACTIVE_USER_COUNT_CACHE_DURATION: Duration = Duration::seconds(30);
+/// The default amount of time the count of active users is cached for.
+const DEFAULT_ACTIVE_USER_COUNT_CACHE_DURATION: Duration = Duration::seconds(30);
+
+/// The default amount of time to wait for a connection to an upstream provider.
+const DEFAULT_UPSTREAM_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The default amount of time an upstream provider may go without sending any data.
+const DEFAULT_UPSTREAM_READ_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The default amount of time a completion stream may go without sending any
+/// data before a heartbeat is sent to keep the connection alive.
+const DEFAULT_HEARTBEAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+
+/// The default amount of time to keep reading a completion from the upstream
+/// provider after the client disconnects.
+const DEFAULT_DISCONNECT_GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// The share of their daily token limit a user can spend before they're warned.
+const USAGE_LIMIT_WARNING_THRESHOLD: f64 = 0.8;
+
+/// The largest a compressed request body may be once it's been decompressed.
+const MAX_DECODED_REQUEST_BODY_SIZE: usize = 32 * 1024 * 1024;
 
 impl LlmState {
     pub async fn new(config: Config, executor: Executor) -> Result<Arc<Self>> {
@@ -63,15 +105,17 @@ impl LlmState {
 
         let db = Arc::new(db);
 
-        let user_agent = format!("Zed Server/{}", env!("CARGO_PKG_VERSION"));
-        let http_client = IsahcHttpClient::builder()
-            .default_header("User-Agent", user_agent)
-            .build()
-            .context("failed to construct http client")?;
+        let http_client = build_http_client(&config)?;
 
         let initial_active_user_count =
             Some((Utc::now(), db.get_active_user_count(Utc::now()).await?));
 
+        let prompt_templates = match config.llm_prompt_templates_path.as_ref() {
+            Some(path) => PromptTemplates::load(path)?,
+            None => PromptTemplates::default(),
+        };
+        let upstream_api_keys = UpstreamApiKeys::new(&config)?;
+
         let this = Self {
             executor,
             db,
@@ -80,6 +124,12 @@ impl LlmState {
                 .clickhouse_url
                 .as_ref()
                 .and_then(|_| build_clickhouse_client(&config).log_err()),
+            usage_queue: config
+                .llm_usage_queue_max_wait_ms
+                .map(|max_wait_ms| UsageQueue::new(std::time::Duration::from_millis(max_wait_ms))),
+            provider_health: ProviderHealth::default(),
+            prompt_templates,
+            upstream_api_keys,
             active_user_count: RwLock::new(initial_active_user_count),
             config,
         };
@@ -87,13 +137,32 @@ impl LlmState {
         Ok(Arc::new(this))
     }
 
+    #[cfg(test)]
+    pub fn test(config: Config, executor: Executor, db: LlmDatabase) -> Arc<Self> {
+        Arc::new(Self {
+            executor,
+            db: Arc::new(db),
+            http_client: build_http_client(&config).unwrap(),
+            clickhouse_client: None,
+            usage_queue: None,
+            provider_health: ProviderHealth::default(),
+            prompt_templates: PromptTemplates::default(),
+            upstream_api_keys: UpstreamApiKeys::new(&config).unwrap(),
+            active_user_count: RwLock::new(None),
+            config,
+        })
+    }
+
     pub async fn get_active_user_count(&self) -> Result<ActiveUserCount> {
         let now = Utc::now();
+        let cache_duration = active_user_count_cache_duration(&self.config);
 
-        if let Some((last_updated, count)) = self.active_user_count.read().await.as_ref() {
-            if now - *last_updated < ACTIVE_USER_COUNT_CACHE_DURATION {
-                return Ok(*count);
-            }
+        if let Some(count) = cached_active_user_count(
+            self.active_user_count.read().await.as_ref(),
+            now,
+            cache_duration,
+        ) {
+            return Ok(count);
         }
 
         let mut cache = self.active_user_count.write().await;
@@ -103,10 +172,236 @@ impl LlmState {
     }
 }
 
+fn active_user_count_cache_duration(config: &Config) -> Duration {
+    config
+        .llm_active_user_count_cache_secs
+        .map_or(DEFAULT_ACTIVE_USER_COUNT_CACHE_DURATION, |secs| {
+            Duration::seconds(secs as i64)
+        })
+}
+
+/// Returns the cached count of active users, unless it's older than `cache_duration`.
+fn cached_active_user_count(
+    cache: Option<&(DateTime<Utc>, ActiveUserCount)>,
+    now: DateTime<Utc>,
+    cache_duration: Duration,
+) -> Option<ActiveUserCount> {
+    let (last_updated, count) = cache?;
+    (now - *last_updated < cache_duration).then_some(*count)
+}
+
+/// Builds the HTTP client used to talk to upstream LLM providers.
+fn build_http_client(config: &Config) -> Result<IsahcHttpClient> {
+    let connect_timeout = config.llm_upstream_connect_timeout_secs.map_or(
+        DEFAULT_UPSTREAM_CONNECT_TIMEOUT,
+        std::time::Duration::from_secs,
+    );
+    let read_timeout = config.llm_upstream_read_timeout_secs.map_or(
+        DEFAULT_UPSTREAM_READ_TIMEOUT,
+        std::time::Duration::from_secs,
+    );
+
+    let user_agent = format!("Zed Server/{}", env!("CARGO_PKG_VERSION"));
+    let http_client = IsahcHttpClient::builder()
+        .default_header("User-Agent", user_agent)
+        .connect_timeout(connect_timeout)
+        // Abort the request if the upstream stops sending data for longer than the read timeout.
+        .low_speed_timeout(1, read_timeout)
+        .build()
+        .context("failed to construct http client")?;
+    Ok(http_client)
+}
+
 pub fn routes() -> Router<(), Body> {
     Router::new()
         .route("/completion", post(perform_completion))
+        .route("/models", get(list_models))
         .layer(middleware::from_fn(validate_api_token))
+        .merge(
+            Router::new()
+                .route("/usage/export", get(export_usage))
+                .route("/health/providers", get(get_provider_health))
+                .layer(middleware::from_fn(validate_admin_token)),
+        )
+}
+
+async fn list_models(
+    Extension(state): Extension<Arc<LlmState>>,
+) -> Json<GetLanguageModelsResponse> {
+    Json(GetLanguageModelsResponse {
+        models: state.db.model_catalog(),
+    })
+}
+
+async fn get_provider_health(
+    Extension(state): Extension<Arc<LlmState>>,
+) -> Json<Vec<ProviderHealthReport>> {
+    Json(
+        state
+            .provider_health
+            .report(&state.config, &state.upstream_api_keys, Utc::now()),
+    )
+}
+
+/// How many usage events are read from the database at a time when exporting.
+const USAGE_EXPORT_PAGE_SIZE: u64 = 1000;
+
+#[derive(Debug, Deserialize)]
+struct ExportUsageParams {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    #[serde(default)]
+    format: UsageExportFormat,
+}
+
+/// The format of a usage export.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum UsageExportFormat {
+    /// Each event is a JSON object on its own line.
+    #[default]
+    Json,
+    /// Each event is a row of comma-separated values, after a header row.
+    Csv,
+}
+
+const USAGE_EXPORT_CSV_COLUMNS: &[&str] = &[
+    "timestamp",
+    "user_id",
+    "provider",
+    "model",
+    "input_token_count",
+    "output_token_count",
+    "cached_input_token_count",
+    "conversation_id",
+    "experiment",
+    "api_key_name",
+    "tags",
+];
+
+impl UsageExportFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            UsageExportFormat::Json => "application/x-ndjson",
+            UsageExportFormat::Csv => "text/csv",
+        }
+    }
+
+    fn header(self) -> Option<Vec<u8>> {
+        match self {
+            UsageExportFormat::Json => None,
+            UsageExportFormat::Csv => {
+                Some(format!("{}\n", USAGE_EXPORT_CSV_COLUMNS.join(",")).into())
+            }
+        }
+    }
+
+    fn encode(self, event: &UsageEvent) -> Result<Vec<u8>> {
+        match self {
+            UsageExportFormat::Json => {
+                let mut line = serde_json::to_vec(event)?;
+                line.push(b'\n');
+                Ok(line)
+            }
+            UsageExportFormat::Csv => {
+                let fields = [
+                    event.timestamp.to_rfc3339(),
+                    event.user_id.to_string(),
+                    event.provider.to_string(),
+                    event.model.clone(),
+                    event.input_token_count.to_string(),
+                    event.output_token_count.to_string(),
+                    event.cached_input_token_count.to_string(),
+                    event.conversation_id.clone().unwrap_or_default(),
+                    event.experiment.clone().unwrap_or_default(),
+                    event.api_key_name.clone().unwrap_or_default(),
+                    serde_json::to_string(&event.tags)?,
+                ];
+                let mut row = fields
+                    .iter()
+                    .map(|field| csv_field(field))
+                    .collect::<Vec<_>>()
+                    .join(",");
+                row.push('\n');
+                Ok(row.into_bytes())
+            }
+        }
+    }
+}
+
+/// Quotes a CSV field if it contains a delimiter, quote, or line break.
+fn csv_field(value: &str) -> Cow<str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Streams the usage of every completion in the requested date range, reading
+/// it from the database a page at a time rather than all at once.
+async fn export_usage(
+    Extension(state): Extension<Arc<LlmState>>,
+    Query(params): Query<ExportUsageParams>,
+) -> Result<impl IntoResponse> {
+    if params.from >= params.to {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            "`from` must be before `to`".to_string(),
+        ));
+    }
+
+    let format = params.format;
+    let header = futures::stream::iter(format.header().map(Ok));
+    let rows = LlmDatabase::usage_event_pages(
+        state.db.clone(),
+        params.from,
+        params.to,
+        USAGE_EXPORT_PAGE_SIZE,
+    )
+    .map(move |events| {
+        let mut chunk = Vec::new();
+        for event in events? {
+            chunk.extend(format.encode(&event)?);
+        }
+        Ok::<_, Error>(chunk)
+    });
+    Ok((
+        [(http::header::CONTENT_TYPE, format.content_type())],
+        Body::wrap_stream(header.chain(rows)),
+    ))
+}
+
+/// Only lets through requests carrying the server's API token, the same one
+/// that guards the collab API, for routes meant for operators.
+async fn validate_admin_token<B>(req: Request<B>, next: Next<B>) -> impl IntoResponse {
+    let token = req
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|header| header.to_str().ok())
+        .ok_or_else(|| {
+            Error::http(
+                StatusCode::BAD_REQUEST,
+                "missing authorization header".to_string(),
+            )
+        })?
+        .strip_prefix("token ")
+        .ok_or_else(|| {
+            Error::http(
+                StatusCode::BAD_REQUEST,
+                "invalid authorization header".to_string(),
+            )
+        })?;
+
+    let state = req.extensions().get::<Arc<LlmState>>().unwrap();
+    if state.config.api_token.is_empty() || token != state.config.api_token {
+        Err(Error::http(
+            StatusCode::UNAUTHORIZED,
+            "invalid authorization token".to_string(),
+        ))?
+    }
+
+    Ok::<_, Error>(next.run(req).await)
 }
 
 async fn validate_api_token<B>(mut req: Request<B>, next: Next<B>) -> impl IntoResponse {
@@ -151,13 +446,221 @@ async fn validate_api_token<B>(mut req: Request<B>, next: Next<B>) -> impl IntoR
     }
 }
 
+/// How the chunks of a completion are delimited in the response body.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum StreamFraming {
+    /// Each JSON chunk is followed by a newline.
+    #[default]
+    Newline,
+    /// Each JSON chunk is preceded by its length as a 4-byte big-endian integer.
+    LengthPrefixed,
+}
+
+impl StreamFraming {
+    fn frame(self, mut chunk: Vec<u8>) -> Vec<u8> {
+        match self {
+            StreamFraming::Newline => {
+                chunk.push(b'\n');
+                chunk
+            }
+            StreamFraming::LengthPrefixed => {
+                let mut frame = Vec::with_capacity(4 + chunk.len());
+                frame.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+                frame.extend_from_slice(&chunk);
+                frame
+            }
+        }
+    }
+}
+
+/// The format of the chunks in a completion's response body.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum EventFormat {
+    /// Each chunk is an event from the upstream provider, as the provider sent it.
+    #[default]
+    Native,
+    /// Each chunk is a [`CompletionEvent`], regardless of the upstream provider.
+    Normalized,
+}
+
+/// A chunk of a completion, along with the tokens it accounts for.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Frame {
+    bytes: Vec<u8>,
+    input_tokens: usize,
+    output_tokens: usize,
+    /// Cached input tokens are also included in `input_tokens`.
+    cached_input_tokens: usize,
+    /// Roughly how many output tokens the chunk's text and tool input amount
+    /// to. Providers only report output tokens once they've finished
+    /// generating, so the token budget goes by this in the meantime.
+    estimated_output_tokens: usize,
+}
+
+impl EventFormat {
+    /// Serializes a provider's chunk into the frames sent for it.
+    fn encode<T: Serialize>(
+        self,
+        chunk: &T,
+        normalize: fn(&T) -> Vec<CompletionEvent>,
+        (input_tokens, output_tokens, cached_input_tokens): (usize, usize, usize),
+    ) -> Vec<Frame> {
+        let events = normalize(chunk);
+        match self {
+            EventFormat::Native => vec![Frame {
+                bytes: serde_json::to_vec(chunk).unwrap(),
+                input_tokens,
+                output_tokens,
+                cached_input_tokens,
+                estimated_output_tokens: events.iter().map(estimated_output_tokens).sum(),
+            }],
+            EventFormat::Normalized => {
+                let mut frames = events
+                    .iter()
+                    .map(|event| Frame {
+                        bytes: serde_json::to_vec(event).unwrap(),
+                        estimated_output_tokens: estimated_output_tokens(event),
+                        ..Default::default()
+                    })
+                    .collect::<Vec<_>>();
+                if input_tokens > 0 || output_tokens > 0 {
+                    let usage = CompletionEvent::Usage {
+                        input_tokens,
+                        output_tokens,
+                    };
+                    frames.push(Frame {
+                        bytes: serde_json::to_vec(&usage).unwrap(),
+                        input_tokens,
+                        output_tokens,
+                        cached_input_tokens,
+                        estimated_output_tokens: 0,
+                    });
+                }
+                frames
+            }
+        }
+    }
+}
+
+/// Roughly how many tokens text of the given length amounts to, at about four
+/// bytes per token.
+fn estimated_tokens(len: usize) -> usize {
+    len.div_ceil(4)
+}
+
+fn estimated_output_tokens(event: &CompletionEvent) -> usize {
+    match event {
+        CompletionEvent::Text { text } => estimated_tokens(text.len()),
+        CompletionEvent::ToolUse { input_json, .. } => estimated_tokens(input_json.len()),
+        _ => 0,
+    }
+}
+
+/// Roughly how many input tokens a request amounts to, going by the length of
+/// the strings in it, such as its messages. Images are sent as base64 data,
+/// whose length says little about their tokens, so they're left out.
+fn estimated_request_tokens(
+    provider_request: &serde_json::value::RawValue,
+    system_prompt: Option<&str>,
+) -> usize {
+    fn strings_len(value: &serde_json::Value) -> usize {
+        match value {
+            serde_json::Value::String(string) => string.len(),
+            serde_json::Value::Array(values) => values.iter().map(strings_len).sum(),
+            serde_json::Value::Object(fields) => fields
+                .iter()
+                .filter(|(key, _)| !matches!(key.as_str(), "data" | "url"))
+                .map(|(_, value)| strings_len(value))
+                .sum(),
+            _ => 0,
+        }
+    }
+
+    let request_len = serde_json::from_str(provider_request.get())
+        .map_or(0, |request: serde_json::Value| strings_len(&request));
+    estimated_tokens(request_len + system_prompt.map_or(0, str::len))
+}
+
+fn flatten_frames(
+    frames: Result<Vec<Frame>, anyhow::Error>,
+) -> impl Stream<Item = Result<Frame, anyhow::Error>> {
+    futures::stream::iter(match frames {
+        Ok(frames) => frames.into_iter().map(Ok).collect(),
+        Err(error) => vec![Err(error)],
+    })
+}
+
+/// Returns the input, output, and cached input tokens reported in an OpenAI
+/// chunk's usage.
+fn open_ai_token_counts(usage: Option<&open_ai::Usage>) -> (usize, usize, usize) {
+    let Some(usage) = usage else {
+        return (0, 0, 0);
+    };
+    let cached_input_tokens = usage
+        .prompt_tokens_details
+        .as_ref()
+        .map_or(0, |details| details.cached_tokens);
+    (
+        usage.prompt_tokens as usize,
+        usage.completion_tokens as usize,
+        cached_input_tokens as usize,
+    )
+}
+
+/// Returns the input, output, and cached input tokens reported in an Anthropic
+/// event.
+fn anthropic_token_counts(event: &anthropic::Event) -> (usize, usize, usize) {
+    match event {
+        anthropic::Event::MessageStart {
+            message: anthropic::Response { usage, .. },
+        }
+        | anthropic::Event::MessageDelta { usage, .. } => (
+            usage.input_tokens.unwrap_or(0) as usize,
+            usage.output_tokens.unwrap_or(0) as usize,
+            0,
+        ),
+        _ => (0, 0, 0),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PerformCompletionQueryParams {
+    #[serde(default)]
+    framing: StreamFraming,
+    #[serde(default)]
+    events: EventFormat,
+}
+
 async fn perform_completion(
     Extension(state): Extension<Arc<LlmState>>,
     Extension(claims): Extension<LlmTokenClaims>,
     country_code_header: Option<TypedHeader<CloudflareIpCountryHeader>>,
-    Json(params): Json<PerformCompletionParams>,
+    Query(query): Query<PerformCompletionQueryParams>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<impl IntoResponse> {
-    let model = normalize_model_name(params.provider, params.model);
+    let params: PerformCompletionParams =
+        serde_json::from_slice(&decode_request_body(&headers, body).await?).map_err(|error| {
+            Error::http(
+                StatusCode::BAD_REQUEST,
+                format!("invalid completion request: {error}"),
+            )
+        })?;
+
+    // Requests in an experiment may be routed to another of the provider's
+    // models, which is then authorized, rate-limited, and billed in place of
+    // the one that was asked for.
+    let experiment_model = params
+        .experiment
+        .as_deref()
+        .and_then(|experiment| state.config.experiment_model(params.provider, experiment))
+        .map(ToString::to_string);
+    let model = normalize_model_name(
+        params.provider,
+        experiment_model.clone().unwrap_or(params.model),
+    );
 
     authorize_access_to_language_model(
         &state.config,
@@ -167,180 +670,403 @@ async fn perform_completion(
         &model,
     )?;
 
-    check_usage_limit(&state, params.provider, &model, &claims).await?;
+    check_provider_enabled(&state.db, params.provider).await?;
+    let (usage, limits) = check_usage_limit(&state, params.provider, &model, &claims).await?;
+    let (token_budget, usage_limit_warning) = remaining_token_budget(&usage, &limits, &claims);
 
-    let stream = match params.provider {
-        LanguageModelProvider::Anthropic => {
-            let api_key = state
-                .config
-                .anthropic_api_key
-                .as_ref()
-                .context("no Anthropic AI API key configured on the server")?;
-
-            let mut request: anthropic::Request =
-                serde_json::from_str(&params.provider_request.get())?;
-
-            // Parse the model, throw away the version that was included, and then set a specific
-            // version that we control on the server.
-            // Right now, we use the version that's defined in `model.id()`, but we will likely
-            // want to change this code once a new version of an Anthropic model is released,
-            // so that users can use the new version, without having to update Zed.
-            request.model = match anthropic::Model::from_id(&request.model) {
-                Ok(model) => model.id().to_string(),
-                Err(_) => request.model,
-            };
+    let system_prompt = params
+        .template
+        .as_deref()
+        .map(|template| state.prompt_templates.expand(template, &params.variables))
+        .transpose()?;
 
-            let chunks = anthropic::stream_completion(
-                &state.http_client,
-                anthropic::ANTHROPIC_API_URL,
-                api_key,
-                request,
-                None,
-            )
-            .await
-            .map_err(|err| match err {
-                anthropic::AnthropicError::ApiError(ref api_error) => {
-                    if api_error.code() == Some(anthropic::ApiErrorCode::RateLimitError) {
-                        return Error::http(
-                            StatusCode::TOO_MANY_REQUESTS,
-                            "Upstream Anthropic rate limit exceeded.".to_string(),
-                        );
-                    }
+    // A request whose prompt alone exceeds the budget would be cut short as
+    // soon as the provider reported its input tokens, after billing for them.
+    if let Some(token_budget) = token_budget {
+        let input_tokens =
+            estimated_request_tokens(&params.provider_request, system_prompt.as_deref());
+        if input_tokens > token_budget.tokens {
+            return Err(rate_limit_exceeded(rate_limit(
+                token_budget.scope,
+                token_budget.limit,
+            )));
+        }
+    }
 
-                    Error::Internal(anyhow!(err))
+    let upstream_api_key = state
+        .upstream_api_keys
+        .choose(params.provider, &mut rand::thread_rng())
+        .cloned();
+
+    let event_format = query.events;
+    let upstream_client = UpstreamHealthClient::new(state.http_client.clone());
+    let stream = async {
+        Ok::<_, Error>(match params.provider {
+            LanguageModelProvider::Anthropic => {
+                let api_key = upstream_api_key
+                    .as_ref()
+                    .map(|api_key| &api_key.key)
+                    .context("no Anthropic AI API key configured on the server")?;
+
+                let mut request: anthropic::Request =
+                    serde_json::from_str(&params.provider_request.get())?;
+
+                // Parse the model, throw away the version that was included, and then set a specific
+                // version that we control on the server.
+                request.model = upstream_anthropic_model(request.model, params.pin_model_version);
+                if let Some(experiment_model) = experiment_model {
+                    request.model = experiment_model;
+                }
+                if let Some(system_prompt) = system_prompt {
+                    request.system = Some(match request.system.take() {
+                        Some(system) => format!("{system_prompt}\n\n{system}"),
+                        None => system_prompt,
+                    });
                 }
-                anthropic::AnthropicError::Other(err) => Error::Internal(err),
-            })?;
 
-            chunks
-                .map(move |event| {
-                    let chunk = event?;
-                    let (input_tokens, output_tokens) = match &chunk {
-                        anthropic::Event::MessageStart {
-                            message: anthropic::Response { usage, .. },
+                let chunks = anthropic::stream_completion(
+                    &upstream_client,
+                    state.config.anthropic_api_url(),
+                    api_key,
+                    request,
+                    None,
+                )
+                .await
+                .map_err(|err| match err {
+                    anthropic::AnthropicError::ApiError(ref api_error) => {
+                        if api_error.code() == Some(anthropic::ApiErrorCode::RateLimitError) {
+                            return Error::http(
+                                StatusCode::TOO_MANY_REQUESTS,
+                                "Upstream Anthropic rate limit exceeded.".to_string(),
+                            );
                         }
-                        | anthropic::Event::MessageDelta { usage, .. } => (
-                            usage.input_tokens.unwrap_or(0) as usize,
-                            usage.output_tokens.unwrap_or(0) as usize,
-                        ),
-                        _ => (0, 0),
-                    };
 
-                    anyhow::Ok((
-                        serde_json::to_vec(&chunk).unwrap(),
-                        input_tokens,
-                        output_tokens,
-                    ))
-                })
-                .boxed()
-        }
-        LanguageModelProvider::OpenAi => {
-            let api_key = state
-                .config
-                .openai_api_key
-                .as_ref()
-                .context("no OpenAI API key configured on the server")?;
-            let chunks = open_ai::stream_completion(
-                &state.http_client,
-                open_ai::OPEN_AI_API_URL,
-                api_key,
-                serde_json::from_str(&params.provider_request.get())?,
-                None,
-            )
-            .await?;
-
-            chunks
-                .map(|event| {
-                    event.map(|chunk| {
-                        let input_tokens =
-                            chunk.usage.as_ref().map_or(0, |u| u.prompt_tokens) as usize;
-                        let output_tokens =
-                            chunk.usage.as_ref().map_or(0, |u| u.completion_tokens) as usize;
-                        (
-                            serde_json::to_vec(&chunk).unwrap(),
-                            input_tokens,
-                            output_tokens,
-                        )
+                        Error::Internal(anyhow!(err))
+                    }
+                    anthropic::AnthropicError::Other(err) => Error::Internal(err),
+                })?;
+
+                chunks
+                    .map(move |event| {
+                        let chunk = event?;
+                        anyhow::Ok(event_format.encode(
+                            &chunk,
+                            normalized_events::from_anthropic,
+                            anthropic_token_counts(&chunk),
+                        ))
                     })
-                })
-                .boxed()
-        }
-        LanguageModelProvider::Google => {
-            let api_key = state
-                .config
-                .google_ai_api_key
-                .as_ref()
-                .context("no Google AI API key configured on the server")?;
-            let chunks = google_ai::stream_generate_content(
-                &state.http_client,
-                google_ai::API_URL,
-                api_key,
-                serde_json::from_str(&params.provider_request.get())?,
-            )
-            .await?;
-
-            chunks
-                .map(|event| {
-                    event.map(|chunk| {
-                        // TODO - implement token counting for Google AI
-                        let input_tokens = 0;
-                        let output_tokens = 0;
-                        (
-                            serde_json::to_vec(&chunk).unwrap(),
-                            input_tokens,
-                            output_tokens,
-                        )
+                    .flat_map(flatten_frames)
+                    .boxed()
+            }
+            LanguageModelProvider::OpenAi => {
+                let api_key = upstream_api_key
+                    .as_ref()
+                    .map(|api_key| &api_key.key)
+                    .context("no OpenAI API key configured on the server")?;
+                let mut request: open_ai::Request =
+                    serde_json::from_str(&params.provider_request.get())?;
+                if let Some(experiment_model) = experiment_model {
+                    request.model = experiment_model;
+                }
+                if let Some(system_prompt) = system_prompt {
+                    request.messages.insert(
+                        0,
+                        open_ai::RequestMessage::System {
+                            content: system_prompt,
+                        },
+                    );
+                }
+                let chunks = open_ai::stream_completion(
+                    &upstream_client,
+                    state.config.openai_api_url(),
+                    api_key,
+                    request,
+                    None,
+                )
+                .await?;
+
+                chunks
+                    .map(move |event| {
+                        event.map(|chunk| {
+                            event_format.encode(
+                                &chunk,
+                                normalized_events::from_open_ai,
+                                open_ai_token_counts(chunk.usage.as_ref()),
+                            )
+                        })
                     })
-                })
-                .boxed()
-        }
-        LanguageModelProvider::Zed => {
-            let api_key = state
-                .config
-                .qwen2_7b_api_key
-                .as_ref()
-                .context("no Qwen2-7B API key configured on the server")?;
-            let api_url = state
-                .config
-                .qwen2_7b_api_url
-                .as_ref()
-                .context("no Qwen2-7B URL configured on the server")?;
-            let chunks = open_ai::stream_completion(
-                &state.http_client,
-                &api_url,
-                api_key,
-                serde_json::from_str(&params.provider_request.get())?,
-                None,
-            )
-            .await?;
-
-            chunks
-                .map(|event| {
-                    event.map(|chunk| {
-                        let input_tokens =
-                            chunk.usage.as_ref().map_or(0, |u| u.prompt_tokens) as usize;
-                        let output_tokens =
-                            chunk.usage.as_ref().map_or(0, |u| u.completion_tokens) as usize;
-                        (
-                            serde_json::to_vec(&chunk).unwrap(),
-                            input_tokens,
-                            output_tokens,
-                        )
+                    .flat_map(flatten_frames)
+                    .boxed()
+            }
+            LanguageModelProvider::Google => {
+                let api_key = upstream_api_key
+                    .as_ref()
+                    .map(|api_key| &api_key.key)
+                    .context("no Google AI API key configured on the server")?;
+                let mut request: google_ai::GenerateContentRequest =
+                    serde_json::from_str(&params.provider_request.get())?;
+                if let Some(experiment_model) = experiment_model {
+                    request.model = experiment_model;
+                }
+                if let Some(system_prompt) = system_prompt {
+                    request.system_instruction = Some(google_ai::Content {
+                        parts: vec![google_ai::Part::TextPart(google_ai::TextPart {
+                            text: system_prompt,
+                        })],
+                        role: google_ai::Role::User,
+                    });
+                }
+                let chunks = google_ai::stream_generate_content(
+                    &upstream_client,
+                    state.config.google_ai_api_url(),
+                    api_key,
+                    request,
+                    None,
+                )
+                .await?;
+
+                chunks
+                    .map(move |event| {
+                        event.map(|chunk| {
+                            // TODO - implement token counting for Google AI
+                            event_format.encode(&chunk, normalized_events::from_google, (0, 0, 0))
+                        })
                     })
-                })
-                .boxed()
-        }
-    };
+                    .flat_map(flatten_frames)
+                    .boxed()
+            }
+            LanguageModelProvider::Zed => {
+                let api_key = state
+                    .config
+                    .qwen2_7b_api_key
+                    .as_ref()
+                    .context("no Qwen2-7B API key configured on the server")?;
+                let api_url = state
+                    .config
+                    .qwen2_7b_api_url
+                    .as_ref()
+                    .context("no Qwen2-7B URL configured on the server")?;
+                let mut request: open_ai::Request =
+                    serde_json::from_str(&params.provider_request.get())?;
+                if let Some(experiment_model) = experiment_model {
+                    request.model = experiment_model;
+                }
+                if let Some(system_prompt) = system_prompt {
+                    request.messages.insert(
+                        0,
+                        open_ai::RequestMessage::System {
+                            content: system_prompt,
+                        },
+                    );
+                }
+                let chunks =
+                    open_ai::stream_completion(&upstream_client, &api_url, api_key, request, None)
+                        .await?;
+
+                chunks
+                    .map(move |event| {
+                        event.map(|chunk| {
+                            event_format.encode(
+                                &chunk,
+                                normalized_events::from_open_ai,
+                                open_ai_token_counts(chunk.usage.as_ref()),
+                            )
+                        })
+                    })
+                    .flat_map(flatten_frames)
+                    .boxed()
+            }
+        })
+    }
+    .await;
+    // Errors that occur after the upstream starts streaming aren't counted here.
+    if let Some(healthy) = upstream_client.outcome() {
+        state
+            .provider_health
+            .record(params.provider, healthy, Utc::now());
+    }
+    let stream = stream?;
 
-    Ok(Response::new(Body::wrap_stream(TokenCountingStream {
+    let heartbeat_interval = state
+        .config
+        .llm_heartbeat_interval_secs
+        .map_or(DEFAULT_HEARTBEAT_INTERVAL, std::time::Duration::from_secs);
+    let disconnect_grace_period = state.config.llm_disconnect_grace_period_secs.map_or(
+        DEFAULT_DISCONNECT_GRACE_PERIOD,
+        std::time::Duration::from_secs,
+    );
+    let executor = state.executor.clone();
+    let stream = TokenCountingStream {
         state,
         claims,
         provider: params.provider,
         model,
+        conversation_id: params.conversation_id,
+        experiment: params.experiment,
+        api_key_name: upstream_api_key.map(|api_key| api_key.name.to_string()),
+        tags: params.tags,
+        framing: query.framing,
         input_tokens: 0,
         output_tokens: 0,
-        inner_stream: stream,
-    })))
+        cached_input_tokens: 0,
+        inner_stream: match token_budget {
+            Some(token_budget) => with_token_budget(stream, token_budget).boxed(),
+            None => stream,
+        },
+        finished: false,
+        disconnect_grace_period,
+    };
+
+    let mut response = Response::new(Body::wrap_stream(with_heartbeats(
+        stream,
+        query.framing,
+        heartbeat_interval,
+        executor,
+    )));
+    if let Some(usage_limit_warning) = usage_limit_warning {
+        response.headers_mut().insert(
+            HeaderName::from_static(USAGE_LIMIT_WARNING_HEADER_NAME),
+            HeaderValue::from_str(&serde_json::to_string(&usage_limit_warning)?)
+                .map_err(|error| anyhow!(error))?,
+        );
+    }
+    Ok(response)
+}
+
+/// Sends an empty frame whenever the upstream provider hasn't produced a chunk
+/// for `interval`, so that proxies don't close the connection as idle during
+/// long pauses, such as while a model is thinking. Clients skip empty frames.
+fn with_heartbeats<S>(
+    stream: S,
+    framing: StreamFraming,
+    interval: std::time::Duration,
+    executor: Executor,
+) -> impl Stream<Item = Result<Vec<u8>, anyhow::Error>>
+where
+    S: Stream<Item = Result<Vec<u8>, anyhow::Error>> + Unpin,
+{
+    futures::stream::unfold(stream, move |mut stream| {
+        let heartbeat = executor.sleep(interval);
+        async move {
+            futures::pin_mut!(heartbeat);
+            let chunk = match futures::future::select(stream.next(), heartbeat).await {
+                Either::Left((chunk, _)) => Some(chunk),
+                Either::Right(_) => None,
+            };
+            match chunk {
+                Some(chunk) => chunk.map(|chunk| (chunk, stream)),
+                None => Some((Ok(framing.frame(Vec::new())), stream)),
+            }
+        }
+    })
+}
+
+/// The number of tokens a user can spend before crossing one of their limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TokenBudget {
+    tokens: usize,
+    /// The limit that the budget is what's left of.
+    scope: RateLimitScope,
+    limit: usize,
+}
+
+/// Cuts a completion short once its input and output tokens exceed `budget`,
+/// ending it with a [`CompletionTruncated`] marker in place of the remaining
+/// chunks, so that a long generation can't run far past the user's limits.
+///
+/// Output tokens are estimated from the chunks' text and tool input as they
+/// stream, and the estimate is replaced whenever the provider reports how many
+/// there actually were. The chunk that crosses the budget is still sent, since
+/// it has already been generated.
+fn with_token_budget<S>(
+    stream: S,
+    budget: TokenBudget,
+) -> impl Stream<Item = Result<Frame, anyhow::Error>>
+where
+    S: Stream<Item = Result<Frame, anyhow::Error>> + Unpin,
+{
+    futures::stream::unfold(
+        (Some(stream), 0, 0),
+        move |(stream, mut reported_tokens, mut unreported_output_tokens)| async move {
+            let mut stream = stream?;
+            let chunk = stream.next().await?;
+            if let Ok(frame) = &chunk {
+                reported_tokens += frame.input_tokens + frame.output_tokens;
+                if frame.output_tokens > 0 {
+                    // Providers report the output generated so far.
+                    unreported_output_tokens = 0;
+                } else {
+                    unreported_output_tokens += frame.estimated_output_tokens;
+                }
+            }
+            let state = (reported_tokens, unreported_output_tokens);
+            if reported_tokens + unreported_output_tokens <= budget.tokens {
+                return Some((vec![chunk], (Some(stream), state.0, state.1)));
+            }
+
+            let marker = serde_json::to_vec(&CompletionTruncated {
+                truncated_by: budget.scope.to_string(),
+            })
+            .unwrap();
+            let marker = Frame {
+                bytes: marker,
+                ..Default::default()
+            };
+            Some((vec![chunk, Ok(marker)], (None, state.0, state.1)))
+        },
+    )
+    .flat_map(futures::stream::iter)
+}
+
+/// Decompresses a request body according to its `Content-Encoding` header.
+async fn decode_request_body(headers: &HeaderMap, body: Bytes) -> Result<Vec<u8>> {
+    let Some(content_encoding) = headers.get(http::header::CONTENT_ENCODING) else {
+        return Ok(body.to_vec());
+    };
+
+    match content_encoding.to_str() {
+        Ok("identity") => Ok(body.to_vec()),
+        Ok("gzip") => {
+            // Stop reading one byte past the limit, so that a small body that
+            // decompresses to far more can't exhaust the server's memory.
+            let mut decoder =
+                GzipDecoder::new(body.as_ref()).take(MAX_DECODED_REQUEST_BODY_SIZE as u64 + 1);
+            let mut decoded = Vec::new();
+            decoder.read_to_end(&mut decoded).await.map_err(|_| {
+                Error::http(
+                    StatusCode::BAD_REQUEST,
+                    "invalid gzip request body".to_string(),
+                )
+            })?;
+            if decoded.len() > MAX_DECODED_REQUEST_BODY_SIZE {
+                return Err(Error::http(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "decompressed request body is too large".to_string(),
+                ));
+            }
+            Ok(decoded)
+        }
+        _ => Err(Error::http(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported content encoding".to_string(),
+        )),
+    }
+}
+
+/// Returns the id of the Anthropic model to send the request to, which is the
+/// version the server currently serves for `model`, unless the client pinned it.
+fn upstream_anthropic_model(model: String, pin_model_version: bool) -> String {
+    if pin_model_version {
+        return model;
+    }
+
+    // Right now, we use the version that's defined in `model.id()`, but we will likely
+    // want to change this code once a new version of an Anthropic model is released,
+    // so that users can use the new version, without having to update Zed.
+    match anthropic::Model::from_id(&model) {
+        Ok(model) => model.id().to_string(),
+        Err(_) => model,
+    }
 }
 
 fn normalize_model_name(provider: LanguageModelProvider, name: String) -> String {
@@ -373,12 +1099,58 @@ fn normalize_model_name(provider: LanguageModelProvider, name: String) -> String
     }
 }
 
+/// Rejects requests to providers that operators have disabled, e.g. during
+/// an upstream incident.
+pub(crate) async fn check_provider_enabled(
+    db: &LlmDatabase,
+    provider: LanguageModelProvider,
+) -> Result<()> {
+    if db.is_provider_enabled(provider).await? {
+        Ok(())
+    } else {
+        Err(Error::http(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{provider:?} is temporarily unavailable"),
+        ))
+    }
+}
+
+/// Checks the request against the user's limits, returning the usage and
+/// limits it was allowed with.
 async fn check_usage_limit(
     state: &Arc<LlmState>,
     provider: LanguageModelProvider,
     model_name: &str,
     claims: &LlmTokenClaims,
-) -> Result<()> {
+) -> Result<(Usage, PerUserLimits)> {
+    let check = || check_usage(state, provider, model_name, claims);
+    if let Some(usage_queue) = state.usage_queue.as_ref() {
+        usage_queue
+            .wait_for_capacity(claims.user_id as i32, &state.executor, check)
+            .await
+    } else {
+        match check().await? {
+            UsageCheck::Allowed(checked) => Ok(checked),
+            UsageCheck::Throttled(rate_limit) | UsageCheck::Exhausted(rate_limit) => {
+                Err(rate_limit_exceeded(rate_limit))
+            }
+        }
+    }
+}
+
+/// A user's share of a model's rate limits, given how many users are active.
+struct PerUserLimits {
+    max_requests_per_minute: usize,
+    max_tokens_per_minute: usize,
+    max_tokens_per_day: usize,
+}
+
+async fn usage_and_limits(
+    state: &Arc<LlmState>,
+    provider: LanguageModelProvider,
+    model_name: &str,
+    claims: &LlmTokenClaims,
+) -> Result<(Usage, PerUserLimits)> {
     let model = state.db.model(provider, model_name)?;
     let usage = state
         .db
@@ -387,88 +1159,218 @@ async fn check_usage_limit(
 
     let active_users = state.get_active_user_count().await?;
 
-    let per_user_max_requests_per_minute =
-        model.max_requests_per_minute as usize / active_users.users_in_recent_minutes.max(1);
-    let per_user_max_tokens_per_minute =
-        model.max_tokens_per_minute as usize / active_users.users_in_recent_minutes.max(1);
-    let per_user_max_tokens_per_day =
-        model.max_tokens_per_day as usize / active_users.users_in_recent_days.max(1);
+    let limits = PerUserLimits {
+        max_requests_per_minute: model.max_requests_per_minute as usize
+            / active_users.users_in_recent_minutes.max(1),
+        max_tokens_per_minute: model.max_tokens_per_minute as usize
+            / active_users.users_in_recent_minutes.max(1),
+        max_tokens_per_day: model.max_tokens_per_day as usize
+            / active_users.users_in_recent_days.max(1),
+    };
+    Ok((usage, limits))
+}
+
+async fn check_usage(
+    state: &Arc<LlmState>,
+    provider: LanguageModelProvider,
+    model_name: &str,
+    claims: &LlmTokenClaims,
+) -> Result<UsageCheck<(Usage, PerUserLimits)>> {
+    let (usage, limits) = usage_and_limits(state, provider, model_name, claims).await?;
+
+    // Temporarily bypass rate-limiting for staff members.
+    if claims.is_staff {
+        return Ok(UsageCheck::Allowed((usage, limits)));
+    }
+
+    if usage.tokens_this_day > limits.max_tokens_per_day {
+        return Ok(UsageCheck::Exhausted(rate_limit(
+            RateLimitScope::TokensPerDay,
+            limits.max_tokens_per_day,
+        )));
+    }
 
     let checks = [
         (
             usage.requests_this_minute,
-            per_user_max_requests_per_minute,
-            "requests per minute",
+            limits.max_requests_per_minute,
+            RateLimitScope::RequestsPerMinute,
         ),
         (
             usage.tokens_this_minute,
-            per_user_max_tokens_per_minute,
-            "tokens per minute",
-        ),
-        (
-            usage.tokens_this_day,
-            per_user_max_tokens_per_day,
-            "tokens per day",
+            limits.max_tokens_per_minute,
+            RateLimitScope::TokensPerMinute,
         ),
     ];
 
-    for (usage, limit, resource) in checks {
-        // Temporarily bypass rate-limiting for staff members.
-        if claims.is_staff {
-            continue;
+    for (used, limit, scope) in checks {
+        if used > limit {
+            return Ok(UsageCheck::Throttled(rate_limit(scope, limit)));
         }
+    }
 
-        if usage > limit {
-            return Err(Error::http(
-                StatusCode::TOO_MANY_REQUESTS,
-                format!("Rate limit exceeded. Maximum {} reached.", resource),
-            ));
-        }
+    Ok(UsageCheck::Allowed((usage, limits)))
+}
+
+/// Describes an exceeded limit to the client. Usage is counted in buckets that
+/// expire one at a time, so the limit's window next moves forward within one
+/// bucket's duration.
+fn rate_limit(scope: RateLimitScope, limit: usize) -> RateLimitExceeded {
+    let measure = match scope {
+        RateLimitScope::RequestsPerMinute => UsageMeasure::RequestsPerMinute,
+        RateLimitScope::TokensPerMinute => UsageMeasure::TokensPerMinute,
+        RateLimitScope::TokensPerDay => UsageMeasure::TokensPerDay,
+    };
+    RateLimitExceeded {
+        scope,
+        limit,
+        reset_after_secs: measure.bucket_duration().num_seconds() as u64,
     }
+}
 
-    Ok(())
+/// Returns how many more tokens the user can spend before crossing their
+/// per-minute or per-day limits, or `None` if they aren't limited, along with
+/// a warning if they're close to their daily limit.
+fn remaining_token_budget(
+    usage: &Usage,
+    limits: &PerUserLimits,
+    claims: &LlmTokenClaims,
+) -> (Option<TokenBudget>, Option<UsageLimitWarning>) {
+    // Temporarily bypass rate-limiting for staff members.
+    if claims.is_staff {
+        return (None, None);
+    }
+
+    let budgets = [
+        TokenBudget {
+            tokens: limits
+                .max_tokens_per_minute
+                .saturating_sub(usage.tokens_this_minute),
+            scope: RateLimitScope::TokensPerMinute,
+            limit: limits.max_tokens_per_minute,
+        },
+        TokenBudget {
+            tokens: limits
+                .max_tokens_per_day
+                .saturating_sub(usage.tokens_this_day),
+            scope: RateLimitScope::TokensPerDay,
+            limit: limits.max_tokens_per_day,
+        },
+    ];
+    (
+        budgets.into_iter().min_by_key(|budget| budget.tokens),
+        usage_limit_warning(usage, limits),
+    )
+}
+
+/// Warns the user once they've used [`USAGE_LIMIT_WARNING_THRESHOLD`] of their
+/// daily token limit. The per-minute limits recover too quickly for a warning
+/// to be worth showing.
+fn usage_limit_warning(usage: &Usage, limits: &PerUserLimits) -> Option<UsageLimitWarning> {
+    let limit = limits.max_tokens_per_day;
+    let used = usage.tokens_this_day;
+    (limit > 0 && used as f64 >= limit as f64 * USAGE_LIMIT_WARNING_THRESHOLD).then_some(
+        UsageLimitWarning {
+            scope: RateLimitScope::TokensPerDay,
+            limit,
+            used,
+        },
+    )
+}
+
+/// Keeps reading a completion whose client has gone away for up to
+/// `grace_period`, returning the input, output and cached input tokens of the
+/// chunks the upstream provider sent in that time.
+async fn drain_upstream(
+    mut stream: BoxStream<'static, Result<Frame, anyhow::Error>>,
+    grace_period: std::time::Duration,
+    executor: &Executor,
+) -> (usize, usize, usize) {
+    let mut token_counts = (0, 0, 0);
+    {
+        let drain = async {
+            while let Some(Ok(frame)) = stream.next().await {
+                token_counts.0 += frame.input_tokens;
+                token_counts.1 += frame.output_tokens;
+                token_counts.2 += frame.cached_input_tokens;
+            }
+        };
+        let timeout = executor.sleep(grace_period);
+        futures::pin_mut!(drain, timeout);
+        futures::future::select(drain, timeout).await;
+    }
+    token_counts
 }
 
-struct TokenCountingStream<S> {
+struct TokenCountingStream {
     state: Arc<LlmState>,
     claims: LlmTokenClaims,
     provider: LanguageModelProvider,
     model: String,
+    conversation_id: Option<String>,
+    experiment: Option<String>,
+    api_key_name: Option<String>,
+    tags: BTreeMap<String, String>,
+    framing: StreamFraming,
     input_tokens: usize,
     output_tokens: usize,
-    inner_stream: S,
+    cached_input_tokens: usize,
+    inner_stream: BoxStream<'static, Result<Frame, anyhow::Error>>,
+    /// Whether the upstream provider has finished sending the completion.
+    finished: bool,
+    disconnect_grace_period: std::time::Duration,
 }
 
-impl<S> Stream for TokenCountingStream<S>
-where
-    S: Stream<Item = Result<(Vec<u8>, usize, usize), anyhow::Error>> + Unpin,
-{
+impl Stream for TokenCountingStream {
     type Item = Result<Vec<u8>, anyhow::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         match Pin::new(&mut self.inner_stream).poll_next(cx) {
-            Poll::Ready(Some(Ok((mut bytes, input_tokens, output_tokens)))) => {
-                bytes.push(b'\n');
-                self.input_tokens += input_tokens;
-                self.output_tokens += output_tokens;
-                Poll::Ready(Some(Ok(bytes)))
+            Poll::Ready(Some(Ok(frame))) => {
+                self.input_tokens += frame.input_tokens;
+                self.output_tokens += frame.output_tokens;
+                self.cached_input_tokens += frame.cached_input_tokens;
+                Poll::Ready(Some(Ok(self.framing.frame(frame.bytes))))
             }
             Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e))),
-            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Ready(None) => {
+                self.finished = true;
+                Poll::Ready(None)
+            }
             Poll::Pending => Poll::Pending,
         }
     }
 }
 
-impl<S> Drop for TokenCountingStream<S> {
+impl Drop for TokenCountingStream {
     fn drop(&mut self) {
         let state = self.state.clone();
         let claims = self.claims.clone();
         let provider = self.provider;
         let model = std::mem::take(&mut self.model);
-        let input_token_count = self.input_tokens;
-        let output_token_count = self.output_tokens;
+        let conversation_id = self.conversation_id.take();
+        let experiment = self.experiment.take();
+        let api_key_name = self.api_key_name.take();
+        let tags = std::mem::take(&mut self.tags);
+        let mut input_token_count = self.input_tokens;
+        let mut output_token_count = self.output_tokens;
+        let mut cached_input_token_count = self.cached_input_tokens;
+        // The client disconnected before the completion finished, but the
+        // upstream provider bills for what it goes on to generate regardless.
+        let unfinished_stream = (!self.finished && !self.disconnect_grace_period.is_zero())
+            .then(|| std::mem::replace(&mut self.inner_stream, futures::stream::empty().boxed()));
+        let disconnect_grace_period = self.disconnect_grace_period;
+        let executor = self.state.executor.clone();
         self.state.executor.spawn_detached(async move {
+            if let Some(unfinished_stream) = unfinished_stream {
+                let (input_tokens, output_tokens, cached_input_tokens) =
+                    drain_upstream(unfinished_stream, disconnect_grace_period, &executor).await;
+                input_token_count += input_tokens;
+                output_token_count += output_tokens;
+                cached_input_token_count += cached_input_tokens;
+            }
+
+            let now = Utc::now();
             let usage = state
                 .db
                 .record_usage(
@@ -477,33 +1379,46 @@ impl<S> Drop for TokenCountingStream<S> {
                     &model,
                     input_token_count,
                     output_token_count,
-                    Utc::now(),
+                    now,
                 )
                 .await
                 .log_err();
+            state
+                .db
+                .record_usage_event(&UsageEvent {
+                    timestamp: now,
+                    user_id: claims.user_id as i32,
+                    provider,
+                    model: model.clone(),
+                    input_token_count,
+                    output_token_count,
+                    cached_input_token_count,
+                    conversation_id: conversation_id.clone(),
+                    experiment: experiment.clone(),
+                    api_key_name: api_key_name.clone(),
+                    tags: tags.clone(),
+                })
+                .await
+                .log_err();
 
             if let Some((clickhouse_client, usage)) = state.clickhouse_client.as_ref().zip(usage) {
                 report_llm_usage(
                     clickhouse_client,
-                    LlmUsageEventRow {
-                        time: Utc::now().timestamp_millis(),
-                        user_id: claims.user_id as i32,
-                        is_staff: claims.is_staff,
-                        plan: match claims.plan {
-                            Plan::Free => "free".to_string(),
-                            Plan::ZedPro => "zed_pro".to_string(),
-                        },
+                    llm_usage_event_row(
+                        &claims,
+                        provider,
                         model,
-                        provider: provider.to_string(),
-                        input_token_count: input_token_count as u64,
-                        output_token_count: output_token_count as u64,
-                        requests_this_minute: usage.requests_this_minute as u64,
-                        tokens_this_minute: usage.tokens_this_minute as u64,
-                        tokens_this_day: usage.tokens_this_day as u64,
-                        input_tokens_this_month: usage.input_tokens_this_month as u64,
-                        output_tokens_this_month: usage.output_tokens_this_month as u64,
-                        spending_this_month: usage.spending_this_month as u64,
-                    },
+                        conversation_id,
+                        experiment,
+                        api_key_name,
+                        &tags,
+                        (
+                            input_token_count,
+                            output_token_count,
+                            cached_input_token_count,
+                        ),
+                        &usage,
+                    ),
                 )
                 .await
                 .log_err();
@@ -511,3 +1426,1041 @@ impl<S> Drop for TokenCountingStream<S> {
         })
     }
 }
+
+fn llm_usage_event_row(
+    claims: &LlmTokenClaims,
+    provider: LanguageModelProvider,
+    model: String,
+    conversation_id: Option<String>,
+    experiment: Option<String>,
+    api_key_name: Option<String>,
+    tags: &BTreeMap<String, String>,
+    (input_token_count, output_token_count, cached_input_token_count): (usize, usize, usize),
+    usage: &Usage,
+) -> LlmUsageEventRow {
+    LlmUsageEventRow {
+        time: Utc::now().timestamp_millis(),
+        user_id: claims.user_id as i32,
+        is_staff: claims.is_staff,
+        plan: match claims.plan {
+            Plan::Free => "free".to_string(),
+            Plan::ZedPro => "zed_pro".to_string(),
+        },
+        model,
+        provider: provider.to_string(),
+        input_token_count: input_token_count as u64,
+        output_token_count: output_token_count as u64,
+        cached_input_token_count: cached_input_token_count as u64,
+        requests_this_minute: usage.requests_this_minute as u64,
+        tokens_this_minute: usage.tokens_this_minute as u64,
+        tokens_this_day: usage.tokens_this_day as u64,
+        input_tokens_this_month: usage.input_tokens_this_month as u64,
+        output_tokens_this_month: usage.output_tokens_this_month as u64,
+        spending_this_month: usage.spending_this_month as u64,
+        conversation_id,
+        experiment,
+        api_key_name,
+        tags: serde_json::to_string(tags).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::futures::bufread::GzipEncoder;
+    use http_client::{AsyncBody, HttpClient};
+    use std::{net::TcpListener, thread, time::Instant};
+
+    use super::*;
+
+    #[test]
+    fn test_active_user_count_cache_duration() {
+        let now = Utc::now();
+        let count = ActiveUserCount {
+            users_in_recent_minutes: 3,
+            users_in_recent_days: 10,
+        };
+        let cache = (now - Duration::seconds(20), count);
+
+        let mut config = Config::test();
+        assert!(cached_active_user_count(
+            Some(&cache),
+            now,
+            active_user_count_cache_duration(&config)
+        )
+        .is_some());
+
+        config.llm_active_user_count_cache_secs = Some(10);
+        assert!(cached_active_user_count(
+            Some(&cache),
+            now,
+            active_user_count_cache_duration(&config)
+        )
+        .is_none());
+
+        config.llm_active_user_count_cache_secs = Some(60);
+        let cached =
+            cached_active_user_count(Some(&cache), now, active_user_count_cache_duration(&config))
+                .unwrap();
+        assert_eq!(cached.users_in_recent_minutes, 3);
+        assert!(
+            cached_active_user_count(None, now, active_user_count_cache_duration(&config))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_upstream_http_client_read_timeout() {
+        // Accept the connection, but never respond, to simulate a hung upstream.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            thread::sleep(std::time::Duration::from_secs(10));
+        });
+
+        let mut config = Config::test();
+        config.llm_upstream_connect_timeout_secs = Some(1);
+        config.llm_upstream_read_timeout_secs = Some(1);
+        let http_client = build_http_client(&config).unwrap();
+
+        let started_at = Instant::now();
+        let response = futures::executor::block_on(HttpClient::get(
+            &http_client,
+            &format!("http://{address}/"),
+            AsyncBody::empty(),
+            false,
+        ));
+        assert!(response.is_err());
+        assert!(started_at.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_upstream_api_urls() {
+        // Serve an empty completion, recording the request line it was asked for.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (request_line_tx, request_line_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            std::io::BufRead::read_line(&mut reader, &mut request_line).unwrap();
+            request_line_tx
+                .send(request_line.trim_end().to_string())
+                .unwrap();
+            loop {
+                let mut header = String::new();
+                std::io::BufRead::read_line(&mut reader, &mut header).unwrap();
+                if header.trim_end().is_empty() {
+                    break;
+                }
+            }
+            std::io::Write::write_all(
+                &mut stream,
+                b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        });
+
+        let mut config = Config::test();
+        assert_eq!(config.openai_api_url(), open_ai::OPEN_AI_API_URL);
+        assert_eq!(config.google_ai_api_url(), google_ai::API_URL);
+        assert_eq!(config.anthropic_api_url(), anthropic::ANTHROPIC_API_URL);
+
+        config.openai_api_url = Some(format!("http://{address}/mock/v1").into());
+        let http_client = build_http_client(&config).unwrap();
+        let request = open_ai::Request {
+            model: "gpt-4o".into(),
+            messages: Vec::new(),
+            stream: true,
+            max_tokens: None,
+            max_completion_tokens: None,
+            stop: Vec::new(),
+            temperature: 1.0,
+            stream_options: None,
+            logit_bias: None,
+            tool_choice: None,
+            tools: Vec::new(),
+            metadata: Default::default(),
+            extra_body: Default::default(),
+        };
+        let events = futures::executor::block_on(async {
+            open_ai::stream_completion(
+                &http_client,
+                config.openai_api_url(),
+                "api-key",
+                request,
+                None,
+            )
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await
+        });
+        assert!(events.is_empty());
+        assert_eq!(
+            request_line_rx.recv().unwrap(),
+            "POST /mock/v1/chat/completions HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn test_normalized_event_format() {
+        let Query(query) = Query::<PerformCompletionQueryParams>::try_from_uri(
+            &"/completion?events=normalized".parse().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(query.events, EventFormat::Normalized);
+
+        let chunk: anthropic::Event = serde_json::from_str(
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":9}}"#,
+        )
+        .unwrap();
+        let frames = query
+            .events
+            .encode(&chunk, normalized_events::from_anthropic, (0, 9, 0));
+        let events = frames
+            .iter()
+            .map(|frame| {
+                (
+                    serde_json::from_slice::<CompletionEvent>(&frame.bytes).unwrap(),
+                    frame.input_tokens,
+                    frame.output_tokens,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                (
+                    CompletionEvent::Stop {
+                        reason: "end_turn".into(),
+                        stop_sequence: None,
+                    },
+                    0,
+                    0
+                ),
+                (
+                    CompletionEvent::Usage {
+                        input_tokens: 0,
+                        output_tokens: 9
+                    },
+                    0,
+                    9
+                ),
+            ]
+        );
+
+        // Chunks are forwarded as-is by default.
+        let frames =
+            EventFormat::default().encode(&chunk, normalized_events::from_anthropic, (0, 9, 0));
+        assert_eq!(
+            frames,
+            vec![Frame {
+                bytes: serde_json::to_vec(&chunk).unwrap(),
+                output_tokens: 9,
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_open_ai_cached_token_counts() {
+        let chunk: open_ai::ResponseStreamEvent = serde_json::from_str(
+            r#"{
+                "id": "chatcmpl-A9kbz4Nd0lR4vXrdjmSZ8Ctvub4Ka",
+                "created": 1726784407,
+                "model": "gpt-4o-2024-08-06",
+                "choices": [],
+                "usage": {
+                    "prompt_tokens": 2006,
+                    "completion_tokens": 300,
+                    "total_tokens": 2306,
+                    "prompt_tokens_details": {"cached_tokens": 1920},
+                    "completion_tokens_details": {"reasoning_tokens": 0}
+                }
+            }"#,
+        )
+        .unwrap();
+        let frames = EventFormat::Native.encode(
+            &chunk,
+            normalized_events::from_open_ai,
+            open_ai_token_counts(chunk.usage.as_ref()),
+        );
+        assert_eq!(
+            (
+                frames[0].input_tokens,
+                frames[0].output_tokens,
+                frames[0].cached_input_tokens
+            ),
+            (2006, 300, 1920)
+        );
+
+        // Responses from before prompt caching don't report any cached tokens.
+        let usage: open_ai::Usage = serde_json::from_str(
+            r#"{"prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15}"#,
+        )
+        .unwrap();
+        assert_eq!(open_ai_token_counts(Some(&usage)), (10, 5, 0));
+        assert_eq!(open_ai_token_counts(None), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_stream_framing() {
+        let chunks = [
+            serde_json::to_vec(&serde_json::json!({ "text": "one\ntwo" })).unwrap(),
+            serde_json::to_vec(&serde_json::json!({ "text": "" })).unwrap(),
+        ];
+
+        let Query(query) =
+            Query::<PerformCompletionQueryParams>::try_from_uri(&"/completion".parse().unwrap())
+                .unwrap();
+        assert_eq!(query.framing, StreamFraming::Newline);
+        let body = chunks
+            .iter()
+            .flat_map(|chunk| query.framing.frame(chunk.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            body,
+            b"{\"text\":\"one\\ntwo\"}\n{\"text\":\"\"}\n".to_vec()
+        );
+
+        let Query(query) = Query::<PerformCompletionQueryParams>::try_from_uri(
+            &"/completion?framing=length-prefixed".parse().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(query.framing, StreamFraming::LengthPrefixed);
+        let body = chunks
+            .iter()
+            .flat_map(|chunk| query.framing.frame(chunk.clone()))
+            .collect::<Vec<_>>();
+
+        let mut decoded = Vec::new();
+        let mut remaining = body.as_slice();
+        while !remaining.is_empty() {
+            let (length, rest) = remaining.split_at(4);
+            let length = u32::from_be_bytes(length.try_into().unwrap()) as usize;
+            let (chunk, rest) = rest.split_at(length);
+            decoded.push(chunk.to_vec());
+            remaining = rest;
+        }
+        assert_eq!(decoded, chunks);
+    }
+
+    #[gpui::test]
+    async fn test_heartbeats(cx: &mut gpui::TestAppContext) {
+        let (upstream_tx, upstream_rx) = futures::channel::mpsc::unbounded();
+        let stream = with_heartbeats(
+            upstream_rx,
+            StreamFraming::Newline,
+            std::time::Duration::from_secs(15),
+            Executor::Deterministic(cx.executor()),
+        );
+        let received = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        cx.executor()
+            .spawn({
+                let received = received.clone();
+                stream.for_each(move |chunk| {
+                    received.lock().push(chunk.unwrap());
+                    async {}
+                })
+            })
+            .detach();
+
+        upstream_tx.unbounded_send(Ok(b"{}\n".to_vec())).unwrap();
+        cx.run_until_parked();
+        assert_eq!(*received.lock(), [b"{}\n".to_vec()]);
+
+        // A heartbeat is sent once the upstream has been quiet for the interval...
+        cx.executor()
+            .advance_clock(std::time::Duration::from_secs(10));
+        cx.run_until_parked();
+        assert_eq!(received.lock().len(), 1);
+        cx.executor()
+            .advance_clock(std::time::Duration::from_secs(5));
+        cx.run_until_parked();
+        assert_eq!(received.lock()[1..], [b"\n".to_vec()]);
+
+        // ...and the interval restarts whenever a chunk arrives.
+        cx.executor()
+            .advance_clock(std::time::Duration::from_secs(10));
+        upstream_tx.unbounded_send(Ok(b"{}\n".to_vec())).unwrap();
+        cx.run_until_parked();
+        cx.executor()
+            .advance_clock(std::time::Duration::from_secs(10));
+        cx.run_until_parked();
+        assert_eq!(received.lock()[2..], [b"{}\n".to_vec()]);
+    }
+
+    #[gpui::test]
+    async fn test_drain_upstream_after_disconnect(cx: &mut gpui::TestAppContext) {
+        let (upstream_tx, upstream_rx) = futures::channel::mpsc::unbounded();
+        let drained = cx.executor().spawn({
+            let executor = Executor::Deterministic(cx.executor());
+            async move {
+                drain_upstream(
+                    upstream_rx.boxed(),
+                    std::time::Duration::from_secs(10),
+                    &executor,
+                )
+                .await
+            }
+        });
+        cx.run_until_parked();
+
+        // Tokens generated during the grace period are counted...
+        upstream_tx
+            .unbounded_send(Ok(Frame {
+                bytes: b"{}".to_vec(),
+                output_tokens: 20,
+                ..Default::default()
+            }))
+            .unwrap();
+        cx.executor()
+            .advance_clock(std::time::Duration::from_secs(5));
+        upstream_tx
+            .unbounded_send(Ok(Frame {
+                bytes: b"{}".to_vec(),
+                output_tokens: 30,
+                ..Default::default()
+            }))
+            .unwrap();
+        cx.run_until_parked();
+
+        // ...but the upstream is abandoned once it's over.
+        cx.executor()
+            .advance_clock(std::time::Duration::from_secs(5));
+        cx.run_until_parked();
+        upstream_tx
+            .unbounded_send(Ok(Frame {
+                bytes: b"{}".to_vec(),
+                output_tokens: 40,
+                ..Default::default()
+            }))
+            .ok();
+        assert_eq!(drained.await, (0, 50, 0));
+    }
+
+    #[gpui::test]
+    async fn test_usage_of_unfinished_completion(cx: &mut gpui::TestAppContext) {
+        if !cfg!(target_os = "macos") {
+            return;
+        }
+        cx.executor().allow_parking();
+
+        let test_db = db::TestLlmDb::postgres(cx.executor().clone());
+        let mut db = test_db.connect();
+        db.initialize().await.unwrap();
+        db::seed_database(&Config::test(), &mut db, false)
+            .await
+            .unwrap();
+        let state = LlmState::test(
+            Config::test(),
+            Executor::Deterministic(cx.executor().clone()),
+            db,
+        );
+
+        let started_at = Utc::now();
+        let (upstream_tx, upstream_rx) = futures::channel::mpsc::unbounded();
+        let mut stream = TokenCountingStream {
+            state: state.clone(),
+            claims: LlmTokenClaims {
+                iat: 0,
+                exp: 0,
+                jti: "token-1".into(),
+                user_id: 1,
+                is_staff: false,
+                plan: Plan::Free,
+            },
+            provider: LanguageModelProvider::Anthropic,
+            model: "claude-3-5-sonnet".into(),
+            conversation_id: None,
+            experiment: None,
+            api_key_name: None,
+            tags: Default::default(),
+            framing: StreamFraming::default(),
+            input_tokens: 0,
+            output_tokens: 0,
+            cached_input_tokens: 0,
+            inner_stream: upstream_rx.boxed(),
+            finished: false,
+            disconnect_grace_period: std::time::Duration::from_secs(10),
+        };
+        upstream_tx
+            .unbounded_send(Ok(Frame {
+                bytes: b"{}".to_vec(),
+                input_tokens: 100,
+                output_tokens: 1,
+                ..Default::default()
+            }))
+            .unwrap();
+        stream.next().await.unwrap().unwrap();
+
+        // The client disconnects, but the upstream goes on generating for a
+        // while within the grace period.
+        drop(stream);
+        cx.run_until_parked();
+        cx.executor()
+            .advance_clock(std::time::Duration::from_secs(5));
+        upstream_tx
+            .unbounded_send(Ok(Frame {
+                bytes: b"{}".to_vec(),
+                output_tokens: 20,
+                ..Default::default()
+            }))
+            .unwrap();
+        drop(upstream_tx);
+        cx.run_until_parked();
+
+        let events = state
+            .db
+            .usage_events(
+                started_at - Duration::minutes(1),
+                Utc::now() + Duration::minutes(1),
+                None,
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            events
+                .iter()
+                .map(|(_, event)| (event.input_token_count, event.output_token_count))
+                .collect::<Vec<_>>(),
+            [(100, 21)]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_token_budget() {
+        // Anthropic only reports output tokens at the start and the end of a message.
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_01","type":"message","role":"assistant","content":[],"model":"claude-3-5-sonnet-20240620","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":100,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Here is a long answer "}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"that keeps going and going "}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"well past the budget."}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":17}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+        let frames = events
+            .into_iter()
+            .flat_map(|event| {
+                let event: anthropic::Event = serde_json::from_str(event).unwrap();
+                EventFormat::Normalized.encode(
+                    &event,
+                    normalized_events::from_anthropic,
+                    anthropic_token_counts(&event),
+                )
+            })
+            .map(anyhow::Ok);
+        let budget = TokenBudget {
+            tokens: 110,
+            scope: RateLimitScope::TokensPerDay,
+            limit: 1000,
+        };
+        let mut frames = with_token_budget(futures::stream::iter(frames), budget)
+            .map(|frame| frame.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        // The completion is cut short mid-generation, before the final usage is reported.
+        let marker = frames.pop().unwrap();
+        assert_eq!(
+            serde_json::from_slice::<CompletionTruncated>(&marker.bytes).unwrap(),
+            CompletionTruncated {
+                truncated_by: "tokens per day".into()
+            }
+        );
+        let texts = frames
+            .iter()
+            .filter_map(
+                |frame| match serde_json::from_slice::<CompletionEvent>(&frame.bytes) {
+                    Ok(CompletionEvent::Text { text }) => Some(text),
+                    _ => None,
+                },
+            )
+            .collect::<Vec<_>>();
+        assert_eq!(
+            texts,
+            ["Here is a long answer ", "that keeps going and going "]
+        );
+    }
+
+    #[test]
+    fn test_estimated_request_tokens() {
+        let request = serde_json::value::RawValue::from_string(
+            serde_json::json!({
+                "model": "claude",
+                "messages": [{
+                    "role": "user",
+                    "content": [
+                        {"type": "text", "text": "1234567890"},
+                        {"type": "image", "source": {"data": "a".repeat(1000)}},
+                    ],
+                }],
+                "max_tokens": 4096,
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        // The image data isn't counted.
+        let len = "claude".len() + "user".len() + "text".len() + 10 + "image".len();
+        assert_eq!(estimated_request_tokens(&request, None), len.div_ceil(4));
+        assert_eq!(
+            estimated_request_tokens(&request, Some("12345678")),
+            (len + 8).div_ceil(4)
+        );
+    }
+
+    #[test]
+    fn test_usage_export_formats() {
+        let event = UsageEvent {
+            timestamp: DateTime::parse_from_rfc3339("2024-08-20T12:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            user_id: 123,
+            provider: LanguageModelProvider::Anthropic,
+            model: "claude-3-5-sonnet".into(),
+            input_token_count: 1000,
+            output_token_count: 200,
+            cached_input_token_count: 100,
+            conversation_id: Some("a \"quoted\", conversation".into()),
+            experiment: None,
+            api_key_name: Some("primary".into()),
+            tags: BTreeMap::from_iter([("project".into(), "zed".into())]),
+        };
+
+        let csv = [
+            UsageExportFormat::Csv.header().unwrap(),
+            UsageExportFormat::Csv.encode(&event).unwrap(),
+        ]
+        .concat();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "timestamp,user_id,provider,model,input_token_count,output_token_count,cached_input_token_count,conversation_id,experiment,api_key_name,tags\n\
+             2024-08-20T12:00:00+00:00,123,anthropic,claude-3-5-sonnet,1000,200,100,\"a \"\"quoted\"\", conversation\",,primary,\"{\"\"project\"\":\"\"zed\"\"}\"\n"
+        );
+
+        assert!(UsageExportFormat::Json.header().is_none());
+        let json = UsageExportFormat::Json.encode(&event).unwrap();
+        assert_eq!(json.last(), Some(&b'\n'));
+        let json = serde_json::from_slice::<serde_json::Value>(&json).unwrap();
+        assert_eq!(json["provider"], "anthropic");
+        assert_eq!(json["output_token_count"], 200);
+        assert_eq!(json["experiment"], serde_json::Value::Null);
+        assert_eq!(json["tags"], serde_json::json!({ "project": "zed" }));
+    }
+
+    #[test]
+    fn test_usage_limit_warning() {
+        let limits = PerUserLimits {
+            max_requests_per_minute: 10,
+            max_tokens_per_minute: 10_000,
+            max_tokens_per_day: 100_000,
+        };
+        let usage = |tokens_this_day| Usage {
+            tokens_this_day,
+            ..Default::default()
+        };
+
+        assert_eq!(usage_limit_warning(&usage(0), &limits), None);
+        assert_eq!(usage_limit_warning(&usage(79_999), &limits), None);
+        assert_eq!(
+            usage_limit_warning(&usage(80_000), &limits),
+            Some(UsageLimitWarning {
+                scope: RateLimitScope::TokensPerDay,
+                limit: 100_000,
+                used: 80_000,
+            })
+        );
+        assert_eq!(
+            usage_limit_warning(&usage(120_000), &limits).map(|warning| warning.used),
+            Some(120_000)
+        );
+
+        // Nearly exhausting a per-minute limit isn't worth warning about.
+        let usage = Usage {
+            requests_this_minute: 10,
+            tokens_this_minute: 9_999,
+            ..usage(0)
+        };
+        assert_eq!(usage_limit_warning(&usage, &limits), None);
+    }
+
+    #[test]
+    fn test_request_metadata_in_usage_event_row() {
+        let params = PerformCompletionParams {
+            provider: LanguageModelProvider::Anthropic,
+            model: "claude-3-5-sonnet".into(),
+            provider_request: serde_json::value::RawValue::from_string("{}".into()).unwrap(),
+            template: None,
+            variables: Default::default(),
+            conversation_id: Some("conversation-1".into()),
+            experiment: Some("bucket-b".into()),
+            tags: BTreeMap::from_iter([
+                ("project".into(), "zed".into()),
+                ("team".into(), "ai".into()),
+            ]),
+            pin_model_version: false,
+        };
+        let params: PerformCompletionParams =
+            serde_json::from_slice(&serde_json::to_vec(&params).unwrap()).unwrap();
+
+        let claims = LlmTokenClaims {
+            iat: 0,
+            exp: 0,
+            jti: "token-1".into(),
+            user_id: 1,
+            is_staff: false,
+            plan: Plan::Free,
+        };
+        let usage = Usage {
+            requests_this_minute: 1,
+            tokens_this_minute: 30,
+            tokens_this_day: 30,
+            input_tokens_this_month: 10,
+            output_tokens_this_month: 20,
+            spending_this_month: 0,
+        };
+        let row = llm_usage_event_row(
+            &claims,
+            params.provider,
+            params.model,
+            params.conversation_id,
+            params.experiment,
+            Some("primary".into()),
+            &params.tags,
+            (10, 20, 4),
+            &usage,
+        );
+        assert_eq!(row.conversation_id.as_deref(), Some("conversation-1"));
+        assert_eq!(row.experiment.as_deref(), Some("bucket-b"));
+        assert_eq!(row.api_key_name.as_deref(), Some("primary"));
+        assert_eq!(row.tags, r#"{"project":"zed","team":"ai"}"#);
+        assert_eq!(row.user_id, 1);
+        assert_eq!(
+            (
+                row.input_token_count,
+                row.output_token_count,
+                row.cached_input_token_count
+            ),
+            (10, 20, 4)
+        );
+    }
+
+    #[test]
+    fn test_experiment_model() {
+        let anthropic = LanguageModelProvider::Anthropic;
+        let open_ai = LanguageModelProvider::OpenAi;
+        let mut config = Config::test();
+        assert_eq!(config.experiment_model(anthropic, "bucket-b"), None);
+
+        config.llm_experiment_models = Some(vec![
+            "anthropic:bucket-a=claude-3-opus-20240229".into(),
+            " open_ai : bucket-b = gpt-4o-2024-08-06 ".into(),
+            "bucket-c=claude-3-opus-20240229".into(),
+        ]);
+        assert_eq!(
+            config.experiment_model(anthropic, "bucket-a"),
+            Some("claude-3-opus-20240229")
+        );
+        assert_eq!(config.experiment_model(open_ai, "bucket-a"), None);
+        assert_eq!(
+            config.experiment_model(open_ai, "bucket-b"),
+            Some("gpt-4o-2024-08-06")
+        );
+        assert_eq!(config.experiment_model(anthropic, "bucket-b"), None);
+        // Entries have to name the provider they apply to.
+        assert_eq!(config.experiment_model(anthropic, "bucket-c"), None);
+    }
+
+    #[gpui::test]
+    async fn test_experiment_model_routing(cx: &mut gpui::TestAppContext) {
+        if !cfg!(target_os = "macos") {
+            return;
+        }
+        cx.executor().allow_parking();
+
+        let (address, upstream_requests) = serve_anthropic_completions(2);
+        let test_db = db::TestLlmDb::postgres(cx.executor().clone());
+        let mut db = test_db.connect();
+        db.initialize().await.unwrap();
+        db::seed_database(&Config::test(), &mut db, false)
+            .await
+            .unwrap();
+
+        let mut config = Config::test();
+        config.zed_environment = "development".into();
+        config.anthropic_api_url = Some(format!("http://{address}").into());
+        config.anthropic_api_key = Some("api-key".into());
+        config.llm_experiment_models = Some(vec![
+            "anthropic:bucket-a=claude-3-opus-20240229".into(),
+            "open_ai:bucket-b=gpt-4o-2024-08-06".into(),
+        ]);
+        let state = LlmState::test(config, Executor::Deterministic(cx.executor().clone()), db);
+        let claims = |is_staff| LlmTokenClaims {
+            iat: 0,
+            exp: 0,
+            jti: "token-1".into(),
+            user_id: 1,
+            is_staff,
+            plan: Plan::Free,
+        };
+        let complete = |claims, model: &str, experiment: &str| {
+            let params = PerformCompletionParams {
+                provider: LanguageModelProvider::Anthropic,
+                model: model.into(),
+                provider_request: serde_json::value::to_raw_value(&serde_json::json!({
+                    "model": "claude-3-5-sonnet-20240620",
+                    "max_tokens": 1024,
+                    "messages": [],
+                }))
+                .unwrap(),
+                template: None,
+                variables: Default::default(),
+                conversation_id: None,
+                experiment: Some(experiment.into()),
+                tags: Default::default(),
+                pin_model_version: false,
+            };
+            perform_completion(
+                Extension(state.clone()),
+                Extension(claims),
+                None,
+                Query(PerformCompletionQueryParams {
+                    framing: StreamFraming::default(),
+                    events: EventFormat::default(),
+                }),
+                HeaderMap::new(),
+                serde_json::to_vec(&params).unwrap().into(),
+            )
+        };
+
+        // The experiment's model is sent the request, and billed for it.
+        let response = complete(claims(true), "claude-3-5-sonnet", "bucket-a")
+            .await
+            .map(IntoResponse::into_response)
+            .unwrap_or_else(|_| panic!("completion failed"));
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            upstream_requests.recv().unwrap()["model"],
+            "claude-3-opus-20240229"
+        );
+
+        // Mappings for other providers' experiments don't apply.
+        let response = complete(claims(true), "claude-3-5-sonnet", "bucket-b")
+            .await
+            .map(IntoResponse::into_response)
+            .unwrap_or_else(|_| panic!("completion failed"));
+        hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            upstream_requests.recv().unwrap()["model"],
+            "claude-3-5-sonnet-20240620"
+        );
+
+        cx.run_until_parked();
+        for model in ["claude-3-5-sonnet", "claude-3-opus"] {
+            let usage = state
+                .db
+                .get_usage(1, LanguageModelProvider::Anthropic, model, Utc::now())
+                .await
+                .unwrap();
+            assert_eq!(usage.requests_this_minute, 1, "{model}");
+            assert_eq!(usage.input_tokens_this_month, 10, "{model}");
+        }
+
+        // Users have to be allowed to use the experiment's model, not just
+        // the one they asked for.
+        match complete(claims(false), "claude-3.5-sonnet", "bucket-a").await {
+            Err(Error::Http(status, _, _)) => assert_eq!(status, StatusCode::FORBIDDEN),
+            _ => panic!("expected the completion to be forbidden"),
+        }
+    }
+
+    /// Serves the given number of Anthropic completions on a local port,
+    /// sending the body of each request it receives to the returned channel.
+    fn serve_anthropic_completions(
+        completions: usize,
+    ) -> (
+        std::net::SocketAddr,
+        std::sync::mpsc::Receiver<serde_json::Value>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (request_tx, request_rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            for _ in 0..completions {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    std::io::BufRead::read_line(&mut reader, &mut line).unwrap();
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(length) = line.strip_prefix("content-length:") {
+                        content_length = length.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                std::io::Read::read_exact(&mut reader, &mut body).unwrap();
+                request_tx
+                    .send(serde_json::from_slice(&body).unwrap())
+                    .unwrap();
+
+                std::io::Write::write_all(
+                    &mut stream,
+                    concat!(
+                        "HTTP/1.1 200 OK\r\n",
+                        "Content-Type: text/event-stream\r\n",
+                        "Connection: close\r\n\r\n",
+                        r#"data: {"type":"message_start","message":{"id":"msg_1","type":"message","role":"assistant","content":[],"model":"claude","usage":{"input_tokens":10,"output_tokens":1}}}"#,
+                        "\n\n",
+                        r#"data: {"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":20}}"#,
+                        "\n\n",
+                        r#"data: {"type":"message_stop"}"#,
+                        "\n\n",
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            }
+        });
+        (address, request_rx)
+    }
+
+    #[test]
+    fn test_pinned_anthropic_model_version() {
+        assert_eq!(
+            upstream_anthropic_model("claude-3-5-sonnet-20241022".into(), false),
+            "claude-3-5-sonnet-20240620"
+        );
+        assert_eq!(
+            upstream_anthropic_model("claude-3-5-sonnet-20241022".into(), true),
+            "claude-3-5-sonnet-20241022"
+        );
+        assert_eq!(
+            upstream_anthropic_model("claude-instant-1".into(), false),
+            "claude-instant-1"
+        );
+
+        // The flag is left out of requests that don't pin a version, so older
+        // clients' requests are read as unpinned.
+        let params = PerformCompletionParams {
+            provider: LanguageModelProvider::Anthropic,
+            model: "claude-3-5-sonnet-20241022".into(),
+            provider_request: serde_json::value::RawValue::from_string("{}".into()).unwrap(),
+            template: None,
+            variables: Default::default(),
+            conversation_id: None,
+            experiment: None,
+            tags: Default::default(),
+            pin_model_version: false,
+        };
+        let mut json = serde_json::to_value(&params).unwrap();
+        assert!(json.get("pin_model_version").is_none());
+        json["pin_model_version"] = true.into();
+        let params: PerformCompletionParams = serde_json::from_value(json).unwrap();
+        assert!(params.pin_model_version);
+    }
+
+    #[test]
+    fn test_decode_compressed_request_body() {
+        let params = PerformCompletionParams {
+            provider: LanguageModelProvider::Anthropic,
+            model: "claude-3-5-sonnet".into(),
+            provider_request: serde_json::value::RawValue::from_string(
+                r#"{"messages":[{"role":"user","content":"Hello"}]}"#.into(),
+            )
+            .unwrap(),
+            template: None,
+            variables: Default::default(),
+            conversation_id: None,
+            experiment: None,
+            tags: Default::default(),
+            pin_model_version: false,
+        };
+        let body = serde_json::to_vec(&params).unwrap();
+
+        // Decode the body exactly as the client compresses it.
+        let compressed_body = futures::executor::block_on(
+            language_model::provider::cloud::encode_completion_params(&params, true),
+        )
+        .unwrap();
+        assert_ne!(compressed_body, body);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_ENCODING,
+            HeaderValue::from_static("gzip"),
+        );
+        let decoded = futures::executor::block_on(decode_request_body(
+            &headers,
+            Bytes::from(compressed_body),
+        ))
+        .unwrap();
+        let decoded: PerformCompletionParams = serde_json::from_slice(&decoded).unwrap();
+        assert_eq!(decoded.provider, params.provider);
+        assert_eq!(decoded.model, params.model);
+        assert_eq!(
+            decoded.provider_request.get(),
+            params.provider_request.get()
+        );
+
+        // Uncompressed bodies are passed through as-is.
+        let decoded = futures::executor::block_on(decode_request_body(
+            &HeaderMap::new(),
+            body.clone().into(),
+        ))
+        .unwrap();
+        assert_eq!(decoded, body);
+
+        headers.insert(
+            http::header::CONTENT_ENCODING,
+            HeaderValue::from_static("br"),
+        );
+        let error =
+            futures::executor::block_on(decode_request_body(&headers, body.into())).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "415 Unsupported Media Type: unsupported content encoding"
+        );
+    }
+
+    #[test]
+    fn test_decompressed_request_body_size_limit() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CONTENT_ENCODING,
+            HeaderValue::from_static("gzip"),
+        );
+        let compress = |body: Vec<u8>| {
+            let mut compressed_body = Vec::new();
+            futures::executor::block_on(
+                GzipEncoder::new(body.as_slice()).read_to_end(&mut compressed_body),
+            )
+            .unwrap();
+            Bytes::from(compressed_body)
+        };
+
+        let body = vec![b' '; MAX_DECODED_REQUEST_BODY_SIZE];
+        let decoded =
+            futures::executor::block_on(decode_request_body(&headers, compress(body))).unwrap();
+        assert_eq!(decoded.len(), MAX_DECODED_REQUEST_BODY_SIZE);
+
+        // A body that's tiny when compressed is still rejected once it expands
+        // past the limit.
+        let body = compress(vec![b' '; MAX_DECODED_REQUEST_BODY_SIZE + 1]);
+        assert!(body.len() < 100 * 1024);
+        let error = futures::executor::block_on(decode_request_body(&headers, body)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "413 Payload Too Large: decompressed request body is too large"
+        );
+    }
+}
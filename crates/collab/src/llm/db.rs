@@ -8,7 +8,7 @@ mod tests;
 
 use collections::HashMap;
 pub use ids::*;
-use rpc::LanguageModelProvider;
+use rpc::{LanguageModelCatalogEntry, LanguageModelProvider, ModelCapabilities};
 pub use seed::*;
 pub use tables::*;
 
@@ -20,7 +20,8 @@ use std::future::Future;
 use std::sync::Arc;
 
 use anyhow::anyhow;
-pub use queries::usages::ActiveUserCount;
+pub use queries::usage_events::UsageEvent;
+pub use queries::usages::{ActiveUserCount, Usage};
 use sea_orm::prelude::*;
 pub use sea_orm::ConnectOptions;
 use sea_orm::{
@@ -74,6 +75,27 @@ impl LlmDatabase {
             .ok_or_else(|| anyhow!("unknown model {provider:?}:{name}"))?)
     }
 
+    /// Returns every model, along with the capabilities configured for it.
+    pub fn model_catalog(&self) -> Vec<LanguageModelCatalogEntry> {
+        let mut catalog = self
+            .models
+            .iter()
+            .map(|((provider, name), model)| LanguageModelCatalogEntry {
+                provider: *provider,
+                name: name.clone(),
+                capabilities: ModelCapabilities {
+                    tools: model.supports_tools,
+                    images: model.supports_images,
+                    max_output_tokens: model
+                        .max_output_tokens
+                        .map(|max_output_tokens| max_output_tokens as u32),
+                },
+            })
+            .collect::<Vec<_>>();
+        catalog.sort_by(|a, b| a.name.cmp(&b.name));
+        catalog
+    }
+
     pub fn options(&self) -> &ConnectOptions {
         &self.options
     }
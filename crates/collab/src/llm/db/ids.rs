@@ -5,5 +5,6 @@ use crate::id_type;
 
 id_type!(ModelId);
 id_type!(ProviderId);
+id_type!(UsageEventId);
 id_type!(UsageId);
 id_type!(UsageMeasureId);
@@ -1,4 +1,5 @@
 use super::*;
 
 pub mod providers;
+pub mod usage_events;
 pub mod usages;
@@ -1,5 +1,5 @@
 use super::*;
-use sea_orm::QueryOrder;
+use sea_orm::{sea_query::Expr, QueryOrder};
 use std::str::FromStr;
 use strum::IntoEnumIterator as _;
 
@@ -107,6 +107,45 @@ impl LlmDatabase {
         self.initialize_models().await
     }
 
+    /// Returns whether completions may currently be requested from the given
+    /// provider.
+    pub async fn is_provider_enabled(&self, provider: LanguageModelProvider) -> Result<bool> {
+        let provider_id = self.provider_id(provider)?;
+        self.transaction(|tx| async move {
+            Ok(provider::Entity::find_by_id(provider_id)
+                .one(&*tx)
+                .await?
+                .map_or(false, |provider| provider.enabled))
+        })
+        .await
+    }
+
+    /// Enables or disables completions for the given provider, taking effect
+    /// on the next request.
+    pub async fn set_provider_enabled(
+        &self,
+        provider: LanguageModelProvider,
+        enabled: bool,
+    ) -> Result<()> {
+        let provider_id = self.provider_id(provider)?;
+        self.transaction(|tx| async move {
+            provider::Entity::update_many()
+                .filter(provider::Column::Id.eq(provider_id))
+                .col_expr(provider::Column::Enabled, Expr::value(enabled))
+                .exec(&*tx)
+                .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    fn provider_id(&self, provider: LanguageModelProvider) -> Result<ProviderId> {
+        Ok(*self
+            .provider_ids
+            .get(&provider)
+            .ok_or_else(|| anyhow!("unknown provider {provider:?}"))?)
+    }
+
     /// Returns the list of LLM providers.
     pub async fn list_providers(&self) -> Result<Vec<LanguageModelProvider>> {
         self.transaction(|tx| async move {
@@ -0,0 +1,139 @@
+use futures::Stream;
+use rpc::LanguageModelProvider;
+use sea_orm::{QueryOrder, QuerySelect};
+use serde::Serialize;
+use std::{borrow::Borrow, collections::BTreeMap};
+
+use super::*;
+
+/// The usage of a single completion.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UsageEvent {
+    pub timestamp: DateTimeUtc,
+    pub user_id: i32,
+    pub provider: LanguageModelProvider,
+    pub model: String,
+    pub input_token_count: usize,
+    pub output_token_count: usize,
+    /// The input tokens that were read from the provider's prompt cache.
+    pub cached_input_token_count: usize,
+    pub conversation_id: Option<String>,
+    pub experiment: Option<String>,
+    /// The name of the upstream API key that served the request.
+    pub api_key_name: Option<String>,
+    /// The labels the client attributed the request's cost to.
+    pub tags: BTreeMap<String, String>,
+}
+
+impl LlmDatabase {
+    pub async fn record_usage_event(&self, event: &UsageEvent) -> Result<()> {
+        self.transaction(|tx| async move {
+            let model = self.model(event.provider, &event.model)?;
+            usage_event::Entity::insert(usage_event::ActiveModel {
+                user_id: ActiveValue::set(event.user_id),
+                model_id: ActiveValue::set(model.id),
+                timestamp: ActiveValue::set(event.timestamp.naive_utc()),
+                input_token_count: ActiveValue::set(event.input_token_count as i64),
+                output_token_count: ActiveValue::set(event.output_token_count as i64),
+                cached_input_token_count: ActiveValue::set(event.cached_input_token_count as i64),
+                conversation_id: ActiveValue::set(event.conversation_id.clone()),
+                experiment: ActiveValue::set(event.experiment.clone()),
+                api_key_name: ActiveValue::set(event.api_key_name.clone()),
+                tags: ActiveValue::set(usage_event::JSONTags(event.tags.clone())),
+                ..Default::default()
+            })
+            .exec_without_returning(&*tx)
+            .await?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns up to `limit` of the usage events recorded from `from` up to,
+    /// but excluding, `to`, in the order they were recorded.
+    ///
+    /// Pass the id of the last event returned as `after` to get the next page.
+    pub async fn usage_events(
+        &self,
+        from: DateTimeUtc,
+        to: DateTimeUtc,
+        after: Option<UsageEventId>,
+        limit: u64,
+    ) -> Result<Vec<(UsageEventId, UsageEvent)>> {
+        self.transaction(|tx| async move {
+            let mut condition = usage_event::Column::Timestamp
+                .gte(from.naive_utc())
+                .and(usage_event::Column::Timestamp.lt(to.naive_utc()));
+            if let Some(after) = after {
+                condition = condition.and(usage_event::Column::Id.gt(after));
+            }
+
+            let events = usage_event::Entity::find()
+                .filter(condition)
+                .order_by_asc(usage_event::Column::Id)
+                .limit(limit)
+                .all(&*tx)
+                .await?;
+
+            events
+                .into_iter()
+                .map(|event| {
+                    let (provider, model) = self
+                        .models
+                        .iter()
+                        .find_map(|((provider, name), model)| {
+                            (model.id == event.model_id).then(|| (*provider, name.clone()))
+                        })
+                        .ok_or_else(|| anyhow!("unknown model id {}", event.model_id.0))?;
+                    Ok((
+                        event.id,
+                        UsageEvent {
+                            timestamp: event.timestamp.and_utc(),
+                            user_id: event.user_id,
+                            provider,
+                            model,
+                            input_token_count: event.input_token_count as usize,
+                            output_token_count: event.output_token_count as usize,
+                            cached_input_token_count: event.cached_input_token_count as usize,
+                            conversation_id: event.conversation_id,
+                            experiment: event.experiment,
+                            api_key_name: event.api_key_name,
+                            tags: event.tags.0,
+                        },
+                    ))
+                })
+                .collect()
+        })
+        .await
+    }
+
+    /// Streams the usage events recorded from `from` up to, but excluding,
+    /// `to`, reading `page_size` of them at a time.
+    pub fn usage_event_pages<D>(
+        db: D,
+        from: DateTimeUtc,
+        to: DateTimeUtc,
+        page_size: u64,
+    ) -> impl Stream<Item = Result<Vec<UsageEvent>>>
+    where
+        D: Borrow<Self> + Clone,
+    {
+        // The state is the id to read after, or `None` once the last page has been read.
+        futures::stream::try_unfold(Some(None), move |after| {
+            let db = db.clone();
+            async move {
+                let Some(after) = after else {
+                    return Ok(None);
+                };
+                let events = db.borrow().usage_events(from, to, after, page_size).await?;
+                if events.is_empty() {
+                    return Ok(None);
+                }
+                let next =
+                    (events.len() as u64 == page_size).then(|| events.last().map(|(id, _)| *id));
+                let events = events.into_iter().map(|(_, event)| event).collect();
+                Ok(Some((events, next)))
+            }
+        })
+    }
+}
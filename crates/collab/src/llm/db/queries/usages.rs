@@ -6,7 +6,7 @@ use strum::IntoEnumIterator as _;
 
 use super::*;
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, Default, PartialEq, Clone, Copy)]
 pub struct Usage {
     pub requests_this_minute: usize,
     pub tokens_this_minute: usize,
@@ -364,7 +364,7 @@ impl UsageMeasure {
         }
     }
 
-    fn bucket_duration(&self) -> Duration {
+    pub fn bucket_duration(&self) -> Duration {
         self.total_duration() / self.bucket_count() as i32
     }
 }
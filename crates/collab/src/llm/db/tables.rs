@@ -1,4 +1,5 @@
 pub mod model;
 pub mod provider;
 pub mod usage;
+pub mod usage_event;
 pub mod usage_measure;
@@ -15,6 +15,9 @@ pub struct Model {
     pub max_tokens_per_day: i64,
     pub price_per_million_input_tokens: i32,
     pub price_per_million_output_tokens: i32,
+    pub supports_tools: Option<bool>,
+    pub supports_images: Option<bool>,
+    pub max_output_tokens: Option<i32>,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
@@ -8,6 +8,9 @@ pub struct Model {
     #[sea_orm(primary_key)]
     pub id: ProviderId,
     pub name: String,
+    /// Whether completions may be requested from this provider. Operators can
+    /// clear this to take a provider out of service without a redeploy.
+    pub enabled: bool,
 }
 
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
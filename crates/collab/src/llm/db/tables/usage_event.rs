@@ -0,0 +1,47 @@
+use crate::llm::db::{ModelId, UsageEventId};
+use sea_orm::{entity::prelude::*, FromJsonQueryResult};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The usage of a single completion, kept so that usage can be exported.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "usage_events")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: UsageEventId,
+    /// The ID of the Zed user.
+    ///
+    /// Corresponds to the `users` table in the primary collab database.
+    pub user_id: i32,
+    pub model_id: ModelId,
+    pub timestamp: DateTime,
+    pub input_token_count: i64,
+    pub output_token_count: i64,
+    pub cached_input_token_count: i64,
+    pub conversation_id: Option<String>,
+    pub experiment: Option<String>,
+    /// The name of the upstream API key that served the request.
+    pub api_key_name: Option<String>,
+    pub tags: JSONTags,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, FromJsonQueryResult)]
+pub struct JSONTags(pub BTreeMap<String, String>);
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::model::Entity",
+        from = "Column::ModelId",
+        to = "super::model::Column::Id"
+    )]
+    Model,
+}
+
+impl Related<super::model::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::Model.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -1,4 +1,5 @@
 mod provider_tests;
+mod usage_event_tests;
 mod usage_tests;
 
 use gpui::BackgroundExecutor;
@@ -62,6 +63,22 @@ impl TestLlmDb {
     pub fn db(&mut self) -> &mut LlmDatabase {
         self.db.as_mut().unwrap()
     }
+
+    /// Opens another connection to the test database, for code that needs to
+    /// own its [`LlmDatabase`].
+    pub fn connect(&self) -> LlmDatabase {
+        let db = self.db.as_ref().unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()
+            .unwrap();
+        let mut connection = runtime
+            .block_on(LlmDatabase::new(db.options.clone(), db.executor.clone()))
+            .unwrap();
+        connection.runtime = Some(runtime);
+        connection
+    }
 }
 
 #[macro_export]
@@ -1,8 +1,10 @@
+use axum::http::StatusCode;
 use pretty_assertions::assert_eq;
 use rpc::LanguageModelProvider;
 
+use crate::llm::check_provider_enabled;
 use crate::llm::db::LlmDatabase;
-use crate::test_llm_db;
+use crate::{test_llm_db, Error};
 
 test_llm_db!(
     test_initialize_providers,
@@ -30,3 +32,36 @@ async fn test_initialize_providers(db: &mut LlmDatabase) {
         ]
     )
 }
+
+test_llm_db!(
+    test_disabled_provider_is_unavailable,
+    test_disabled_provider_is_unavailable_postgres
+);
+
+async fn test_disabled_provider_is_unavailable(db: &mut LlmDatabase) {
+    db.initialize_providers().await.unwrap();
+    check_provider_enabled(db, LanguageModelProvider::Anthropic)
+        .await
+        .unwrap();
+
+    db.set_provider_enabled(LanguageModelProvider::Anthropic, false)
+        .await
+        .unwrap();
+    let error = check_provider_enabled(db, LanguageModelProvider::Anthropic)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error,
+        Error::Http(StatusCode::SERVICE_UNAVAILABLE, _, _)
+    ));
+    check_provider_enabled(db, LanguageModelProvider::OpenAi)
+        .await
+        .unwrap();
+
+    db.set_provider_enabled(LanguageModelProvider::Anthropic, true)
+        .await
+        .unwrap();
+    check_provider_enabled(db, LanguageModelProvider::Anthropic)
+        .await
+        .unwrap();
+}
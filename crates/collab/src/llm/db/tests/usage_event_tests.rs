@@ -0,0 +1,65 @@
+use crate::{
+    llm::db::{queries::providers::ModelParams, LlmDatabase, UsageEvent},
+    test_llm_db,
+};
+use chrono::{Duration, SubsecRound as _, Utc};
+use futures::TryStreamExt as _;
+use pretty_assertions::assert_eq;
+use rpc::LanguageModelProvider;
+use std::collections::BTreeMap;
+
+test_llm_db!(
+    test_exporting_usage_events,
+    test_exporting_usage_events_postgres
+);
+
+async fn test_exporting_usage_events(db: &mut LlmDatabase) {
+    let provider = LanguageModelProvider::Anthropic;
+    let model = "claude-3-5-sonnet";
+
+    db.initialize().await.unwrap();
+    db.insert_models(&[ModelParams {
+        provider,
+        name: model.to_string(),
+        max_requests_per_minute: 5,
+        max_tokens_per_minute: 10_000,
+        max_tokens_per_day: 50_000,
+        price_per_million_input_tokens: 50,
+        price_per_million_output_tokens: 50,
+    }])
+    .await
+    .unwrap();
+
+    // One event a day for a week, at a precision the database can store.
+    let t0 = Utc::now().trunc_subsecs(0) - Duration::days(7);
+    let events = (0..7)
+        .map(|day| UsageEvent {
+            timestamp: t0 + Duration::days(day),
+            user_id: 123,
+            provider,
+            model: model.to_string(),
+            input_token_count: 1000,
+            output_token_count: day as usize,
+            cached_input_token_count: 0,
+            conversation_id: Some(format!("conversation-{day}")),
+            experiment: None,
+            api_key_name: None,
+            tags: BTreeMap::from_iter([("project".to_string(), format!("project-{}", day % 2))]),
+        })
+        .collect::<Vec<_>>();
+    for event in &events {
+        db.record_usage_event(event).await.unwrap();
+    }
+
+    // The range includes its start but not its end, and spans several pages.
+    let pages =
+        LlmDatabase::usage_event_pages(&*db, t0 + Duration::days(1), t0 + Duration::days(6), 2)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+    assert_eq!(
+        pages.iter().map(|page| page.len()).collect::<Vec<_>>(),
+        [2, 2, 1]
+    );
+    assert_eq!(pages.concat(), events[1..6].to_vec());
+}
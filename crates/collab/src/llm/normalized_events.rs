@@ -0,0 +1,295 @@
+use rpc::CompletionEvent;
+
+/// Converts an event streamed by Anthropic into normalized events.
+///
+/// Usage isn't included, since the server reports the tokens it counts itself.
+pub fn from_anthropic(event: &anthropic::Event) -> Vec<CompletionEvent> {
+    match event {
+        anthropic::Event::MessageStart { message } => vec![CompletionEvent::Model {
+            model: message.model.clone(),
+        }],
+        anthropic::Event::ContentBlockStart {
+            content_block: anthropic::Content::Text { text, .. },
+            ..
+        }
+        | anthropic::Event::ContentBlockDelta {
+            delta: anthropic::ContentDelta::TextDelta { text },
+            ..
+        } => text_event(text).into_iter().collect(),
+        anthropic::Event::ContentBlockStart {
+            index,
+            content_block: anthropic::Content::ToolUse { id, name, input },
+        } => {
+            // The input is streamed separately, unless the block already has it.
+            let is_empty = input.as_object().map_or(false, |input| input.is_empty());
+            vec![CompletionEvent::ToolUse {
+                index: *index,
+                id: Some(id.clone()),
+                name: Some(name.clone()),
+                input_json: if is_empty {
+                    String::new()
+                } else {
+                    input.to_string()
+                },
+            }]
+        }
+        anthropic::Event::ContentBlockDelta {
+            index,
+            delta: anthropic::ContentDelta::InputJsonDelta { partial_json },
+        } => vec![CompletionEvent::ToolUse {
+            index: *index,
+            id: None,
+            name: None,
+            input_json: partial_json.clone(),
+        }],
+        anthropic::Event::MessageDelta { delta, .. } => delta
+            .stop_reason
+            .as_ref()
+            .map(|reason| CompletionEvent::Stop {
+                reason: reason.clone(),
+                stop_sequence: delta.stop_sequence.clone(),
+            })
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Converts an event streamed by OpenAI, or an OpenAI-compatible API, into
+/// normalized events.
+///
+/// Usage isn't included, since the server reports the tokens it counts itself.
+pub fn from_open_ai(event: &open_ai::ResponseStreamEvent) -> Vec<CompletionEvent> {
+    let mut events = Vec::new();
+    for choice in &event.choices {
+        // Every chunk echoes the model, but only the first has the role.
+        if choice.delta.role.is_some() && !event.model.is_empty() {
+            events.push(CompletionEvent::Model {
+                model: event.model.clone(),
+            });
+        }
+        events.extend(choice.delta.content.as_deref().and_then(text_event));
+        for tool_call in choice.delta.tool_calls.iter().flatten() {
+            let function = tool_call.function.as_ref();
+            events.push(CompletionEvent::ToolUse {
+                index: tool_call.index,
+                id: tool_call.id.clone(),
+                name: function.and_then(|function| function.name.clone()),
+                input_json: function
+                    .and_then(|function| function.arguments.clone())
+                    .unwrap_or_default(),
+            });
+        }
+        if let Some(reason) = choice.finish_reason.as_deref() {
+            let reason = match reason {
+                "stop" => "end_turn",
+                "length" => "max_tokens",
+                "tool_calls" | "function_call" => "tool_use",
+                reason => reason,
+            };
+            events.push(CompletionEvent::Stop {
+                reason: reason.to_string(),
+                stop_sequence: None,
+            });
+        }
+    }
+    events
+}
+
+/// Converts a response streamed by Google AI into normalized events.
+///
+/// Usage isn't included, since the server reports the tokens it counts itself.
+pub fn from_google(response: &google_ai::GenerateContentResponse) -> Vec<CompletionEvent> {
+    let mut events = Vec::new();
+    let Some(candidate) = response.candidates.iter().flatten().next() else {
+        return events;
+    };
+    for (index, part) in candidate.content.parts.iter().enumerate() {
+        match part {
+            google_ai::Part::TextPart(part) => events.extend(text_event(&part.text)),
+            google_ai::Part::InlineDataPart(part) => events.push(CompletionEvent::Image {
+                mime_type: part.inline_data.mime_type.clone(),
+                data: part.inline_data.data.clone(),
+            }),
+            google_ai::Part::FunctionCallPart(part) => {
+                let args = &part.function_call.args;
+                events.push(CompletionEvent::ToolUse {
+                    index,
+                    id: None,
+                    name: Some(part.function_call.name.clone()),
+                    input_json: if args.is_null() {
+                        "{}".to_string()
+                    } else {
+                        args.to_string()
+                    },
+                });
+            }
+        }
+    }
+    if let Some(reason) = candidate.finish_reason.as_deref() {
+        // Every response repeats the model version, so it's only reported
+        // with the one that finishes the candidate.
+        if let Some(model) = &response.model_version {
+            events.push(CompletionEvent::Model {
+                model: model.clone(),
+            });
+        }
+        let reason = match reason {
+            "STOP" => "end_turn",
+            "MAX_TOKENS" => "max_tokens",
+            reason => reason,
+        };
+        events.push(CompletionEvent::Stop {
+            reason: reason.to_string(),
+            stop_sequence: None,
+        });
+    }
+    events
+}
+
+fn text_event(text: &str) -> Option<CompletionEvent> {
+    (!text.is_empty()).then(|| CompletionEvent::Text {
+        text: text.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_anthropic_events() {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_01","type":"message","role":"assistant","content":[],"model":"claude-3-5-sonnet-20240620","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":12,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Let me check."}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_01","name":"get_weather","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"city\": "}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"Paris\"}"}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use","stop_sequence":null},"usage":{"output_tokens":20}}"#,
+            r#"{"type":"message_stop"}"#,
+        ];
+        let events = events
+            .iter()
+            .flat_map(|event| from_anthropic(&serde_json::from_str(event).unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            vec![
+                CompletionEvent::Model {
+                    model: "claude-3-5-sonnet-20240620".into()
+                },
+                CompletionEvent::Text {
+                    text: "Let me check.".into()
+                },
+                CompletionEvent::ToolUse {
+                    index: 1,
+                    id: Some("toolu_01".into()),
+                    name: Some("get_weather".into()),
+                    input_json: String::new(),
+                },
+                CompletionEvent::ToolUse {
+                    index: 1,
+                    id: None,
+                    name: None,
+                    input_json: "{\"city\": ".into(),
+                },
+                CompletionEvent::ToolUse {
+                    index: 1,
+                    id: None,
+                    name: None,
+                    input_json: "\"Paris\"}".into(),
+                },
+                CompletionEvent::Stop {
+                    reason: "tool_use".into(),
+                    stop_sequence: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_open_ai_events() {
+        let events = [
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o","choices":[{"index":0,"delta":{"content":"Let me check."},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_01","type":"function","function":{"name":"get_weather","arguments":""}}]},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"function":{"arguments":"{\"city\":\"Paris\"}"}}]},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o","choices":[{"index":0,"delta":{},"finish_reason":"tool_calls"}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o","choices":[],"usage":{"prompt_tokens":9,"completion_tokens":3,"total_tokens":12}}"#,
+        ];
+        let events = events
+            .iter()
+            .flat_map(|event| from_open_ai(&serde_json::from_str(event).unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            vec![
+                CompletionEvent::Model {
+                    model: "gpt-4o".into()
+                },
+                CompletionEvent::Text {
+                    text: "Let me check.".into()
+                },
+                CompletionEvent::ToolUse {
+                    index: 0,
+                    id: Some("call_01".into()),
+                    name: Some("get_weather".into()),
+                    input_json: String::new(),
+                },
+                CompletionEvent::ToolUse {
+                    index: 0,
+                    id: None,
+                    name: None,
+                    input_json: "{\"city\":\"Paris\"}".into(),
+                },
+                CompletionEvent::Stop {
+                    reason: "tool_use".into(),
+                    stop_sequence: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_google_events() {
+        let events = [
+            r#"{"candidates":[{"index":0,"content":{"parts":[{"text":"Let me check."}],"role":"model"}}],"modelVersion":"gemini-1.5-pro-002"}"#,
+            r#"{"candidates":[{"index":0,"content":{"parts":[{"functionCall":{"name":"get_weather","args":{"city":"Paris"}}},{"inlineData":{"mimeType":"image/png","data":"iVBORw0KGgo="}}],"role":"model"},"finishReason":"STOP"}],"modelVersion":"gemini-1.5-pro-002"}"#,
+        ];
+        let events = events
+            .iter()
+            .flat_map(|event| from_google(&serde_json::from_str(event).unwrap()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            vec![
+                CompletionEvent::Text {
+                    text: "Let me check.".into()
+                },
+                CompletionEvent::ToolUse {
+                    index: 0,
+                    id: None,
+                    name: Some("get_weather".into()),
+                    input_json: "{\"city\":\"Paris\"}".into(),
+                },
+                CompletionEvent::Image {
+                    mime_type: "image/png".into(),
+                    data: "iVBORw0KGgo=".into(),
+                },
+                CompletionEvent::Model {
+                    model: "gemini-1.5-pro-002".into()
+                },
+                CompletionEvent::Stop {
+                    reason: "end_turn".into(),
+                    stop_sequence: None,
+                },
+            ]
+        );
+    }
+}
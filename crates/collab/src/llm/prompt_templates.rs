@@ -0,0 +1,112 @@
+use crate::{Error, Result};
+use anyhow::Context as _;
+use axum::http::StatusCode;
+use collections::{BTreeSet, HashMap};
+use std::path::Path;
+
+/// Named prompt templates defined by the operator, which clients can ask to
+/// have expanded on the server instead of sending the full prompt.
+///
+/// Templates reference variables as `{{name}}`.
+#[derive(Debug, Default)]
+pub struct PromptTemplates {
+    templates: HashMap<String, String>,
+}
+
+impl PromptTemplates {
+    pub fn new(templates: HashMap<String, String>) -> Self {
+        Self { templates }
+    }
+
+    /// Loads templates from a JSON file mapping template names to their text.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read prompt templates {path:?}"))?;
+        let templates = serde_json::from_str(&contents)
+            .with_context(|| format!("invalid prompt templates {path:?}"))?;
+        Ok(Self::new(templates))
+    }
+
+    /// Expands the named template, substituting every variable it references.
+    pub fn expand(&self, name: &str, variables: &HashMap<String, String>) -> Result<String> {
+        let template = self.templates.get(name).ok_or_else(|| {
+            Error::http(
+                StatusCode::BAD_REQUEST,
+                format!("unknown prompt template {name:?}"),
+            )
+        })?;
+
+        let mut expanded = String::with_capacity(template.len());
+        let mut missing_variables = BTreeSet::new();
+        let mut remaining = template.as_str();
+        while let Some(start) = remaining.find("{{") {
+            let Some(end) = remaining[start..].find("}}") else {
+                break;
+            };
+            expanded.push_str(&remaining[..start]);
+            let variable = remaining[start + 2..start + end].trim();
+            match variables.get(variable) {
+                Some(value) => expanded.push_str(value),
+                None => {
+                    missing_variables.insert(variable);
+                }
+            }
+            remaining = &remaining[start + end + 2..];
+        }
+        expanded.push_str(remaining);
+
+        if !missing_variables.is_empty() {
+            return Err(Error::http(
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "prompt template {name:?} is missing variables: {}",
+                    missing_variables.into_iter().collect::<Vec<_>>().join(", ")
+                ),
+            ));
+        }
+
+        Ok(expanded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_prompt_template() {
+        let templates = PromptTemplates::new(HashMap::from_iter([(
+            "rewrite".to_string(),
+            "Rewrite the following {{ language }} code so that it {{goal}}:\n\n{{code}}"
+                .to_string(),
+        )]));
+
+        let mut variables = HashMap::from_iter([
+            ("language".to_string(), "Rust".to_string()),
+            ("goal".to_string(), "compiles".to_string()),
+            ("code".to_string(), "fn main() {}".to_string()),
+        ]);
+        assert_eq!(
+            templates.expand("rewrite", &variables).unwrap(),
+            "Rewrite the following Rust code so that it compiles:\n\nfn main() {}"
+        );
+
+        variables.remove("goal");
+        variables.remove("code");
+        assert_eq!(
+            templates
+                .expand("rewrite", &variables)
+                .unwrap_err()
+                .to_string(),
+            "400 Bad Request: prompt template \"rewrite\" is missing variables: code, goal"
+        );
+
+        assert_eq!(
+            templates
+                .expand("summarize", &variables)
+                .unwrap_err()
+                .to_string(),
+            "400 Bad Request: unknown prompt template \"summarize\""
+        );
+    }
+}
@@ -0,0 +1,295 @@
+use super::upstream_api_keys::UpstreamApiKeys;
+use crate::Config;
+use chrono::{DateTime, Duration, Utc};
+use collections::HashMap;
+use futures::{future::BoxFuture, FutureExt as _};
+use http_client::{AsyncBody, HttpClient, IsahcHttpClient, Request, Response, Uri};
+use parking_lot::Mutex;
+use rpc::LanguageModelProvider;
+use serde::Serialize;
+use std::{collections::VecDeque, sync::Arc};
+use strum::IntoEnumIterator;
+
+/// How far back request outcomes count towards a provider's health.
+const HEALTH_WINDOW: Duration = Duration::minutes(5);
+
+/// The most request outcomes kept for each provider, however recent.
+const MAX_OUTCOMES_PER_PROVIDER: usize = 1000;
+
+/// The health of an upstream provider, based on its recent request outcomes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderStatus {
+    /// No requests have been made to the provider recently.
+    Unknown,
+    /// All recent requests to the provider succeeded.
+    Healthy,
+    /// Some recent requests to the provider failed.
+    Degraded,
+    /// All recent requests to the provider failed.
+    Unavailable,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProviderHealthReport {
+    pub provider: LanguageModelProvider,
+    pub status: ProviderStatus,
+    pub recent_successes: usize,
+    pub recent_failures: usize,
+}
+
+/// Tracks whether requests to each upstream provider have recently succeeded.
+#[derive(Default)]
+pub struct ProviderHealth {
+    outcomes: Mutex<HashMap<LanguageModelProvider, VecDeque<(DateTime<Utc>, bool)>>>,
+}
+
+impl ProviderHealth {
+    pub fn record(&self, provider: LanguageModelProvider, succeeded: bool, now: DateTime<Utc>) {
+        let mut outcomes = self.outcomes.lock();
+        let outcomes = outcomes.entry(provider).or_default();
+        outcomes.push_back((now, succeeded));
+        if outcomes.len() > MAX_OUTCOMES_PER_PROVIDER {
+            outcomes.pop_front();
+        }
+        prune_outcomes(outcomes, now);
+    }
+
+    /// Reports the health of every provider configured on this server.
+    pub fn report(
+        &self,
+        config: &Config,
+        upstream_api_keys: &UpstreamApiKeys,
+        now: DateTime<Utc>,
+    ) -> Vec<ProviderHealthReport> {
+        let mut outcomes = self.outcomes.lock();
+        LanguageModelProvider::iter()
+            .filter(|provider| is_provider_configured(config, upstream_api_keys, *provider))
+            .map(|provider| {
+                let (recent_successes, recent_failures) = match outcomes.get_mut(&provider) {
+                    Some(outcomes) => {
+                        prune_outcomes(outcomes, now);
+                        let successes = outcomes.iter().filter(|(_, succeeded)| *succeeded).count();
+                        (successes, outcomes.len() - successes)
+                    }
+                    None => (0, 0),
+                };
+
+                let status = match (recent_successes, recent_failures) {
+                    (0, 0) => ProviderStatus::Unknown,
+                    (_, 0) => ProviderStatus::Healthy,
+                    (0, _) => ProviderStatus::Unavailable,
+                    _ => ProviderStatus::Degraded,
+                };
+
+                ProviderHealthReport {
+                    provider,
+                    status,
+                    recent_successes,
+                    recent_failures,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Sends requests to an upstream provider, noting whether the provider could
+/// be reached and responded without a server error.
+///
+/// Requests that fail for other reasons, such as being malformed or exceeding
+/// the rate limits of our own API keys, say nothing about the provider's
+/// health, and so have no outcome.
+pub struct UpstreamHealthClient {
+    client: IsahcHttpClient,
+    outcome: Arc<Mutex<Option<bool>>>,
+}
+
+impl UpstreamHealthClient {
+    pub fn new(client: IsahcHttpClient) -> Self {
+        Self {
+            client,
+            outcome: Arc::default(),
+        }
+    }
+
+    /// Whether the provider was healthy when it was last sent a request, if
+    /// that request's outcome reflects on its health.
+    pub fn outcome(&self) -> Option<bool> {
+        *self.outcome.lock()
+    }
+}
+
+impl HttpClient for UpstreamHealthClient {
+    fn send(
+        &self,
+        req: Request<AsyncBody>,
+    ) -> BoxFuture<'static, Result<Response<AsyncBody>, http_client::Error>> {
+        let outcome = self.outcome.clone();
+        let response = self.client.send(req);
+        async move {
+            let response = response.await;
+            *outcome.lock() = match &response {
+                Ok(response) if response.status().is_success() => Some(true),
+                Ok(response) if response.status().is_server_error() => Some(false),
+                Ok(_) => None,
+                Err(_) => Some(false),
+            };
+            response
+        }
+        .boxed()
+    }
+
+    fn proxy(&self) -> Option<&Uri> {
+        None
+    }
+}
+
+fn prune_outcomes(outcomes: &mut VecDeque<(DateTime<Utc>, bool)>, now: DateTime<Utc>) {
+    while outcomes
+        .front()
+        .map_or(false, |(recorded_at, _)| now - *recorded_at > HEALTH_WINDOW)
+    {
+        outcomes.pop_front();
+    }
+}
+
+fn is_provider_configured(
+    config: &Config,
+    upstream_api_keys: &UpstreamApiKeys,
+    provider: LanguageModelProvider,
+) -> bool {
+    match provider {
+        LanguageModelProvider::Anthropic
+        | LanguageModelProvider::OpenAi
+        | LanguageModelProvider::Google => upstream_api_keys.has_keys(provider),
+        LanguageModelProvider::Zed => {
+            config.qwen2_7b_api_key.is_some() && config.qwen2_7b_api_url.is_some()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead as _, BufReader, Write as _},
+        net::TcpListener,
+        thread,
+    };
+
+    #[test]
+    fn test_provider_health_report() {
+        let mut config = Config::test();
+        config.anthropic_api_key = Some("anthropic-key".into());
+        // Providers configured with only a list of keys count too.
+        config.openai_api_keys = Some(vec!["primary:1:openai-key".into()]);
+        config.google_ai_api_key = Some("google-key".into());
+        let upstream_api_keys = UpstreamApiKeys::new(&config).unwrap();
+
+        let health = ProviderHealth::default();
+        let now = Utc::now();
+        health.record(LanguageModelProvider::Anthropic, true, now);
+        health.record(LanguageModelProvider::Anthropic, true, now);
+        health.record(
+            LanguageModelProvider::OpenAi,
+            true,
+            now - Duration::minutes(10),
+        );
+        health.record(LanguageModelProvider::OpenAi, false, now);
+        health.record(LanguageModelProvider::Google, true, now);
+        health.record(LanguageModelProvider::Google, false, now);
+        // Zed isn't configured on this server, so it isn't reported.
+        health.record(LanguageModelProvider::Zed, true, now);
+
+        assert_eq!(
+            health.report(&config, &upstream_api_keys, now),
+            vec![
+                ProviderHealthReport {
+                    provider: LanguageModelProvider::Anthropic,
+                    status: ProviderStatus::Healthy,
+                    recent_successes: 2,
+                    recent_failures: 0,
+                },
+                ProviderHealthReport {
+                    provider: LanguageModelProvider::OpenAi,
+                    status: ProviderStatus::Unavailable,
+                    recent_successes: 0,
+                    recent_failures: 1,
+                },
+                ProviderHealthReport {
+                    provider: LanguageModelProvider::Google,
+                    status: ProviderStatus::Degraded,
+                    recent_successes: 1,
+                    recent_failures: 1,
+                },
+            ]
+        );
+
+        // Outcomes age out of the health window.
+        assert_eq!(
+            health.report(&config, &upstream_api_keys, now + Duration::minutes(10))[0].status,
+            ProviderStatus::Unknown
+        );
+
+        // Only so many recent outcomes are kept.
+        for _ in 0..MAX_OUTCOMES_PER_PROVIDER {
+            health.record(LanguageModelProvider::Google, true, now);
+        }
+        let report = &health.report(&config, &upstream_api_keys, now)[2];
+        assert_eq!(report.status, ProviderStatus::Healthy);
+        assert_eq!(report.recent_successes, MAX_OUTCOMES_PER_PROVIDER);
+    }
+
+    #[test]
+    fn test_upstream_health_client() {
+        // Respond to each request with the next of the given statuses.
+        let statuses = [200, 400, 429, 500, 529];
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim_end().is_empty() {
+                        break;
+                    }
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+            }
+        });
+
+        let client = UpstreamHealthClient::new(IsahcHttpClient::new().unwrap());
+        let mut outcomes = Vec::new();
+        for _ in statuses {
+            futures::executor::block_on(client.get(
+                &format!("http://{address}/"),
+                AsyncBody::empty(),
+                false,
+            ))
+            .unwrap();
+            outcomes.push(client.outcome());
+        }
+        // Rejected requests, including those exceeding the rate limits of our
+        // own API keys, don't reflect on the provider's health.
+        assert_eq!(outcomes, [Some(true), None, None, Some(false), Some(false)]);
+
+        // Failing to reach the provider at all counts against it.
+        let unreachable = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = unreachable.local_addr().unwrap();
+        drop(unreachable);
+        assert!(futures::executor::block_on(client.get(
+            &format!("http://{address}/"),
+            AsyncBody::empty(),
+            false,
+        ))
+        .is_err());
+        assert_eq!(client.outcome(), Some(false));
+    }
+}
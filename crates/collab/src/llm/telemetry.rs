@@ -11,12 +11,20 @@ pub struct LlmUsageEventRow {
     pub provider: String,
     pub input_token_count: u64,
     pub output_token_count: u64,
+    /// The input tokens that were read from the provider's prompt cache.
+    pub cached_input_token_count: u64,
     pub requests_this_minute: u64,
     pub tokens_this_minute: u64,
     pub tokens_this_day: u64,
     pub input_tokens_this_month: u64,
     pub output_tokens_this_month: u64,
     pub spending_this_month: u64,
+    pub conversation_id: Option<String>,
+    pub experiment: Option<String>,
+    /// The name of the upstream API key that served the request.
+    pub api_key_name: Option<String>,
+    /// The labels the client attributed the request's cost to, as a JSON object.
+    pub tags: String,
 }
 
 pub async fn report_llm_usage(client: &clickhouse::Client, row: LlmUsageEventRow) -> Result<()> {
@@ -0,0 +1,172 @@
+use crate::Config;
+use anyhow::{anyhow, Context as _, Result};
+use collections::HashMap;
+use rand::{seq::SliceRandom as _, Rng};
+use rpc::LanguageModelProvider;
+use std::sync::Arc;
+
+/// The name that a provider's single configured API key is reported under.
+const DEFAULT_KEY_NAME: &str = "default";
+
+/// One of the API keys that requests to an upstream provider are spread across.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamApiKey {
+    /// Identifies the key in telemetry without revealing it.
+    pub name: Arc<str>,
+    pub weight: u32,
+    pub key: Arc<str>,
+}
+
+/// The API keys configured for each upstream provider.
+#[derive(Debug, Default)]
+pub struct UpstreamApiKeys {
+    keys: HashMap<LanguageModelProvider, Vec<UpstreamApiKey>>,
+}
+
+impl UpstreamApiKeys {
+    pub fn new(config: &Config) -> Result<Self> {
+        let mut keys = HashMap::default();
+        for (provider, entries, default_key) in [
+            (
+                LanguageModelProvider::Anthropic,
+                &config.anthropic_api_keys,
+                &config.anthropic_api_key,
+            ),
+            (
+                LanguageModelProvider::OpenAi,
+                &config.openai_api_keys,
+                &config.openai_api_key,
+            ),
+            (
+                LanguageModelProvider::Google,
+                &config.google_ai_api_keys,
+                &config.google_ai_api_key,
+            ),
+        ] {
+            keys.insert(
+                provider,
+                parse_keys(entries.as_deref(), default_key.as_ref())
+                    .with_context(|| format!("invalid API keys for {provider}"))?,
+            );
+        }
+        Ok(Self { keys })
+    }
+
+    /// Whether any of the provider's keys can be chosen, i.e. whether it has a
+    /// key with a nonzero weight.
+    pub fn has_keys(&self, provider: LanguageModelProvider) -> bool {
+        self.keys
+            .get(&provider)
+            .map_or(false, |keys| keys.iter().any(|key| key.weight > 0))
+    }
+
+    /// Picks one of the provider's keys at random, in proportion to their weights.
+    pub fn choose(
+        &self,
+        provider: LanguageModelProvider,
+        rng: &mut impl Rng,
+    ) -> Option<&UpstreamApiKey> {
+        if !self.has_keys(provider) {
+            return None;
+        }
+        self.keys[&provider]
+            .choose_weighted(rng, |key| key.weight)
+            .ok()
+    }
+}
+
+/// Parses keys configured as `name:weight:key` entries. When there are none,
+/// the provider's single key is used on its own.
+fn parse_keys(
+    entries: Option<&[String]>,
+    default_key: Option<&Arc<str>>,
+) -> Result<Vec<UpstreamApiKey>> {
+    let Some(entries) = entries.filter(|entries| !entries.is_empty()) else {
+        return Ok(default_key
+            .map(|key| UpstreamApiKey {
+                name: DEFAULT_KEY_NAME.into(),
+                weight: 1,
+                key: key.clone(),
+            })
+            .into_iter()
+            .collect());
+    };
+
+    entries
+        .iter()
+        .map(|entry| {
+            let mut parts = entry.trim().splitn(3, ':');
+            let (Some(name), Some(weight), Some(key)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(anyhow!("expected an entry of the form name:weight:key"));
+            };
+            let weight = weight
+                .parse()
+                .with_context(|| format!("invalid weight for API key {name:?}"))?;
+            if name.is_empty() || key.is_empty() {
+                return Err(anyhow!("API key entries need a name and a key"));
+            }
+            Ok(UpstreamApiKey {
+                name: name.into(),
+                weight,
+                key: key.into(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng as _};
+
+    #[test]
+    fn test_weighted_key_selection() {
+        let mut config = Config::test();
+        config.anthropic_api_key = Some("sk-single".into());
+        config.openai_api_key = Some("sk-single".into());
+        config.openai_api_keys = Some(vec![
+            "primary:3:sk-primary".into(),
+            "secondary:1:sk-secondary".into(),
+            "drained:0:sk-drained".into(),
+        ]);
+        let keys = UpstreamApiKeys::new(&config).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let mut counts = HashMap::<&str, usize>::default();
+        for _ in 0..4000 {
+            let key = keys
+                .choose(LanguageModelProvider::OpenAi, &mut rng)
+                .unwrap();
+            *counts.entry(key.name.as_ref()).or_default() += 1;
+        }
+        assert_eq!(counts.get("drained"), None);
+        let primary = counts["primary"];
+        assert!((2800..=3200).contains(&primary), "{primary}");
+        assert_eq!(primary + counts["secondary"], 4000);
+
+        // A provider with a single key always uses it.
+        let key = keys
+            .choose(LanguageModelProvider::Anthropic, &mut rng)
+            .unwrap();
+        assert_eq!(
+            (key.name.as_ref(), key.key.as_ref()),
+            (DEFAULT_KEY_NAME, "sk-single")
+        );
+
+        // Providers without any keys have nothing to choose from.
+        assert_eq!(keys.choose(LanguageModelProvider::Google, &mut rng), None);
+        assert_eq!(keys.choose(LanguageModelProvider::Zed, &mut rng), None);
+        assert!(keys.has_keys(LanguageModelProvider::OpenAi));
+        assert!(!keys.has_keys(LanguageModelProvider::Google));
+
+        // Nor do providers whose keys have all been drained.
+        config.google_ai_api_keys = Some(vec!["drained:0:sk-drained".into()]);
+        let keys = UpstreamApiKeys::new(&config).unwrap();
+        assert!(!keys.has_keys(LanguageModelProvider::Google));
+        assert_eq!(keys.choose(LanguageModelProvider::Google, &mut rng), None);
+
+        config.google_ai_api_keys = Some(vec!["missing-weight:sk-key".into()]);
+        assert!(UpstreamApiKeys::new(&config).is_err());
+    }
+}
@@ -0,0 +1,249 @@
+use crate::{executor::Executor, Error, Result};
+use axum::http::{
+    header::{CONTENT_TYPE, RETRY_AFTER},
+    HeaderMap, HeaderValue, StatusCode,
+};
+use collections::HashMap;
+use futures::future::{select, Either};
+use parking_lot::Mutex;
+use rpc::RateLimitExceeded;
+use std::{future::Future, pin::pin, sync::Arc, time::Duration};
+
+/// How often a queued request re-checks whether it fits within the rate limits.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The outcome of checking a request against a user's rate limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsageCheck<T> {
+    /// The request fits within the user's limits, given the usage that was
+    /// checked.
+    Allowed(T),
+    /// A short-term limit was exceeded, so the request may fit if it waits briefly.
+    Throttled(RateLimitExceeded),
+    /// A long-term limit was exceeded, so waiting won't help.
+    Exhausted(RateLimitExceeded),
+}
+
+/// A short, bounded queue that lets bursts of requests wait for rate limit
+/// headroom instead of being rejected immediately.
+///
+/// Each user gets their own FIFO lane, so a user who is bursting only ever
+/// delays their own requests.
+pub struct UsageQueue {
+    max_wait: Duration,
+    lanes: Mutex<HashMap<i32, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl UsageQueue {
+    pub fn new(max_wait: Duration) -> Self {
+        Self {
+            max_wait,
+            lanes: Mutex::default(),
+        }
+    }
+
+    /// Waits until `check` allows the request, returning what the allowing
+    /// check was given, or rejects it with a 429 once the queue's maximum wait
+    /// has elapsed or a long-term limit is exhausted.
+    pub async fn wait_for_capacity<F, Fut, T>(
+        &self,
+        user_id: i32,
+        executor: &Executor,
+        mut check: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<UsageCheck<T>>>,
+    {
+        let mut rate_limit = match check().await? {
+            UsageCheck::Allowed(checked) => return Ok(checked),
+            UsageCheck::Exhausted(rate_limit) => return Err(rate_limit_exceeded(rate_limit)),
+            UsageCheck::Throttled(rate_limit) => rate_limit,
+        };
+
+        // The maximum wait includes the time spent behind the user's earlier
+        // requests in their lane, so no request is held for longer than that.
+        let mut deadline = pin!(executor.sleep(self.max_wait));
+        let lane = self.lanes.lock().entry(user_id).or_default().clone();
+        let guard = match select(pin!(lane.lock()), deadline.as_mut()).await {
+            Either::Left((guard, _)) => Some(guard),
+            Either::Right(_) => None,
+        };
+
+        let result = match guard {
+            None => Err(rate_limit_exceeded(rate_limit)),
+            Some(_guard) => loop {
+                if let Either::Right(_) =
+                    select(pin!(executor.sleep(POLL_INTERVAL)), deadline.as_mut()).await
+                {
+                    break Err(rate_limit_exceeded(rate_limit));
+                }
+
+                match check().await {
+                    Ok(UsageCheck::Allowed(checked)) => break Ok(checked),
+                    Ok(UsageCheck::Exhausted(rate_limit)) => {
+                        break Err(rate_limit_exceeded(rate_limit))
+                    }
+                    Ok(UsageCheck::Throttled(throttled_rate_limit)) => {
+                        rate_limit = throttled_rate_limit
+                    }
+                    Err(error) => break Err(error),
+                }
+            },
+        };
+
+        // Forget about this user's lane once nobody else is waiting in it.
+        drop(lane);
+        self.lanes
+            .lock()
+            .retain(|_, lane| Arc::strong_count(lane) > 1);
+
+        result
+    }
+}
+
+/// Rejects a request with a 429 whose JSON body describes the limit it
+/// exceeded, so that clients can tell the user when to try again.
+pub fn rate_limit_exceeded(rate_limit: RateLimitExceeded) -> Error {
+    let mut headers = HeaderMap::new();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    headers.insert(RETRY_AFTER, HeaderValue::from(rate_limit.reset_after_secs));
+    let body = serde_json::to_string(&rate_limit).unwrap_or_default();
+    Error::Http(StatusCode::TOO_MANY_REQUESTS, body, headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt as _;
+    use gpui::TestAppContext;
+    use rpc::RateLimitScope;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    #[gpui::test]
+    async fn test_usage_queue(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let queue = Arc::new(UsageQueue::new(Duration::from_secs(2)));
+
+        // A brief burst is queued until the per-minute window has room again.
+        let checks = Arc::new(AtomicUsize::new(0));
+        let burst = cx.executor().spawn({
+            let queue = queue.clone();
+            let executor = executor.clone();
+            let checks = checks.clone();
+            async move {
+                queue
+                    .wait_for_capacity(1, &executor, || {
+                        let check_count = checks.fetch_add(1, SeqCst);
+                        async move {
+                            if check_count < 3 {
+                                Ok(UsageCheck::Throttled(REQUESTS_PER_MINUTE))
+                            } else {
+                                Ok(UsageCheck::Allowed(()))
+                            }
+                        }
+                    })
+                    .await
+            }
+        });
+        cx.executor().advance_clock(Duration::from_secs(1));
+        burst.await.unwrap();
+        assert_eq!(checks.load(SeqCst), 4);
+
+        // Sustained overuse is still rejected once the maximum wait elapses.
+        let sustained = cx.executor().spawn({
+            let queue = queue.clone();
+            let executor = executor.clone();
+            async move {
+                queue
+                    .wait_for_capacity(1, &executor, || async {
+                        Ok(UsageCheck::<()>::Throttled(REQUESTS_PER_MINUTE))
+                    })
+                    .await
+            }
+        });
+        cx.executor().advance_clock(Duration::from_secs(3));
+        let error = sustained.await.unwrap_err();
+        assert_eq!(rate_limit_in(&error), REQUESTS_PER_MINUTE);
+
+        // Exhausting a long-term limit is rejected without queueing.
+        let error = queue
+            .wait_for_capacity(1, &executor, || async {
+                Ok(UsageCheck::<()>::Exhausted(TOKENS_PER_DAY))
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(rate_limit_in(&error), TOKENS_PER_DAY);
+        assert!(queue.lanes.lock().is_empty());
+    }
+
+    #[gpui::test]
+    async fn test_usage_queue_max_wait_includes_time_in_lane(cx: &mut TestAppContext) {
+        let executor = Executor::Deterministic(cx.executor());
+        let queue = Arc::new(UsageQueue::new(Duration::from_secs(2)));
+
+        // Requests queued behind each other all give up once the maximum wait
+        // has elapsed since they arrived, rather than each waiting in turn.
+        let requests = (0..3)
+            .map(|_| {
+                cx.executor().spawn({
+                    let queue = queue.clone();
+                    let executor = executor.clone();
+                    async move {
+                        queue
+                            .wait_for_capacity(1, &executor, || async {
+                                Ok(UsageCheck::<()>::Throttled(REQUESTS_PER_MINUTE))
+                            })
+                            .await
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        cx.executor().advance_clock(Duration::from_secs(2));
+        for request in requests {
+            let error = request
+                .now_or_never()
+                .expect("request should have stopped waiting")
+                .unwrap_err();
+            assert_eq!(rate_limit_in(&error), REQUESTS_PER_MINUTE);
+        }
+        assert!(queue.lanes.lock().is_empty());
+    }
+
+    #[test]
+    fn test_rate_limit_exceeded_response() {
+        let Error::Http(status, body, headers) = rate_limit_exceeded(TOKENS_PER_DAY) else {
+            panic!("expected an HTTP error");
+        };
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(headers[CONTENT_TYPE], "application/json");
+        assert_eq!(headers[RETRY_AFTER], "1800");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "scope": "tokens-day",
+                "limit": 100000,
+                "reset_after_secs": 1800,
+            })
+        );
+    }
+
+    const REQUESTS_PER_MINUTE: RateLimitExceeded = RateLimitExceeded {
+        scope: RateLimitScope::RequestsPerMinute,
+        limit: 10,
+        reset_after_secs: 5,
+    };
+
+    const TOKENS_PER_DAY: RateLimitExceeded = RateLimitExceeded {
+        scope: RateLimitScope::TokensPerDay,
+        limit: 100000,
+        reset_after_secs: 1800,
+    };
+
+    fn rate_limit_in(error: &Error) -> RateLimitExceeded {
+        let Error::Http(StatusCode::TOO_MANY_REQUESTS, body, _) = error else {
+            panic!("expected a 429, got {error}");
+        };
+        serde_json::from_str(body).unwrap()
+    }
+}
@@ -655,6 +655,14 @@ impl TestServer {
                 llm_database_max_connections: None,
                 llm_database_migrations_path: None,
                 llm_api_secret: None,
+                llm_upstream_connect_timeout_secs: None,
+                llm_upstream_read_timeout_secs: None,
+                llm_heartbeat_interval_secs: None,
+                llm_disconnect_grace_period_secs: None,
+                llm_active_user_count_cache_secs: None,
+                llm_usage_queue_max_wait_ms: None,
+                llm_prompt_templates_path: None,
+                llm_experiment_models: None,
                 rust_log: None,
                 log_json: None,
                 zed_environment: "test".into(),
@@ -666,6 +674,12 @@ impl TestServer {
                 openai_api_key: None,
                 google_ai_api_key: None,
                 anthropic_api_key: None,
+                openai_api_keys: None,
+                google_ai_api_keys: None,
+                anthropic_api_keys: None,
+                openai_api_url: None,
+                google_ai_api_url: None,
+                anthropic_api_url: None,
                 clickhouse_url: None,
                 clickhouse_user: None,
                 clickhouse_password: None,
@@ -0,0 +1,252 @@
+//! Recording and replaying of HTTP interactions, so that tests can exercise
+//! response parsers against real provider output without hitting the network.
+//!
+//! Wrap a real client in a [`RecordingHttpClient`] to capture a cassette, then
+//! load it into a [`ReplayHttpClient`] in tests.
+
+use crate::{AsyncBody, Error, HttpClient, Request, Response, Uri};
+use anyhow::Context as _;
+use futures::{future::BoxFuture, AsyncReadExt as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+/// A sequence of recorded HTTP interactions.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cassette {
+    pub interactions: Vec<Interaction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Interaction {
+    pub request: RecordedRequest,
+    pub response: RecordedResponse,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedRequest {
+    pub method: String,
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecordedResponse {
+    pub status: u16,
+    #[serde(default)]
+    pub headers: Vec<(String, String)>,
+    /// The raw body, stored as base64 since it isn't necessarily UTF-8.
+    #[serde(with = "base64_body")]
+    pub body: Vec<u8>,
+}
+
+mod base64_body {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde::{Deserialize as _, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(body: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(body))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let body = String::deserialize(deserializer)?;
+        STANDARD.decode(body).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Cassette {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read cassette {path:?}"))?;
+        serde_json::from_str(&contents).with_context(|| format!("invalid cassette {path:?}"))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write cassette {path:?}"))
+    }
+}
+
+/// An [`HttpClient`] that forwards requests to another client and records
+/// every interaction, to be written out with [`RecordingHttpClient::save`].
+///
+/// Response bodies are read in full before being handed back, so streamed
+/// responses arrive all at once while recording.
+pub struct RecordingHttpClient {
+    client: Arc<dyn HttpClient>,
+    path: PathBuf,
+    cassette: Arc<Mutex<Cassette>>,
+}
+
+impl RecordingHttpClient {
+    pub fn new(client: Arc<dyn HttpClient>, path: impl Into<PathBuf>) -> Self {
+        Self {
+            client,
+            path: path.into(),
+            cassette: Default::default(),
+        }
+    }
+
+    pub fn cassette(&self) -> Cassette {
+        self.cassette.lock().unwrap().clone()
+    }
+
+    /// Writes the interactions recorded so far to the cassette file.
+    pub fn save(&self) -> anyhow::Result<()> {
+        self.cassette().save(&self.path)
+    }
+}
+
+impl HttpClient for RecordingHttpClient {
+    fn send(
+        &self,
+        req: Request<AsyncBody>,
+    ) -> BoxFuture<'static, Result<Response<AsyncBody>, Error>> {
+        let request = RecordedRequest {
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+        };
+        let response = self.client.send(req);
+        let cassette = self.cassette.clone();
+        Box::pin(async move {
+            let (parts, mut body) = response.await?.into_parts();
+            let mut bytes = Vec::new();
+            body.read_to_end(&mut bytes).await?;
+
+            let headers = parts
+                .headers
+                .iter()
+                .filter_map(|(name, value)| {
+                    Some((name.to_string(), value.to_str().ok()?.to_string()))
+                })
+                .collect();
+            cassette.lock().unwrap().interactions.push(Interaction {
+                request,
+                response: RecordedResponse {
+                    status: parts.status.as_u16(),
+                    headers,
+                    body: bytes.clone(),
+                },
+            });
+
+            Ok(Response::from_parts(parts, bytes.into()))
+        })
+    }
+
+    fn proxy(&self) -> Option<&Uri> {
+        None
+    }
+}
+
+/// An [`HttpClient`] that answers requests from a [`Cassette`], in order.
+///
+/// Panics if a request doesn't match the next recorded one, or if the
+/// cassette has run out of interactions.
+pub struct ReplayHttpClient {
+    interactions: Mutex<VecDeque<Interaction>>,
+}
+
+impl ReplayHttpClient {
+    pub fn new(cassette: Cassette) -> Self {
+        Self {
+            interactions: Mutex::new(cassette.interactions.into()),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(Self::new(Cassette::load(path)?))
+    }
+}
+
+impl HttpClient for ReplayHttpClient {
+    fn send(
+        &self,
+        req: Request<AsyncBody>,
+    ) -> BoxFuture<'static, Result<Response<AsyncBody>, Error>> {
+        let interaction = self
+            .interactions
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| panic!("no recorded response for {} {}", req.method(), req.uri()));
+        assert_eq!(
+            (req.method().as_str(), req.uri().to_string()),
+            (
+                interaction.request.method.as_str(),
+                interaction.request.uri.clone()
+            ),
+            "request doesn't match the cassette"
+        );
+
+        let mut response = Response::builder().status(interaction.response.status);
+        for (name, value) in &interaction.response.headers {
+            response = response.header(name, value);
+        }
+        let response = response
+            .body(interaction.response.body.into())
+            .map_err(Error::from);
+        Box::pin(async move { response })
+    }
+
+    fn proxy(&self) -> Option<&Uri> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay() {
+        let cassette = Cassette {
+            interactions: vec![Interaction {
+                request: RecordedRequest {
+                    method: "GET".into(),
+                    uri: "http://test.example/completions".into(),
+                },
+                response: RecordedResponse {
+                    status: 200,
+                    headers: vec![("content-type".into(), "text/event-stream".into())],
+                    body: b"data: {\"text\":\"Hello\"}\n\ndata: [DONE]\n\n\xff".to_vec(),
+                },
+            }],
+        };
+
+        // Record a session against a client that serves the cassette...
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cassettes/completions.json");
+        let recorder =
+            RecordingHttpClient::new(Arc::new(ReplayHttpClient::new(cassette.clone())), &path);
+        let recorded_body = futures::executor::block_on(get_body(&recorder));
+        recorder.save().unwrap();
+        assert_eq!(Cassette::load(&path).unwrap(), cassette);
+
+        // ...and replay it from disk.
+        let replayer = ReplayHttpClient::load(&path).unwrap();
+        let replayed_body = futures::executor::block_on(get_body(&replayer));
+        assert_eq!(replayed_body, recorded_body);
+        assert_eq!(replayed_body, cassette.interactions[0].response.body);
+    }
+
+    async fn get_body(client: &dyn HttpClient) -> Vec<u8> {
+        let mut response = client
+            .get("http://test.example/completions", AsyncBody::empty(), false)
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers()["content-type"].to_str().unwrap(),
+            "text/event-stream"
+        );
+        let mut body = Vec::new();
+        response.body_mut().read_to_end(&mut body).await.unwrap();
+        body
+    }
+}
@@ -0,0 +1,175 @@
+//! Scripted failures for HTTP requests, so that tests can check how callers
+//! handle errors, slow responses and dropped connections.
+
+use crate::{AsyncBody, Error, HttpClient, Request, Response, StatusCode, Uri};
+use futures::{future::BoxFuture, io::Cursor, AsyncRead, AsyncReadExt as _};
+use std::{
+    collections::VecDeque,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// What [`FaultInjectingHttpClient`] does with a request.
+pub enum Fault {
+    /// Forwards the request unchanged.
+    None,
+    /// Responds with the given status and an empty body, without forwarding
+    /// the request.
+    Status(StatusCode),
+    /// Waits for the given future, such as a timer on the test's executor,
+    /// before forwarding the request.
+    Delay(BoxFuture<'static, ()>),
+    /// Forwards the request, but fails reading the response body after the
+    /// given number of bytes, as if the connection had dropped mid-stream.
+    Disconnect { after_bytes: usize },
+}
+
+/// An [`HttpClient`] that applies a script of [`Fault`]s to the requests sent
+/// through it, one per request, and forwards requests normally once the
+/// script runs out.
+pub struct FaultInjectingHttpClient {
+    client: Arc<dyn HttpClient>,
+    faults: Mutex<VecDeque<Fault>>,
+}
+
+impl FaultInjectingHttpClient {
+    pub fn new(client: Arc<dyn HttpClient>, faults: impl IntoIterator<Item = Fault>) -> Self {
+        Self {
+            client,
+            faults: Mutex::new(faults.into_iter().collect()),
+        }
+    }
+
+    /// Adds faults to the end of the script.
+    pub fn push_faults(&self, faults: impl IntoIterator<Item = Fault>) {
+        self.faults.lock().unwrap().extend(faults);
+    }
+}
+
+impl HttpClient for FaultInjectingHttpClient {
+    fn send(
+        &self,
+        req: Request<AsyncBody>,
+    ) -> BoxFuture<'static, Result<Response<AsyncBody>, Error>> {
+        let fault = self
+            .faults
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(Fault::None);
+        match fault {
+            Fault::None => self.client.send(req),
+            Fault::Status(status) => {
+                let response = Response::builder()
+                    .status(status)
+                    .body(AsyncBody::empty())
+                    .map_err(Error::from);
+                Box::pin(async move { response })
+            }
+            Fault::Delay(delay) => {
+                let client = self.client.clone();
+                Box::pin(async move {
+                    delay.await;
+                    client.send(req).await
+                })
+            }
+            Fault::Disconnect { after_bytes } => {
+                let response = self.client.send(req);
+                Box::pin(async move {
+                    let (parts, mut body) = response.await?.into_parts();
+                    let mut bytes = Vec::new();
+                    body.read_to_end(&mut bytes).await?;
+                    bytes.truncate(after_bytes);
+                    let body = AsyncBody::from_reader(DisconnectingReader(Cursor::new(bytes)));
+                    Ok(Response::from_parts(parts, body))
+                })
+            }
+        }
+    }
+
+    fn proxy(&self) -> Option<&Uri> {
+        None
+    }
+}
+
+/// Reads the given bytes, then fails as though the connection was reset.
+struct DisconnectingReader(Cursor<Vec<u8>>);
+
+impl AsyncRead for DisconnectingReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match Pin::new(&mut self.0).poll_read(cx, buf) {
+            Poll::Ready(Ok(0)) if !buf.is_empty() => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "connection reset by fault injection",
+            ))),
+            poll => poll,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cassette::{
+        Cassette, Interaction, RecordedRequest, RecordedResponse, ReplayHttpClient,
+    };
+    use futures::{channel::oneshot, executor::block_on, FutureExt as _};
+
+    #[test]
+    fn test_fault_injection() {
+        let body = "data: {\"text\":\"Hello\"}\n\ndata: [DONE]\n\n";
+        let interaction = Interaction {
+            request: RecordedRequest {
+                method: "GET".into(),
+                uri: "http://test.example/completions".into(),
+            },
+            response: RecordedResponse {
+                status: 200,
+                headers: Vec::new(),
+                body: body.into(),
+            },
+        };
+        let client = FaultInjectingHttpClient::new(
+            Arc::new(ReplayHttpClient::new(Cassette {
+                interactions: vec![interaction.clone(), interaction],
+            })),
+            [
+                Fault::Status(StatusCode::TOO_MANY_REQUESTS),
+                Fault::Disconnect { after_bytes: 10 },
+            ],
+        );
+
+        // Injected statuses don't reach the underlying client...
+        let response = block_on(get(&client)).unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // ...while disconnects cut the real response short...
+        let mut response = block_on(get(&client)).unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut read = Vec::new();
+        let error = block_on(response.body_mut().read_to_end(&mut read)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(read, &body.as_bytes()[..10]);
+
+        // ...and delayed requests are only sent once the delay completes.
+        let (finish_delay, delay) = oneshot::channel::<()>();
+        client.push_faults([Fault::Delay(delay.map(|_| ()).boxed())]);
+        let mut response = get(&client);
+        assert!((&mut response).now_or_never().is_none());
+        finish_delay.send(()).unwrap();
+        let mut response = block_on(response).unwrap();
+        let mut read = String::new();
+        block_on(response.body_mut().read_to_string(&mut read)).unwrap();
+        assert_eq!(read, body);
+    }
+
+    fn get(client: &dyn HttpClient) -> BoxFuture<'_, Result<Response<AsyncBody>, Error>> {
+        client.get("http://test.example/completions", AsyncBody::empty(), false)
+    }
+}
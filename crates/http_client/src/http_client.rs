@@ -1,3 +1,7 @@
+#[cfg(any(test, feature = "test-support"))]
+pub mod cassette;
+#[cfg(any(test, feature = "test-support"))]
+pub mod fault_injection;
 pub mod github;
 
 pub use anyhow::{anyhow, Result};
@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Result};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering::SeqCst},
+        Arc,
+    },
+};
+
+/// Splits a stored API key into the keys it contains, since several keys can
+/// be entered at once, separated by commas or whitespace.
+pub fn parse_api_keys(api_key: &str) -> Vec<String> {
+    api_key
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|key| !key.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Spreads requests across a provider's API keys in round-robin order.
+#[derive(Clone, Default)]
+pub struct ApiKeyRotation {
+    next_index: Arc<AtomicUsize>,
+}
+
+impl ApiKeyRotation {
+    /// Returns the keys to try for the next request, starting with the next
+    /// key in the rotation and followed by the others as fallbacks.
+    pub fn keys_for_next_request(&self, api_key: Option<&str>) -> Result<Vec<String>> {
+        let mut keys = api_key.map(parse_api_keys).unwrap_or_default();
+        if keys.is_empty() {
+            return Err(anyhow!("missing api key"));
+        }
+
+        let start = self.next_index.fetch_add(1, SeqCst) % keys.len();
+        keys.rotate_left(start);
+        Ok(keys)
+    }
+}
+
+/// Makes a request with each of the given keys in turn, moving on to the next
+/// key only when the previous one was rate limited.
+pub async fn with_api_key_failover<T, E, Fut>(
+    keys: Vec<String>,
+    is_rate_limited: impl Fn(&E) -> bool,
+    mut request: impl FnMut(String) -> Fut,
+) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+    E: From<anyhow::Error>,
+{
+    let mut keys = keys.into_iter().peekable();
+    while let Some(key) = keys.next() {
+        match request(key).await {
+            Err(error) if keys.peek().is_some() && is_rate_limited(&error) => {
+                log::warn!("API key was rate limited, retrying with the next key");
+            }
+            result => return result,
+        }
+    }
+    Err(anyhow!("missing api key").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_robin_api_keys() {
+        assert_eq!(
+            parse_api_keys(" key-a,key-b\n key-c ,"),
+            vec!["key-a", "key-b", "key-c"]
+        );
+
+        let rotation = ApiKeyRotation::default();
+        let api_key = Some("key-a, key-b, key-c");
+        assert_eq!(
+            rotation.keys_for_next_request(api_key).unwrap(),
+            vec!["key-a", "key-b", "key-c"]
+        );
+        assert_eq!(
+            rotation.keys_for_next_request(api_key).unwrap(),
+            vec!["key-b", "key-c", "key-a"]
+        );
+        assert_eq!(
+            rotation.keys_for_next_request(api_key).unwrap(),
+            vec!["key-c", "key-a", "key-b"]
+        );
+        assert_eq!(
+            rotation.keys_for_next_request(api_key).unwrap(),
+            vec!["key-a", "key-b", "key-c"]
+        );
+
+        assert_eq!(
+            rotation
+                .keys_for_next_request(Some(" , "))
+                .unwrap_err()
+                .to_string(),
+            "missing api key"
+        );
+        assert!(rotation.keys_for_next_request(None).is_err());
+    }
+
+    #[gpui::test]
+    async fn test_api_key_failover() {
+        let keys = vec!["key-a".to_string(), "key-b".into(), "key-c".into()];
+        let is_rate_limited = |error: &anyhow::Error| error.to_string() == "rate limited";
+
+        // Rate-limited keys fail over to the next one...
+        let mut attempted_keys = Vec::new();
+        let result = with_api_key_failover(keys.clone(), is_rate_limited, |key| {
+            attempted_keys.push(key.clone());
+            async move {
+                if key == "key-c" {
+                    Ok(key)
+                } else {
+                    Err(anyhow!("rate limited"))
+                }
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "key-c");
+        assert_eq!(attempted_keys, ["key-a", "key-b", "key-c"]);
+
+        // ...but other errors don't.
+        let mut attempted_keys = Vec::new();
+        let result: Result<String> = with_api_key_failover(keys.clone(), is_rate_limited, |key| {
+            attempted_keys.push(key);
+            async move { Err(anyhow!("invalid request")) }
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "invalid request");
+        assert_eq!(attempted_keys, ["key-a"]);
+
+        // The last key's error is returned once every key is rate limited.
+        let result: Result<String> = with_api_key_failover(keys, is_rate_limited, |_| async move {
+            Err(anyhow!("rate limited"))
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "rate limited");
+    }
+}
@@ -0,0 +1,92 @@
+use crate::{settings::AllLanguageModelSettings, LanguageModelId, LanguageModelRequest};
+use gpui::AppContext;
+use settings::Settings;
+
+/// What a model charges for the tokens it reads and writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LanguageModelPricing {
+    /// The price of a million input tokens, in dollars.
+    pub input_price_per_million_tokens: f64,
+    /// The price of a million output tokens, in dollars.
+    pub output_price_per_million_tokens: f64,
+}
+
+/// Returns how many tokens to assume a completion from the given model will
+/// produce, for estimates made before it's sent.
+pub fn assumed_output_tokens(model_id: &LanguageModelId, cx: &AppContext) -> usize {
+    let settings = AllLanguageModelSettings::get_global(cx);
+    settings
+        .assumed_output_tokens_per_model
+        .get(model_id.0.as_ref())
+        .copied()
+        .unwrap_or(settings.assumed_output_tokens)
+}
+
+impl LanguageModelRequest {
+    /// Estimates what sending the request would cost, in dollars, assuming
+    /// that the completion will be `assumed_output_tokens` long.
+    pub fn estimate_cost(
+        &self,
+        pricing: &LanguageModelPricing,
+        assumed_output_tokens: usize,
+    ) -> f64 {
+        let input_cost =
+            self.estimated_token_count() as f64 * pricing.input_price_per_million_tokens;
+        let output_cost = assumed_output_tokens as f64 * pricing.output_price_per_million_tokens;
+        (input_cost + output_cost) / 1_000_000.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LanguageModelRequestMessage, Role};
+    use settings::SettingsStore;
+
+    #[gpui::test]
+    fn test_assumed_output_tokens_in_cost_estimate(cx: &mut AppContext) {
+        let settings_store = SettingsStore::test(cx);
+        cx.set_global(settings_store);
+        AllLanguageModelSettings::register(cx);
+
+        let pricing = LanguageModelPricing {
+            input_price_per_million_tokens: 3.,
+            output_price_per_million_tokens: 15.,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "a".repeat(400),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let model_id = LanguageModelId::from("claude-3-5-sonnet-20240620".to_string());
+        let estimate_cost =
+            |cx: &AppContext| request.estimate_cost(&pricing, assumed_output_tokens(&model_id, cx));
+
+        // 100 input tokens at $3 per million, and the default of 1000 output
+        // tokens at $15 per million.
+        assert_eq!(assumed_output_tokens(&model_id, cx), 1000);
+        assert_eq!(estimate_cost(cx), 0.0153);
+
+        SettingsStore::update_global(cx, |store, cx| {
+            store.update_user_settings::<AllLanguageModelSettings>(cx, |settings| {
+                settings.assumed_output_tokens = Some(2000);
+            });
+        });
+        assert_eq!(estimate_cost(cx), 0.0303);
+
+        SettingsStore::update_global(cx, |store, cx| {
+            store.update_user_settings::<AllLanguageModelSettings>(cx, |settings| {
+                settings.assumed_output_tokens_per_model = Some(
+                    [("claude-3-5-sonnet-20240620".to_string(), 0)]
+                        .into_iter()
+                        .collect(),
+                );
+            });
+        });
+        assert_eq!(estimate_cost(cx), 0.0003);
+    }
+}
@@ -0,0 +1,60 @@
+use gpui::SharedString;
+use http_client::Url;
+
+/// A problem with a provider's configuration, such as a setting that is
+/// missing or invalid, with a message explaining how to fix it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LanguageModelProviderDiagnostic {
+    pub message: SharedString,
+}
+
+impl LanguageModelProviderDiagnostic {
+    pub fn new(message: impl Into<SharedString>) -> Self {
+        Self {
+            message: message.into(),
+        }
+    }
+}
+
+/// Checks the settings shared by providers that talk to an HTTP API, where
+/// `settings_key` is the provider's key within the `language_models` settings.
+pub(crate) fn diagnose_provider_settings<'a>(
+    settings_key: &str,
+    api_url: &str,
+    custom_models: impl IntoIterator<Item = (&'a str, usize)>,
+    has_models: bool,
+) -> Vec<LanguageModelProviderDiagnostic> {
+    let mut diagnostics = Vec::new();
+
+    let is_valid_url =
+        Url::parse(api_url).map_or(false, |url| matches!(url.scheme(), "http" | "https"));
+    if !is_valid_url {
+        diagnostics.push(LanguageModelProviderDiagnostic::new(format!(
+            "`language_models.{settings_key}.api_url` is not a valid URL: {api_url:?}. \
+             Set it to an http:// or https:// URL, or remove it to use the default."
+        )));
+    }
+
+    for (name, max_tokens) in custom_models {
+        if name.trim().is_empty() {
+            diagnostics.push(LanguageModelProviderDiagnostic::new(format!(
+                "A model in `language_models.{settings_key}.available_models` has an empty \
+                 `name`. Set it to the model's id in the provider's API."
+            )));
+        } else if max_tokens == 0 {
+            diagnostics.push(LanguageModelProviderDiagnostic::new(format!(
+                "Model {name:?} in `language_models.{settings_key}.available_models` has a \
+                 `max_tokens` of 0. Set it to the size of the model's context window."
+            )));
+        }
+    }
+
+    if !has_models {
+        diagnostics.push(LanguageModelProviderDiagnostic::new(format!(
+            "Every model is listed in `language_models.{settings_key}.disabled_models`. \
+             Remove at least one of them to use this provider."
+        )));
+    }
+
+    diagnostics
+}
@@ -0,0 +1,179 @@
+use client::RateLimitScope;
+use std::{fmt, time::Duration};
+
+/// How similar a known model id has to be to one that wasn't found for it to
+/// be suggested instead, as a normalized edit distance.
+const MIN_MODEL_SUGGESTION_SIMILARITY: f64 = 0.8;
+const MAX_MODEL_SUGGESTIONS: usize = 3;
+
+/// An error reported by a language model provider that callers may want to
+/// handle specifically, regardless of which provider produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanguageModelError {
+    /// The request didn't fit in the model's context window. `limit` is the
+    /// maximum number of tokens the provider reported, if it reported one.
+    ContextWindowExceeded { limit: Option<usize> },
+    /// The provider is temporarily overloaded. Unlike rate limits, this
+    /// doesn't depend on the caller, so the request can be retried as is
+    /// after a short wait.
+    Overloaded,
+    /// The model doesn't support a feature the request uses, such as tools.
+    UnsupportedFeature { feature: String },
+    /// One of the request's parameters has a value that isn't accepted.
+    InvalidParameter { name: String, reason: String },
+    /// The user exceeded one of their zed.dev rate limits. The request may
+    /// fit within the limit again after `retry_after`.
+    RateLimited {
+        scope: RateLimitScope,
+        retry_after: Duration,
+    },
+    /// The request waited `waited` for a slot in the client's rate limiter
+    /// without being admitted, so it was never sent.
+    QueueTimeout { waited: Duration },
+    /// The provider doesn't know the requested model, which is usually a typo
+    /// in a custom model's name. `available` holds the provider's models
+    /// whose ids are closest to `requested`, most similar first.
+    ModelNotFound {
+        requested: String,
+        available: Vec<String>,
+    },
+    /// The provider no longer accepts the `version` of its API that the
+    /// request was sent with, in the `header` it's configured by.
+    ApiVersionUnsupported { header: String, version: String },
+}
+
+impl LanguageModelError {
+    /// Whether the same request may succeed if it's sent again later.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Overloaded | Self::RateLimited { .. })
+    }
+
+    /// Reports that `requested` wasn't found, suggesting the most similar of
+    /// the provider's `known_model_ids`.
+    pub(crate) fn model_not_found<'a>(
+        requested: &str,
+        known_model_ids: impl IntoIterator<Item = &'a str>,
+    ) -> Self {
+        let mut suggestions = known_model_ids
+            .into_iter()
+            .filter(|id| *id != requested)
+            .map(|id| (strsim::normalized_damerau_levenshtein(requested, id), id))
+            .filter(|(similarity, _)| *similarity >= MIN_MODEL_SUGGESTION_SIMILARITY)
+            .collect::<Vec<_>>();
+        suggestions.sort_by(|(a_similarity, a_id), (b_similarity, b_id)| {
+            b_similarity.total_cmp(a_similarity).then(a_id.cmp(b_id))
+        });
+        suggestions.dedup_by_key(|(_, id)| *id);
+
+        Self::ModelNotFound {
+            requested: requested.into(),
+            available: suggestions
+                .into_iter()
+                .take(MAX_MODEL_SUGGESTIONS)
+                .map(|(_, id)| id.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for LanguageModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ContextWindowExceeded { limit: Some(limit) } => write!(
+                f,
+                "The prompt exceeds the model's context window of {limit} tokens. \
+                Try removing some messages or context and sending it again."
+            ),
+            Self::ContextWindowExceeded { limit: None } => write!(
+                f,
+                "The prompt exceeds the model's context window. \
+                Try removing some messages or context and sending it again."
+            ),
+            Self::Overloaded => write!(
+                f,
+                "The model's provider is currently overloaded. Try again in a moment."
+            ),
+            Self::UnsupportedFeature { feature } => {
+                write!(f, "The model doesn't support {feature}.")
+            }
+            Self::InvalidParameter { name, reason } => write!(f, "Invalid {name}: {reason}."),
+            Self::RateLimited { scope, retry_after } => {
+                let seconds = retry_after.as_secs().max(1);
+                let wait = if seconds < 60 {
+                    format!("{seconds} second{}", if seconds == 1 { "" } else { "s" })
+                } else {
+                    let minutes = seconds.div_ceil(60);
+                    format!("{minutes} minute{}", if minutes == 1 { "" } else { "s" })
+                };
+                write!(
+                    f,
+                    "You've exceeded your limit of {scope}. Try again in {wait}."
+                )
+            }
+            Self::QueueTimeout { waited } => write!(
+                f,
+                "The request waited {} seconds to be sent without getting a turn. \
+                Try again once other requests have finished.",
+                waited.as_secs()
+            ),
+            Self::ModelNotFound {
+                requested,
+                available,
+            } => {
+                write!(f, "The model \"{requested}\" wasn't found. ")?;
+                match available.split_last() {
+                    Some((last, [])) => write!(f, "Did you mean \"{last}\"?"),
+                    Some((last, rest)) => {
+                        let rest = rest
+                            .iter()
+                            .map(|id| format!("\"{id}\""))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        write!(f, "Did you mean {rest} or \"{last}\"?")
+                    }
+                    None => write!(f, "Check that its id is spelled correctly."),
+                }
+            }
+            Self::ApiVersionUnsupported { header, version } => write!(
+                f,
+                "The provider no longer supports version \"{version}\" of its API. \
+                Update the configured `{header}` to a supported version, \
+                which may require updating Zed."
+            ),
+        }
+    }
+}
+
+impl std::error::Error for LanguageModelError {}
+
+/// Returns the number that immediately follows `prefix` in `message`, e.g.
+/// the `8192` in "maximum context length is 8192 tokens".
+pub(crate) fn number_after(message: &str, prefix: &str) -> Option<usize> {
+    let (_, rest) = message.split_once(prefix)?;
+    let digits = rest
+        .trim_start()
+        .split(|c: char| !c.is_ascii_digit())
+        .next()?;
+    digits.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_number_after() {
+        assert_eq!(
+            number_after(
+                "This model's maximum context length is 8192 tokens. However, ...",
+                "maximum context length is"
+            ),
+            Some(8192)
+        );
+        assert_eq!(
+            number_after("prompt is too long: 208310 tokens > 200000 maximum", ">"),
+            Some(200000)
+        );
+        assert_eq!(number_after("prompt is too long", ">"), None);
+    }
+}
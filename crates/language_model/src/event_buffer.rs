@@ -0,0 +1,181 @@
+use futures::{future, Stream, StreamExt};
+use gpui::{BackgroundExecutor, Task};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+};
+
+/// What [`buffer_events`] does when its buffer is full because the consumer
+/// isn't keeping up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BufferPolicy {
+    /// Stops reading from the provider until the consumer catches up.
+    Block,
+    /// Keeps reading from the provider, dropping the oldest buffered events to
+    /// make room and reporting how many were dropped in their place.
+    DropOldest,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BufferedEvent<T> {
+    Event(T),
+    /// The given number of events were dropped here to keep up.
+    Dropped(usize),
+}
+
+struct Buffer<T> {
+    events: VecDeque<T>,
+    dropped: usize,
+    is_finished: bool,
+    consumer: Option<Waker>,
+    producer: Option<Waker>,
+}
+
+/// Reads a stream, such as the events from a completion, into a buffer of at
+/// most `capacity` events, so that a consumer that falls behind during a burst
+/// is handled according to `policy` rather than stalling the provider.
+///
+/// The stream is read on the background executor until the returned stream is
+/// dropped.
+pub fn buffer_events<T: Send + 'static>(
+    events: impl Stream<Item = T> + Send + 'static,
+    capacity: usize,
+    policy: BufferPolicy,
+    executor: &BackgroundExecutor,
+) -> impl Stream<Item = BufferedEvent<T>> {
+    let capacity = capacity.max(1);
+    let buffer = Arc::new(Mutex::new(Buffer {
+        events: VecDeque::with_capacity(capacity),
+        dropped: 0,
+        is_finished: false,
+        consumer: None,
+        producer: None,
+    }));
+
+    let producer = executor.spawn({
+        let buffer = buffer.clone();
+        async move {
+            futures::pin_mut!(events);
+            while let Some(event) = events.next().await {
+                if policy == BufferPolicy::Block {
+                    future::poll_fn(|cx| {
+                        let mut buffer = buffer.lock();
+                        if buffer.events.len() < capacity {
+                            Poll::Ready(())
+                        } else {
+                            buffer.producer = Some(cx.waker().clone());
+                            Poll::Pending
+                        }
+                    })
+                    .await;
+                }
+
+                let mut buffer = buffer.lock();
+                if buffer.events.len() >= capacity {
+                    buffer.events.pop_front();
+                    buffer.dropped += 1;
+                }
+                buffer.events.push_back(event);
+                if let Some(consumer) = buffer.consumer.take() {
+                    consumer.wake();
+                }
+            }
+
+            let mut buffer = buffer.lock();
+            buffer.is_finished = true;
+            if let Some(consumer) = buffer.consumer.take() {
+                consumer.wake();
+            }
+        }
+    });
+
+    BufferedEvents {
+        buffer,
+        _producer: producer,
+    }
+}
+
+struct BufferedEvents<T> {
+    buffer: Arc<Mutex<Buffer<T>>>,
+    _producer: Task<()>,
+}
+
+impl<T> Stream for BufferedEvents<T> {
+    type Item = BufferedEvent<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let mut buffer = self.buffer.lock();
+        // Events are only ever dropped from the front of the buffer, so the
+        // gap they leave is always before the next buffered event.
+        if buffer.dropped > 0 {
+            return Poll::Ready(Some(BufferedEvent::Dropped(std::mem::take(
+                &mut buffer.dropped,
+            ))));
+        }
+
+        if let Some(event) = buffer.events.pop_front() {
+            if let Some(producer) = buffer.producer.take() {
+                producer.wake();
+            }
+            Poll::Ready(Some(BufferedEvent::Event(event)))
+        } else if buffer.is_finished {
+            Poll::Ready(None)
+        } else {
+            buffer.consumer = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpui::TestAppContext;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    #[gpui::test]
+    async fn test_block_policy(cx: &mut TestAppContext) {
+        let read_count = Arc::new(AtomicUsize::new(0));
+        let events = futures::stream::iter(0..10).inspect({
+            let read_count = read_count.clone();
+            move |_| {
+                read_count.fetch_add(1, SeqCst);
+            }
+        });
+        let buffered = buffer_events(events, 2, BufferPolicy::Block, &cx.executor());
+
+        // Reading stops once the buffer is full, holding one event until there's room.
+        cx.run_until_parked();
+        assert_eq!(read_count.load(SeqCst), 3);
+
+        assert_eq!(
+            buffered.collect::<Vec<_>>().await,
+            (0..10).map(BufferedEvent::Event).collect::<Vec<_>>()
+        );
+        assert_eq!(read_count.load(SeqCst), 10);
+    }
+
+    #[gpui::test]
+    async fn test_drop_oldest_policy(cx: &mut TestAppContext) {
+        let buffered = buffer_events(
+            futures::stream::iter(0..5),
+            2,
+            BufferPolicy::DropOldest,
+            &cx.executor(),
+        );
+
+        // The consumer falls behind while every event is read.
+        cx.run_until_parked();
+        assert_eq!(
+            buffered.collect::<Vec<_>>().await,
+            [
+                BufferedEvent::Dropped(3),
+                BufferedEvent::Event(3),
+                BufferedEvent::Event(4),
+            ]
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{anyhow, Result};
+use collections::HashMap;
+use futures::{
+    future::{self, AbortHandle, Abortable, BoxFuture},
+    stream::BoxStream,
+    FutureExt, StreamExt,
+};
+use parking_lot::Mutex;
+use std::{sync::Arc, task::Poll};
+
+/// The completions that a provider's models are streaming, so that they can
+/// all be aborted at once.
+#[derive(Clone, Default)]
+pub struct InFlightCompletions {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: usize,
+    abort_handles: HashMap<usize, AbortHandle>,
+}
+
+/// Stops tracking a completion when it's dropped.
+struct TrackedCompletion {
+    id: usize,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for TrackedCompletion {
+    fn drop(&mut self) {
+        self.state.lock().abort_handles.remove(&self.id);
+    }
+}
+
+impl InFlightCompletions {
+    /// Tracks a completion from when it's requested until its stream ends or
+    /// is dropped.
+    ///
+    /// Aborting a completion that hasn't started streaming fails it, while
+    /// aborting one that has drops the underlying request and ends its stream.
+    pub fn track<T: 'static + Send>(
+        &self,
+        completion: BoxFuture<'static, Result<BoxStream<'static, Result<T>>>>,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<T>>>> {
+        let (tracked, mut aborted) = self.register();
+        let mut completion = completion;
+
+        async move {
+            let events = future::poll_fn(|cx| {
+                if aborted.poll_unpin(cx).is_ready() {
+                    return Poll::Ready(Err(anyhow!("completion was cancelled")));
+                }
+                completion.poll_unpin(cx)
+            })
+            .await?;
+
+            let mut events = Some(events);
+            Ok(futures::stream::poll_fn(move |cx| {
+                let _tracked = &tracked;
+                if aborted.poll_unpin(cx).is_ready() {
+                    events = None;
+                }
+                match events.as_mut() {
+                    Some(events) => events.poll_next_unpin(cx),
+                    None => Poll::Ready(None),
+                }
+            })
+            .boxed())
+        }
+        .boxed()
+    }
+
+    /// Tracks a request that resolves all at once, such as a tool use, until it
+    /// resolves or is dropped. Aborting it fails it.
+    pub fn track_future<T: 'static + Send>(
+        &self,
+        future: BoxFuture<'static, Result<T>>,
+    ) -> BoxFuture<'static, Result<T>> {
+        let (tracked, mut aborted) = self.register();
+        let mut future = future;
+        future::poll_fn(move |cx| {
+            let _tracked = &tracked;
+            if aborted.poll_unpin(cx).is_ready() {
+                return Poll::Ready(Err(anyhow!("completion was cancelled")));
+            }
+            future.poll_unpin(cx)
+        })
+        .boxed()
+    }
+
+    /// Starts tracking a request, returning a guard that stops tracking it and
+    /// a future that resolves once it's aborted.
+    fn register(&self) -> (TrackedCompletion, Abortable<future::Pending<()>>) {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let mut state = self.state.lock();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.abort_handles.insert(id, abort_handle);
+        let tracked = TrackedCompletion {
+            id,
+            state: self.state.clone(),
+        };
+        (
+            tracked,
+            Abortable::new(future::pending(), abort_registration),
+        )
+    }
+
+    /// Aborts every completion that's currently being tracked.
+    pub fn abort_all(&self) {
+        for abort_handle in self.state.lock().abort_handles.values() {
+            abort_handle.abort();
+        }
+    }
+}
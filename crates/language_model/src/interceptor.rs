@@ -0,0 +1,387 @@
+use crate::{
+    LanguageModelCompletionEvent, LanguageModelId, LanguageModelProviderId, LanguageModelRegistry,
+    LanguageModelRequest, PiiRedactor,
+};
+use anyhow::Result;
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use gpui::AsyncAppContext;
+use std::sync::Arc;
+
+/// Observes, and may adjust, the completions every model streams, for
+/// behavior that applies regardless of the provider, such as logging.
+///
+/// Interceptors are added to the [`LanguageModelRegistry`] and called in the
+/// order they were added.
+pub trait CompletionInterceptor: Send + Sync {
+    /// Called with each request before it's sent, after PII has been redacted.
+    fn on_request(
+        &self,
+        _provider: &LanguageModelProviderId,
+        _model: &LanguageModelId,
+        _request: &mut LanguageModelRequest,
+    ) {
+    }
+
+    /// Called with each event the completion streams.
+    fn on_event(
+        &self,
+        _provider: &LanguageModelProviderId,
+        _model: &LanguageModelId,
+        _event: &LanguageModelCompletionEvent,
+    ) {
+    }
+
+    /// Called when the completion fails, whether before or while streaming.
+    fn on_error(
+        &self,
+        _provider: &LanguageModelProviderId,
+        _model: &LanguageModelId,
+        _error: &anyhow::Error,
+    ) {
+    }
+
+    /// Called when the completion's stream ends, unless it was dropped first.
+    fn on_finish(&self, _provider: &LanguageModelProviderId, _model: &LanguageModelId) {}
+}
+
+/// Logs every completion's request and events at the debug and trace levels.
+pub struct LoggingInterceptor;
+
+impl CompletionInterceptor for LoggingInterceptor {
+    fn on_request(
+        &self,
+        provider: &LanguageModelProviderId,
+        model: &LanguageModelId,
+        request: &mut LanguageModelRequest,
+    ) {
+        log::debug!(
+            "requesting a completion of {} message(s) from {}/{}",
+            request.messages.len(),
+            provider.0,
+            model.0
+        );
+    }
+
+    fn on_event(
+        &self,
+        provider: &LanguageModelProviderId,
+        model: &LanguageModelId,
+        event: &LanguageModelCompletionEvent,
+    ) {
+        log::trace!(
+            "completion event from {}/{}: {event:?}",
+            provider.0,
+            model.0
+        );
+    }
+
+    fn on_error(
+        &self,
+        provider: &LanguageModelProviderId,
+        model: &LanguageModelId,
+        error: &anyhow::Error,
+    ) {
+        log::debug!(
+            "completion from {}/{} failed: {error:#}",
+            provider.0,
+            model.0
+        );
+    }
+
+    fn on_finish(&self, provider: &LanguageModelProviderId, model: &LanguageModelId) {
+        log::debug!("completion from {}/{} finished", provider.0, model.0);
+    }
+}
+
+/// The interceptors registered when a completion was requested.
+pub(crate) struct CompletionInterceptors {
+    provider: LanguageModelProviderId,
+    model: LanguageModelId,
+    interceptors: Vec<Arc<dyn CompletionInterceptor>>,
+}
+
+/// Passes `request` through the registry's interceptors, returning them so
+/// that they can observe the completion with [`CompletionInterceptors::intercept`].
+///
+/// PII is redacted first when it's enabled, so that no other interceptor sees it.
+pub(crate) fn intercept_request(
+    mut request: LanguageModelRequest,
+    provider: LanguageModelProviderId,
+    model: LanguageModelId,
+    cx: &AsyncAppContext,
+) -> (LanguageModelRequest, CompletionInterceptors) {
+    let interceptors = cx
+        .update(|cx| {
+            let redactor = PiiRedactor::from_settings(cx)
+                .map(|redactor| Arc::new(redactor) as Arc<dyn CompletionInterceptor>);
+            redactor
+                .into_iter()
+                .chain(LanguageModelRegistry::global_interceptors(cx))
+                .collect()
+        })
+        .unwrap_or_default();
+    for interceptor in &interceptors {
+        interceptor.on_request(&provider, &model, &mut request);
+    }
+    (
+        request,
+        CompletionInterceptors {
+            provider,
+            model,
+            interceptors,
+        },
+    )
+}
+
+impl CompletionInterceptors {
+    pub fn intercept(
+        self,
+        completion: BoxFuture<
+            'static,
+            Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
+        >,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        if self.interceptors.is_empty() {
+            return completion;
+        }
+
+        let this = Arc::new(self);
+        async move {
+            let events = match completion.await {
+                Ok(events) => events,
+                Err(error) => {
+                    this.on_error(&error);
+                    return Err(error);
+                }
+            };
+            let finished = this.clone();
+            Ok(events
+                .inspect(move |event| match event {
+                    Ok(event) => {
+                        for interceptor in &this.interceptors {
+                            interceptor.on_event(&this.provider, &this.model, event);
+                        }
+                    }
+                    Err(error) => this.on_error(error),
+                })
+                .chain(futures::stream::poll_fn(move |_| {
+                    for interceptor in &finished.interceptors {
+                        interceptor.on_finish(&finished.provider, &finished.model);
+                    }
+                    std::task::Poll::Ready(None)
+                }))
+                .boxed())
+        }
+        .boxed()
+    }
+
+    /// Observes a tool use, which has no events, so only its failure or
+    /// finish is reported.
+    pub fn intercept_tool_use(
+        self,
+        tool_use: BoxFuture<'static, Result<serde_json::Value>>,
+    ) -> BoxFuture<'static, Result<serde_json::Value>> {
+        if self.interceptors.is_empty() {
+            return tool_use;
+        }
+
+        async move {
+            let result = tool_use.await;
+            match &result {
+                Ok(_) => {
+                    for interceptor in &self.interceptors {
+                        interceptor.on_finish(&self.provider, &self.model);
+                    }
+                }
+                Err(error) => self.on_error(error),
+            }
+            result
+        }
+        .boxed()
+    }
+
+    fn on_error(&self, error: &anyhow::Error) {
+        for interceptor in &self.interceptors {
+            interceptor.on_error(&self.provider, &self.model, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::AllLanguageModelSettings;
+    use gpui::TestAppContext;
+    use parking_lot::Mutex;
+    use settings::{Settings, SettingsStore};
+
+    #[derive(Default)]
+    struct RecordingInterceptor {
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl CompletionInterceptor for RecordingInterceptor {
+        fn on_request(
+            &self,
+            provider: &LanguageModelProviderId,
+            model: &LanguageModelId,
+            request: &mut LanguageModelRequest,
+        ) {
+            self.calls.lock().push(format!(
+                "request to {}/{} with {} message(s)",
+                provider.0,
+                model.0,
+                request.messages.len()
+            ));
+            request.temperature = 0.5;
+        }
+
+        fn on_event(
+            &self,
+            _: &LanguageModelProviderId,
+            _: &LanguageModelId,
+            event: &LanguageModelCompletionEvent,
+        ) {
+            self.calls.lock().push(format!("event {event:?}"));
+        }
+
+        fn on_error(
+            &self,
+            _: &LanguageModelProviderId,
+            _: &LanguageModelId,
+            error: &anyhow::Error,
+        ) {
+            self.calls.lock().push(format!("error {error}"));
+        }
+
+        fn on_finish(&self, _: &LanguageModelProviderId, _: &LanguageModelId) {
+            self.calls.lock().push("finish".into());
+        }
+    }
+
+    #[gpui::test]
+    async fn test_interceptor_observes_request_and_events(cx: &mut TestAppContext) {
+        let interceptor = Arc::new(RecordingInterceptor::default());
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            AllLanguageModelSettings::register(cx);
+            LanguageModelRegistry::test(cx);
+            LanguageModelRegistry::global(cx).update(cx, |registry, _| {
+                registry.add_interceptor(Arc::new(LoggingInterceptor));
+                registry.add_interceptor(interceptor.clone());
+            });
+        });
+
+        let request = LanguageModelRequest {
+            messages: vec![crate::LanguageModelRequestMessage {
+                role: crate::Role::User,
+                content: "Hello".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let (request, interceptors) = intercept_request(
+            request,
+            LanguageModelProviderId::from("fake".to_string()),
+            LanguageModelId::from("fake".to_string()),
+            &cx.to_async(),
+        );
+        assert_eq!(request.temperature, 0.5);
+
+        let events = futures::stream::iter([
+            Ok(LanguageModelCompletionEvent::Text("Hi".into())),
+            Ok(LanguageModelCompletionEvent::Text(" there".into())),
+        ]);
+        let events = interceptors
+            .intercept(futures::future::ready(Ok(events.boxed())).boxed())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            *interceptor.calls.lock(),
+            [
+                "request to fake/fake with 1 message(s)",
+                "event Text(\"Hi\")",
+                "event Text(\" there\")",
+                "finish",
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_interceptor_observes_failed_completion(cx: &mut TestAppContext) {
+        let interceptor = Arc::new(RecordingInterceptor::default());
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            AllLanguageModelSettings::register(cx);
+            LanguageModelRegistry::test(cx);
+            LanguageModelRegistry::global(cx).update(cx, |registry, _| {
+                registry.add_interceptor(interceptor.clone());
+            });
+        });
+
+        let (_, interceptors) = intercept_request(
+            LanguageModelRequest::default(),
+            LanguageModelProviderId::from("fake".to_string()),
+            LanguageModelId::from("fake".to_string()),
+            &cx.to_async(),
+        );
+        let result = interceptors
+            .intercept(futures::future::ready(Err(anyhow::anyhow!("overloaded"))).boxed())
+            .await;
+        assert!(result.is_err());
+        assert_eq!(
+            *interceptor.calls.lock(),
+            ["request to fake/fake with 0 message(s)", "error overloaded"]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_interceptor_observes_tool_use(cx: &mut TestAppContext) {
+        let interceptor = Arc::new(RecordingInterceptor::default());
+        cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            AllLanguageModelSettings::register(cx);
+            LanguageModelRegistry::test(cx);
+            LanguageModelRegistry::global(cx).update(cx, |registry, _| {
+                registry.add_interceptor(interceptor.clone());
+            });
+        });
+
+        let (_, interceptors) = intercept_request(
+            LanguageModelRequest::default(),
+            LanguageModelProviderId::from("fake".to_string()),
+            LanguageModelId::from("fake".to_string()),
+            &cx.to_async(),
+        );
+        let input = interceptors
+            .intercept_tool_use(futures::future::ready(Ok(serde_json::json!({}))).boxed())
+            .await;
+        assert_eq!(input.unwrap(), serde_json::json!({}));
+
+        let (_, interceptors) = intercept_request(
+            LanguageModelRequest::default(),
+            LanguageModelProviderId::from("fake".to_string()),
+            LanguageModelId::from("fake".to_string()),
+            &cx.to_async(),
+        );
+        let result = interceptors
+            .intercept_tool_use(
+                futures::future::ready(Err(anyhow::anyhow!("tool not used"))).boxed(),
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(
+            *interceptor.calls.lock(),
+            [
+                "request to fake/fake with 0 message(s)",
+                "finish",
+                "request to fake/fake with 0 message(s)",
+                "error tool not used",
+            ]
+        );
+    }
+}
@@ -1,28 +1,69 @@
+mod api_keys;
+mod cost;
+mod diagnostics;
+mod error;
+mod event_buffer;
+mod in_flight;
+mod interceptor;
+mod load_test;
 mod model;
 pub mod provider;
+mod race;
 mod rate_limiter;
+mod redaction;
 mod registry;
 mod request;
+mod request_transform;
+mod retry;
 mod role;
 pub mod settings;
+mod structured_output;
+mod summarization;
+mod text_stream;
+mod tokenizer;
+mod transcript_log;
+mod warmup;
 
 use anyhow::Result;
+pub(crate) use api_keys::*;
 use client::{Client, UserStore};
-use futures::{future::BoxFuture, stream::BoxStream};
+pub use cost::*;
+pub use diagnostics::*;
+pub use error::*;
+pub use event_buffer::*;
+use futures::{
+    future::{self, AbortHandle, Abortable, BoxFuture},
+    stream::BoxStream,
+    AsyncWriteExt as _, FutureExt, StreamExt,
+};
 use gpui::{
     AnyElement, AnyView, AppContext, AsyncAppContext, Model, SharedString, Task, WindowContext,
 };
+pub(crate) use in_flight::*;
+pub use interceptor::*;
+pub use load_test::*;
 pub use model::*;
 use project::Fs;
 use proto::Plan;
+pub use race::*;
 pub(crate) use rate_limiter::*;
+pub(crate) use redaction::*;
 pub use registry::*;
 pub use request::*;
+pub use request_transform::*;
+pub(crate) use retry::*;
 pub use role::*;
 use schemars::JsonSchema;
 use serde::de::DeserializeOwned;
-use std::{future::Future, sync::Arc};
+use std::{future::Future, path::PathBuf, sync::Arc, task::Poll, time::Duration};
+pub(crate) use structured_output::*;
+pub use text_stream::*;
+use time::OffsetDateTime;
+pub(crate) use tokenizer::*;
+pub use transcript_log::*;
 use ui::IconName;
+use util::ResultExt as _;
+pub(crate) use warmup::*;
 
 pub fn init(
     user_store: Model<UserStore>,
@@ -31,9 +72,14 @@ pub fn init(
     cx: &mut AppContext,
 ) {
     settings::init(fs, cx);
+    transcript_log::init(cx);
     registry::init(user_store, client, cx);
 }
 
+/// How long [`LanguageModel::stream_completion_with_prefill`] waits for the
+/// first token before reporting that the prompt is still being read.
+pub const PREFILL_NOTICE_DELAY: Duration = Duration::from_millis(500);
+
 /// The availability of a [`LanguageModel`].
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum LanguageModelAvailability {
@@ -43,6 +89,81 @@ pub enum LanguageModelAvailability {
     RequiresPlan(Plan),
 }
 
+/// What a [`LanguageModel`] supports.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct LanguageModelCapabilities {
+    /// Whether the model can call tools.
+    pub tools: bool,
+    /// Whether the model accepts images as input.
+    pub images: bool,
+    /// Whether the model accepts attached documents, such as PDFs.
+    pub documents: bool,
+    /// The most tokens the model will generate in one completion, if known.
+    pub max_output_tokens: Option<u32>,
+}
+
+impl LanguageModelCapabilities {
+    /// Replaces these capabilities with the ones the server reported,
+    /// keeping those it didn't report.
+    pub fn with_overrides(self, overrides: &client::ModelCapabilities) -> Self {
+        Self {
+            tools: overrides.tools.unwrap_or(self.tools),
+            images: overrides.images.unwrap_or(self.images),
+            documents: self.documents,
+            max_output_tokens: overrides.max_output_tokens.or(self.max_output_tokens),
+        }
+    }
+}
+
+/// An event produced while streaming a completion from a [`LanguageModel`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LanguageModelCompletionEvent {
+    /// The id of the model that the provider reports actually served the
+    /// request, which may differ from the requested one when aliases or
+    /// server-side fallbacks are involved.
+    ReportedModel(String),
+    Text(String),
+    /// An image the model generated, as base64-encoded `data`.
+    ///
+    /// Only Google models generate images; other providers' streams are
+    /// text-only.
+    Image {
+        mime_type: String,
+        data: String,
+    },
+    /// The number of input and output tokens the provider has counted since
+    /// the previous `UsageUpdate`, for providers that report usage as they
+    /// stream, so that a running total can be shown.
+    UsageUpdate {
+        input_tokens: usize,
+        output_tokens: usize,
+    },
+    /// Why the model stopped generating, for providers that report it.
+    Stop(StopReason),
+    /// The provider has accepted the request and is still reading the prompt
+    /// [`PREFILL_NOTICE_DELAY`] later, without having produced any output.
+    /// Only emitted by [`LanguageModel::stream_completion_with_prefill`],
+    /// before the first `Text`.
+    Prefilling,
+    /// How long the first `Text` or `Image` took to arrive after the
+    /// completion was requested. Only emitted by
+    /// [`LanguageModel::stream_completion_with_prefill`], just before it.
+    TimeToFirstToken(Duration),
+    /// A local provider, such as Ollama, is unavailable while it loads the
+    /// model, so the request is being retried. Emitted before the first `Text`.
+    ModelLoading,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    EndTurn,
+    MaxTokens,
+    /// The completion ended on one of the request's stop sequences, which is
+    /// the one given here.
+    StopSequence(String),
+    ToolUse,
+}
+
 pub trait LanguageModel: Send + Sync {
     fn id(&self) -> LanguageModelId;
     fn name(&self) -> LanguageModelName;
@@ -57,17 +178,327 @@ pub trait LanguageModel: Send + Sync {
 
     fn max_token_count(&self) -> usize;
 
+    /// Returns what this model supports. Models that don't say are assumed
+    /// to support neither tools nor images.
+    fn capabilities(&self) -> LanguageModelCapabilities {
+        LanguageModelCapabilities::default()
+    }
+
+    /// Checks `request` against this model's context window, capabilities and
+    /// parameters, returning every problem found, so that callers can report
+    /// them all at once rather than discovering them one upstream error at a
+    /// time.
+    ///
+    /// The request's size is only estimated, so a request that passes may
+    /// still turn out to be too long for the model.
+    fn validate_request(
+        &self,
+        request: &LanguageModelRequest,
+    ) -> Result<(), Vec<LanguageModelError>> {
+        let mut errors = Vec::new();
+
+        let max_token_count = self.max_token_count();
+        if request.estimated_token_count() > max_token_count {
+            errors.push(LanguageModelError::ContextWindowExceeded {
+                limit: Some(max_token_count),
+            });
+        }
+
+        let capabilities = self.capabilities();
+        if request.tool_choice.is_some() && !capabilities.tools {
+            errors.push(LanguageModelError::UnsupportedFeature {
+                feature: "tools".into(),
+            });
+        }
+        if request.has_documents() && !capabilities.documents {
+            errors.push(LanguageModelError::UnsupportedFeature {
+                feature: "documents".into(),
+            });
+        }
+
+        errors.extend(request.parameter_errors());
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns what the model charges for tokens, if it's known.
+    fn pricing(&self) -> Option<LanguageModelPricing> {
+        None
+    }
+
+    /// Estimates what sending `request` to this model would cost, in dollars,
+    /// if its pricing is known. The completion is assumed to be as long as the
+    /// `assumed_output_tokens` setting says.
+    fn estimate_cost(&self, request: &LanguageModelRequest, cx: &AppContext) -> Option<f64> {
+        let pricing = self.pricing()?;
+        Some(request.estimate_cost(&pricing, assumed_output_tokens(&self.id(), cx)))
+    }
+
+    /// Returns whether the provider has deprecated this model, meaning it will
+    /// stop being served.
+    fn is_deprecated(&self) -> bool {
+        self.deprecation_date().is_some()
+    }
+
+    /// Returns the date after which the provider will stop serving this model,
+    /// as `YYYY-MM-DD`, if it has been deprecated.
+    fn deprecation_date(&self) -> Option<SharedString> {
+        None
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
         cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>>;
 
+    /// Counts the tokens in each of `requests`, in order. Models whose
+    /// tokenizer is costly to set up override this to share it between them.
+    fn count_tokens_batch(
+        &self,
+        requests: Vec<LanguageModelRequest>,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<usize>>> {
+        let counts = requests
+            .into_iter()
+            .map(|request| self.count_tokens(request, cx))
+            .collect::<Vec<_>>();
+        future::try_join_all(counts).boxed()
+    }
+
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>>;
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>>;
+
+    /// Streams the provider's events as JSON, without converting them into
+    /// [`LanguageModelCompletionEvent`]s, for callers that need fields this
+    /// crate doesn't model.
+    ///
+    /// Only supported by OpenAI models.
+    fn stream_raw(
+        &self,
+        _request: LanguageModelRequest,
+        _cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<serde_json::Value>>>> {
+        future::ready(Err(anyhow::anyhow!(
+            LanguageModelError::UnsupportedFeature {
+                feature: "raw provider events".into(),
+            }
+        )))
+        .boxed()
+    }
+
+    /// Streams a completion, keeping only the text it produces and ending it
+    /// early if the request's `stop_regex` matches.
+    fn stream_completion_text(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        self.stream_completion_text_reporting_loading(request, Arc::new(|| {}), cx)
+    }
+
+    /// Like [`Self::stream_completion_text`], but calls `on_model_loading`
+    /// whenever the provider reports that it's waiting for the model to load.
+    fn stream_completion_text_reporting_loading(
+        &self,
+        request: LanguageModelRequest,
+        on_model_loading: Arc<dyn Fn() + Send + Sync>,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        let stop_regex = match request.compile_stop_regex() {
+            Ok(stop_regex) => stop_regex,
+            Err(error) => return futures::future::ready(Err(error)).boxed(),
+        };
+        let transcript = cx
+            .update(|cx| TranscriptLog::recording(cx))
+            .ok()
+            .flatten()
+            .map(|log| (log, self.provider_id(), self.id(), request.clone()));
+        let events = self.stream_completion(request, cx);
+        async move {
+            let chunks = events
+                .await?
+                .filter_map(move |event| {
+                    futures::future::ready(match event {
+                        Ok(LanguageModelCompletionEvent::Text(text)) => Some(Ok(text)),
+                        Ok(LanguageModelCompletionEvent::ModelLoading) => {
+                            on_model_loading();
+                            None
+                        }
+                        Ok(LanguageModelCompletionEvent::ReportedModel(_))
+                        | Ok(LanguageModelCompletionEvent::UsageUpdate { .. })
+                        | Ok(LanguageModelCompletionEvent::Stop(_))
+                        | Ok(LanguageModelCompletionEvent::Image { .. })
+                        | Ok(LanguageModelCompletionEvent::Prefilling)
+                        | Ok(LanguageModelCompletionEvent::TimeToFirstToken(_)) => None,
+                        Err(error) => Some(Err(error)),
+                    })
+                })
+                .boxed();
+            let chunks = match transcript {
+                Some((log, provider, model, request)) => {
+                    record_transcript(chunks, log, provider, model, request).boxed()
+                }
+                None => chunks,
+            };
+            match stop_regex {
+                Some(stop_regex) => Ok(stop_on_regex(chunks, stop_regex).boxed()),
+                None => Ok(chunks),
+            }
+        }
+        .boxed()
+    }
+
+    /// Streams a completion, emitting [`LanguageModelCompletionEvent::Prefilling`]
+    /// if the first token hasn't arrived within [`PREFILL_NOTICE_DELAY`], so
+    /// that callers can show that a large prompt is still being read, and
+    /// [`LanguageModelCompletionEvent::TimeToFirstToken`] once it does.
+    fn stream_completion_with_prefill(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let executor = cx.background_executor().clone();
+        let requested_at = executor.now();
+        let events = self.stream_completion(request, cx);
+        async move {
+            let mut events = events.await?;
+            let mut prefill_timer = Some(executor.timer(PREFILL_NOTICE_DELAY));
+            let mut first_token = None;
+            let mut awaiting_first_token = true;
+            Ok(futures::stream::poll_fn(move |cx| {
+                if let Some(event) = first_token.take() {
+                    return Poll::Ready(Some(Ok(event)));
+                }
+                match events.poll_next_unpin(cx) {
+                    Poll::Ready(Some(Ok(
+                        event @ (LanguageModelCompletionEvent::Text(_)
+                        | LanguageModelCompletionEvent::Image { .. }),
+                    ))) if awaiting_first_token => {
+                        awaiting_first_token = false;
+                        prefill_timer = None;
+                        first_token = Some(event);
+                        Poll::Ready(Some(Ok(LanguageModelCompletionEvent::TimeToFirstToken(
+                            executor.now() - requested_at,
+                        ))))
+                    }
+                    Poll::Pending => match prefill_timer.as_mut() {
+                        Some(timer) if timer.poll_unpin(cx).is_ready() => {
+                            prefill_timer = None;
+                            Poll::Ready(Some(Ok(LanguageModelCompletionEvent::Prefilling)))
+                        }
+                        _ => Poll::Pending,
+                    },
+                    event => event,
+                }
+            })
+            .boxed())
+        }
+        .boxed()
+    }
+
+    /// Streams a completion along with a handle that cancels it, for callers
+    /// that can't drop the stream to cancel it, such as while iterating it.
+    ///
+    /// Aborting before the completion starts streaming fails it, while
+    /// aborting afterwards drops the underlying request and ends the stream.
+    fn stream_completion_with_abort_handle(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> (
+        BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>>,
+        AbortHandle,
+    ) {
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let mut aborted = Abortable::new(future::pending::<()>(), abort_registration);
+        let mut events = self.stream_completion(request, cx);
+        let events = async move {
+            let events = future::poll_fn(|cx| {
+                if aborted.poll_unpin(cx).is_ready() {
+                    return Poll::Ready(Err(anyhow::anyhow!("completion was cancelled")));
+                }
+                events.poll_unpin(cx)
+            })
+            .await?;
+
+            let mut events = Some(events);
+            Ok(futures::stream::poll_fn(move |cx| {
+                if aborted.poll_unpin(cx).is_ready() {
+                    events = None;
+                }
+                match events.as_mut() {
+                    Some(events) => events.poll_next_unpin(cx),
+                    None => Poll::Ready(None),
+                }
+            })
+            .boxed())
+        }
+        .boxed();
+        (events, abort_handle)
+    }
+
+    /// Streams a completion's text while appending it to a transcript at
+    /// `path`, after a header naming the model, and returns the full text.
+    ///
+    /// Failing to write the transcript is logged rather than failing the
+    /// completion.
+    fn stream_completion_to_file(
+        &self,
+        request: LanguageModelRequest,
+        path: PathBuf,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<String>> {
+        let header = format!(
+            "--- {} ({}) at {} ---\n",
+            self.name().0,
+            self.provider_name().0,
+            chrono::Utc::now().to_rfc3339()
+        );
+        let chunks = self.stream_completion_text(request, cx);
+        async move {
+            let mut chunks = chunks.await?;
+            let mut transcript = async {
+                let mut file = smol::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await?;
+                file.write_all(header.as_bytes()).await?;
+                anyhow::Ok(file)
+            }
+            .await
+            .log_err();
+
+            let mut text = String::new();
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+                if let Some(file) = transcript.as_mut() {
+                    if file.write_all(chunk.as_bytes()).await.log_err().is_none() {
+                        transcript = None;
+                    }
+                }
+                text.push_str(&chunk);
+            }
+
+            if let Some(mut file) = transcript {
+                async {
+                    file.write_all(b"\n").await?;
+                    file.flush().await
+                }
+                .await
+                .log_err();
+            }
+            Ok(text)
+        }
+        .boxed()
+    }
 
     fn use_any_tool(
         &self,
@@ -112,7 +543,17 @@ pub trait LanguageModelProvider: 'static {
         IconName::ZedAssistant
     }
     fn provided_models(&self, cx: &AppContext) -> Vec<Arc<dyn LanguageModel>>;
+    /// Returns when the provider last refreshed its catalog of models, if it
+    /// discovers them dynamically.
+    fn models_last_refreshed(&self, _cx: &AppContext) -> Option<OffsetDateTime> {
+        None
+    }
     fn load_model(&self, _model: Arc<dyn LanguageModel>, _cx: &AppContext) {}
+    /// Returns problems with the provider's configuration that keep it from
+    /// working as expected, to be shown in its configuration view.
+    fn diagnostics(&self, _cx: &AppContext) -> Vec<LanguageModelProviderDiagnostic> {
+        Vec::new()
+    }
     fn is_authenticated(&self, cx: &AppContext) -> bool;
     fn authenticate(&self, cx: &mut AppContext) -> Task<Result<()>>;
     fn configuration_view(&self, cx: &mut WindowContext) -> AnyView;
@@ -122,7 +563,14 @@ pub trait LanguageModelProvider: 'static {
     fn render_accept_terms(&self, _cx: &mut WindowContext) -> Option<AnyElement> {
         None
     }
+    /// Returns a warning about the user's usage of the provider, such as that
+    /// they're close to one of their limits, to be shown to them.
+    fn usage_warning(&self, _cx: &AppContext) -> Option<SharedString> {
+        None
+    }
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>>;
+    /// Aborts every completion that the provider's models are streaming.
+    fn cancel_all(&self, cx: &mut AppContext);
 }
 
 pub trait LanguageModelProviderState: 'static {
@@ -177,3 +625,159 @@ impl From<String> for LanguageModelProviderName {
         Self(SharedString::from(value))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::fake::FakeLanguageModel;
+    use gpui::TestAppContext;
+
+    #[test]
+    fn test_validate_request_reports_every_violation() {
+        let model = FakeLanguageModel::default();
+        let mut request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Summarize this document.".into(),
+                attachments: vec![MessageContent::Document {
+                    mime_type: "application/pdf".into(),
+                    data: "JVBERi0xLjQK".into(),
+                }],
+                cache: false,
+            }],
+            temperature: 1.,
+            ..Default::default()
+        };
+        assert_eq!(
+            model.validate_request(&request),
+            Err(vec![LanguageModelError::UnsupportedFeature {
+                feature: "documents".into()
+            }])
+        );
+
+        request.messages[0].content = "a".repeat(4 * model.max_token_count() + 4);
+        request.tool_choice = Some(ToolChoice::Required);
+        request.temperature = 3.;
+        request.stop_regex = Some("(".into());
+        assert_eq!(
+            model.validate_request(&request),
+            Err(vec![
+                LanguageModelError::ContextWindowExceeded {
+                    limit: Some(model.max_token_count())
+                },
+                LanguageModelError::UnsupportedFeature {
+                    feature: "tools".into()
+                },
+                LanguageModelError::UnsupportedFeature {
+                    feature: "documents".into()
+                },
+                LanguageModelError::InvalidParameter {
+                    name: "temperature".into(),
+                    reason: "3 is outside of the range 0 to 2".into()
+                },
+                LanguageModelError::InvalidParameter {
+                    name: "stop_regex".into(),
+                    reason: "it isn't a valid regular expression".into()
+                },
+            ])
+        );
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_to_file(cx: &mut TestAppContext) {
+        cx.executor().allow_parking();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transcript.md");
+        let model = FakeLanguageModel::default();
+        let request = LanguageModelRequest::default();
+
+        let text = model.stream_completion_to_file(request.clone(), path.clone(), &cx.to_async());
+        model.stream_completion_response(&request, "Hello, ".into());
+        model.stream_completion_response(&request, "world!".into());
+        model.end_completion_stream(&request);
+        assert_eq!(text.await.unwrap(), "Hello, world!");
+
+        let transcript = std::fs::read_to_string(&path).unwrap();
+        let (header, body) = transcript.split_once('\n').unwrap();
+        assert!(header.starts_with("--- Fake (Fake) at "));
+        assert_eq!(body, "Hello, world!\n");
+    }
+
+    #[gpui::test]
+    async fn test_prefill_precedes_first_text(cx: &mut TestAppContext) {
+        let model = FakeLanguageModel::default();
+        let request = LanguageModelRequest::default();
+
+        // A response that arrives quickly doesn't report prefilling.
+        let events = model.stream_completion_with_prefill(request.clone(), &cx.to_async());
+        model.stream_completion_response(&request, "Hello".into());
+        model.end_completion_stream(&request);
+        let events = events
+            .await
+            .unwrap()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::TimeToFirstToken(Duration::ZERO),
+                LanguageModelCompletionEvent::Text("Hello".into()),
+            ]
+        );
+
+        // A slow one does, until the first token arrives.
+        let events = model.stream_completion_with_prefill(request.clone(), &cx.to_async());
+        let mut events = events.await.unwrap();
+        cx.executor().advance_clock(PREFILL_NOTICE_DELAY);
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            LanguageModelCompletionEvent::Prefilling
+        );
+        cx.executor().advance_clock(Duration::from_secs(1));
+        model.stream_completion_response(&request, "Hello".into());
+        model.end_completion_stream(&request);
+        assert_eq!(
+            events.map(Result::unwrap).collect::<Vec<_>>().await,
+            vec![
+                LanguageModelCompletionEvent::TimeToFirstToken(
+                    PREFILL_NOTICE_DELAY + Duration::from_secs(1)
+                ),
+                LanguageModelCompletionEvent::Text("Hello".into()),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_abort_completion_stream(cx: &mut TestAppContext) {
+        let model = FakeLanguageModel::default();
+        let request = LanguageModelRequest::default();
+
+        let (events, abort_handle) =
+            model.stream_completion_with_abort_handle(request.clone(), &cx.to_async());
+        let mut events = events.await.unwrap();
+        model.stream_completion_response(&request, "Hello".into());
+        assert_eq!(
+            events.next().await.unwrap().unwrap(),
+            LanguageModelCompletionEvent::Text("Hello".into())
+        );
+
+        abort_handle.abort();
+        assert!(events.next().await.is_none());
+        assert!(model.is_completion_stream_closed(&request));
+
+        // Aborting before the completion starts streaming fails it.
+        let (events, abort_handle) =
+            model.stream_completion_with_abort_handle(request.clone(), &cx.to_async());
+        abort_handle.abort();
+        assert_eq!(
+            events
+                .await
+                .err()
+                .expect("completion should fail")
+                .to_string(),
+            "completion was cancelled"
+        );
+    }
+}
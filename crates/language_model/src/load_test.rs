@@ -0,0 +1,116 @@
+use crate::{
+    LanguageModel, LanguageModelCompletionEvent, LanguageModelId, LanguageModelProviderId,
+    LanguageModelRegistry, LanguageModelRequest,
+};
+use anyhow::{anyhow, Context as _, Result};
+use futures::{future::BoxFuture, stream::BoxStream};
+use gpui::{AppContext, AsyncAppContext};
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+use std::sync::Arc;
+
+/// Picks one of several models at random for each request, in proportion to
+/// their weights, to spread a load test across models.
+pub struct WeightedModelSelector {
+    models: Vec<Arc<dyn LanguageModel>>,
+    weights: WeightedIndex<f64>,
+}
+
+impl WeightedModelSelector {
+    /// Fails if there are no models, or if any weight is negative or they're
+    /// all zero.
+    pub fn new(models: Vec<(Arc<dyn LanguageModel>, f64)>) -> Result<Self> {
+        let (models, weights): (Vec<_>, Vec<_>) = models.into_iter().unzip();
+        let weights = WeightedIndex::new(weights).context("invalid model weights")?;
+        Ok(Self { models, weights })
+    }
+
+    /// Looks up each `(provider, model)` pair in the registry, failing if any
+    /// of them isn't available.
+    pub fn from_registry(
+        weights: &[(LanguageModelProviderId, LanguageModelId, f64)],
+        registry: &LanguageModelRegistry,
+        cx: &AppContext,
+    ) -> Result<Self> {
+        let models = weights
+            .iter()
+            .map(|(provider_id, model_id, weight)| {
+                let model = registry
+                    .provider(provider_id)
+                    .and_then(|provider| {
+                        provider
+                            .provided_models(cx)
+                            .into_iter()
+                            .find(|model| &model.id() == model_id)
+                    })
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "model {:?} of {:?} isn't available",
+                            model_id.0,
+                            provider_id.0
+                        )
+                    })?;
+                Ok((model, *weight))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(models)
+    }
+
+    pub fn select(&self, rng: &mut impl Rng) -> Arc<dyn LanguageModel> {
+        self.models[self.weights.sample(rng)].clone()
+    }
+
+    /// Streams the completion of `request` from a randomly selected model.
+    pub fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        rng: &mut impl Rng,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        self.select(rng).stream_completion(request, cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::fake::FakeLanguageModel;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_selection_follows_weights() {
+        let light: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::default());
+        let heavy: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::default());
+        let unused: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::default());
+        let selector = WeightedModelSelector::new(vec![
+            (light.clone(), 1.),
+            (heavy.clone(), 3.),
+            (unused.clone(), 0.),
+        ])
+        .unwrap();
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let draws = 10_000;
+        let mut counts = [0; 3];
+        for _ in 0..draws {
+            let model = selector.select(&mut rng);
+            let index = [&light, &heavy, &unused]
+                .iter()
+                .position(|candidate| Arc::ptr_eq(candidate, &model))
+                .unwrap();
+            counts[index] += 1;
+        }
+
+        let share = |count: usize| count as f64 / draws as f64;
+        assert!((share(counts[0]) - 0.25).abs() < 0.02, "{counts:?}");
+        assert!((share(counts[1]) - 0.75).abs() < 0.02, "{counts:?}");
+        assert_eq!(counts[2], 0);
+    }
+
+    #[test]
+    fn test_invalid_weights() {
+        let model: Arc<dyn LanguageModel> = Arc::new(FakeLanguageModel::default());
+        assert!(WeightedModelSelector::new(Vec::new()).is_err());
+        assert!(WeightedModelSelector::new(vec![(model.clone(), 0.)]).is_err());
+        assert!(WeightedModelSelector::new(vec![(model, -1.)]).is_err());
+    }
+}
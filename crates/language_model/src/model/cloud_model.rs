@@ -3,7 +3,7 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use strum::EnumIter;
 
-use crate::LanguageModelAvailability;
+use crate::{LanguageModelAvailability, LanguageModelCapabilities};
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 #[serde(tag = "provider", rename_all = "lowercase")]
@@ -40,6 +40,36 @@ impl ZedModel {
     }
 }
 
+pub(crate) fn anthropic_capabilities(model: &anthropic::Model) -> LanguageModelCapabilities {
+    LanguageModelCapabilities {
+        tools: true,
+        images: !matches!(model, anthropic::Model::Custom { .. }),
+        documents: true,
+        max_output_tokens: Some(model.max_output_tokens()),
+    }
+}
+
+pub(crate) fn open_ai_capabilities(model: &open_ai::Model) -> LanguageModelCapabilities {
+    LanguageModelCapabilities {
+        tools: true,
+        images: matches!(
+            model,
+            open_ai::Model::FourTurbo | open_ai::Model::FourOmni | open_ai::Model::FourOmniMini
+        ),
+        documents: false,
+        max_output_tokens: None,
+    }
+}
+
+pub(crate) fn google_capabilities(model: &google_ai::Model) -> LanguageModelCapabilities {
+    LanguageModelCapabilities {
+        tools: true,
+        images: !matches!(model, google_ai::Model::Custom { .. }),
+        documents: false,
+        max_output_tokens: None,
+    }
+}
+
 impl Default for CloudModel {
     fn default() -> Self {
         Self::Anthropic(anthropic::Model::default())
@@ -74,6 +104,26 @@ impl CloudModel {
         }
     }
 
+    /// Returns the date after which this model will stop being served, if it
+    /// has been deprecated.
+    pub fn deprecation_date(&self) -> Option<&'static str> {
+        match self {
+            Self::Anthropic(model) => model.deprecation_date(),
+            Self::OpenAi(_) | Self::Google(_) | Self::Zed(_) => None,
+        }
+    }
+
+    /// Returns what this model is known to support, for when the server
+    /// doesn't report its capabilities.
+    pub fn capabilities(&self) -> LanguageModelCapabilities {
+        match self {
+            Self::Anthropic(model) => anthropic_capabilities(model),
+            Self::OpenAi(model) => open_ai_capabilities(model),
+            Self::Google(model) => google_capabilities(model),
+            Self::Zed(_) => LanguageModelCapabilities::default(),
+        }
+    }
+
     /// Returns the availability of this model.
     pub fn availability(&self) -> LanguageModelAvailability {
         match self {
@@ -1,13 +1,18 @@
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelId, LanguageModelName,
-    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest, RateLimiter, Role,
+    anthropic_capabilities, count_tiktoken_tokens, count_tiktoken_tokens_batch,
+    diagnose_provider_settings, intercept_request, number_after,
+    settings::AllLanguageModelSettings, warm_up_after_authentication, with_api_key_failover,
+    with_backoff, ApiKeyRotation, InFlightCompletions, LanguageModel, LanguageModelCapabilities,
+    LanguageModelCompletionEvent, LanguageModelError, LanguageModelId, LanguageModelName,
+    LanguageModelProvider, LanguageModelProviderDiagnostic, LanguageModelProviderId,
+    LanguageModelProviderName, LanguageModelProviderState, LanguageModelRequest, RateLimiter,
+    RequestTransform, StopReason, StreamingSchemaValidator, TransformingHttpClient,
 };
-use anthropic::AnthropicError;
+use anthropic::{AnthropicError, ApiError, ApiErrorCode, Content, ContentDelta, Event};
 use anyhow::{anyhow, Context as _, Result};
 use collections::BTreeMap;
 use editor::{Editor, EditorElement, EditorStyle};
-use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, Stream, StreamExt};
 use gpui::{
     AnyView, AppContext, AsyncAppContext, FontStyle, ModelContext, Subscription, Task, TextStyle,
     View, WhiteSpace,
@@ -25,17 +30,28 @@ use util::ResultExt;
 const PROVIDER_ID: &str = "anthropic";
 const PROVIDER_NAME: &str = "Anthropic";
 
+/// How far, relative to the input tokens Anthropic reports, the estimate from
+/// [`count_anthropic_tokens`] may drift before it's logged.
+const TOKEN_COUNT_DIVERGENCE_THRESHOLD: f64 = 0.2;
+/// Differences smaller than this are never logged, since short requests are
+/// dominated by tokens the estimate doesn't account for.
+const MIN_LOGGED_TOKEN_COUNT_DIFFERENCE: usize = 100;
+
 #[derive(Default, Clone, Debug, PartialEq)]
 pub struct AnthropicSettings {
     pub api_url: String,
     pub low_speed_timeout: Option<Duration>,
     pub available_models: Vec<AvailableModel>,
+    /// Ids of built-in models to hide from the model picker.
+    pub disabled_models: Vec<String>,
     pub needs_setting_migration: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct AvailableModel {
     pub name: String,
+    /// The name displayed in the model picker, defaulting to `name`.
+    pub display_name: Option<String>,
     pub max_tokens: usize,
     pub tool_override: Option<String>,
 }
@@ -43,10 +59,12 @@ pub struct AvailableModel {
 pub struct AnthropicLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Model<State>,
+    in_flight: InFlightCompletions,
 }
 
 pub struct State {
     api_key: Option<String>,
+    api_key_rotation: ApiKeyRotation,
     _subscription: Subscription,
 }
 
@@ -119,12 +137,24 @@ impl AnthropicLanguageModelProvider {
     pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut AppContext) -> Self {
         let state = cx.new_model(|cx| State {
             api_key: None,
+            api_key_rotation: ApiKeyRotation::default(),
             _subscription: cx.observe_global::<SettingsStore>(|_, cx| {
                 cx.notify();
             }),
         });
 
-        Self { http_client, state }
+        Self {
+            http_client,
+            state,
+            in_flight: InFlightCompletions::default(),
+        }
+    }
+
+    /// Applies `transform` to the body of every request the models send to
+    /// Anthropic, e.g. to adapt requests for a gateway in front of it.
+    pub fn with_request_transform(mut self, transform: RequestTransform) -> Self {
+        self.http_client = Arc::new(TransformingHttpClient::new(self.http_client, transform));
+        self
     }
 }
 
@@ -169,12 +199,18 @@ impl LanguageModelProvider for AnthropicLanguageModelProvider {
                 model.name.clone(),
                 anthropic::Model::Custom {
                     name: model.name.clone(),
+                    display_name: model.display_name.clone(),
                     max_tokens: model.max_tokens,
                     tool_override: model.tool_override.clone(),
                 },
             );
         }
 
+        let disabled_models = &AllLanguageModelSettings::get_global(cx)
+            .anthropic
+            .disabled_models;
+        models.retain(|id, _| !disabled_models.contains(id));
+
         models
             .into_values()
             .map(|model| {
@@ -183,18 +219,41 @@ impl LanguageModelProvider for AnthropicLanguageModelProvider {
                     model,
                     state: self.state.clone(),
                     http_client: self.http_client.clone(),
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: RateLimiter::new(4).with_configured_max_queue_wait(cx),
+                    in_flight: self.in_flight.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
     }
 
+    fn diagnostics(&self, cx: &AppContext) -> Vec<LanguageModelProviderDiagnostic> {
+        let settings = &AllLanguageModelSettings::get_global(cx).anthropic;
+        diagnose_provider_settings(
+            "anthropic",
+            &settings.api_url,
+            settings
+                .available_models
+                .iter()
+                .map(|model| (model.name.as_str(), model.max_tokens)),
+            !self.provided_models(cx).is_empty(),
+        )
+    }
+
     fn is_authenticated(&self, cx: &AppContext) -> bool {
         self.state.read(cx).is_authenticated()
     }
 
     fn authenticate(&self, cx: &mut AppContext) -> Task<Result<()>> {
-        self.state.update(cx, |state, cx| state.authenticate(cx))
+        let was_authenticated = self.state.read(cx).is_authenticated();
+        let authenticate = self.state.update(cx, |state, cx| state.authenticate(cx));
+        if was_authenticated {
+            return authenticate;
+        }
+        let api_url = AllLanguageModelSettings::get_global(cx)
+            .anthropic
+            .api_url
+            .clone();
+        warm_up_after_authentication(authenticate, self.http_client.clone(), api_url, cx)
     }
 
     fn configuration_view(&self, cx: &mut WindowContext) -> AnyView {
@@ -205,6 +264,10 @@ impl LanguageModelProvider for AnthropicLanguageModelProvider {
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
         self.state.update(cx, |state, cx| state.reset_api_key(cx))
     }
+
+    fn cancel_all(&self, _cx: &mut AppContext) {
+        self.in_flight.abort_all();
+    }
 }
 
 pub struct AnthropicModel {
@@ -213,60 +276,255 @@ pub struct AnthropicModel {
     state: gpui::Model<State>,
     http_client: Arc<dyn HttpClient>,
     request_limiter: RateLimiter,
+    in_flight: InFlightCompletions,
 }
 
 pub fn count_anthropic_tokens(
     request: LanguageModelRequest,
     cx: &AppContext,
 ) -> BoxFuture<'static, Result<usize>> {
+    let assets_dir = AllLanguageModelSettings::get_global(cx)
+        .tokenizer_assets_dir
+        .clone();
     cx.background_executor()
         .spawn(async move {
-            let messages = request
-                .messages
-                .into_iter()
-                .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
-                    role: match message.role {
-                        Role::User => "user".into(),
-                        Role::Assistant => "assistant".into(),
-                        Role::System => "system".into(),
-                    },
-                    content: Some(message.content),
-                    name: None,
-                    function_call: None,
-                })
-                .collect::<Vec<_>>();
-
             // Tiktoken doesn't yet support these models, so we manually use the
             // same tokenizer as GPT-4.
-            tiktoken_rs::num_tokens_from_messages("gpt-4", &messages)
+            count_tiktoken_tokens("gpt-4", request, assets_dir.as_deref())
         })
         .boxed()
 }
 
-impl AnthropicModel {
-    fn request_completion(
-        &self,
-        request: anthropic::Request,
-        cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<anthropic::Response>> {
-        let http_client = self.http_client.clone();
+/// Counts the tokens in each of `requests` in one pass on the background
+/// executor, sharing the tokenizer between them.
+pub fn count_anthropic_tokens_batch(
+    requests: Vec<LanguageModelRequest>,
+    cx: &AppContext,
+) -> BoxFuture<'static, Result<Vec<usize>>> {
+    let assets_dir = AllLanguageModelSettings::get_global(cx)
+        .tokenizer_assets_dir
+        .clone();
+    cx.background_executor()
+        .spawn(async move { count_tiktoken_tokens_batch("gpt-4", requests, assets_dir.as_deref()) })
+        .boxed()
+}
 
-        let Ok((api_key, api_url)) = cx.read_model(&self.state, |state, cx| {
-            let settings = &AllLanguageModelSettings::get_global(cx).anthropic;
-            (state.api_key.clone(), settings.api_url.clone())
-        }) else {
-            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
-        };
+fn is_rate_limit_error(error: &AnthropicError) -> bool {
+    matches!(error, AnthropicError::ApiError(error) if error.is_rate_limit_error())
+}
 
-        async move {
-            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
-            anthropic::complete(http_client.as_ref(), &api_url, &api_key, request)
-                .await
-                .context("failed to retrieve completion")
+fn is_overloaded_error(error: &AnthropicError) -> bool {
+    matches!(error, AnthropicError::ApiError(error) if error.is_overloaded_error())
+}
+
+/// Converts the errors Anthropic reports for prompts that don't fit in the
+/// model's context window or for overloaded servers into the matching
+/// [`LanguageModelError`], leaving other errors untouched.
+fn map_anthropic_error(error: anyhow::Error) -> anyhow::Error {
+    let language_model_error = match error.downcast_ref::<AnthropicError>() {
+        Some(AnthropicError::ApiError(error)) if error.is_overloaded_error() => {
+            Some(LanguageModelError::Overloaded)
         }
-        .boxed()
+        Some(AnthropicError::ApiError(error)) => {
+            context_window_exceeded(error).or_else(|| api_version_unsupported(error))
+        }
+        _ => None,
+    };
+    match language_model_error {
+        Some(language_model_error) => anyhow!(language_model_error),
+        None => error,
     }
+}
 
+fn context_window_exceeded(error: &ApiError) -> Option<LanguageModelError> {
+    match error.code()? {
+        ApiErrorCode::RequestTooLarge => {
+            Some(LanguageModelError::ContextWindowExceeded { limit: None })
+        }
+        // e.g. "prompt is too long: 208310 tokens > 200000 maximum"
+        ApiErrorCode::InvalidRequestError if error.message.starts_with("prompt is too long") => {
+            Some(LanguageModelError::ContextWindowExceeded {
+                limit: number_after(&error.message, ">"),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Detects Anthropic rejecting the `anthropic-version` header, which happens
+/// once the version it names has been retired.
+fn api_version_unsupported(error: &ApiError) -> Option<LanguageModelError> {
+    // e.g. "anthropic-version: invalid version 2023-06-01"
+    let message = error.message.to_lowercase();
+    (error.code()? == ApiErrorCode::InvalidRequestError
+        && (message.contains("anthropic-version") || message.contains("api version")))
+    .then(|| LanguageModelError::ApiVersionUnsupported {
+        header: "anthropic-version".into(),
+        version: anthropic::ANTHROPIC_API_VERSION.into(),
+    })
+}
+
+/// Converts the error Anthropic reports for a model it doesn't know into
+/// [`LanguageModelError::ModelNotFound`], leaving other errors untouched.
+fn map_model_not_found(
+    error: anyhow::Error,
+    requested: &str,
+    known_model_ids: &[String],
+) -> anyhow::Error {
+    match error.downcast_ref::<AnthropicError>() {
+        Some(AnthropicError::ApiError(api_error))
+            if api_error.code() == Some(ApiErrorCode::NotFoundError) =>
+        {
+            anyhow!(LanguageModelError::model_not_found(
+                requested,
+                known_model_ids.iter().map(String::as_str)
+            ))
+        }
+        _ => error,
+    }
+}
+
+/// The ids of the built-in and configured models, which a model id Anthropic
+/// doesn't know is compared against.
+fn known_model_ids(settings: &AnthropicSettings) -> Vec<String> {
+    anthropic::Model::iter()
+        .filter(|model| !matches!(model, anthropic::Model::Custom { .. }))
+        .map(|model| model.id().to_string())
+        .chain(
+            settings
+                .available_models
+                .iter()
+                .map(|model| model.name.clone()),
+        )
+        .collect()
+}
+
+/// Logs when the estimated number of input tokens for a request diverges
+/// significantly from the number Anthropic reported, so that the estimator can
+/// be tuned. Returns whether anything was logged.
+fn log_token_count_divergence(model_id: &str, estimated: usize, actual: usize) -> bool {
+    let difference = estimated.abs_diff(actual);
+    let divergence = difference as f64 / actual.max(1) as f64;
+    if difference < MIN_LOGGED_TOKEN_COUNT_DIFFERENCE
+        || divergence <= TOKEN_COUNT_DIVERGENCE_THRESHOLD
+    {
+        return false;
+    }
+
+    log::debug!(
+        "estimated {estimated} input tokens for {model_id}, but Anthropic reported {actual} ({:.0}% off)",
+        divergence * 100.0
+    );
+    true
+}
+
+/// Returns the input of the model's use of `tool_name`, abandoning the response
+/// as soon as the input streamed so far can no longer match `input_schema`.
+async fn stream_tool_input(
+    events: impl Stream<Item = Result<Event>>,
+    tool_name: &str,
+    input_schema: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let mut schema_validator = StreamingSchemaValidator::new(input_schema);
+    let mut tool_use_index = None;
+    let mut tool_input = String::new();
+    futures::pin_mut!(events);
+    while let Some(event) = events.next().await {
+        match event? {
+            Event::ContentBlockStart {
+                index,
+                content_block: Content::ToolUse { name, .. },
+            } if name == tool_name => tool_use_index = Some(index),
+            Event::ContentBlockDelta {
+                index,
+                delta: ContentDelta::InputJsonDelta { partial_json },
+            } if Some(index) == tool_use_index => {
+                schema_validator.push(&partial_json)?;
+                tool_input.push_str(&partial_json);
+            }
+            Event::ContentBlockStop { index } if Some(index) == tool_use_index => {
+                return Ok(serde_json::from_str(&tool_input)?);
+            }
+            _ => {}
+        }
+    }
+
+    if tool_use_index.is_some() {
+        Err(anyhow!("tool content incomplete"))
+    } else {
+        Err(anyhow!("tool not used"))
+    }
+}
+
+pub fn map_to_language_model_completion_events(
+    events: impl Stream<Item = Result<Event, AnthropicError>>,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    // Anthropic reports cumulative token counts, so remember the last totals
+    // in order to emit deltas.
+    let mut reported_tokens = (0, 0);
+    events.flat_map(move |event| {
+        let mut completion_events = Vec::new();
+        match event {
+            Ok(Event::MessageStart { message }) => {
+                completion_events.push(Ok(LanguageModelCompletionEvent::ReportedModel(
+                    message.model,
+                )));
+                completion_events.extend(usage_update(&mut reported_tokens, message.usage).map(Ok));
+            }
+            Ok(Event::MessageDelta { delta, usage }) => {
+                completion_events.extend(usage_update(&mut reported_tokens, usage).map(Ok));
+                let stop_reason = match (delta.stop_reason.as_deref(), delta.stop_sequence) {
+                    (Some("end_turn"), _) => Some(StopReason::EndTurn),
+                    (Some("max_tokens"), _) => Some(StopReason::MaxTokens),
+                    (Some("stop_sequence"), Some(stop_sequence)) => {
+                        Some(StopReason::StopSequence(stop_sequence))
+                    }
+                    (Some("tool_use"), _) => Some(StopReason::ToolUse),
+                    _ => None,
+                };
+                completion_events.extend(
+                    stop_reason.map(|reason| Ok(LanguageModelCompletionEvent::Stop(reason))),
+                );
+            }
+            Ok(Event::ContentBlockStart {
+                content_block: Content::Text { text, .. },
+                ..
+            }) => completion_events.push(Ok(LanguageModelCompletionEvent::Text(text))),
+            Ok(Event::ContentBlockDelta {
+                delta: ContentDelta::TextDelta { text },
+                ..
+            }) => completion_events.push(Ok(LanguageModelCompletionEvent::Text(text))),
+            Ok(Event::Error { error }) => completion_events.push(Err(map_anthropic_error(
+                anyhow!(AnthropicError::ApiError(error)),
+            ))),
+            Ok(_) => {}
+            Err(error) => completion_events.push(Err(map_anthropic_error(anyhow!(error)))),
+        }
+        futures::stream::iter(completion_events)
+    })
+}
+
+/// Returns how far `usage` has grown past the `(input, output)` token totals
+/// reported so far, adding the difference to them.
+fn usage_update(
+    (reported_input_tokens, reported_output_tokens): &mut (u32, u32),
+    usage: anthropic::Usage,
+) -> Option<LanguageModelCompletionEvent> {
+    let input_tokens = usage
+        .input_tokens
+        .map_or(0, |total| total.saturating_sub(*reported_input_tokens));
+    let output_tokens = usage
+        .output_tokens
+        .map_or(0, |total| total.saturating_sub(*reported_output_tokens));
+    *reported_input_tokens += input_tokens;
+    *reported_output_tokens += output_tokens;
+    (input_tokens > 0 || output_tokens > 0).then_some(LanguageModelCompletionEvent::UsageUpdate {
+        input_tokens: input_tokens as usize,
+        output_tokens: output_tokens as usize,
+    })
+}
+
+impl AnthropicModel {
     fn stream_completion(
         &self,
         request: anthropic::Request,
@@ -275,27 +533,47 @@ impl AnthropicModel {
     {
         let http_client = self.http_client.clone();
 
-        let Ok((api_key, api_url, low_speed_timeout)) = cx.read_model(&self.state, |state, cx| {
-            let settings = &AllLanguageModelSettings::get_global(cx).anthropic;
-            (
-                state.api_key.clone(),
-                settings.api_url.clone(),
-                settings.low_speed_timeout,
-            )
-        }) else {
+        let Ok((api_key, api_key_rotation, api_url, low_speed_timeout, known_model_ids)) = cx
+            .read_model(&self.state, |state, cx| {
+                let settings = &AllLanguageModelSettings::get_global(cx).anthropic;
+                (
+                    state.api_key.clone(),
+                    state.api_key_rotation.clone(),
+                    settings.api_url.clone(),
+                    settings.low_speed_timeout,
+                    known_model_ids(settings),
+                )
+            })
+        else {
             return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
         };
 
+        let executor = cx.background_executor().clone();
         async move {
-            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
-            let request = anthropic::stream_completion(
-                http_client.as_ref(),
-                &api_url,
-                &api_key,
-                request,
-                low_speed_timeout,
-            );
-            request.await.context("failed to stream completion")
+            let api_keys = api_key_rotation.keys_for_next_request(api_key.as_deref())?;
+            let (http_client, api_url, request) = (&http_client, &api_url, &request);
+            let response = with_backoff(&executor, is_overloaded_error, move || {
+                with_api_key_failover(
+                    api_keys.clone(),
+                    is_rate_limit_error,
+                    move |api_key| async move {
+                        anthropic::stream_completion(
+                            http_client.as_ref(),
+                            api_url,
+                            &api_key,
+                            request.clone(),
+                            low_speed_timeout,
+                        )
+                        .await
+                    },
+                )
+            });
+            response
+                .await
+                .map_err(|error| {
+                    map_model_not_found(error.into(), &request.model, &known_model_ids)
+                })
+                .context("failed to stream completion")
         }
         .boxed()
     }
@@ -326,6 +604,14 @@ impl LanguageModel for AnthropicModel {
         self.model.max_token_count()
     }
 
+    fn capabilities(&self) -> LanguageModelCapabilities {
+        anthropic_capabilities(&self.model)
+    }
+
+    fn deprecation_date(&self) -> Option<SharedString> {
+        self.model.deprecation_date().map(SharedString::from)
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
@@ -334,24 +620,68 @@ impl LanguageModel for AnthropicModel {
         count_anthropic_tokens(request, cx)
     }
 
+    fn count_tokens_batch(
+        &self,
+        requests: Vec<LanguageModelRequest>,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<usize>>> {
+        count_anthropic_tokens_batch(requests, cx)
+    }
+
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        // Estimating the request's tokens is only worth it when the estimate's
+        // divergence from Anthropic's count will be logged. The estimate runs
+        // in the background and is compared once the response starts.
+        let mut token_count_check = log::log_enabled!(log::Level::Debug)
+            .then(|| {
+                cx.update(|cx| {
+                    (
+                        count_anthropic_tokens(request.clone(), cx),
+                        cx.background_executor().clone(),
+                    )
+                })
+                .ok()
+            })
+            .flatten();
+        let model_id = self.model.id().to_string();
+        let priority = request.priority;
         let request = request.into_anthropic(self.model.id().into());
         let request = self.stream_completion(request, cx);
-        let future = self.request_limiter.stream(async move {
-            let response = request.await.map_err(|err| anyhow!(err))?;
-            Ok(anthropic::extract_text_from_events(response))
+        let future = self.request_limiter.stream(priority, async move {
+            let response = request.await.map_err(map_anthropic_error)?;
+            let response = response.inspect(move |event| {
+                let Ok(Event::MessageStart { message }) = event else {
+                    return;
+                };
+                let Some(input_tokens) = message.usage.input_tokens else {
+                    return;
+                };
+                if let Some((estimated_tokens, executor)) = token_count_check.take() {
+                    let model_id = model_id.clone();
+                    executor
+                        .spawn(async move {
+                            if let Some(estimated_tokens) = estimated_tokens.await.log_err() {
+                                log_token_count_divergence(
+                                    &model_id,
+                                    estimated_tokens,
+                                    input_tokens as usize,
+                                );
+                            }
+                        })
+                        .detach();
+                }
+            });
+            Ok(map_to_language_model_completion_events(response))
         });
-        async move {
-            Ok(future
-                .await?
-                .map(|result| result.map_err(|err| anyhow!(err)))
-                .boxed())
-        }
-        .boxed()
+        interceptors.intercept(
+            self.in_flight
+                .track(async move { Ok(future.await?.boxed()) }.boxed()),
+        )
     }
 
     fn use_any_tool(
@@ -362,6 +692,8 @@ impl LanguageModel for AnthropicModel {
         input_schema: serde_json::Value,
         cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<serde_json::Value>> {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        let priority = request.priority;
         let mut request = request.into_anthropic(self.model.tool_model_id().into());
         request.tool_choice = Some(anthropic::ToolChoice::Tool {
             name: tool_name.clone(),
@@ -369,30 +701,23 @@ impl LanguageModel for AnthropicModel {
         request.tools = vec![anthropic::Tool {
             name: tool_name.clone(),
             description: tool_description,
-            input_schema,
+            input_schema: input_schema.clone(),
         }];
 
-        let response = self.request_completion(request, cx);
-        self.request_limiter
-            .run(async move {
-                let response = response.await?;
-                response
-                    .content
-                    .into_iter()
-                    .find_map(|content| {
-                        if let anthropic::Content::ToolUse { name, input, .. } = content {
-                            if name == tool_name {
-                                Some(input)
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
+        let response = self.stream_completion(request, cx);
+        interceptors.intercept_tool_use(
+            self.in_flight.track_future(
+                self.request_limiter
+                    .run(priority, async move {
+                        let events = response
+                            .await
+                            .map_err(map_anthropic_error)?
+                            .map(|event| event.map_err(|error| map_anthropic_error(error.into())));
+                        stream_tool_input(events, &tool_name, input_schema).await
                     })
-                    .context("tool not used")
-            })
-            .boxed()
+                    .boxed(),
+            ),
+        )
     }
 }
 
@@ -506,9 +831,10 @@ impl ConfigurationView {
 
 impl Render for ConfigurationView {
     fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
-        const INSTRUCTIONS: [&str; 4] = [
+        const INSTRUCTIONS: [&str; 5] = [
             "To use the assistant panel or inline assistant, you need to add your Anthropic API key.",
             "You can create an API key at: https://console.anthropic.com/settings/keys",
+            "To spread requests across several keys, separate them with commas.",
             "",
             "Paste your Anthropic API key below and hit enter to use the assistant:",
         ];
@@ -560,3 +886,335 @@ impl Render for ConfigurationView {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::INITIAL_BACKOFF;
+    use futures::TryStreamExt as _;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    #[test]
+    fn test_log_token_count_divergence() {
+        assert!(!log_token_count_divergence("claude-3-5-sonnet", 0, 0));
+        assert!(!log_token_count_divergence("claude-3-5-sonnet", 1000, 1150));
+        assert!(!log_token_count_divergence("claude-3-5-sonnet", 1150, 1000));
+        // Small requests are dominated by overhead the estimate ignores.
+        assert!(!log_token_count_divergence("claude-3-5-sonnet", 10, 60));
+
+        assert!(log_token_count_divergence("claude-3-5-sonnet", 1000, 1500));
+        assert!(log_token_count_divergence("claude-3-5-sonnet", 1500, 1000));
+        assert!(log_token_count_divergence("claude-3-5-sonnet", 0, 400));
+    }
+
+    #[gpui::test]
+    async fn test_completion_events() {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_01","type":"message","role":"assistant","content":[],"model":"claude-3-5-sonnet-20240620","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":12,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Hello!"}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":" How can I help?"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn","stop_sequence":null},"usage":{"output_tokens":9}}"#,
+            r#"{"type":"message_stop"}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<Event>(event).unwrap()));
+
+        let events = map_to_language_model_completion_events(futures::stream::iter(events))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::ReportedModel("claude-3-5-sonnet-20240620".into()),
+                LanguageModelCompletionEvent::UsageUpdate {
+                    input_tokens: 12,
+                    output_tokens: 1,
+                },
+                LanguageModelCompletionEvent::Text(String::new()),
+                LanguageModelCompletionEvent::Text("Hello!".into()),
+                LanguageModelCompletionEvent::Text(" How can I help?".into()),
+                LanguageModelCompletionEvent::UsageUpdate {
+                    input_tokens: 0,
+                    output_tokens: 8,
+                },
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_usage_update() {
+        let usage = |input_tokens, output_tokens| anthropic::Usage {
+            input_tokens,
+            output_tokens,
+        };
+        let mut reported_tokens = (0, 0);
+        assert_eq!(
+            usage_update(&mut reported_tokens, usage(Some(12), Some(1))),
+            Some(LanguageModelCompletionEvent::UsageUpdate {
+                input_tokens: 12,
+                output_tokens: 1,
+            })
+        );
+        // Totals that are repeated, or left out, aren't counted again.
+        assert_eq!(
+            usage_update(&mut reported_tokens, usage(Some(12), Some(5))),
+            Some(LanguageModelCompletionEvent::UsageUpdate {
+                input_tokens: 0,
+                output_tokens: 4,
+            })
+        );
+        assert_eq!(
+            usage_update(&mut reported_tokens, usage(None, Some(5))),
+            None
+        );
+        assert_eq!(reported_tokens, (12, 5));
+    }
+
+    #[gpui::test]
+    async fn test_stop_sequence() {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_02","type":"message","role":"assistant","content":[],"model":"claude-3-5-sonnet-20240620","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":20,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"fn main() {}"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"stop_sequence","stop_sequence":"</rewritten>"},"usage":{"output_tokens":6}}"#,
+            r#"{"type":"message_stop"}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<Event>(event).unwrap()));
+
+        let events = map_to_language_model_completion_events(futures::stream::iter(events))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events.last(),
+            Some(&LanguageModelCompletionEvent::Stop(
+                StopReason::StopSequence("</rewritten>".into())
+            ))
+        );
+    }
+
+    #[gpui::test]
+    fn test_deprecated_models(cx: &mut gpui::TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        let provider = cx.update(|cx| {
+            AnthropicLanguageModelProvider::new(
+                http_client::FakeHttpClient::with_404_response(),
+                cx,
+            )
+        });
+        let deprecations = cx.update(|cx| {
+            provider
+                .provided_models(cx)
+                .iter()
+                .map(|model| {
+                    (
+                        model.id().0.to_string(),
+                        (model.is_deprecated(), model.deprecation_date()),
+                    )
+                })
+                .collect::<collections::HashMap<_, _>>()
+        });
+        assert_eq!(
+            deprecations["claude-3-sonnet-20240229"],
+            (true, Some("2025-07-21".into()))
+        );
+        assert_eq!(deprecations["claude-3-5-sonnet-20240620"], (false, None));
+    }
+
+    fn test_model(
+        http_client: Arc<dyn HttpClient>,
+        cx: &mut gpui::TestAppContext,
+    ) -> Arc<dyn LanguageModel> {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+        let provider = cx.update(|cx| AnthropicLanguageModelProvider::new(http_client, cx));
+        provider.state.update(cx, |state, _| {
+            state.api_key = Some("test-key".into());
+        });
+        cx.update(|cx| {
+            provider
+                .provided_models(cx)
+                .into_iter()
+                .find(|model| model.id().0.as_ref() == "claude-3-5-sonnet-20240620")
+                .unwrap()
+        })
+    }
+
+    fn tool_use_events(partial_json: &str) -> String {
+        [
+            r#"{"type":"message_start","message":{"id":"msg_01","type":"message","role":"assistant","content":[],"model":"claude-3-5-sonnet-20240620","stop_reason":null,"stop_sequence":null,"usage":{"input_tokens":10,"output_tokens":1}}}"#.to_string(),
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"tool_use","id":"toolu_01","name":"rename","input":{}}}"#.to_string(),
+            serde_json::json!({
+                "type": "content_block_delta",
+                "index": 0,
+                "delta": { "type": "input_json_delta", "partial_json": partial_json },
+            })
+            .to_string(),
+        ]
+        .iter()
+        .map(|event| format!("data: {event}\n\n"))
+        .collect()
+    }
+
+    #[gpui::test]
+    async fn test_overloaded_requests_are_retried(cx: &mut gpui::TestAppContext) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let http_client = http_client::FakeHttpClient::create({
+            let attempts = attempts.clone();
+            move |_| {
+                let attempts = attempts.clone();
+                async move {
+                    let (status, body) = if attempts.fetch_add(1, SeqCst) == 0 {
+                        (
+                            529,
+                            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#
+                                .to_string(),
+                        )
+                    } else {
+                        let mut events = tool_use_events(r#"{"name":"Anthropic"}"#);
+                        events.push_str("data: {\"type\":\"content_block_stop\",\"index\":0}\n\n");
+                        events.push_str("data: {\"type\":\"message_stop\"}\n\n");
+                        (200, events)
+                    };
+                    Ok(http_client::Response::builder()
+                        .status(status)
+                        .body(body.into())
+                        .unwrap())
+                }
+            }
+        });
+        let model = test_model(http_client, cx);
+
+        let response = model.use_any_tool(
+            LanguageModelRequest::default(),
+            "rename".into(),
+            "Renames the item.".into(),
+            serde_json::json!({ "type": "object" }),
+            &cx.to_async(),
+        );
+        let response = cx.background_executor.spawn(response);
+        cx.run_until_parked();
+        assert_eq!(attempts.load(SeqCst), 1);
+
+        cx.executor().advance_clock(INITIAL_BACKOFF);
+        assert_eq!(
+            response.await.unwrap(),
+            serde_json::json!({ "name": "Anthropic" })
+        );
+        assert_eq!(attempts.load(SeqCst), 2);
+    }
+
+    #[gpui::test]
+    async fn test_tool_input_stops_streaming_on_schema_violation(cx: &mut gpui::TestAppContext) {
+        // The connection stays open after the invalid input, so the call only
+        // returns if the stream is abandoned as soon as the input is invalid.
+        let http_client = http_client::FakeHttpClient::create(|_| async {
+            let events = tool_use_events(r#"{"name": 42"#);
+            let body = futures::stream::iter([Ok::<_, std::io::Error>(events.into_bytes())])
+                .chain(futures::stream::pending())
+                .into_async_read();
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(http_client::AsyncBody::from_reader(body))
+                .unwrap())
+        });
+        let model = test_model(http_client, cx);
+
+        let error = model
+            .use_any_tool(
+                LanguageModelRequest::default(),
+                "rename".into(),
+                "Renames the item.".into(),
+                serde_json::json!({
+                    "type": "object",
+                    "properties": { "name": { "type": "string" } },
+                }),
+                &cx.to_async(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "output does not match the schema: expected string at $.name, found number"
+        );
+    }
+
+    #[test]
+    fn test_overloaded_error() {
+        let error = map_anthropic_error(anyhow!(AnthropicError::ApiError(ApiError {
+            error_type: "overloaded_error".into(),
+            message: "Overloaded".into(),
+        })));
+        let error = error.downcast_ref::<LanguageModelError>().unwrap();
+        assert_eq!(error, &LanguageModelError::Overloaded);
+        assert!(error.is_retryable());
+    }
+
+    #[test]
+    fn test_context_window_exceeded() {
+        let api_error = |error_type: &str, message: &str| {
+            anyhow!(AnthropicError::ApiError(ApiError {
+                error_type: error_type.into(),
+                message: message.into(),
+            }))
+            .context("failed to stream completion")
+        };
+
+        let error = map_anthropic_error(api_error(
+            "invalid_request_error",
+            "prompt is too long: 208310 tokens > 200000 maximum",
+        ));
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::ContextWindowExceeded {
+                limit: Some(200000)
+            })
+        );
+
+        let error = map_anthropic_error(api_error(
+            "request_too_large",
+            "Request exceeds the maximum allowed number of bytes.",
+        ));
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::ContextWindowExceeded { limit: None })
+        );
+
+        let error = map_anthropic_error(api_error(
+            "invalid_request_error",
+            "messages: roles must alternate",
+        ));
+        assert!(error.downcast_ref::<LanguageModelError>().is_none());
+        assert!(error.downcast_ref::<AnthropicError>().is_some());
+    }
+
+    #[test]
+    fn test_api_version_unsupported() {
+        let error = map_anthropic_error(anyhow!(AnthropicError::ApiError(ApiError {
+            error_type: "invalid_request_error".into(),
+            message: "anthropic-version: invalid version 2023-06-01".into(),
+        })));
+        let error = error.downcast_ref::<LanguageModelError>().unwrap();
+        assert_eq!(
+            error,
+            &LanguageModelError::ApiVersionUnsupported {
+                header: "anthropic-version".into(),
+                version: anthropic::ANTHROPIC_API_VERSION.into(),
+            }
+        );
+        assert!(!error.is_retryable());
+        assert!(error.to_string().contains("`anthropic-version`"));
+    }
+}
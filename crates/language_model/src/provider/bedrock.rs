@@ -0,0 +1,664 @@
+use anyhow::{anyhow, Result};
+use bedrock::{stream_converse, ContentBlockDelta, StreamEvent};
+use collections::BTreeMap;
+use editor::{Editor, EditorElement, EditorStyle};
+use futures::{future::BoxFuture, stream::BoxStream, FutureExt, Stream, StreamExt};
+use gpui::{
+    AnyView, AppContext, AsyncAppContext, FontStyle, ModelContext, Subscription, Task, TextStyle,
+    View, WhiteSpace,
+};
+use http_client::HttpClient;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use settings::{Settings, SettingsStore};
+use std::{future, sync::Arc, time::Duration};
+use strum::IntoEnumIterator;
+use theme::ThemeSettings;
+use ui::{prelude::*, Icon, IconName};
+use util::ResultExt;
+
+use crate::{
+    intercept_request, settings::AllLanguageModelSettings, InFlightCompletions, LanguageModel,
+    LanguageModelCompletionEvent, LanguageModelId, LanguageModelName, LanguageModelProvider,
+    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState,
+    LanguageModelRequest, RateLimiter, StopReason,
+};
+
+const PROVIDER_ID: &str = "bedrock";
+const PROVIDER_NAME: &str = "Amazon Bedrock";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BedrockSettings {
+    pub region: String,
+    pub low_speed_timeout: Option<Duration>,
+    pub available_models: Vec<AvailableModel>,
+    /// Ids of built-in models to hide from the model picker.
+    pub disabled_models: Vec<String>,
+}
+
+impl Default for BedrockSettings {
+    fn default() -> Self {
+        Self {
+            region: "us-east-1".into(),
+            low_speed_timeout: None,
+            available_models: Vec::new(),
+            disabled_models: Vec::new(),
+        }
+    }
+}
+
+impl BedrockSettings {
+    /// The runtime endpoint for the configured region, which is also what the
+    /// API key is stored under.
+    pub fn api_url(&self) -> String {
+        bedrock::runtime_api_url(&self.region)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AvailableModel {
+    /// The model id or inference profile, such as
+    /// `us.meta.llama3-2-90b-instruct-v1:0`.
+    name: String,
+    /// The name displayed in the model picker, defaulting to `name`.
+    display_name: Option<String>,
+    max_tokens: usize,
+    max_output_tokens: Option<u32>,
+}
+
+pub struct BedrockLanguageModelProvider {
+    http_client: Arc<dyn HttpClient>,
+    state: gpui::Model<State>,
+    in_flight: InFlightCompletions,
+}
+
+pub struct State {
+    api_key: Option<String>,
+    _subscription: Subscription,
+}
+
+impl State {
+    fn is_authenticated(&self) -> bool {
+        self.api_key.is_some()
+    }
+
+    fn reset_api_key(&self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        let delete_credentials =
+            cx.delete_credentials(&AllLanguageModelSettings::get_global(cx).bedrock.api_url());
+        cx.spawn(|this, mut cx| async move {
+            delete_credentials.await.ok();
+            this.update(&mut cx, |this, cx| {
+                this.api_key = None;
+                cx.notify();
+            })
+        })
+    }
+
+    fn set_api_key(&mut self, api_key: String, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        let api_url = AllLanguageModelSettings::get_global(cx).bedrock.api_url();
+        let write_credentials = cx.write_credentials(&api_url, "Bearer", api_key.as_bytes());
+
+        cx.spawn(|this, mut cx| async move {
+            write_credentials.await?;
+            this.update(&mut cx, |this, cx| {
+                this.api_key = Some(api_key);
+                cx.notify();
+            })
+        })
+    }
+
+    fn authenticate(&self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
+        if self.is_authenticated() {
+            Task::ready(Ok(()))
+        } else {
+            let api_url = AllLanguageModelSettings::get_global(cx).bedrock.api_url();
+
+            cx.spawn(|this, mut cx| async move {
+                let api_key = if let Ok(api_key) = std::env::var("AWS_BEARER_TOKEN_BEDROCK") {
+                    api_key
+                } else {
+                    let (_, api_key) = cx
+                        .update(|cx| cx.read_credentials(&api_url))?
+                        .await?
+                        .ok_or_else(|| anyhow!("credentials not found"))?;
+                    String::from_utf8(api_key)?
+                };
+
+                this.update(&mut cx, |this, cx| {
+                    this.api_key = Some(api_key);
+                    cx.notify();
+                })
+            })
+        }
+    }
+}
+
+impl BedrockLanguageModelProvider {
+    pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut AppContext) -> Self {
+        let state = cx.new_model(|cx| State {
+            api_key: None,
+            _subscription: cx.observe_global::<SettingsStore>(|_, cx| {
+                cx.notify();
+            }),
+        });
+
+        Self {
+            http_client,
+            state,
+            in_flight: InFlightCompletions::default(),
+        }
+    }
+}
+
+impl LanguageModelProviderState for BedrockLanguageModelProvider {
+    type ObservableEntity = State;
+
+    fn observable_entity(&self) -> Option<gpui::Model<Self::ObservableEntity>> {
+        Some(self.state.clone())
+    }
+}
+
+impl LanguageModelProvider for BedrockLanguageModelProvider {
+    fn id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn icon(&self) -> IconName {
+        IconName::Ai
+    }
+
+    fn provided_models(&self, cx: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
+        let mut models = BTreeMap::default();
+
+        for model in bedrock::Model::iter() {
+            if !matches!(model, bedrock::Model::Custom { .. }) {
+                models.insert(model.id().to_string(), model);
+            }
+        }
+
+        // Override with available models from settings
+        for model in &AllLanguageModelSettings::get_global(cx)
+            .bedrock
+            .available_models
+        {
+            models.insert(
+                model.name.clone(),
+                bedrock::Model::Custom {
+                    name: model.name.clone(),
+                    display_name: model.display_name.clone(),
+                    max_tokens: model.max_tokens,
+                    max_output_tokens: model.max_output_tokens,
+                },
+            );
+        }
+
+        let disabled_models = &AllLanguageModelSettings::get_global(cx)
+            .bedrock
+            .disabled_models;
+        models.retain(|id, _| !disabled_models.contains(id));
+
+        models
+            .into_values()
+            .map(|model| {
+                Arc::new(BedrockLanguageModel {
+                    id: LanguageModelId::from(model.id().to_string()),
+                    model,
+                    state: self.state.clone(),
+                    http_client: self.http_client.clone(),
+                    rate_limiter: RateLimiter::new(4).with_configured_max_queue_wait(cx),
+                    in_flight: self.in_flight.clone(),
+                }) as Arc<dyn LanguageModel>
+            })
+            .collect()
+    }
+
+    fn is_authenticated(&self, cx: &AppContext) -> bool {
+        self.state.read(cx).is_authenticated()
+    }
+
+    fn authenticate(&self, cx: &mut AppContext) -> Task<Result<()>> {
+        self.state.update(cx, |state, cx| state.authenticate(cx))
+    }
+
+    fn configuration_view(&self, cx: &mut WindowContext) -> AnyView {
+        cx.new_view(|cx| ConfigurationView::new(self.state.clone(), cx))
+            .into()
+    }
+
+    fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
+        self.state.update(cx, |state, cx| state.reset_api_key(cx))
+    }
+
+    fn cancel_all(&self, _cx: &mut AppContext) {
+        self.in_flight.abort_all();
+    }
+}
+
+pub struct BedrockLanguageModel {
+    id: LanguageModelId,
+    model: bedrock::Model,
+    state: gpui::Model<State>,
+    http_client: Arc<dyn HttpClient>,
+    rate_limiter: RateLimiter,
+    in_flight: InFlightCompletions,
+}
+
+impl LanguageModel for BedrockLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.id.clone()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        LanguageModelName::from(self.model.display_name().to_string())
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn telemetry_id(&self) -> String {
+        format!("bedrock/{}", self.model.id())
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.max_token_count()
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        _cx: &AppContext,
+    ) -> BoxFuture<'static, Result<usize>> {
+        // The Converse API has no way to count tokens, and each model family
+        // on Bedrock has its own tokenizer.
+        let token_count = request.estimated_token_count();
+        async move { Ok(token_count) }.boxed()
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        let http_client = self.http_client.clone();
+        let Ok((api_key, api_url, low_speed_timeout)) = cx.read_model(&self.state, |state, cx| {
+            let settings = &AllLanguageModelSettings::get_global(cx).bedrock;
+            (
+                state.api_key.clone(),
+                settings.api_url(),
+                settings.low_speed_timeout,
+            )
+        }) else {
+            return interceptors
+                .intercept(futures::future::ready(Err(anyhow!("App state dropped"))).boxed());
+        };
+        let priority = request.priority;
+        let request = match request
+            .into_bedrock(self.model.id().to_string(), self.model.max_output_tokens())
+        {
+            Ok(request) => request,
+            Err(error) => {
+                return interceptors.intercept(futures::future::ready(Err(error)).boxed())
+            }
+        };
+
+        let future = self.rate_limiter.stream(priority, async move {
+            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
+            let events = stream_converse(
+                http_client.as_ref(),
+                &api_url,
+                &api_key,
+                request,
+                low_speed_timeout,
+            )
+            .await?;
+            Ok(map_to_language_model_completion_events(events).boxed())
+        });
+        interceptors.intercept(
+            self.in_flight
+                .track(async move { Ok(future.await?.boxed()) }.boxed()),
+        )
+    }
+
+    fn use_any_tool(
+        &self,
+        _request: LanguageModelRequest,
+        _name: String,
+        _description: String,
+        _schema: serde_json::Value,
+        _cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<serde_json::Value>> {
+        self.in_flight
+            .track_future(future::ready(Err(anyhow!("not implemented"))).boxed())
+    }
+}
+
+/// Converts the events of a `ConverseStream` response into completion events.
+pub fn map_to_language_model_completion_events(
+    events: impl Stream<Item = Result<StreamEvent>>,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    events.filter_map(|event| async move {
+        match event {
+            Ok(StreamEvent::ContentBlockDelta {
+                delta: ContentBlockDelta::Text(text),
+                ..
+            }) => Some(Ok(LanguageModelCompletionEvent::Text(text))),
+            Ok(StreamEvent::MessageStop { stop_reason }) => {
+                let stop_reason = match stop_reason.as_str() {
+                    "end_turn" => StopReason::EndTurn,
+                    "max_tokens" => StopReason::MaxTokens,
+                    "tool_use" => StopReason::ToolUse,
+                    // Bedrock doesn't say which stop sequence was matched.
+                    _ => return None,
+                };
+                Some(Ok(LanguageModelCompletionEvent::Stop(stop_reason)))
+            }
+            Ok(StreamEvent::Metadata { usage }) => {
+                Some(Ok(LanguageModelCompletionEvent::UsageUpdate {
+                    input_tokens: usage.input_tokens as usize,
+                    output_tokens: usage.output_tokens as usize,
+                }))
+            }
+            Ok(_) => None,
+            Err(error) => Some(Err(error)),
+        }
+    })
+}
+
+struct ConfigurationView {
+    api_key_editor: View<Editor>,
+    state: gpui::Model<State>,
+    load_credentials_task: Option<Task<()>>,
+}
+
+impl ConfigurationView {
+    fn new(state: gpui::Model<State>, cx: &mut ViewContext<Self>) -> Self {
+        cx.observe(&state, |_, _, cx| {
+            cx.notify();
+        })
+        .detach();
+
+        let load_credentials_task = Some(cx.spawn({
+            let state = state.clone();
+            |this, mut cx| async move {
+                if let Some(task) = state
+                    .update(&mut cx, |state, cx| state.authenticate(cx))
+                    .log_err()
+                {
+                    // We don't log an error, because "not signed in" is also an error.
+                    let _ = task.await;
+                }
+                this.update(&mut cx, |this, cx| {
+                    this.load_credentials_task = None;
+                    cx.notify();
+                })
+                .log_err();
+            }
+        }));
+
+        Self {
+            api_key_editor: cx.new_view(|cx| {
+                let mut editor = Editor::single_line(cx);
+                editor.set_placeholder_text("ABSK...", cx);
+                editor
+            }),
+            state,
+            load_credentials_task,
+        }
+    }
+
+    fn save_api_key(&mut self, _: &menu::Confirm, cx: &mut ViewContext<Self>) {
+        let api_key = self.api_key_editor.read(cx).text(cx);
+        if api_key.is_empty() {
+            return;
+        }
+
+        let state = self.state.clone();
+        cx.spawn(|_, mut cx| async move {
+            state
+                .update(&mut cx, |state, cx| state.set_api_key(api_key, cx))?
+                .await
+        })
+        .detach_and_log_err(cx);
+
+        cx.notify();
+    }
+
+    fn reset_api_key(&mut self, cx: &mut ViewContext<Self>) {
+        self.api_key_editor
+            .update(cx, |editor, cx| editor.set_text("", cx));
+
+        let state = self.state.clone();
+        cx.spawn(|_, mut cx| async move {
+            state
+                .update(&mut cx, |state, cx| state.reset_api_key(cx))?
+                .await
+        })
+        .detach_and_log_err(cx);
+
+        cx.notify();
+    }
+
+    fn render_api_key_editor(&self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        let settings = ThemeSettings::get_global(cx);
+        let text_style = TextStyle {
+            color: cx.theme().colors().text,
+            font_family: settings.ui_font.family.clone(),
+            font_features: settings.ui_font.features.clone(),
+            font_fallbacks: settings.ui_font.fallbacks.clone(),
+            font_size: rems(0.875).into(),
+            font_weight: settings.ui_font.weight,
+            font_style: FontStyle::Normal,
+            line_height: relative(1.3),
+            background_color: None,
+            underline: None,
+            strikethrough: None,
+            white_space: WhiteSpace::Normal,
+        };
+        EditorElement::new(
+            &self.api_key_editor,
+            EditorStyle {
+                background: cx.theme().colors().editor_background,
+                local_player: cx.theme().players().local(),
+                text: text_style,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn should_render_editor(&self, cx: &mut ViewContext<Self>) -> bool {
+        !self.state.read(cx).is_authenticated()
+    }
+}
+
+impl Render for ConfigurationView {
+    fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
+        const INSTRUCTIONS: [&str; 4] = [
+            "To use Amazon Bedrock, you need to add a Bedrock API key.",
+            "You can generate one under API keys in the Amazon Bedrock console.",
+            "",
+            "Paste your Bedrock API key below and hit enter to use the assistant:",
+        ];
+
+        if self.load_credentials_task.is_some() {
+            div().child(Label::new("Loading credentials...")).into_any()
+        } else if self.should_render_editor(cx) {
+            v_flex()
+                .size_full()
+                .on_action(cx.listener(Self::save_api_key))
+                .children(
+                    INSTRUCTIONS.map(|instruction| Label::new(instruction)),
+                )
+                .child(
+                    h_flex()
+                        .w_full()
+                        .my_2()
+                        .px_2()
+                        .py_1()
+                        .bg(cx.theme().colors().editor_background)
+                        .rounded_md()
+                        .child(self.render_api_key_editor(cx)),
+                )
+                .child(
+                    Label::new(
+                        "You can also assign the AWS_BEARER_TOKEN_BEDROCK environment variable and restart Zed.",
+                    )
+                    .size(LabelSize::Small),
+                )
+                .into_any()
+        } else {
+            h_flex()
+                .size_full()
+                .justify_between()
+                .child(
+                    h_flex()
+                        .gap_1()
+                        .child(Icon::new(IconName::Check).color(Color::Success))
+                        .child(Label::new("API key configured.")),
+                )
+                .child(
+                    Button::new("reset-key", "Reset key")
+                        .icon(Some(IconName::Trash))
+                        .icon_size(IconSize::Small)
+                        .icon_position(IconPosition::Start)
+                        .on_click(cx.listener(|this, _, cx| this.reset_api_key(cx))),
+                )
+                .into_any()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncReadExt as _;
+    use gpui::TestAppContext;
+    use http_client::FakeHttpClient;
+
+    #[gpui::test]
+    async fn test_completion_events() {
+        let events = [
+            StreamEvent::MessageStart {
+                role: bedrock::Role::Assistant,
+            },
+            StreamEvent::ContentBlockDelta {
+                content_block_index: 0,
+                delta: ContentBlockDelta::Text("Hello".into()),
+            },
+            StreamEvent::ContentBlockStop {
+                content_block_index: 0,
+            },
+            StreamEvent::MessageStop {
+                stop_reason: "end_turn".into(),
+            },
+            StreamEvent::Metadata {
+                usage: bedrock::Usage {
+                    input_tokens: 9,
+                    output_tokens: 1,
+                    total_tokens: 10,
+                },
+            },
+        ]
+        .map(Ok);
+
+        let events = map_to_language_model_completion_events(futures::stream::iter(events))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+                LanguageModelCompletionEvent::UsageUpdate {
+                    input_tokens: 9,
+                    output_tokens: 1,
+                },
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_stream_completion_request(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        let sent_requests = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_requests = sent_requests.clone();
+            move |mut request| {
+                let sent_requests = sent_requests.clone();
+                async move {
+                    let mut body = String::new();
+                    request.body_mut().read_to_string(&mut body).await.unwrap();
+                    sent_requests.lock().push((
+                        request.uri().to_string(),
+                        request.headers()["Authorization"]
+                            .to_str()
+                            .unwrap()
+                            .to_string(),
+                        serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+                    ));
+                    // An event stream with no messages in it.
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(http_client::AsyncBody::empty())
+                        .unwrap())
+                }
+            }
+        });
+        let provider = cx.update(|cx| BedrockLanguageModelProvider::new(http_client, cx));
+        provider.state.update(cx, |state, _| {
+            state.api_key = Some("api-key".into());
+        });
+        let model = cx.update(|cx| {
+            provider
+                .provided_models(cx)
+                .into_iter()
+                .find(|model| model.id().0 == "amazon.nova-lite-v1:0")
+                .unwrap()
+        });
+
+        let request = LanguageModelRequest {
+            messages: vec![crate::LanguageModelRequestMessage {
+                role: crate::Role::User,
+                content: "Hello".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let events = model
+            .stream_completion(request, &cx.to_async())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+        assert!(events.is_empty());
+
+        let sent_requests = sent_requests.lock();
+        let (uri, authorization, body) = &sent_requests[0];
+        assert_eq!(
+            uri,
+            "https://bedrock-runtime.us-east-1.amazonaws.com/model/amazon.nova-lite-v1:0/converse-stream"
+        );
+        assert_eq!(authorization, "Bearer api-key");
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([{ "role": "user", "content": [{ "text": "Hello" }] }])
+        );
+        assert_eq!(body["inferenceConfig"]["maxTokens"], 5000);
+    }
+}
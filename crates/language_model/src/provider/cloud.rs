@@ -1,42 +1,76 @@
-use super::open_ai::count_open_ai_tokens;
+use super::open_ai::{count_open_ai_tokens, count_open_ai_tokens_batch};
 use crate::{
-    settings::AllLanguageModelSettings, CloudModel, LanguageModel, LanguageModelId,
-    LanguageModelName, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest, RateLimiter, ZedModel,
+    intercept_request, remove_unsupported_gemini_schema_keys, settings::AllLanguageModelSettings,
+    CloudModel, InFlightCompletions, LanguageModel, LanguageModelCapabilities,
+    LanguageModelCompletionEvent, LanguageModelError, LanguageModelId, LanguageModelName,
+    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState,
+    LanguageModelRequest, RateLimiter, StopReason, ZedModel,
+};
+use crate::{with_configured_backoff, Backoff, Retry};
+use anyhow::{anyhow, bail, Result};
+use async_compression::futures::bufread::GzipEncoder;
+use client::{
+    Client, CompletionEvent, CompletionTruncated, GetLanguageModelsResponse,
+    LanguageModelCatalogEntry, PerformCompletionParams, RateLimitExceeded, UsageLimitWarning,
+    UserStore, EXPIRED_LLM_TOKEN_HEADER_NAME, USAGE_LIMIT_WARNING_HEADER_NAME,
 };
-use anthropic::AnthropicError;
-use anyhow::{anyhow, bail, Context as _, Result};
-use client::{Client, PerformCompletionParams, UserStore, EXPIRED_LLM_TOKEN_HEADER_NAME};
 use collections::BTreeMap;
+use db::kvp::KEY_VALUE_STORE;
 use feature_flags::{FeatureFlagAppExt, LanguageModels};
-use futures::{future::BoxFuture, stream::BoxStream, AsyncBufReadExt, FutureExt, StreamExt};
+use futures::{
+    future::BoxFuture, stream::BoxStream, AsyncBufReadExt, AsyncReadExt, FutureExt, Stream,
+    StreamExt,
+};
 use gpui::{
-    AnyElement, AnyView, AppContext, AsyncAppContext, FontWeight, Model, ModelContext,
-    Subscription, Task,
+    AnyElement, AnyView, AppContext, AsyncAppContext, BackgroundExecutor, FontWeight, Model,
+    ModelContext, Subscription, Task,
 };
-use http_client::{AsyncBody, HttpClient, Method, Response};
+use http_client::{AsyncBody, HttpClient, Method, Response, StatusCode};
+use parking_lot::Mutex;
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::value::RawValue;
 use settings::{Settings, SettingsStore};
 use smol::{
     io::BufReader,
     lock::{RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard},
 };
-use std::{future, sync::Arc};
+use std::{
+    future,
+    sync::{
+        atomic::{AtomicBool, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
 use strum::IntoEnumIterator;
 use ui::prelude::*;
+use util::ResultExt as _;
 
-use crate::{LanguageModelAvailability, LanguageModelProvider};
+use crate::{LanguageModelAvailability, LanguageModelProvider, StreamingSchemaValidator};
 
-use super::anthropic::count_anthropic_tokens;
+use super::anthropic::{count_anthropic_tokens, count_anthropic_tokens_batch};
 
 pub const PROVIDER_ID: &str = "zed.dev";
 pub const PROVIDER_NAME: &str = "Zed";
 
+/// Remembers whether the user was signed in when the app last ran.
+const SIGNED_IN_KEY: &str = "zed_dot_dev_signed_in";
+
 #[derive(Default, Clone, Debug, PartialEq)]
 pub struct ZedDotDevSettings {
+    /// Whether to offer Zed's hosted models at all. Users who only bring their
+    /// own API keys can turn this off to hide the provider from the picker.
+    pub enabled: bool,
     pub available_models: Vec<AvailableModel>,
+    /// Whether to gzip the bodies of completion requests, which saves
+    /// bandwidth on slow connections when sending large prompts.
+    pub compress_requests: bool,
+    /// Ids of built-in models to hide from the model picker.
+    pub disabled_models: Vec<String>,
+    /// How completions are retried when the server fails with a transient
+    /// error.
+    pub retry: Backoff,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
@@ -51,6 +85,8 @@ pub enum AvailableProvider {
 pub struct AvailableModel {
     provider: AvailableProvider,
     name: String,
+    /// The name displayed in the model picker, defaulting to `name`.
+    display_name: Option<String>,
     max_tokens: usize,
     tool_override: Option<String>,
 }
@@ -58,21 +94,99 @@ pub struct AvailableModel {
 pub struct CloudLanguageModelProvider {
     client: Arc<Client>,
     llm_api_token: LlmApiToken,
+    usage_limit_warning: LastUsageLimitWarning,
     state: gpui::Model<State>,
+    in_flight: InFlightCompletions,
     _maintain_client_status: Task<()>,
 }
 
 pub struct State {
     client: Arc<Client>,
+    llm_api_token: LlmApiToken,
     user_store: Model<UserStore>,
-    status: client::Status,
+    /// The models the server serves and their capabilities, fetched each time
+    /// the client connects.
+    model_catalog: Vec<LanguageModelCatalogEntry>,
+    sign_in_status: SignInStatus,
     accept_terms: Option<Task<Result<()>>>,
     _subscription: Subscription,
 }
 
+/// Tracks whether the user is signed in, assuming that they still are while
+/// the client reconnects after a restart, so that the UI doesn't briefly show
+/// them as signed out.
+struct SignInStatus {
+    status: client::Status,
+    /// Whether the user was signed in when the app last ran, and the client
+    /// hasn't yet confirmed or denied that they still are.
+    resuming_session: bool,
+    was_signed_in: bool,
+}
+
+/// How long to wait for the client to start reconnecting after a restart,
+/// before a user who was signed in is shown as signed out.
+const SESSION_RESUME_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl SignInStatus {
+    fn new(status: client::Status, was_signed_in: bool) -> Self {
+        Self {
+            status,
+            resuming_session: was_signed_in && status.is_signed_out(),
+            was_signed_in,
+        }
+    }
+
+    fn is_signed_out(&self) -> bool {
+        self.status.is_signed_out() && !self.resuming_session
+    }
+
+    /// Updates the client's status, returning whether the user is signed in
+    /// if that has changed since it was last remembered.
+    fn set_status(&mut self, status: client::Status) -> Option<bool> {
+        self.status = status;
+        let is_signed_in = match status {
+            client::Status::Connected { .. } => true,
+            client::Status::SignedOut | client::Status::UpgradeRequired => false,
+            _ => return None,
+        };
+        self.resuming_session = false;
+        if is_signed_in == self.was_signed_in {
+            return None;
+        }
+        self.was_signed_in = is_signed_in;
+        Some(is_signed_in)
+    }
+
+    /// Stops assuming that the user is still signed in if the client never
+    /// started reconnecting, returning whether the user is signed in if that
+    /// has changed.
+    fn stop_resuming_session(&mut self) -> Option<bool> {
+        if !self.resuming_session || !self.status.is_signed_out() {
+            return None;
+        }
+        self.resuming_session = false;
+        self.was_signed_in = false;
+        Some(false)
+    }
+}
+
+/// Remembers whether the user is signed in, for the next time the app starts.
+fn remember_sign_in(is_signed_in: bool, cx: &AppContext) {
+    let key = SIGNED_IN_KEY.to_string();
+    cx.background_executor()
+        .spawn(async move {
+            if is_signed_in {
+                KEY_VALUE_STORE.write_kvp(key, "true".into()).await
+            } else {
+                KEY_VALUE_STORE.delete_kvp(key).await
+            }
+        })
+        .detach_and_log_err(cx);
+}
+
 impl State {
     fn is_signed_out(&self) -> bool {
-        self.status.is_signed_out()
+        self.sign_in_status.is_signed_out()
     }
 
     fn authenticate(&self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
@@ -83,6 +197,19 @@ impl State {
         })
     }
 
+    fn fetch_model_catalog(&self, cx: &mut ModelContext<Self>) {
+        let client = self.client.clone();
+        let llm_api_token = self.llm_api_token.clone();
+        cx.spawn(move |this, mut cx| async move {
+            let model_catalog = fetch_model_catalog(client, llm_api_token).await?;
+            this.update(&mut cx, |this, cx| {
+                this.model_catalog = model_catalog;
+                cx.notify();
+            })
+        })
+        .detach_and_log_err(cx);
+    }
+
     fn has_accepted_terms_of_service(&self, cx: &AppContext) -> bool {
         self.user_store
             .read(cx)
@@ -108,15 +235,42 @@ impl CloudLanguageModelProvider {
     pub fn new(user_store: Model<UserStore>, client: Arc<Client>, cx: &mut AppContext) -> Self {
         let mut status_rx = client.status();
         let status = *status_rx.borrow();
+        let was_signed_in = KEY_VALUE_STORE
+            .read_kvp(SIGNED_IN_KEY)
+            .log_err()
+            .flatten()
+            .is_some();
+
+        let llm_api_token = LlmApiToken::default();
+        let state = cx.new_model(|cx| {
+            let sign_in_status = SignInStatus::new(status, was_signed_in);
+            // The app doesn't always try to sign back in, e.g. if the user's
+            // credentials are gone, in which case the client's status never
+            // changes from signed out.
+            if sign_in_status.resuming_session {
+                cx.spawn(|this, mut cx| async move {
+                    cx.background_executor().timer(SESSION_RESUME_TIMEOUT).await;
+                    _ = this.update(&mut cx, |this: &mut State, cx| {
+                        if let Some(is_signed_in) = this.sign_in_status.stop_resuming_session() {
+                            remember_sign_in(is_signed_in, cx);
+                            cx.notify();
+                        }
+                    });
+                })
+                .detach();
+            }
 
-        let state = cx.new_model(|cx| State {
-            client: client.clone(),
-            user_store,
-            status,
-            accept_terms: None,
-            _subscription: cx.observe_global::<SettingsStore>(|_, cx| {
-                cx.notify();
-            }),
+            State {
+                client: client.clone(),
+                llm_api_token: llm_api_token.clone(),
+                user_store,
+                model_catalog: Vec::new(),
+                sign_in_status,
+                accept_terms: None,
+                _subscription: cx.observe_global::<SettingsStore>(|_, cx| {
+                    cx.notify();
+                }),
+            }
         });
 
         let state_ref = state.downgrade();
@@ -124,8 +278,13 @@ impl CloudLanguageModelProvider {
             while let Some(status) = status_rx.next().await {
                 if let Some(this) = state_ref.upgrade() {
                     _ = this.update(&mut cx, |this, cx| {
-                        if this.status != status {
-                            this.status = status;
+                        if this.sign_in_status.status != status {
+                            if let Some(is_signed_in) = this.sign_in_status.set_status(status) {
+                                remember_sign_in(is_signed_in, cx);
+                            }
+                            if matches!(status, client::Status::Connected { .. }) {
+                                this.fetch_model_catalog(cx);
+                            }
                             cx.notify();
                         }
                     });
@@ -138,7 +297,9 @@ impl CloudLanguageModelProvider {
         Self {
             client,
             state,
-            llm_api_token: LlmApiToken::default(),
+            llm_api_token,
+            usage_limit_warning: LastUsageLimitWarning::default(),
+            in_flight: InFlightCompletions::default(),
             _maintain_client_status: maintain_client_status,
         }
     }
@@ -203,16 +364,19 @@ impl LanguageModelProvider for CloudLanguageModelProvider {
                     AvailableProvider::Anthropic => {
                         CloudModel::Anthropic(anthropic::Model::Custom {
                             name: model.name.clone(),
+                            display_name: model.display_name.clone(),
                             max_tokens: model.max_tokens,
                             tool_override: model.tool_override.clone(),
                         })
                     }
                     AvailableProvider::OpenAi => CloudModel::OpenAi(open_ai::Model::Custom {
                         name: model.name.clone(),
+                        display_name: model.display_name.clone(),
                         max_tokens: model.max_tokens,
                     }),
                     AvailableProvider::Google => CloudModel::Google(google_ai::Model::Custom {
                         name: model.name.clone(),
+                        display_name: model.display_name.clone(),
                         max_tokens: model.max_tokens,
                     }),
                 };
@@ -220,15 +384,24 @@ impl LanguageModelProvider for CloudLanguageModelProvider {
             }
         }
 
+        let disabled_models = &AllLanguageModelSettings::get_global(cx)
+            .zed_dot_dev
+            .disabled_models;
+        models.retain(|id, _| !disabled_models.contains(id));
+
+        let model_catalog = &self.state.read(cx).model_catalog;
         models
             .into_values()
             .map(|model| {
                 Arc::new(CloudLanguageModel {
                     id: LanguageModelId::from(model.id().to_string()),
+                    capabilities: model_capabilities(&model, model_catalog),
                     model,
                     llm_api_token: self.llm_api_token.clone(),
+                    usage_limit_warning: self.usage_limit_warning.clone(),
                     client: self.client.clone(),
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: RateLimiter::new(4).with_configured_max_queue_wait(cx),
+                    in_flight: self.in_flight.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
@@ -243,12 +416,26 @@ impl LanguageModelProvider for CloudLanguageModelProvider {
     }
 
     fn configuration_view(&self, cx: &mut WindowContext) -> AnyView {
+        let models = self.provided_models(cx);
         cx.new_view(|_cx| ConfigurationView {
             state: self.state.clone(),
+            models,
         })
         .into()
     }
 
+    fn usage_warning(&self, _cx: &AppContext) -> Option<SharedString> {
+        let warning = (*self.usage_limit_warning.0.lock())?;
+        let percent_used = warning.used * 100 / warning.limit.max(1);
+        Some(
+            format!(
+                "You've used {percent_used}% of your limit of {} {}.",
+                warning.limit, warning.scope
+            )
+            .into(),
+        )
+    }
+
     fn must_accept_terms(&self, cx: &AppContext) -> bool {
         !self.state.read(cx).has_accepted_terms_of_service(cx)
     }
@@ -303,60 +490,254 @@ impl LanguageModelProvider for CloudLanguageModelProvider {
     fn reset_credentials(&self, _cx: &mut AppContext) -> Task<Result<()>> {
         Task::ready(Ok(()))
     }
+
+    fn cancel_all(&self, _cx: &mut AppContext) {
+        self.in_flight.abort_all();
+    }
 }
 
 pub struct CloudLanguageModel {
     id: LanguageModelId,
     model: CloudModel,
+    capabilities: LanguageModelCapabilities,
     llm_api_token: LlmApiToken,
+    usage_limit_warning: LastUsageLimitWarning,
     client: Arc<Client>,
     request_limiter: RateLimiter,
+    in_flight: InFlightCompletions,
 }
 
 #[derive(Clone, Default)]
 struct LlmApiToken(Arc<RwLock<Option<String>>>);
 
+/// The usage limit warning that the server sent with the latest completion,
+/// if any, which is cleared once a completion comes back without one.
+#[derive(Clone, Default)]
+struct LastUsageLimitWarning(Arc<Mutex<Option<UsageLimitWarning>>>);
+
+/// How [`CloudLanguageModel::perform_llm_completion`] sends its request.
+struct CompletionRequestOptions {
+    compress: bool,
+    retry: Backoff,
+    executor: BackgroundExecutor,
+    /// Where to keep the usage limit warning the server responds with.
+    usage_limit_warning: LastUsageLimitWarning,
+}
+
+impl CompletionRequestOptions {
+    fn read(usage_limit_warning: &LastUsageLimitWarning, cx: &AsyncAppContext) -> Self {
+        let (compress, retry) = cx
+            .update(|cx| {
+                let settings = &AllLanguageModelSettings::get_global(cx).zed_dot_dev;
+                (settings.compress_requests, settings.retry)
+            })
+            .unwrap_or_default();
+        Self {
+            compress,
+            retry,
+            executor: cx.background_executor().clone(),
+            usage_limit_warning: usage_limit_warning.clone(),
+        }
+    }
+}
+
 impl CloudLanguageModel {
+    /// Sends a completion request to the server. The LLM token is refreshed
+    /// once if it has expired, and independently of that, the request is
+    /// retried with backoff while the server fails with a transient error.
     async fn perform_llm_completion(
         client: Arc<Client>,
         llm_api_token: LlmApiToken,
         body: PerformCompletionParams,
+        options: CompletionRequestOptions,
     ) -> Result<Response<AsyncBody>> {
         let http_client = &client.http_client();
 
-        let mut token = llm_api_token.acquire(&client).await?;
-        let mut did_retry = false;
-
-        let response = loop {
-            let request = http_client::Request::builder()
-                .method(Method::POST)
-                .uri(http_client.build_zed_llm_url("/completion", &[])?.as_ref())
-                .header("Content-Type", "application/json")
-                .header("Authorization", format!("Bearer {token}"))
-                .body(serde_json::to_string(&body)?.into())?;
-            let response = http_client.send(request).await?;
-            if response.status().is_success() {
-                break response;
-            } else if !did_retry
-                && response
+        let token = &Mutex::new(llm_api_token.acquire(&client).await?);
+        let did_refresh_token = &AtomicBool::new(false);
+        let body = &encode_completion_params(&body, options.compress).await?;
+        let (client, llm_api_token) = (&client, &llm_api_token);
+        let compress = options.compress;
+
+        let send = move || async move {
+            loop {
+                let mut request = http_client::Request::builder()
+                    .method(Method::POST)
+                    .uri(
+                        http_client
+                            .build_zed_llm_url("/completion", &[("events", "normalized")])?
+                            .as_ref(),
+                    )
+                    .header("Content-Type", "application/json")
+                    .header("Authorization", format!("Bearer {}", token.lock()));
+                if compress {
+                    request = request.header("Content-Encoding", "gzip");
+                }
+                let request = request.body(body.clone().into())?;
+                let mut response = http_client.send(request).await?;
+                let status = response.status();
+                if status.is_success() {
+                    return Ok(response);
+                } else if response
                     .headers()
                     .get(EXPIRED_LLM_TOKEN_HEADER_NAME)
                     .is_some()
-            {
-                did_retry = true;
-                token = llm_api_token.refresh(&client).await?;
-            } else {
-                break Err(anyhow!(
-                    "cloud language model completion failed with status {}",
-                    response.status()
-                ))?;
+                    && !did_refresh_token.swap(true, SeqCst)
+                {
+                    let refreshed_token = llm_api_token.refresh(client).await?;
+                    *token.lock() = refreshed_token;
+                    continue;
+                }
+
+                let retry_after = retry_after(&response);
+                let mut error_body = String::new();
+                response.body_mut().read_to_string(&mut error_body).await?;
+                return Err(anyhow!(FailedCompletion {
+                    status,
+                    retry_after,
+                    body: error_body,
+                }));
             }
         };
+        let should_retry = |error: &anyhow::Error| match error.downcast_ref::<FailedCompletion>() {
+            Some(failure) if failure.is_transient() => failure
+                .retry_after
+                .map_or(Retry::AfterBackoff, Retry::After),
+            _ => Retry::Never,
+        };
 
+        let response =
+            with_configured_backoff(&options.executor, options.retry, should_retry, send)
+                .await
+                .map_err(|error| match error.downcast::<FailedCompletion>() {
+                    Ok(failure) => failure.into_error(),
+                    Err(error) => error,
+                })?;
+        *options.usage_limit_warning.0.lock() = usage_limit_warning(&response);
         Ok(response)
     }
 }
 
+/// A completion request that the server responded to with an error status.
+#[derive(Debug)]
+struct FailedCompletion {
+    status: StatusCode,
+    /// How long the server asked to wait before retrying.
+    retry_after: Option<Duration>,
+    body: String,
+}
+
+impl FailedCompletion {
+    /// Whether the request may succeed if it's retried. The user's own rate
+    /// limits reset too far in the future to wait for, so they're reported
+    /// straight away.
+    fn is_transient(&self) -> bool {
+        is_transient_status(self.status) && self.rate_limit_error().is_none()
+    }
+
+    fn rate_limit_error(&self) -> Option<LanguageModelError> {
+        if self.status == StatusCode::TOO_MANY_REQUESTS {
+            rate_limit_error(&self.body)
+        } else {
+            None
+        }
+    }
+
+    fn into_error(self) -> anyhow::Error {
+        if let Some(error) = self.rate_limit_error() {
+            anyhow!(error)
+        } else if self.status == StatusCode::TOO_MANY_REQUESTS {
+            anyhow!(
+                "cloud language model completion was rate limited{}: {}",
+                self.retry_after_suffix(),
+                self.body
+            )
+        } else {
+            anyhow!(self)
+        }
+    }
+
+    /// Mentions how long the server asked to wait, for requests that weren't
+    /// retried because it asked for longer than the backoff allows.
+    fn retry_after_suffix(&self) -> String {
+        self.retry_after
+            .map(|retry_after| format!(" (retry after {}s)", retry_after.as_secs()))
+            .unwrap_or_default()
+    }
+}
+
+impl std::fmt::Display for FailedCompletion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "cloud language model completion failed with status {}{}",
+            self.status,
+            self.retry_after_suffix()
+        )
+    }
+}
+
+impl std::error::Error for FailedCompletion {}
+
+/// Serializes a completion request into the body that's sent to the server,
+/// gzipped if `compress` is set.
+pub async fn encode_completion_params(
+    params: &PerformCompletionParams,
+    compress: bool,
+) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(params)?;
+    if !compress {
+        return Ok(body);
+    }
+    let mut compressed_body = Vec::new();
+    GzipEncoder::new(body.as_slice())
+        .read_to_end(&mut compressed_body)
+        .await?;
+    Ok(compressed_body)
+}
+
+/// Whether a request that failed with `status` may succeed if it's retried.
+fn is_transient_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::INTERNAL_SERVER_ERROR
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Reads how long the server asked to wait before retrying, given in seconds.
+fn retry_after(response: &Response<AsyncBody>) -> Option<Duration> {
+    let seconds = response
+        .headers()
+        .get("Retry-After")?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// Parses the warning the server sends when the user is close to one of their
+/// limits.
+fn usage_limit_warning(response: &Response<AsyncBody>) -> Option<UsageLimitWarning> {
+    let header = response.headers().get(USAGE_LIMIT_WARNING_HEADER_NAME)?;
+    serde_json::from_slice(header.as_bytes()).log_err()
+}
+
+/// Parses the body of a `429 Too Many Requests` from the server, which
+/// describes the rate limit that the request exceeded.
+fn rate_limit_error(body: &str) -> Option<LanguageModelError> {
+    let rate_limit = serde_json::from_str::<RateLimitExceeded>(body).ok()?;
+    Some(LanguageModelError::RateLimited {
+        scope: rate_limit.scope,
+        retry_after: Duration::from_secs(rate_limit.reset_after_secs),
+    })
+}
+
 impl LanguageModel for CloudLanguageModel {
     fn id(&self) -> LanguageModelId {
         self.id.clone()
@@ -386,6 +767,14 @@ impl LanguageModel for CloudLanguageModel {
         self.model.max_token_count()
     }
 
+    fn capabilities(&self) -> LanguageModelCapabilities {
+        self.capabilities
+    }
+
+    fn deprecation_date(&self) -> Option<SharedString> {
+        self.model.deprecation_date().map(SharedString::from)
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
@@ -396,7 +785,10 @@ impl LanguageModel for CloudLanguageModel {
             CloudModel::OpenAi(model) => count_open_ai_tokens(request, model, cx),
             CloudModel::Google(model) => {
                 let client = self.client.clone();
-                let request = request.into_google(model.id().into());
+                let request = match request.into_google(model.id().into(), &BTreeMap::default()) {
+                    Ok(request) => request,
+                    Err(error) => return future::ready(Err(error)).boxed(),
+                };
                 let request = google_ai::CountTokensRequest {
                     contents: request.contents,
                 };
@@ -418,17 +810,46 @@ impl LanguageModel for CloudLanguageModel {
         }
     }
 
+    fn count_tokens_batch(
+        &self,
+        requests: Vec<LanguageModelRequest>,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<usize>>> {
+        match self.model.clone() {
+            CloudModel::Anthropic(_) => count_anthropic_tokens_batch(requests, cx),
+            CloudModel::OpenAi(model) => count_open_ai_tokens_batch(requests, model, cx),
+            // Gemini counts each request remotely, so there's nothing to share.
+            CloudModel::Google(_) => {
+                let counts = requests
+                    .into_iter()
+                    .map(|request| self.count_tokens(request, cx))
+                    .collect::<Vec<_>>();
+                futures::future::try_join_all(counts).boxed()
+            }
+            CloudModel::Zed(_) => {
+                count_open_ai_tokens_batch(requests, open_ai::Model::ThreePointFiveTurbo, cx)
+            }
+        }
+    }
+
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
-        _cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        let options = CompletionRequestOptions::read(&self.usage_limit_warning, cx);
+        let conversation_id = request.conversation_id.clone();
+        let experiment = request.experiment.clone();
+        let tags = request.tags.clone();
+        let pin_model_version = request.pin_model_version;
+        let priority = request.priority;
         match &self.model {
             CloudModel::Anthropic(model) => {
                 let request = request.into_anthropic(model.id().into());
                 let client = self.client.clone();
                 let llm_api_token = self.llm_api_token.clone();
-                let future = self.request_limiter.stream(async move {
+                let future = self.request_limiter.stream(priority, async move {
                     let response = Self::perform_llm_completion(
                         client.clone(),
                         llm_api_token,
@@ -438,38 +859,37 @@ impl LanguageModel for CloudLanguageModel {
                             provider_request: RawValue::from_string(serde_json::to_string(
                                 &request,
                             )?)?,
+                            template: None,
+                            variables: Default::default(),
+                            conversation_id,
+                            experiment,
+                            tags,
+                            pin_model_version,
                         },
+                        options,
                     )
                     .await?;
-                    let body = BufReader::new(response.into_body());
-                    let stream = futures::stream::try_unfold(body, move |mut body| async move {
-                        let mut buffer = String::new();
-                        match body.read_line(&mut buffer).await {
-                            Ok(0) => Ok(None),
-                            Ok(_) => {
-                                let event: anthropic::Event = serde_json::from_str(&buffer)
-                                    .context("failed to parse Anthropic event")?;
-                                Ok(Some((event, body)))
-                            }
-                            Err(err) => Err(AnthropicError::Other(err.into())),
-                        }
-                    });
-
-                    Ok(anthropic::extract_text_from_events(stream))
+                    Ok(map_to_language_model_completion_events(response_lines(
+                        response,
+                    )))
                 });
-                async move {
-                    Ok(future
-                        .await?
-                        .map(|result| result.map_err(|err| anyhow!(err)))
-                        .boxed())
-                }
-                .boxed()
+                interceptors.intercept(
+                    self.in_flight
+                        .track(async move { Ok(future.await?.boxed()) }.boxed()),
+                )
             }
             CloudModel::OpenAi(model) => {
                 let client = self.client.clone();
-                let request = request.into_open_ai(model.id().into());
+                let mut request = match request.into_open_ai(model.id().into(), None) {
+                    Ok(request) => request,
+                    Err(error) => return interceptors.intercept(future::ready(Err(error)).boxed()),
+                };
+                // The request is forwarded to OpenAI itself, which reports usage.
+                request.stream_options = Some(open_ai::StreamOptions {
+                    include_usage: true,
+                });
                 let llm_api_token = self.llm_api_token.clone();
-                let future = self.request_limiter.stream(async move {
+                let future = self.request_limiter.stream(priority, async move {
                     let response = Self::perform_llm_completion(
                         client.clone(),
                         llm_api_token,
@@ -479,32 +899,33 @@ impl LanguageModel for CloudLanguageModel {
                             provider_request: RawValue::from_string(serde_json::to_string(
                                 &request,
                             )?)?,
+                            template: None,
+                            variables: Default::default(),
+                            conversation_id,
+                            experiment,
+                            tags,
+                            pin_model_version,
                         },
+                        options,
                     )
                     .await?;
-                    let body = BufReader::new(response.into_body());
-                    let stream = futures::stream::try_unfold(body, move |mut body| async move {
-                        let mut buffer = String::new();
-                        match body.read_line(&mut buffer).await {
-                            Ok(0) => Ok(None),
-                            Ok(_) => {
-                                let event: open_ai::ResponseStreamEvent =
-                                    serde_json::from_str(&buffer)?;
-                                Ok(Some((event, body)))
-                            }
-                            Err(e) => Err(e.into()),
-                        }
-                    });
-
-                    Ok(open_ai::extract_text_from_events(stream))
+                    Ok(map_to_language_model_completion_events(response_lines(
+                        response,
+                    )))
                 });
-                async move { Ok(future.await?.boxed()) }.boxed()
+                interceptors.intercept(
+                    self.in_flight
+                        .track(async move { Ok(future.await?.boxed()) }.boxed()),
+                )
             }
             CloudModel::Google(model) => {
                 let client = self.client.clone();
-                let request = request.into_google(model.id().into());
+                let request = match request.into_google(model.id().into(), &BTreeMap::default()) {
+                    Ok(request) => request,
+                    Err(error) => return interceptors.intercept(future::ready(Err(error)).boxed()),
+                };
                 let llm_api_token = self.llm_api_token.clone();
-                let future = self.request_limiter.stream(async move {
+                let future = self.request_limiter.stream(priority, async move {
                     let response = Self::perform_llm_completion(
                         client.clone(),
                         llm_api_token,
@@ -514,33 +935,33 @@ impl LanguageModel for CloudLanguageModel {
                             provider_request: RawValue::from_string(serde_json::to_string(
                                 &request,
                             )?)?,
+                            template: None,
+                            variables: Default::default(),
+                            conversation_id,
+                            experiment,
+                            tags,
+                            pin_model_version,
                         },
+                        options,
                     )
                     .await?;
-                    let body = BufReader::new(response.into_body());
-                    let stream = futures::stream::try_unfold(body, move |mut body| async move {
-                        let mut buffer = String::new();
-                        match body.read_line(&mut buffer).await {
-                            Ok(0) => Ok(None),
-                            Ok(_) => {
-                                let event: google_ai::GenerateContentResponse =
-                                    serde_json::from_str(&buffer)?;
-                                Ok(Some((event, body)))
-                            }
-                            Err(e) => Err(e.into()),
-                        }
-                    });
-
-                    Ok(google_ai::extract_text_from_events(stream))
+                    Ok(map_to_language_model_completion_events(response_lines(
+                        response,
+                    )))
                 });
-                async move { Ok(future.await?.boxed()) }.boxed()
+                interceptors.intercept(
+                    self.in_flight
+                        .track(async move { Ok(future.await?.boxed()) }.boxed()),
+                )
             }
             CloudModel::Zed(model) => {
                 let client = self.client.clone();
-                let mut request = request.into_open_ai(model.id().into());
-                request.max_tokens = Some(4000);
+                let request = match request.into_open_ai(model.id().into(), Some(4000)) {
+                    Ok(request) => request,
+                    Err(error) => return interceptors.intercept(future::ready(Err(error)).boxed()),
+                };
                 let llm_api_token = self.llm_api_token.clone();
-                let future = self.request_limiter.stream(async move {
+                let future = self.request_limiter.stream(priority, async move {
                     let response = Self::perform_llm_completion(
                         client.clone(),
                         llm_api_token,
@@ -550,26 +971,24 @@ impl LanguageModel for CloudLanguageModel {
                             provider_request: RawValue::from_string(serde_json::to_string(
                                 &request,
                             )?)?,
+                            template: None,
+                            variables: Default::default(),
+                            conversation_id,
+                            experiment,
+                            tags,
+                            pin_model_version,
                         },
+                        options,
                     )
                     .await?;
-                    let body = BufReader::new(response.into_body());
-                    let stream = futures::stream::try_unfold(body, move |mut body| async move {
-                        let mut buffer = String::new();
-                        match body.read_line(&mut buffer).await {
-                            Ok(0) => Ok(None),
-                            Ok(_) => {
-                                let event: open_ai::ResponseStreamEvent =
-                                    serde_json::from_str(&buffer)?;
-                                Ok(Some((event, body)))
-                            }
-                            Err(e) => Err(e.into()),
-                        }
-                    });
-
-                    Ok(open_ai::extract_text_from_events(stream))
+                    Ok(map_to_language_model_completion_events(response_lines(
+                        response,
+                    )))
                 });
-                async move { Ok(future.await?.boxed()) }.boxed()
+                interceptors.intercept(
+                    self.in_flight
+                        .track(async move { Ok(future.await?.boxed()) }.boxed()),
+                )
             }
         }
     }
@@ -580,9 +999,16 @@ impl LanguageModel for CloudLanguageModel {
         tool_name: String,
         tool_description: String,
         input_schema: serde_json::Value,
-        _cx: &AsyncAppContext,
+        cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<serde_json::Value>> {
-        match &self.model {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        let options = CompletionRequestOptions::read(&self.usage_limit_warning, cx);
+        let conversation_id = request.conversation_id.clone();
+        let experiment = request.experiment.clone();
+        let tags = request.tags.clone();
+        let pin_model_version = request.pin_model_version;
+        let priority = request.priority;
+        let tool_use = match &self.model {
             CloudModel::Anthropic(model) => {
                 let client = self.client.clone();
                 let mut request = request.into_anthropic(model.tool_model_id().into());
@@ -592,12 +1018,12 @@ impl LanguageModel for CloudLanguageModel {
                 request.tools = vec![anthropic::Tool {
                     name: tool_name.clone(),
                     description: tool_description,
-                    input_schema,
+                    input_schema: input_schema.clone(),
                 }];
 
                 let llm_api_token = self.llm_api_token.clone();
                 self.request_limiter
-                    .run(async move {
+                    .run(priority, async move {
                         let response = Self::perform_llm_completion(
                             client.clone(),
                             llm_api_token,
@@ -607,58 +1033,28 @@ impl LanguageModel for CloudLanguageModel {
                                 provider_request: RawValue::from_string(serde_json::to_string(
                                     &request,
                                 )?)?,
+                                template: None,
+                                variables: Default::default(),
+                                conversation_id,
+                                experiment,
+                                tags,
+                                pin_model_version,
                             },
+                            options,
                         )
                         .await?;
 
-                        let mut tool_use_index = None;
-                        let mut tool_input = String::new();
-                        let mut body = BufReader::new(response.into_body());
-                        let mut line = String::new();
-                        while body.read_line(&mut line).await? > 0 {
-                            let event: anthropic::Event = serde_json::from_str(&line)?;
-                            line.clear();
-
-                            match event {
-                                anthropic::Event::ContentBlockStart {
-                                    content_block,
-                                    index,
-                                } => {
-                                    if let anthropic::Content::ToolUse { name, .. } = content_block
-                                    {
-                                        if name == tool_name {
-                                            tool_use_index = Some(index);
-                                        }
-                                    }
-                                }
-                                anthropic::Event::ContentBlockDelta { index, delta } => match delta
-                                {
-                                    anthropic::ContentDelta::TextDelta { .. } => {}
-                                    anthropic::ContentDelta::InputJsonDelta { partial_json } => {
-                                        if Some(index) == tool_use_index {
-                                            tool_input.push_str(&partial_json);
-                                        }
-                                    }
-                                },
-                                anthropic::Event::ContentBlockStop { index } => {
-                                    if Some(index) == tool_use_index {
-                                        return Ok(serde_json::from_str(&tool_input)?);
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
-
-                        if tool_use_index.is_some() {
-                            Err(anyhow!("tool content incomplete"))
-                        } else {
-                            Err(anyhow!("tool not used"))
-                        }
+                        stream_tool_input(response_lines(response), &tool_name, input_schema).await
                     })
                     .boxed()
             }
             CloudModel::OpenAi(model) => {
-                let mut request = request.into_open_ai(model.id().into());
+                let mut request = match request.into_open_ai(model.id().into(), None) {
+                    Ok(request) => request,
+                    Err(error) => {
+                        return interceptors.intercept_tool_use(future::ready(Err(error)).boxed())
+                    }
+                };
                 let client = self.client.clone();
                 let mut function = open_ai::FunctionDefinition {
                     name: tool_name.clone(),
@@ -671,12 +1067,12 @@ impl LanguageModel for CloudLanguageModel {
                 request.tool_choice = Some(open_ai::ToolChoice::Other(func.clone()));
                 // Fill in description and params separately, as they're not needed for tool_choice field.
                 function.description = Some(tool_description);
-                function.parameters = Some(input_schema);
+                function.parameters = Some(input_schema.clone());
                 request.tools = vec![open_ai::ToolDefinition::Function { function }];
 
                 let llm_api_token = self.llm_api_token.clone();
                 self.request_limiter
-                    .run(async move {
+                    .run(priority, async move {
                         let response = Self::perform_llm_completion(
                             client.clone(),
                             llm_api_token,
@@ -686,54 +1082,72 @@ impl LanguageModel for CloudLanguageModel {
                                 provider_request: RawValue::from_string(serde_json::to_string(
                                     &request,
                                 )?)?,
+                                template: None,
+                                variables: Default::default(),
+                                conversation_id,
+                                experiment,
+                                tags,
+                                pin_model_version,
                             },
+                            options,
                         )
                         .await?;
 
-                        let mut body = BufReader::new(response.into_body());
-                        let mut line = String::new();
-                        let mut load_state = None;
-
-                        while body.read_line(&mut line).await? > 0 {
-                            let part: open_ai::ResponseStreamEvent = serde_json::from_str(&line)?;
-                            line.clear();
-
-                            for choice in part.choices {
-                                let Some(tool_calls) = choice.delta.tool_calls else {
-                                    continue;
-                                };
-
-                                for call in tool_calls {
-                                    if let Some(func) = call.function {
-                                        if func.name.as_deref() == Some(tool_name.as_str()) {
-                                            load_state = Some((String::default(), call.index));
-                                        }
-                                        if let Some((arguments, (output, index))) =
-                                            func.arguments.zip(load_state.as_mut())
-                                        {
-                                            if call.index == *index {
-                                                output.push_str(&arguments);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-
-                        if let Some((arguments, _)) = load_state {
-                            return Ok(serde_json::from_str(&arguments)?);
-                        } else {
-                            bail!("tool not used");
-                        }
+                        stream_tool_input(response_lines(response), &tool_name, input_schema).await
                     })
                     .boxed()
             }
-            CloudModel::Google(_) => {
-                future::ready(Err(anyhow!("tool use not implemented for Google AI"))).boxed()
+            CloudModel::Google(model) => {
+                let client = self.client.clone();
+                let request = match request.into_google(model.id().into(), &BTreeMap::default()) {
+                    Ok(request) => request,
+                    Err(error) => {
+                        return interceptors.intercept_tool_use(future::ready(Err(error)).boxed())
+                    }
+                };
+                let mut parameters = input_schema.clone();
+                remove_unsupported_gemini_schema_keys(&mut parameters);
+                let request = request.with_function(google_ai::FunctionDeclaration {
+                    name: tool_name.clone(),
+                    description: tool_description,
+                    parameters,
+                });
+
+                let llm_api_token = self.llm_api_token.clone();
+                self.request_limiter
+                    .run(priority, async move {
+                        let response = Self::perform_llm_completion(
+                            client.clone(),
+                            llm_api_token,
+                            PerformCompletionParams {
+                                provider: client::LanguageModelProvider::Google,
+                                model: request.model.clone(),
+                                provider_request: RawValue::from_string(serde_json::to_string(
+                                    &request,
+                                )?)?,
+                                template: None,
+                                variables: Default::default(),
+                                conversation_id,
+                                experiment,
+                                tags,
+                                pin_model_version,
+                            },
+                            options,
+                        )
+                        .await?;
+
+                        stream_tool_input(response_lines(response), &tool_name, input_schema).await
+                    })
+                    .boxed()
             }
             CloudModel::Zed(model) => {
                 // All Zed models are OpenAI-based at the time of writing.
-                let mut request = request.into_open_ai(model.id().into());
+                let mut request = match request.into_open_ai(model.id().into(), None) {
+                    Ok(request) => request,
+                    Err(error) => {
+                        return interceptors.intercept_tool_use(future::ready(Err(error)).boxed())
+                    }
+                };
                 let client = self.client.clone();
                 let mut function = open_ai::FunctionDefinition {
                     name: tool_name.clone(),
@@ -746,12 +1160,12 @@ impl LanguageModel for CloudLanguageModel {
                 request.tool_choice = Some(open_ai::ToolChoice::Other(func.clone()));
                 // Fill in description and params separately, as they're not needed for tool_choice field.
                 function.description = Some(tool_description);
-                function.parameters = Some(input_schema);
+                function.parameters = Some(input_schema.clone());
                 request.tools = vec![open_ai::ToolDefinition::Function { function }];
 
                 let llm_api_token = self.llm_api_token.clone();
                 self.request_limiter
-                    .run(async move {
+                    .run(priority, async move {
                         let response = Self::perform_llm_completion(
                             client.clone(),
                             llm_api_token,
@@ -761,48 +1175,23 @@ impl LanguageModel for CloudLanguageModel {
                                 provider_request: RawValue::from_string(serde_json::to_string(
                                     &request,
                                 )?)?,
+                                template: None,
+                                variables: Default::default(),
+                                conversation_id,
+                                experiment,
+                                tags,
+                                pin_model_version,
                             },
+                            options,
                         )
                         .await?;
 
-                        let mut body = BufReader::new(response.into_body());
-                        let mut line = String::new();
-                        let mut load_state = None;
-
-                        while body.read_line(&mut line).await? > 0 {
-                            let part: open_ai::ResponseStreamEvent = serde_json::from_str(&line)?;
-                            line.clear();
-
-                            for choice in part.choices {
-                                let Some(tool_calls) = choice.delta.tool_calls else {
-                                    continue;
-                                };
-
-                                for call in tool_calls {
-                                    if let Some(func) = call.function {
-                                        if func.name.as_deref() == Some(tool_name.as_str()) {
-                                            load_state = Some((String::default(), call.index));
-                                        }
-                                        if let Some((arguments, (output, index))) =
-                                            func.arguments.zip(load_state.as_mut())
-                                        {
-                                            if call.index == *index {
-                                                output.push_str(&arguments);
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        if let Some((arguments, _)) = load_state {
-                            return Ok(serde_json::from_str(&arguments)?);
-                        } else {
-                            bail!("tool not used");
-                        }
+                        stream_tool_input(response_lines(response), &tool_name, input_schema).await
                     })
                     .boxed()
             }
-        }
+        };
+        interceptors.intercept_tool_use(self.in_flight.track_future(tool_use))
     }
 }
 
@@ -830,8 +1219,209 @@ impl LlmApiToken {
     }
 }
 
+/// Fetches the models the server serves, along with their capabilities.
+async fn fetch_model_catalog(
+    client: Arc<Client>,
+    llm_api_token: LlmApiToken,
+) -> Result<Vec<LanguageModelCatalogEntry>> {
+    let http_client = &client.http_client();
+    let mut token = llm_api_token.acquire(&client).await?;
+    let mut did_retry = false;
+
+    let mut response = loop {
+        let request = http_client::Request::builder()
+            .method(Method::GET)
+            .uri(http_client.build_zed_llm_url("/models", &[])?.as_ref())
+            .header("Authorization", format!("Bearer {token}"))
+            .body(AsyncBody::empty())?;
+        let response = http_client.send(request).await?;
+        if response.status().is_success() {
+            break response;
+        } else if !did_retry
+            && response
+                .headers()
+                .get(EXPIRED_LLM_TOKEN_HEADER_NAME)
+                .is_some()
+        {
+            did_retry = true;
+            token = llm_api_token.refresh(&client).await?;
+        } else {
+            bail!("failed to fetch models with status {}", response.status());
+        }
+    };
+
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
+    let response: GetLanguageModelsResponse = serde_json::from_str(&body)?;
+    Ok(response.models)
+}
+
+/// Returns the capabilities of the given model, preferring the ones the server
+/// reports over the built-in defaults. The server's catalog names models
+/// without their version suffix, so the longest name that prefixes the model's
+/// id is used.
+fn model_capabilities(
+    model: &CloudModel,
+    model_catalog: &[LanguageModelCatalogEntry],
+) -> LanguageModelCapabilities {
+    let provider = match model {
+        CloudModel::Anthropic(_) => client::LanguageModelProvider::Anthropic,
+        CloudModel::OpenAi(_) => client::LanguageModelProvider::OpenAi,
+        CloudModel::Google(_) => client::LanguageModelProvider::Google,
+        CloudModel::Zed(_) => client::LanguageModelProvider::Zed,
+    };
+    let entry = model_catalog
+        .iter()
+        .filter(|entry| entry.provider == provider && model.id().starts_with(&entry.name))
+        .max_by_key(|entry| entry.name.len());
+    match entry {
+        Some(entry) => model.capabilities().with_overrides(&entry.capabilities),
+        None => model.capabilities(),
+    }
+}
+
+/// Maps the normalized events the server streams, whichever provider served
+/// the completion, into completion events.
+fn map_to_language_model_completion_events(
+    events: impl Stream<Item = Result<CompletionEvent>>,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    events.filter_map(|event| {
+        future::ready(match event {
+            Ok(CompletionEvent::Model { model }) => {
+                Some(Ok(LanguageModelCompletionEvent::ReportedModel(model)))
+            }
+            Ok(CompletionEvent::Text { text }) => {
+                Some(Ok(LanguageModelCompletionEvent::Text(text)))
+            }
+            Ok(CompletionEvent::Image { mime_type, data }) => {
+                Some(Ok(LanguageModelCompletionEvent::Image { mime_type, data }))
+            }
+            Ok(CompletionEvent::ToolUse { .. }) => None,
+            Ok(CompletionEvent::Usage {
+                input_tokens,
+                output_tokens,
+            }) => Some(Ok(LanguageModelCompletionEvent::UsageUpdate {
+                input_tokens,
+                output_tokens,
+            })),
+            Ok(CompletionEvent::Stop {
+                reason,
+                stop_sequence,
+            }) => {
+                let stop_reason = match (reason.as_str(), stop_sequence) {
+                    ("end_turn", _) => Some(StopReason::EndTurn),
+                    ("max_tokens", _) => Some(StopReason::MaxTokens),
+                    ("stop_sequence", Some(stop_sequence)) => {
+                        Some(StopReason::StopSequence(stop_sequence))
+                    }
+                    ("tool_use", _) => Some(StopReason::ToolUse),
+                    _ => None,
+                };
+                stop_reason.map(|reason| Ok(LanguageModelCompletionEvent::Stop(reason)))
+            }
+            Err(error) => Some(Err(error)),
+        })
+    })
+}
+
+/// Returns the input of the model's call to `tool_name`, checking it against
+/// `input_schema` as it streams in, so that the stream is abandoned as soon as
+/// the input can no longer conform to it.
+async fn stream_tool_input(
+    events: impl Stream<Item = Result<CompletionEvent>>,
+    tool_name: &str,
+    input_schema: serde_json::Value,
+) -> Result<serde_json::Value> {
+    let mut schema_validator = StreamingSchemaValidator::new(input_schema);
+    let mut tool_use_index = None;
+    let mut tool_input = String::new();
+    futures::pin_mut!(events);
+    while let Some(event) = events.next().await {
+        let CompletionEvent::ToolUse {
+            index,
+            name,
+            input_json,
+            ..
+        } = event?
+        else {
+            continue;
+        };
+        match name {
+            Some(name) if name != tool_name => continue,
+            Some(_) if tool_use_index.is_none() => tool_use_index = Some(index),
+            _ if tool_use_index != Some(index) => continue,
+            Some(_) if !tool_input.is_empty() => {
+                // The input was sent as several objects, so merge this one into
+                // the earlier ones, concatenating any arrays they both have.
+                // Later objects may still add required properties, so the
+                // merged input is only checked against what it has so far.
+                let mut input = serde_json::from_str(&tool_input)?;
+                super::google::merge_json(&mut input, serde_json::from_str(&input_json)?);
+                schema_validator.validate_partial(&input)?;
+                tool_input = input.to_string();
+                continue;
+            }
+            _ => {}
+        }
+        schema_validator.push(&input_json)?;
+        tool_input.push_str(&input_json);
+    }
+
+    if tool_use_index.is_none() {
+        bail!("tool not used");
+    } else if tool_input.is_empty() {
+        Ok(serde_json::Value::Object(Default::default()))
+    } else {
+        Ok(serde_json::from_str(&tool_input)?)
+    }
+}
+
+/// Parses the newline-delimited events of a completion response, skipping the
+/// empty lines the server sends as heartbeats while the upstream is idle.
+fn response_lines<T: DeserializeOwned + Send + 'static>(
+    response: Response<AsyncBody>,
+) -> BoxStream<'static, Result<T>> {
+    let body = BufReader::new(response.into_body());
+    futures::stream::try_unfold(body, move |mut body| async move {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if body.read_line(&mut line).await? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                if let Ok(truncated) = serde_json::from_str::<CompletionTruncated>(&line) {
+                    return Err(anyhow!(
+                        "The response was cut short because the maximum {} was reached.",
+                        truncated.truncated_by
+                    ));
+                }
+                let event = serde_json::from_str(&line)?;
+                return Ok(Some((event, body)));
+            }
+        }
+    })
+    .boxed()
+}
+
 struct ConfigurationView {
     state: gpui::Model<State>,
+    models: Vec<Arc<dyn LanguageModel>>,
+}
+
+/// Explains why a model with the given availability can't be used on `plan`.
+fn plan_requirement_label(
+    availability: LanguageModelAvailability,
+    plan: Option<proto::Plan>,
+) -> Option<&'static str> {
+    match availability {
+        LanguageModelAvailability::RequiresPlan(proto::Plan::ZedPro)
+            if plan != Some(proto::Plan::ZedPro) =>
+        {
+            Some("Requires Zed Pro")
+        }
+        LanguageModelAvailability::Public | LanguageModelAvailability::RequiresPlan(_) => None,
+    }
 }
 
 impl ConfigurationView {
@@ -869,6 +1459,24 @@ impl Render for ConfigurationView {
                     } else {
                         "You have basic access to models from Anthropic, OpenAI, Google and more through the Zed AI Free plan."
                     }))
+                .child(
+                    v_flex()
+                        .gap_1()
+                        .children(self.models.iter().map(|model| {
+                            h_flex()
+                                .gap_2()
+                                .child(Label::new(model.name().0.clone()))
+                                .children(
+                                    plan_requirement_label(model.availability(), plan).map(
+                                        |requirement| {
+                                            Label::new(requirement)
+                                                .size(LabelSize::XSmall)
+                                                .color(Color::Muted)
+                                        },
+                                    ),
+                                )
+                        })),
+                )
                 .child(
                     if is_pro {
                         h_flex().child(
@@ -922,3 +1530,465 @@ impl Render for ConfigurationView {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use client::RateLimitScope;
+
+    #[test]
+    fn test_rate_limit_error() {
+        let error =
+            rate_limit_error(r#"{"scope":"tokens-minute","limit":10000,"reset_after_secs":5}"#)
+                .unwrap();
+        assert_eq!(
+            error,
+            LanguageModelError::RateLimited {
+                scope: RateLimitScope::TokensPerMinute,
+                retry_after: Duration::from_secs(5),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "You've exceeded your limit of tokens per minute. Try again in 5 seconds."
+        );
+
+        let error =
+            rate_limit_error(r#"{"scope":"tokens-day","limit":100000,"reset_after_secs":1800}"#)
+                .unwrap();
+        assert_eq!(
+            error.to_string(),
+            "You've exceeded your limit of tokens per day. Try again in 30 minutes."
+        );
+
+        // Responses from servers that predate structured rate limits.
+        assert_eq!(
+            rate_limit_error("Rate limit exceeded. Maximum requests per minute reached."),
+            None
+        );
+    }
+
+    /// Sends a completion request to a server that responds with each of
+    /// `responses` in turn, returning the result and how many requests it saw.
+    async fn perform_completion_against(
+        responses: Vec<(u16, Vec<(&'static str, &'static str)>, &'static str)>,
+        cx: &mut gpui::TestAppContext,
+    ) -> (
+        Result<Response<AsyncBody>>,
+        usize,
+        Option<UsageLimitWarning>,
+    ) {
+        use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let http_client = http_client::FakeHttpClient::create({
+            let attempts = attempts.clone();
+            move |_| {
+                let attempt = attempts.fetch_add(1, SeqCst);
+                let (status, headers, body) = responses[attempt.min(responses.len() - 1)].clone();
+                async move {
+                    let mut response = Response::builder().status(status);
+                    for (name, value) in headers {
+                        response = response.header(name, value);
+                    }
+                    Ok(response.body(AsyncBody::from(body)).unwrap())
+                }
+            }
+        });
+        let client = cx.update(|cx| {
+            cx.set_global(SettingsStore::test(cx));
+            Client::new(Arc::new(clock::FakeSystemClock::default()), http_client, cx)
+        });
+        let options = CompletionRequestOptions {
+            compress: false,
+            retry: Backoff {
+                max_retries: 2,
+                base_delay: Duration::from_secs(1),
+                max_jitter: Duration::ZERO,
+                max_retry_after: Duration::from_secs(60),
+            },
+            executor: cx.executor(),
+            usage_limit_warning: LastUsageLimitWarning::default(),
+        };
+        let usage_limit_warning = options.usage_limit_warning.clone();
+        let params = PerformCompletionParams {
+            provider: client::LanguageModelProvider::Anthropic,
+            model: "claude-3-5-sonnet".into(),
+            provider_request: RawValue::from_string("{}".into()).unwrap(),
+            template: None,
+            variables: Default::default(),
+            conversation_id: None,
+            experiment: None,
+            tags: Default::default(),
+            pin_model_version: false,
+        };
+        let llm_api_token = LlmApiToken(Arc::new(RwLock::new(Some("token".into()))));
+        let task = cx
+            .executor()
+            .spawn(CloudLanguageModel::perform_llm_completion(
+                client,
+                llm_api_token,
+                params,
+                options,
+            ));
+        for _ in 0..10 {
+            cx.executor().advance_clock(Duration::from_secs(10));
+            cx.run_until_parked();
+        }
+        let response = task.await;
+        let usage_limit_warning = *usage_limit_warning.0.lock();
+        (response, attempts.load(SeqCst), usage_limit_warning)
+    }
+
+    #[gpui::test]
+    async fn test_transient_errors_are_retried(cx: &mut gpui::TestAppContext) {
+        let (response, attempts, _) = perform_completion_against(
+            vec![
+                (503, Vec::new(), ""),
+                (429, vec![("Retry-After", "3")], "overloaded"),
+                (200, Vec::new(), ""),
+            ],
+            cx,
+        )
+        .await;
+        assert!(response.unwrap().status().is_success());
+        assert_eq!(attempts, 3);
+    }
+
+    #[gpui::test]
+    async fn test_retries_are_limited(cx: &mut gpui::TestAppContext) {
+        let (response, attempts, _) =
+            perform_completion_against(vec![(502, Vec::new(), "")], cx).await;
+        assert_eq!(
+            response.err().expect("completion should fail").to_string(),
+            "cloud language model completion failed with status 502 Bad Gateway"
+        );
+        assert_eq!(attempts, 3);
+
+        // Errors that won't go away on their own aren't retried.
+        let (response, attempts, _) =
+            perform_completion_against(vec![(400, Vec::new(), "")], cx).await;
+        assert!(response.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[gpui::test]
+    async fn test_long_retry_after_fails_fast(cx: &mut gpui::TestAppContext) {
+        let (response, attempts, _) =
+            perform_completion_against(vec![(503, vec![("Retry-After", "3600")], "")], cx).await;
+        assert_eq!(
+            response.err().expect("completion should fail").to_string(),
+            "cloud language model completion failed with status 503 Service Unavailable \
+             (retry after 3600s)"
+        );
+        assert_eq!(attempts, 1);
+    }
+
+    #[gpui::test]
+    async fn test_user_rate_limits_are_not_retried(cx: &mut gpui::TestAppContext) {
+        let (response, attempts, _) = perform_completion_against(
+            vec![(
+                429,
+                Vec::new(),
+                r#"{"scope":"tokens-day","limit":100000,"reset_after_secs":1800}"#,
+            )],
+            cx,
+        )
+        .await;
+        assert!(matches!(
+            response
+                .err()
+                .expect("completion should fail")
+                .downcast_ref::<LanguageModelError>(),
+            Some(LanguageModelError::RateLimited { .. })
+        ));
+        assert_eq!(attempts, 1);
+    }
+
+    #[gpui::test]
+    async fn test_usage_limit_warning_is_kept(cx: &mut gpui::TestAppContext) {
+        let (response, _, usage_limit_warning) = perform_completion_against(
+            vec![(
+                200,
+                vec![(
+                    USAGE_LIMIT_WARNING_HEADER_NAME,
+                    r#"{"scope":"tokens-day","limit":100000,"used":85000}"#,
+                )],
+                "",
+            )],
+            cx,
+        )
+        .await;
+        assert!(response.unwrap().status().is_success());
+        assert_eq!(
+            usage_limit_warning,
+            Some(UsageLimitWarning {
+                scope: RateLimitScope::TokensPerDay,
+                limit: 100000,
+                used: 85000,
+            })
+        );
+
+        let (_, _, usage_limit_warning) =
+            perform_completion_against(vec![(200, Vec::new(), "")], cx).await;
+        assert_eq!(usage_limit_warning, None);
+    }
+
+    #[gpui::test]
+    async fn test_response_lines_skip_heartbeats() {
+        let body = "\n{\"text\":\"one\"}\n\n\n{\"text\":\"two\"}\n\n";
+        let response = Response::builder().body(AsyncBody::from(body)).unwrap();
+        let events = response_lines::<serde_json::Value>(response)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            [
+                serde_json::json!({ "text": "one" }),
+                serde_json::json!({ "text": "two" })
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_normalized_completion_events() {
+        let body = [
+            r#"{"type":"model","model":"claude-3-5-sonnet-20240620"}"#,
+            r#"{"type":"text","text":"Hello"}"#,
+            r#"{"type":"tool_use","index":1,"name":"search","input_json":""}"#,
+            r#"{"type":"usage","input_tokens":12,"output_tokens":3}"#,
+            r#"{"type":"stop","reason":"stop_sequence","stop_sequence":"\n\n"}"#,
+        ]
+        .join("\n");
+        let response = Response::builder().body(AsyncBody::from(body)).unwrap();
+        let events = map_to_language_model_completion_events(response_lines(response))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::ReportedModel("claude-3-5-sonnet-20240620".into()),
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::UsageUpdate {
+                    input_tokens: 12,
+                    output_tokens: 3
+                },
+                LanguageModelCompletionEvent::Stop(StopReason::StopSequence("\n\n".into())),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_stream_tool_input() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string" },
+                "limit": { "type": "integer" },
+                "tags": { "type": "array", "items": { "type": "string" } }
+            }
+        });
+        let tool_use = |index, name: Option<&str>, input_json: &str| {
+            Ok(CompletionEvent::ToolUse {
+                index,
+                id: None,
+                name: name.map(Into::into),
+                input_json: input_json.into(),
+            })
+        };
+
+        // Input streamed as JSON text, alongside a call to another tool.
+        let events = futures::stream::iter([
+            tool_use(0, Some("other"), "{}"),
+            tool_use(1, Some("search"), ""),
+            tool_use(1, None, r#"{"query": "#),
+            tool_use(0, None, "ignored"),
+            tool_use(1, None, r#""rust"}"#),
+            tool_use(2, Some("search"), r#"{"query": "second call"}"#),
+        ]);
+        assert_eq!(
+            stream_tool_input(events, "search", schema.clone())
+                .await
+                .unwrap(),
+            serde_json::json!({ "query": "rust" })
+        );
+
+        // Input sent as several objects, as Google does.
+        let events = futures::stream::iter([
+            tool_use(0, Some("search"), r#"{"query":"rust","tags":["lang"]}"#),
+            tool_use(0, Some("search"), r#"{"limit":5,"tags":["systems"]}"#),
+        ]);
+        assert_eq!(
+            stream_tool_input(events, "search", schema.clone())
+                .await
+                .unwrap(),
+            serde_json::json!({ "query": "rust", "limit": 5, "tags": ["lang", "systems"] })
+        );
+
+        // Merged input is checked against the schema too.
+        let events = futures::stream::iter([
+            tool_use(0, Some("search"), r#"{"query":"rust","tags":["lang"]}"#),
+            tool_use(0, Some("search"), r#"{"tags":[5]}"#),
+        ]);
+        assert_eq!(
+            stream_tool_input(events, "search", schema.clone())
+                .await
+                .unwrap_err()
+                .to_string(),
+            "output does not match the schema: expected string at $.tags[1], found number"
+        );
+
+        let events = futures::stream::iter([Ok(CompletionEvent::Text {
+            text: "No tools needed.".into(),
+        })]);
+        assert_eq!(
+            stream_tool_input(events, "search", schema)
+                .await
+                .unwrap_err()
+                .to_string(),
+            "tool not used"
+        );
+    }
+
+    #[test]
+    fn test_resume_session_while_reconnecting() {
+        use client::{ConnectionId, Status};
+
+        let connection_id = ConnectionId { owner_id: 0, id: 0 };
+        let connected = Status::Connected {
+            peer_id: connection_id.into(),
+            connection_id,
+        };
+
+        // The user stays signed in while the client takes a while to reconnect...
+        let mut sign_in_status = SignInStatus::new(Status::SignedOut, true);
+        assert!(!sign_in_status.is_signed_out());
+        assert_eq!(sign_in_status.set_status(Status::Authenticating), None);
+        assert_eq!(sign_in_status.set_status(Status::Connecting), None);
+        assert!(!sign_in_status.is_signed_out());
+        assert_eq!(sign_in_status.set_status(connected), None);
+        assert!(!sign_in_status.is_signed_out());
+
+        // ...and only appears signed out once reconnecting fails.
+        let mut sign_in_status = SignInStatus::new(Status::SignedOut, true);
+        assert_eq!(sign_in_status.set_status(Status::Authenticating), None);
+        assert!(!sign_in_status.is_signed_out());
+        assert_eq!(sign_in_status.set_status(Status::SignedOut), Some(false));
+        assert!(sign_in_status.is_signed_out());
+
+        // Users who weren't signed in before are shown as signed out right away.
+        let mut sign_in_status = SignInStatus::new(Status::SignedOut, false);
+        assert!(sign_in_status.is_signed_out());
+        assert_eq!(sign_in_status.set_status(connected), Some(true));
+        assert!(!sign_in_status.is_signed_out());
+    }
+
+    #[gpui::test]
+    async fn test_resume_session_with_reconnect_latency(cx: &mut gpui::TestAppContext) {
+        cx.update(|cx| cx.set_global(SettingsStore::test(cx)));
+        let new_provider = |cx: &mut gpui::TestAppContext| {
+            cx.update(|cx| {
+                let clock = Arc::new(clock::FakeSystemClock::default());
+                let http_client = http_client::FakeHttpClient::with_404_response();
+                let client = Client::new(clock, http_client, cx);
+                let user_store = cx.new_model(|cx| UserStore::new(client.clone(), cx));
+                let provider = CloudLanguageModelProvider::new(user_store, client.clone(), cx);
+                (client, provider)
+            })
+        };
+
+        // If the app never tries to sign back in, the user is shown as signed
+        // out once they've had time to.
+        KEY_VALUE_STORE
+            .write_kvp(SIGNED_IN_KEY.into(), "true".into())
+            .await
+            .unwrap();
+        let (_client, provider) = new_provider(cx);
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+        cx.executor()
+            .advance_clock(SESSION_RESUME_TIMEOUT - Duration::from_secs(1));
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+        cx.executor().advance_clock(Duration::from_secs(1));
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+        assert_eq!(KEY_VALUE_STORE.read_kvp(SIGNED_IN_KEY).unwrap(), None);
+
+        // Otherwise, they stay signed in while the client takes its time to
+        // reconnect.
+        KEY_VALUE_STORE
+            .write_kvp(SIGNED_IN_KEY.into(), "true".into())
+            .await
+            .unwrap();
+        let (client, provider) = new_provider(cx);
+        cx.executor().advance_clock(Duration::from_secs(3));
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+        let _server = client::test::FakeServer::for_client(1, &client, cx).await;
+        cx.executor().advance_clock(SESSION_RESUME_TIMEOUT);
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+    }
+
+    #[test]
+    fn test_plan_requirement_label() {
+        let free_model = CloudModel::Anthropic(anthropic::Model::Claude3_5Sonnet);
+        let pro_model = CloudModel::Anthropic(anthropic::Model::Claude3Opus);
+
+        let free_plan = Some(proto::Plan::Free);
+        assert_eq!(
+            plan_requirement_label(free_model.availability(), free_plan),
+            None
+        );
+        assert_eq!(
+            plan_requirement_label(pro_model.availability(), free_plan),
+            Some("Requires Zed Pro")
+        );
+        assert_eq!(
+            plan_requirement_label(pro_model.availability(), None),
+            Some("Requires Zed Pro")
+        );
+
+        let pro_plan = Some(proto::Plan::ZedPro);
+        assert_eq!(
+            plan_requirement_label(free_model.availability(), pro_plan),
+            None
+        );
+        assert_eq!(
+            plan_requirement_label(pro_model.availability(), pro_plan),
+            None
+        );
+    }
+
+    #[test]
+    fn test_server_capabilities_override_defaults() {
+        let model = CloudModel::OpenAi(open_ai::Model::FourOmni);
+        assert_eq!(
+            model_capabilities(&model, &[]),
+            LanguageModelCapabilities {
+                tools: true,
+                images: true,
+                documents: false,
+                max_output_tokens: None,
+            }
+        );
+
+        let response: GetLanguageModelsResponse = serde_json::from_str(
+            r#"{"models":[
+                {"provider":"open_ai","name":"gpt-4","capabilities":{"tools":false}},
+                {"provider":"open_ai","name":"gpt-4o","capabilities":{"images":false,"max_output_tokens":16384}},
+                {"provider":"anthropic","name":"gpt-4o","capabilities":{"tools":false}}
+            ]}"#,
+        )
+        .unwrap();
+        // The most specific entry for the model's provider wins, and the
+        // capabilities it leaves out keep their defaults.
+        assert_eq!(
+            model_capabilities(&model, &response.models),
+            LanguageModelCapabilities {
+                tools: true,
+                images: false,
+                documents: false,
+                max_output_tokens: Some(16384),
+            }
+        );
+    }
+}
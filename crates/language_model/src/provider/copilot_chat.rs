@@ -26,11 +26,12 @@ use ui::{
 use crate::settings::AllLanguageModelSettings;
 use crate::LanguageModelProviderState;
 use crate::{
-    LanguageModel, LanguageModelId, LanguageModelName, LanguageModelProvider,
-    LanguageModelProviderId, LanguageModelProviderName, LanguageModelRequest, RateLimiter, Role,
+    intercept_request, InFlightCompletions, LanguageModel, LanguageModelCompletionEvent,
+    LanguageModelId, LanguageModelName, LanguageModelProvider, LanguageModelProviderId,
+    LanguageModelProviderName, LanguageModelRequest, RateLimiter, Role,
 };
 
-use super::open_ai::count_open_ai_tokens;
+use super::open_ai::{count_open_ai_tokens, count_open_ai_tokens_batch};
 
 const PROVIDER_ID: &str = "copilot_chat";
 const PROVIDER_NAME: &str = "GitHub Copilot Chat";
@@ -38,10 +39,13 @@ const PROVIDER_NAME: &str = "GitHub Copilot Chat";
 #[derive(Default, Clone, Debug, PartialEq)]
 pub struct CopilotChatSettings {
     pub low_speed_timeout: Option<Duration>,
+    /// Ids of built-in models to hide from the model picker.
+    pub disabled_models: Vec<String>,
 }
 
 pub struct CopilotChatLanguageModelProvider {
     state: Model<State>,
+    in_flight: InFlightCompletions,
 }
 
 pub struct State {
@@ -70,7 +74,10 @@ impl CopilotChatLanguageModelProvider {
             }
         });
 
-        Self { state }
+        Self {
+            state,
+            in_flight: InFlightCompletions::default(),
+        }
     }
 }
 
@@ -95,12 +102,17 @@ impl LanguageModelProvider for CopilotChatLanguageModelProvider {
         IconName::Copilot
     }
 
-    fn provided_models(&self, _cx: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
+    fn provided_models(&self, cx: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
+        let disabled_models = &AllLanguageModelSettings::get_global(cx)
+            .copilot_chat
+            .disabled_models;
         CopilotChatModel::iter()
+            .filter(|model| !disabled_models.iter().any(|id| id == model.id()))
             .map(|model| {
                 Arc::new(CopilotChatLanguageModel {
                     model,
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: RateLimiter::new(4).with_configured_max_queue_wait(cx),
+                    in_flight: self.in_flight.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
@@ -142,11 +154,16 @@ impl LanguageModelProvider for CopilotChatLanguageModelProvider {
             "Signing out of GitHub Copilot Chat is currently not supported."
         )))
     }
+
+    fn cancel_all(&self, _cx: &mut AppContext) {
+        self.in_flight.abort_all();
+    }
 }
 
 pub struct CopilotChatLanguageModel {
     model: CopilotChatModel,
     request_limiter: RateLimiter,
+    in_flight: InFlightCompletions,
 }
 
 impl LanguageModel for CopilotChatLanguageModel {
@@ -179,24 +196,34 @@ impl LanguageModel for CopilotChatLanguageModel {
         request: LanguageModelRequest,
         cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>> {
-        let model = match self.model {
-            CopilotChatModel::Gpt4 => open_ai::Model::Four,
-            CopilotChatModel::Gpt3_5Turbo => open_ai::Model::ThreePointFiveTurbo,
-        };
+        count_open_ai_tokens(request, self.open_ai_model(), cx)
+    }
 
-        count_open_ai_tokens(request, model, cx)
+    fn count_tokens_batch(
+        &self,
+        requests: Vec<LanguageModelRequest>,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<usize>>> {
+        count_open_ai_tokens_batch(requests, self.open_ai_model(), cx)
     }
 
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        if let Err(error) = request.ensure_no_documents("Copilot Chat") {
+            return interceptors.intercept(futures::future::ready(Err(error)).boxed());
+        }
+
         if let Some(message) = request.messages.last() {
             if message.content.trim().is_empty() {
                 const EMPTY_PROMPT_MSG: &str =
                     "Empty prompts aren't allowed. Please provide a non-empty prompt.";
-                return futures::future::ready(Err(anyhow::anyhow!(EMPTY_PROMPT_MSG))).boxed();
+                return interceptors.intercept(
+                    futures::future::ready(Err(anyhow::anyhow!(EMPTY_PROMPT_MSG))).boxed(),
+                );
             }
 
             // Copilot Chat has a restriction that the final message must be from the user.
@@ -204,23 +231,28 @@ impl LanguageModel for CopilotChatLanguageModel {
             // and provide a more helpful error message.
             if !matches!(message.role, Role::User) {
                 const USER_ROLE_MSG: &str = "The final message must be from the user. To provide a system prompt, you must provide the system prompt followed by a user prompt.";
-                return futures::future::ready(Err(anyhow::anyhow!(USER_ROLE_MSG))).boxed();
+                return interceptors.intercept(
+                    futures::future::ready(Err(anyhow::anyhow!(USER_ROLE_MSG))).boxed(),
+                );
             }
         }
 
+        let priority = request.priority;
         let request = self.to_copilot_chat_request(request);
         let Ok(low_speed_timeout) = cx.update(|cx| {
             AllLanguageModelSettings::get_global(cx)
                 .copilot_chat
                 .low_speed_timeout
         }) else {
-            return futures::future::ready(Err(anyhow::anyhow!("App state dropped"))).boxed();
+            return interceptors.intercept(
+                futures::future::ready(Err(anyhow::anyhow!("App state dropped"))).boxed(),
+            );
         };
 
         let request_limiter = self.request_limiter.clone();
         let future = cx.spawn(|cx| async move {
             let response = CopilotChat::stream_completion(request, low_speed_timeout, cx);
-            request_limiter.stream(async move {
+            request_limiter.stream(priority, async move {
                 let response = response.await?;
                 let stream = response
                     .filter_map(|response| async move {
@@ -228,7 +260,7 @@ impl LanguageModel for CopilotChatLanguageModel {
                             Ok(result) => {
                                 let choice = result.choices.first();
                                 match choice {
-                                    Some(choice) => Some(Ok(choice.delta.content.clone().unwrap_or_default())),
+                                    Some(choice) => Some(Ok(LanguageModelCompletionEvent::Text(choice.delta.content.clone().unwrap_or_default()))),
                                     None => Some(Err(anyhow::anyhow!(
                                         "The Copilot Chat API returned a response with no choices, but hadn't finished the message yet. Please try again."
                                     ))),
@@ -242,7 +274,10 @@ impl LanguageModel for CopilotChatLanguageModel {
             }).await
         });
 
-        async move { Ok(future.await?.boxed()) }.boxed()
+        interceptors.intercept(
+            self.in_flight
+                .track(async move { Ok(future.await?.boxed()) }.boxed()),
+        )
     }
 
     fn use_any_tool(
@@ -253,12 +288,23 @@ impl LanguageModel for CopilotChatLanguageModel {
         _schema: serde_json::Value,
         _cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<serde_json::Value>> {
-        future::ready(Err(anyhow!("not implemented"))).boxed()
+        self.in_flight
+            .track_future(future::ready(Err(anyhow!("not implemented"))).boxed())
     }
 }
 
 impl CopilotChatLanguageModel {
-    pub fn to_copilot_chat_request(&self, request: LanguageModelRequest) -> CopilotChatRequest {
+    /// The OpenAI model whose tokenizer counts this model's tokens.
+    fn open_ai_model(&self) -> open_ai::Model {
+        match self.model {
+            CopilotChatModel::Gpt4 => open_ai::Model::Four,
+            CopilotChatModel::Gpt3_5Turbo => open_ai::Model::ThreePointFiveTurbo,
+        }
+    }
+
+    pub fn to_copilot_chat_request(&self, mut request: LanguageModelRequest) -> CopilotChatRequest {
+        request.apply_max_messages();
+        request.apply_empty_assistant_messages();
         CopilotChatRequest::new(
             self.model.clone(),
             request
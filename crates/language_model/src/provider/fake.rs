@@ -1,7 +1,7 @@
 use crate::{
-    LanguageModel, LanguageModelId, LanguageModelName, LanguageModelProvider,
-    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState,
-    LanguageModelRequest,
+    InFlightCompletions, LanguageModel, LanguageModelCompletionEvent, LanguageModelId,
+    LanguageModelName, LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelProviderState, LanguageModelRequest,
 };
 use anyhow::Context as _;
 use futures::{
@@ -13,7 +13,10 @@ use futures::{
 use gpui::{AnyView, AppContext, AsyncAppContext, Task};
 use http_client::Result;
 use parking_lot::Mutex;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{self, AtomicBool},
+    Arc,
+};
 use ui::WindowContext;
 
 pub fn language_model_id() -> LanguageModelId {
@@ -32,8 +35,20 @@ pub fn provider_name() -> LanguageModelProviderName {
     LanguageModelProviderName::from("Fake".to_string())
 }
 
-#[derive(Clone, Default)]
-pub struct FakeLanguageModelProvider;
+#[derive(Clone)]
+pub struct FakeLanguageModelProvider {
+    is_authenticated: Arc<AtomicBool>,
+    in_flight: InFlightCompletions,
+}
+
+impl Default for FakeLanguageModelProvider {
+    fn default() -> Self {
+        Self {
+            is_authenticated: Arc::new(AtomicBool::new(true)),
+            in_flight: InFlightCompletions::default(),
+        }
+    }
+}
 
 impl LanguageModelProviderState for FakeLanguageModelProvider {
     type ObservableEntity = ();
@@ -53,11 +68,11 @@ impl LanguageModelProvider for FakeLanguageModelProvider {
     }
 
     fn provided_models(&self, _: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
-        vec![Arc::new(FakeLanguageModel::default())]
+        vec![Arc::new(self.test_model())]
     }
 
     fn is_authenticated(&self, _: &AppContext) -> bool {
-        true
+        self.is_authenticated.load(atomic::Ordering::SeqCst)
     }
 
     fn authenticate(&self, _: &mut AppContext) -> Task<Result<()>> {
@@ -71,11 +86,23 @@ impl LanguageModelProvider for FakeLanguageModelProvider {
     fn reset_credentials(&self, _: &mut AppContext) -> Task<Result<()>> {
         Task::ready(Ok(()))
     }
+
+    fn cancel_all(&self, _: &mut AppContext) {
+        self.in_flight.abort_all();
+    }
 }
 
 impl FakeLanguageModelProvider {
     pub fn test_model(&self) -> FakeLanguageModel {
-        FakeLanguageModel::default()
+        FakeLanguageModel {
+            in_flight: self.in_flight.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn set_authenticated(&self, is_authenticated: bool) {
+        self.is_authenticated
+            .store(is_authenticated, atomic::Ordering::SeqCst);
     }
 }
 
@@ -91,6 +118,7 @@ pub struct ToolUseRequest {
 pub struct FakeLanguageModel {
     current_completion_txs: Mutex<Vec<(LanguageModelRequest, mpsc::UnboundedSender<String>)>>,
     current_tool_use_txs: Mutex<Vec<(ToolUseRequest, oneshot::Sender<Result<serde_json::Value>>)>>,
+    in_flight: InFlightCompletions,
 }
 
 impl FakeLanguageModel {
@@ -116,6 +144,15 @@ impl FakeLanguageModel {
         tx.unbounded_send(chunk).unwrap();
     }
 
+    /// Returns whether the caller has stopped listening to the completion.
+    pub fn is_completion_stream_closed(&self, request: &LanguageModelRequest) -> bool {
+        self.current_completion_txs
+            .lock()
+            .iter()
+            .find(|(req, _)| req == request)
+            .map_or(true, |(_, tx)| tx.is_closed())
+    }
+
     pub fn end_completion_stream(&self, request: &LanguageModelRequest) {
         self.current_completion_txs
             .lock()
@@ -189,10 +226,17 @@ impl LanguageModel for FakeLanguageModel {
         &self,
         request: LanguageModelRequest,
         _: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
         let (tx, rx) = mpsc::unbounded();
         self.current_completion_txs.lock().push((request, tx));
-        async move { Ok(rx.map(Ok).boxed()) }.boxed()
+        self.in_flight.track(
+            async move {
+                Ok(rx
+                    .map(|text| Ok(LanguageModelCompletionEvent::Text(text)))
+                    .boxed())
+            }
+            .boxed(),
+        )
     }
 
     fn use_any_tool(
@@ -211,7 +255,8 @@ impl LanguageModel for FakeLanguageModel {
             schema,
         };
         self.current_tool_use_txs.lock().push((tool_call, tx));
-        async move { rx.await.context("FakeLanguageModel was dropped")? }.boxed()
+        self.in_flight
+            .track_future(async move { rx.await.context("FakeLanguageModel was dropped")? }.boxed())
     }
 
     fn as_fake(&self) -> &Self {
@@ -1,8 +1,11 @@
 use anyhow::{anyhow, Result};
 use collections::BTreeMap;
 use editor::{Editor, EditorElement, EditorStyle};
-use futures::{future::BoxFuture, FutureExt, StreamExt};
-use google_ai::stream_generate_content;
+use futures::{future::BoxFuture, FutureExt, Stream, StreamExt};
+use google_ai::{
+    stream_generate_content, FunctionCallPart, FunctionDeclaration, GenerateContentResponse,
+    InlineDataPart, Part, TextPart,
+};
 use gpui::{
     AnyView, AppContext, AsyncAppContext, FontStyle, ModelContext, Subscription, Task, TextStyle,
     View, WhiteSpace,
@@ -18,30 +21,50 @@ use ui::{prelude::*, Icon, IconName};
 use util::ResultExt;
 
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelId, LanguageModelName,
-    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest, RateLimiter,
+    diagnose_provider_settings, google_capabilities, intercept_request,
+    remove_unsupported_gemini_schema_keys, settings::AllLanguageModelSettings, InFlightCompletions,
+    LanguageModel, LanguageModelCapabilities, LanguageModelCompletionEvent, LanguageModelId,
+    LanguageModelName, LanguageModelProvider, LanguageModelProviderDiagnostic,
+    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState,
+    LanguageModelRequest, RateLimiter, RequestPriority, StreamingSchemaValidator,
 };
 
 const PROVIDER_ID: &str = "google";
 const PROVIDER_NAME: &str = "Google AI";
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 4;
+const DEFAULT_MAX_CONCURRENT_TOKEN_COUNTS: usize = 2;
 
 #[derive(Default, Clone, Debug, PartialEq)]
 pub struct GoogleSettings {
     pub api_url: String,
     pub low_speed_timeout: Option<Duration>,
     pub available_models: Vec<AvailableModel>,
+    /// Ids of built-in models to hide from the model picker.
+    pub disabled_models: Vec<String>,
+    /// How many completions may stream from each model at once, which can be
+    /// lowered to stay within the request limits of Gemini's free tier.
+    pub max_concurrent_requests: Option<usize>,
+    /// How many token counts, which Gemini does with a request of its own, may
+    /// be in flight for each model at once. These are limited separately from
+    /// completions so that counting in the background never delays them.
+    pub max_concurrent_token_counts: Option<usize>,
+    /// The threshold at which Gemini blocks content in each harm category,
+    /// for categories that shouldn't use Gemini's default.
+    pub safety_settings: BTreeMap<google_ai::HarmCategory, google_ai::HarmBlockThreshold>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct AvailableModel {
     name: String,
+    /// The name displayed in the model picker, defaulting to `name`.
+    display_name: Option<String>,
     max_tokens: usize,
 }
 
 pub struct GoogleLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Model<State>,
+    in_flight: InFlightCompletions,
 }
 
 pub struct State {
@@ -118,7 +141,11 @@ impl GoogleLanguageModelProvider {
             }),
         });
 
-        Self { http_client, state }
+        Self {
+            http_client,
+            state,
+            in_flight: InFlightCompletions::default(),
+        }
     }
 }
 
@@ -162,11 +189,28 @@ impl LanguageModelProvider for GoogleLanguageModelProvider {
                 model.name.clone(),
                 google_ai::Model::Custom {
                     name: model.name.clone(),
+                    display_name: model.display_name.clone(),
                     max_tokens: model.max_tokens,
                 },
             );
         }
 
+        let disabled_models = &AllLanguageModelSettings::get_global(cx)
+            .google
+            .disabled_models;
+        models.retain(|id, _| !disabled_models.contains(id));
+
+        let max_concurrent_requests = AllLanguageModelSettings::get_global(cx)
+            .google
+            .max_concurrent_requests
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS)
+            .max(1);
+        let max_concurrent_token_counts = AllLanguageModelSettings::get_global(cx)
+            .google
+            .max_concurrent_token_counts
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_TOKEN_COUNTS)
+            .max(1);
+
         models
             .into_values()
             .map(|model| {
@@ -175,12 +219,28 @@ impl LanguageModelProvider for GoogleLanguageModelProvider {
                     model,
                     state: self.state.clone(),
                     http_client: self.http_client.clone(),
-                    rate_limiter: RateLimiter::new(4),
+                    rate_limiter: RateLimiter::new(max_concurrent_requests)
+                        .with_configured_max_queue_wait(cx),
+                    token_count_limiter: RateLimiter::new(max_concurrent_token_counts),
+                    in_flight: self.in_flight.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
     }
 
+    fn diagnostics(&self, cx: &AppContext) -> Vec<LanguageModelProviderDiagnostic> {
+        let settings = &AllLanguageModelSettings::get_global(cx).google;
+        diagnose_provider_settings(
+            "google",
+            &settings.api_url,
+            settings
+                .available_models
+                .iter()
+                .map(|model| (model.name.as_str(), model.max_tokens)),
+            !self.provided_models(cx).is_empty(),
+        )
+    }
+
     fn is_authenticated(&self, cx: &AppContext) -> bool {
         self.state.read(cx).is_authenticated()
     }
@@ -206,6 +266,10 @@ impl LanguageModelProvider for GoogleLanguageModelProvider {
             })
         })
     }
+
+    fn cancel_all(&self, _cx: &mut AppContext) {
+        self.in_flight.abort_all();
+    }
 }
 
 pub struct GoogleLanguageModel {
@@ -214,6 +278,8 @@ pub struct GoogleLanguageModel {
     state: gpui::Model<State>,
     http_client: Arc<dyn HttpClient>,
     rate_limiter: RateLimiter,
+    token_count_limiter: RateLimiter,
+    in_flight: InFlightCompletions,
 }
 
 impl LanguageModel for GoogleLanguageModel {
@@ -241,12 +307,19 @@ impl LanguageModel for GoogleLanguageModel {
         self.model.max_token_count()
     }
 
+    fn capabilities(&self) -> LanguageModelCapabilities {
+        google_capabilities(&self.model)
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
         cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>> {
-        let request = request.into_google(self.model.id().to_string());
+        let request = match request.into_google(self.model.id().to_string(), &BTreeMap::default()) {
+            Ok(request) => request,
+            Err(error) => return futures::future::ready(Err(error)).boxed(),
+        };
         let http_client = self.http_client.clone();
         let api_key = self.state.read(cx).api_key.clone();
         let api_url = AllLanguageModelSettings::get_global(cx)
@@ -254,56 +327,130 @@ impl LanguageModel for GoogleLanguageModel {
             .api_url
             .clone();
 
-        async move {
-            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
-            let response = google_ai::count_tokens(
-                http_client.as_ref(),
-                &api_url,
-                &api_key,
-                google_ai::CountTokensRequest {
-                    contents: request.contents,
-                },
-            )
-            .await?;
-            Ok(response.total_tokens)
-        }
-        .boxed()
+        self.token_count_limiter
+            .run(RequestPriority::Low, async move {
+                let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
+                let response = google_ai::count_tokens(
+                    http_client.as_ref(),
+                    &api_url,
+                    &api_key,
+                    google_ai::CountTokensRequest {
+                        contents: request.contents,
+                    },
+                )
+                .await?;
+                Ok(response.total_tokens)
+            })
+            .boxed()
     }
 
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<futures::stream::BoxStream<'static, Result<String>>>> {
-        let request = request.into_google(self.model.id().to_string());
-
+    ) -> BoxFuture<
+        'static,
+        Result<futures::stream::BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
+    > {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
         let http_client = self.http_client.clone();
-        let Ok((api_key, api_url)) = cx.read_model(&self.state, |state, cx| {
-            let settings = &AllLanguageModelSettings::get_global(cx).google;
-            (state.api_key.clone(), settings.api_url.clone())
-        }) else {
-            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+        let Ok((api_key, api_url, low_speed_timeout, safety_settings)) =
+            cx.read_model(&self.state, |state, cx| {
+                let settings = &AllLanguageModelSettings::get_global(cx).google;
+                (
+                    state.api_key.clone(),
+                    settings.api_url.clone(),
+                    settings.low_speed_timeout,
+                    settings.safety_settings.clone(),
+                )
+            })
+        else {
+            return interceptors
+                .intercept(futures::future::ready(Err(anyhow!("App state dropped"))).boxed());
+        };
+        let priority = request.priority;
+        let request = match request.into_google(self.model.id().to_string(), &safety_settings) {
+            Ok(request) => request,
+            Err(error) => {
+                return interceptors.intercept(futures::future::ready(Err(error)).boxed())
+            }
         };
 
-        let future = self.rate_limiter.stream(async move {
+        let future = self.rate_limiter.stream(priority, async move {
             let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
-            let response =
-                stream_generate_content(http_client.as_ref(), &api_url, &api_key, request);
+            let response = stream_generate_content(
+                http_client.as_ref(),
+                &api_url,
+                &api_key,
+                request,
+                low_speed_timeout,
+            );
             let events = response.await?;
-            Ok(google_ai::extract_text_from_events(events).boxed())
+            Ok(map_to_language_model_completion_events(events).boxed())
         });
-        async move { Ok(future.await?.boxed()) }.boxed()
+        interceptors.intercept(
+            self.in_flight
+                .track(async move { Ok(future.await?.boxed()) }.boxed()),
+        )
     }
 
     fn use_any_tool(
         &self,
-        _request: LanguageModelRequest,
-        _name: String,
-        _description: String,
-        _schema: serde_json::Value,
-        _cx: &AsyncAppContext,
+        request: LanguageModelRequest,
+        name: String,
+        description: String,
+        mut schema: serde_json::Value,
+        cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<serde_json::Value>> {
-        future::ready(Err(anyhow!("not implemented"))).boxed()
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        let http_client = self.http_client.clone();
+        let Ok((api_key, api_url, low_speed_timeout, safety_settings)) =
+            cx.read_model(&self.state, |state, cx| {
+                let settings = &AllLanguageModelSettings::get_global(cx).google;
+                (
+                    state.api_key.clone(),
+                    settings.api_url.clone(),
+                    settings.low_speed_timeout,
+                    settings.safety_settings.clone(),
+                )
+            })
+        else {
+            return interceptors
+                .intercept_tool_use(future::ready(Err(anyhow!("App state dropped"))).boxed());
+        };
+        let priority = request.priority;
+        let request = match request.into_google(self.model.id().to_string(), &safety_settings) {
+            Ok(request) => request,
+            Err(error) => {
+                return interceptors.intercept_tool_use(future::ready(Err(error)).boxed())
+            }
+        };
+        let input_schema = schema.clone();
+        remove_unsupported_gemini_schema_keys(&mut schema);
+        let request = request.with_function(FunctionDeclaration {
+            name: name.clone(),
+            description,
+            parameters: schema,
+        });
+
+        interceptors.intercept_tool_use(
+            self.in_flight.track_future(
+                self.rate_limiter
+                    .run(priority, async move {
+                        let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
+                        let events = stream_generate_content(
+                            http_client.as_ref(),
+                            &api_url,
+                            &api_key,
+                            request,
+                            low_speed_timeout,
+                        )
+                        .await?;
+                        merge_function_call_args(events, &name, &input_schema).await
+                    })
+                    .boxed(),
+            ),
+        )
     }
 }
 
@@ -469,3 +616,395 @@ impl Render for ConfigurationView {
         }
     }
 }
+
+/// Converts Gemini's responses into completion events, including any images
+/// that the model generated alongside its text.
+pub fn map_to_language_model_completion_events(
+    events: impl Stream<Item = Result<GenerateContentResponse>>,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    events.flat_map(|event| {
+        let completion_events = match event {
+            Ok(response) => response
+                .candidates
+                .and_then(|candidates| candidates.into_iter().next())
+                .map_or(Vec::new(), |candidate| {
+                    candidate
+                        .content
+                        .parts
+                        .into_iter()
+                        .filter_map(|part| match part {
+                            Part::TextPart(TextPart { text }) => {
+                                Some(Ok(LanguageModelCompletionEvent::Text(text)))
+                            }
+                            Part::InlineDataPart(InlineDataPart { inline_data }) => {
+                                Some(Ok(LanguageModelCompletionEvent::Image {
+                                    mime_type: inline_data.mime_type,
+                                    data: inline_data.data,
+                                }))
+                            }
+                            Part::FunctionCallPart(_) => None,
+                        })
+                        .collect()
+                }),
+            Err(error) => vec![Err(error)],
+        };
+        futures::stream::iter(completion_events)
+    })
+}
+
+/// Returns the args of the model's call to `function_name`, merging them when
+/// the call is split across several responses. Any text the model responds
+/// with alongside the call is ignored.
+///
+/// The args are checked against `schema` after every response, so that the
+/// stream is abandoned as soon as they can no longer conform to it.
+async fn merge_function_call_args(
+    events: impl Stream<Item = Result<GenerateContentResponse>>,
+    function_name: &str,
+    schema: &serde_json::Value,
+) -> Result<serde_json::Value> {
+    let schema_validator = StreamingSchemaValidator::new(schema.clone());
+    let mut args = None;
+    futures::pin_mut!(events);
+    while let Some(response) = events.next().await {
+        let parts = response?
+            .candidates
+            .into_iter()
+            .flatten()
+            .flat_map(|candidate| candidate.content.parts);
+        for part in parts {
+            if let Part::FunctionCallPart(FunctionCallPart { function_call }) = part {
+                if function_call.name == function_name {
+                    let args =
+                        args.get_or_insert_with(|| serde_json::Value::Object(Default::default()));
+                    merge_json(args, function_call.args);
+                    schema_validator.validate_partial(args)?;
+                }
+            }
+        }
+    }
+    args.ok_or_else(|| anyhow!("tool not used"))
+}
+
+/// Merges `source` into `target`, for args sent as several objects. Objects
+/// are merged key by key and arrays are concatenated, since later objects add
+/// to them, while any other value replaces the one before it.
+pub(crate) fn merge_json(target: &mut serde_json::Value, source: serde_json::Value) {
+    match (target, source) {
+        (_, serde_json::Value::Null) => {}
+        (serde_json::Value::Array(target), serde_json::Value::Array(source)) => {
+            target.extend(source);
+        }
+        (serde_json::Value::Object(target), serde_json::Value::Object(source)) => {
+            for (key, value) in source {
+                match target.get_mut(&key) {
+                    Some(existing) => merge_json(existing, value),
+                    None => {
+                        target.insert(key, value);
+                    }
+                }
+            }
+        }
+        (target, source) => *target = source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LanguageModelError;
+    use gpui::TestAppContext;
+    use http_client::FakeHttpClient;
+    use parking_lot::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+
+    #[gpui::test]
+    async fn test_max_concurrent_requests(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+            cx.update_global(|store: &mut SettingsStore, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "google": { "max_concurrent_requests": 1 }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+        });
+
+        // Requests never complete, so each one holds on to its slot.
+        let sent_requests = Arc::new(AtomicUsize::new(0));
+        let http_client = FakeHttpClient::create({
+            let sent_requests = sent_requests.clone();
+            move |_| {
+                sent_requests.fetch_add(1, SeqCst);
+                futures::future::pending()
+            }
+        });
+        let provider = cx.update(|cx| GoogleLanguageModelProvider::new(http_client, cx));
+        provider.state.update(cx, |state, _| {
+            state.api_key = Some("api-key".into());
+        });
+
+        let model = cx.update(|cx| provider.provided_models(cx)[0].clone());
+        let completions = (0..2)
+            .map(|_| {
+                let completion =
+                    model.stream_completion(LanguageModelRequest::default(), &cx.to_async());
+                cx.executor().spawn(completion)
+            })
+            .collect::<Vec<_>>();
+        cx.run_until_parked();
+        assert_eq!(sent_requests.load(SeqCst), 1);
+
+        drop(completions);
+        cx.run_until_parked();
+    }
+
+    #[gpui::test]
+    async fn test_max_queue_wait(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+            cx.update_global(|store: &mut SettingsStore, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "max_queue_wait_in_seconds": 5,
+                                "google": { "max_concurrent_requests": 1 }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+        });
+
+        // Requests never complete, so the first one holds on to the only slot.
+        let http_client = FakeHttpClient::create(|_| futures::future::pending());
+        let provider = cx.update(|cx| GoogleLanguageModelProvider::new(http_client, cx));
+        provider.state.update(cx, |state, _| {
+            state.api_key = Some("api-key".into());
+        });
+
+        let model = cx.update(|cx| provider.provided_models(cx)[0].clone());
+        let first = cx
+            .executor()
+            .spawn(model.stream_completion(LanguageModelRequest::default(), &cx.to_async()));
+        let second = cx
+            .executor()
+            .spawn(model.stream_completion(LanguageModelRequest::default(), &cx.to_async()));
+        cx.executor().advance_clock(Duration::from_secs(5));
+        let Err(error) = second.await else {
+            panic!("expected the queued request to time out");
+        };
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::QueueTimeout {
+                waited: Duration::from_secs(5)
+            })
+        );
+
+        drop(first);
+        cx.run_until_parked();
+    }
+
+    #[gpui::test]
+    async fn test_token_counts_dont_block_completions(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+            cx.update_global(|store: &mut SettingsStore, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "google": {
+                                    "max_concurrent_requests": 1,
+                                    "max_concurrent_token_counts": 1
+                                }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+        });
+
+        // Requests never complete, so each one holds on to its slot.
+        let sent_requests = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let sent_requests = sent_requests.clone();
+            move |request| {
+                let method = if request.uri().path().ends_with(":countTokens") {
+                    "countTokens"
+                } else {
+                    "streamGenerateContent"
+                };
+                sent_requests.lock().push(method);
+                futures::future::pending()
+            }
+        });
+        let provider = cx.update(|cx| GoogleLanguageModelProvider::new(http_client, cx));
+        provider.state.update(cx, |state, _| {
+            state.api_key = Some("api-key".into());
+        });
+
+        let model = cx.update(|cx| provider.provided_models(cx)[0].clone());
+        let counts = (0..3)
+            .map(|_| {
+                let count = cx.update(|cx| model.count_tokens(LanguageModelRequest::default(), cx));
+                cx.executor().spawn(count)
+            })
+            .collect::<Vec<_>>();
+        cx.run_until_parked();
+        assert_eq!(*sent_requests.lock(), ["countTokens"]);
+
+        let completion = cx
+            .executor()
+            .spawn(model.stream_completion(LanguageModelRequest::default(), &cx.to_async()));
+        cx.run_until_parked();
+        assert_eq!(
+            *sent_requests.lock(),
+            ["countTokens", "streamGenerateContent"]
+        );
+
+        drop((counts, completion));
+        cx.run_until_parked();
+    }
+
+    #[gpui::test]
+    async fn test_image_parts() {
+        // A stream from a model that returns an image between two pieces of text.
+        let responses = [
+            r#"{"candidates":[{"index":0,"content":{"role":"model","parts":[{"text":"Here's a red pixel:"}]}}]}"#,
+            r#"{"candidates":[{"index":0,"content":{"role":"model","parts":[{"inlineData":{"mimeType":"image/png","data":"iVBORw0KGgo="}},{"text":" Enjoy!"}]},"finishReason":"STOP"}]}"#,
+        ];
+        let events = futures::stream::iter(
+            responses.map(|response| Ok(serde_json::from_str(response).unwrap())),
+        );
+        let events = map_to_language_model_completion_events(events)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::Text("Here's a red pixel:".into()),
+                LanguageModelCompletionEvent::Image {
+                    mime_type: "image/png".into(),
+                    data: "iVBORw0KGgo=".into(),
+                },
+                LanguageModelCompletionEvent::Text(" Enjoy!".into()),
+            ]
+        );
+    }
+
+    fn search_schema() -> serde_json::Value {
+        serde_json::json!({
+            "type": "object",
+            "properties": {
+                "query": { "type": "string" },
+                "limit": { "type": "integer" },
+                "filters": { "type": "object" },
+                "sources": { "type": "array", "items": { "type": "string" } },
+            },
+            "required": ["query"],
+        })
+    }
+
+    #[gpui::test]
+    async fn test_function_call_split_across_responses() {
+        // The call's args arrive in two responses, the first of which also
+        // has some text, and a call to another function is ignored.
+        let responses = [
+            r#"{"candidates":[{"index":0,"content":{"role":"model","parts":[{"text":"Let me look that up."},{"functionCall":{"name":"search","args":{"query":"rust","filters":{"language":"en"},"sources":["docs"]}}}]}}]}"#,
+            r#"{"candidates":[{"index":0,"content":{"role":"model","parts":[{"functionCall":{"name":"other","args":{"query":"go"}}},{"functionCall":{"name":"search","args":{"limit":10,"filters":{"recent":true},"sources":["forum"]}}}]},"finishReason":"STOP"}]}"#,
+        ];
+        let events = futures::stream::iter(
+            responses.map(|response| Ok(serde_json::from_str(response).unwrap())),
+        );
+        assert_eq!(
+            merge_function_call_args(events, "search", &search_schema())
+                .await
+                .unwrap(),
+            serde_json::json!({
+                "query": "rust",
+                "limit": 10,
+                "filters": { "language": "en", "recent": true },
+                "sources": ["docs", "forum"],
+            })
+        );
+
+        let responses = [
+            r#"{"candidates":[{"index":0,"content":{"role":"model","parts":[{"text":"I can't help with that."}]},"finishReason":"STOP"}]}"#,
+        ];
+        let events = futures::stream::iter(
+            responses.map(|response| Ok(serde_json::from_str(response).unwrap())),
+        );
+        assert_eq!(
+            merge_function_call_args(events, "search", &search_schema())
+                .await
+                .unwrap_err()
+                .to_string(),
+            "tool not used"
+        );
+    }
+
+    #[gpui::test]
+    async fn test_function_call_args_stop_streaming_on_schema_violation() {
+        // The stream never ends, so the args are only returned if they're
+        // rejected as soon as they can no longer match the schema.
+        let response = r#"{"candidates":[{"index":0,"content":{"role":"model","parts":[{"functionCall":{"name":"search","args":{"query":"rust","limit":"ten"}}}]}}]}"#;
+        let events = futures::stream::iter([Ok(serde_json::from_str(response).unwrap())])
+            .chain(futures::stream::pending());
+        assert_eq!(
+            merge_function_call_args(events, "search", &search_schema())
+                .await
+                .unwrap_err()
+                .to_string(),
+            "output does not match the schema: expected integer at $.limit, found string"
+        );
+    }
+
+    #[test]
+    fn test_request_with_function() {
+        let request = LanguageModelRequest::default()
+            .into_google("gemini-1.5-pro".into(), &BTreeMap::default())
+            .unwrap()
+            .with_function(FunctionDeclaration {
+                name: "search".into(),
+                description: "Searches the web".into(),
+                parameters: serde_json::json!({ "type": "object" }),
+            });
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(
+            json["tools"],
+            serde_json::json!([{
+                "functionDeclarations": [{
+                    "name": "search",
+                    "description": "Searches the web",
+                    "parameters": { "type": "object" },
+                }],
+            }])
+        );
+        assert_eq!(
+            json["toolConfig"],
+            serde_json::json!({
+                "functionCallingConfig": {
+                    "mode": "ANY",
+                    "allowedFunctionNames": ["search"],
+                },
+            })
+        );
+    }
+}
@@ -1,21 +1,29 @@
 use anyhow::{anyhow, bail, Result};
-use futures::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
-use gpui::{AnyView, AppContext, AsyncAppContext, ModelContext, Subscription, Task};
+use futures::{
+    channel::oneshot, future::BoxFuture, stream::BoxStream, Future, FutureExt, StreamExt,
+};
+use gpui::{
+    AnyView, AppContext, AsyncAppContext, BackgroundExecutor, ModelContext, Subscription, Task,
+};
 use http_client::HttpClient;
 use ollama::{
     get_models, preload_model, stream_chat_completion, ChatMessage, ChatOptions, ChatRequest,
-    ChatResponseDelta, OllamaToolCall,
+    ChatResponseDelta, OllamaToolCall, OllamaUnavailable,
 };
 use serde_json::Value;
 use settings::{Settings, SettingsStore};
 use std::{sync::Arc, time::Duration};
-use ui::{prelude::*, ButtonLike, Indicator};
+use time::{OffsetDateTime, UtcOffset};
+use ui::{prelude::*, ButtonLike, Indicator, Tooltip};
 use util::ResultExt;
 
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelId, LanguageModelName,
-    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest, RateLimiter, Role,
+    diagnose_provider_settings, intercept_request, max_output_tokens_reserving_system_prompt,
+    settings::AllLanguageModelSettings, with_configured_backoff, Backoff, InFlightCompletions,
+    LanguageModel, LanguageModelCompletionEvent, LanguageModelId, LanguageModelName,
+    LanguageModelProvider, LanguageModelProviderDiagnostic, LanguageModelProviderId,
+    LanguageModelProviderName, LanguageModelProviderState, LanguageModelRequest,
+    LanguageModelRequestMessage, RateLimiter, Retry, Role,
 };
 
 const OLLAMA_DOWNLOAD_URL: &str = "https://ollama.com/download";
@@ -34,17 +42,23 @@ pub struct OllamaSettings {
 pub struct OllamaLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Model<State>,
+    in_flight: InFlightCompletions,
 }
 
 pub struct State {
     http_client: Arc<dyn HttpClient>,
     available_models: Vec<ollama::Model>,
+    models_last_refreshed: Option<OffsetDateTime>,
+    /// Whether the last attempt to list the server's models succeeded.
+    is_reachable: bool,
     _subscription: Subscription,
 }
 
 impl State {
+    /// Ollama doesn't need an API key, so it's ready as soon as the server
+    /// can be reached, even before any models have been installed.
     fn is_authenticated(&self) -> bool {
-        !self.available_models.is_empty()
+        self.is_reachable
     }
 
     fn fetch_models(&mut self, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
@@ -52,9 +66,17 @@ impl State {
         let http_client = self.http_client.clone();
         let api_url = settings.api_url.clone();
 
-        // As a proxy for the server being "authenticated", we'll check if its up by fetching the models
         cx.spawn(|this, mut cx| async move {
-            let models = get_models(http_client.as_ref(), &api_url, None).await?;
+            let models = match get_models(http_client.as_ref(), &api_url, None).await {
+                Ok(models) => models,
+                Err(error) => {
+                    this.update(&mut cx, |this, cx| {
+                        this.is_reachable = false;
+                        cx.notify();
+                    })?;
+                    return Err(error);
+                }
+            };
 
             let mut models: Vec<ollama::Model> = models
                 .into_iter()
@@ -69,6 +91,8 @@ impl State {
 
             this.update(&mut cx, |this, cx| {
                 this.available_models = models;
+                this.models_last_refreshed = Some(OffsetDateTime::now_utc());
+                this.is_reachable = true;
                 cx.notify();
             })
         })
@@ -90,11 +114,14 @@ impl OllamaLanguageModelProvider {
             state: cx.new_model(|cx| State {
                 http_client,
                 available_models: Default::default(),
+                models_last_refreshed: None,
+                is_reachable: false,
                 _subscription: cx.observe_global::<SettingsStore>(|this: &mut State, cx| {
                     this.fetch_models(cx).detach();
                     cx.notify();
                 }),
             }),
+            in_flight: InFlightCompletions::default(),
         };
         this.state
             .update(cx, |state, cx| state.fetch_models(cx).detach());
@@ -133,12 +160,17 @@ impl LanguageModelProvider for OllamaLanguageModelProvider {
                     id: LanguageModelId::from(model.name.clone()),
                     model: model.clone(),
                     http_client: self.http_client.clone(),
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: RateLimiter::new(4).with_configured_max_queue_wait(cx),
+                    in_flight: self.in_flight.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
     }
 
+    fn models_last_refreshed(&self, cx: &AppContext) -> Option<OffsetDateTime> {
+        self.state.read(cx).models_last_refreshed
+    }
+
     fn load_model(&self, model: Arc<dyn LanguageModel>, cx: &AppContext) {
         let settings = &AllLanguageModelSettings::get_global(cx).ollama;
         let http_client = self.http_client.clone();
@@ -148,6 +180,13 @@ impl LanguageModelProvider for OllamaLanguageModelProvider {
             .detach_and_log_err(cx);
     }
 
+    fn diagnostics(&self, cx: &AppContext) -> Vec<LanguageModelProviderDiagnostic> {
+        // Models are discovered from the Ollama server, so there's nothing to
+        // check besides the URL.
+        let settings = &AllLanguageModelSettings::get_global(cx).ollama;
+        diagnose_provider_settings("ollama", &settings.api_url, [], true)
+    }
+
     fn is_authenticated(&self, cx: &AppContext) -> bool {
         self.state.read(cx).is_authenticated()
     }
@@ -164,6 +203,10 @@ impl LanguageModelProvider for OllamaLanguageModelProvider {
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
         self.state.update(cx, |state, cx| state.fetch_models(cx))
     }
+
+    fn cancel_all(&self, _cx: &mut AppContext) {
+        self.in_flight.abort_all();
+    }
 }
 
 pub struct OllamaLanguageModel {
@@ -171,12 +214,131 @@ pub struct OllamaLanguageModel {
     model: ollama::Model,
     http_client: Arc<dyn HttpClient>,
     request_limiter: RateLimiter,
+    in_flight: InFlightCompletions,
+}
+
+/// How many times to retry connecting to Ollama while it's unavailable, such
+/// as while it starts up or loads the model.
+const CONNECT_RETRIES: u32 = 6;
+/// How long to wait before the first retry. Each later retry waits twice as
+/// long as the one before it.
+const CONNECT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+enum Connection {
+    /// Ollama is unavailable, and connecting to it is about to be retried.
+    Loading,
+    Delta(ChatResponseDelta),
+}
+
+/// Connects with `connect`, retrying with exponential backoff while Ollama is
+/// unavailable, and then streams the response.
+///
+/// Yields [`Connection::Loading`] once, before the first retry, so that the
+/// wait can be shown while the model loads.
+fn connect_with_backoff<F, Fut>(
+    connect: F,
+    executor: BackgroundExecutor,
+) -> BoxStream<'static, Result<Connection>>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<BoxStream<'static, Result<ChatResponseDelta>>>> + Send + 'static,
+{
+    let (loading_tx, loading_rx) = oneshot::channel();
+    let connection = async move {
+        let mut loading_tx = Some(loading_tx);
+        let backoff = Backoff {
+            max_retries: CONNECT_RETRIES,
+            base_delay: CONNECT_RETRY_BASE_DELAY,
+            max_jitter: Duration::ZERO,
+            max_retry_after: Duration::ZERO,
+        };
+        let should_retry = |error: &anyhow::Error| {
+            if !error.is::<OllamaUnavailable>() {
+                return Retry::Never;
+            }
+            if let Some(loading_tx) = loading_tx.take() {
+                loading_tx.send(()).ok();
+            }
+            Retry::AfterBackoff
+        };
+        with_configured_backoff(&executor, backoff, should_retry, connect).await
+    };
+
+    // The loading event ends once the connection does, so selecting between
+    // the two only yields it while still connecting.
+    let loading = loading_rx.into_stream().filter_map(|loading| {
+        futures::future::ready(loading.ok().map(|()| Ok(Connection::Loading)))
+    });
+    let connection = futures::stream::once(connection).flat_map(|response| match response {
+        Ok(deltas) => deltas.map(|delta| delta.map(Connection::Delta)).boxed(),
+        Err(error) => futures::stream::once(futures::future::ready(Err(error))).boxed(),
+    });
+    futures::stream::select(loading, connection).boxed()
+}
+
+/// Estimates the number of tokens in the given messages.
+fn estimate_token_count<'a>(
+    messages: impl IntoIterator<Item = &'a LanguageModelRequestMessage>,
+) -> usize {
+    // There is no endpoint for this _yet_ in Ollama
+    // see: https://github.com/ollama/ollama/issues/1716 and https://github.com/ollama/ollama/issues/3582
+    messages
+        .into_iter()
+        .map(|msg| msg.content.chars().count())
+        .sum::<usize>()
+        / 4
 }
 
 impl OllamaLanguageModel {
-    fn to_ollama_request(&self, request: LanguageModelRequest) -> ChatRequest {
+    fn to_ollama_request(
+        &self,
+        mut request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<ChatRequest>> {
+        request.apply_max_messages();
+        request.apply_empty_assistant_messages();
+
+        // Ollama generates until the context window is full by default, which
+        // would crowd out the system prompt on the next turn.
+        let (system_prompt, conversation): (Vec<_>, Vec<_>) = request
+            .messages
+            .iter()
+            .cloned()
+            .partition(|message| message.role == Role::System);
+        let token_counts = cx.update(|cx| {
+            let count = |messages| {
+                let request = LanguageModelRequest {
+                    messages,
+                    ..Default::default()
+                };
+                self.count_tokens(request, cx)
+            };
+            (count(system_prompt), count(conversation))
+        });
+        let model = self.model.clone();
+        async move {
+            let (system_prompt_tokens, conversation_tokens) = token_counts?;
+            let max_output_tokens = max_output_tokens_reserving_system_prompt(
+                model.max_tokens,
+                conversation_tokens.await?,
+                system_prompt_tokens.await?,
+            );
+            Ok(Self::build_ollama_request(
+                model,
+                request,
+                max_output_tokens,
+            ))
+        }
+        .boxed()
+    }
+
+    fn build_ollama_request(
+        model: ollama::Model,
+        request: LanguageModelRequest,
+        max_output_tokens: usize,
+    ) -> ChatRequest {
         ChatRequest {
-            model: self.model.name.clone(),
+            model: model.name,
             messages: request
                 .messages
                 .into_iter()
@@ -193,10 +355,11 @@ impl OllamaLanguageModel {
                     },
                 })
                 .collect(),
-            keep_alive: self.model.keep_alive.clone().unwrap_or_default(),
+            keep_alive: model.keep_alive.unwrap_or_default(),
             stream: true,
             options: Some(ChatOptions {
-                num_ctx: Some(self.model.max_tokens),
+                num_ctx: Some(model.max_tokens),
+                num_predict: Some(max_output_tokens as isize),
                 stop: Some(request.stop),
                 temperature: Some(request.temperature),
                 ..Default::default()
@@ -204,22 +367,6 @@ impl OllamaLanguageModel {
             tools: vec![],
         }
     }
-    fn request_completion(
-        &self,
-        request: ChatRequest,
-        cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<ChatResponseDelta>> {
-        let http_client = self.http_client.clone();
-
-        let Ok(api_url) = cx.update(|cx| {
-            let settings = &AllLanguageModelSettings::get_global(cx).ollama;
-            settings.api_url.clone()
-        }) else {
-            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
-        };
-
-        async move { ollama::complete(http_client.as_ref(), &api_url, request).await }.boxed()
-    }
 }
 
 impl LanguageModel for OllamaLanguageModel {
@@ -252,15 +399,7 @@ impl LanguageModel for OllamaLanguageModel {
         request: LanguageModelRequest,
         _cx: &AppContext,
     ) -> BoxFuture<'static, Result<usize>> {
-        // There is no endpoint for this _yet_ in Ollama
-        // see: https://github.com/ollama/ollama/issues/1716 and https://github.com/ollama/ollama/issues/3582
-        let token_count = request
-            .messages
-            .iter()
-            .map(|msg| msg.content.chars().count())
-            .sum::<usize>()
-            / 4;
-
+        let token_count = estimate_token_count(&request.messages);
         async move { Ok(token_count) }.boxed()
     }
 
@@ -268,31 +407,53 @@ impl LanguageModel for OllamaLanguageModel {
         &self,
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<String>>>> {
-        let request = self.to_ollama_request(request);
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        if let Err(error) = request.ensure_no_documents("Ollama") {
+            return interceptors.intercept(futures::future::ready(Err(error)).boxed());
+        }
+        let priority = request.priority;
+        let request = self.to_ollama_request(request, cx);
 
         let http_client = self.http_client.clone();
         let Ok((api_url, low_speed_timeout)) = cx.update(|cx| {
             let settings = &AllLanguageModelSettings::get_global(cx).ollama;
             (settings.api_url.clone(), settings.low_speed_timeout)
         }) else {
-            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+            return interceptors
+                .intercept(futures::future::ready(Err(anyhow!("App state dropped"))).boxed());
         };
 
-        let future = self.request_limiter.stream(async move {
-            let response =
-                stream_chat_completion(http_client.as_ref(), &api_url, request, low_speed_timeout)
-                    .await?;
-            let stream = response
+        let executor = cx.background_executor().clone();
+        let future = self.request_limiter.stream(priority, async move {
+            let request = request.await?;
+            let connect = move || {
+                let http_client = http_client.clone();
+                let api_url = api_url.clone();
+                let request = request.clone();
+                async move {
+                    stream_chat_completion(
+                        http_client.as_ref(),
+                        &api_url,
+                        request,
+                        low_speed_timeout,
+                    )
+                    .await
+                }
+            };
+            let stream = connect_with_backoff(connect, executor)
                 .filter_map(|response| async move {
                     match response {
-                        Ok(delta) => {
+                        Ok(Connection::Loading) => {
+                            Some(Ok(LanguageModelCompletionEvent::ModelLoading))
+                        }
+                        Ok(Connection::Delta(delta)) => {
                             let content = match delta.message {
                                 ChatMessage::User { content } => content,
                                 ChatMessage::Assistant { content, .. } => content,
                                 ChatMessage::System { content } => content,
                             };
-                            Some(Ok(content))
+                            Some(Ok(LanguageModelCompletionEvent::Text(content)))
                         }
                         Err(error) => Some(Err(error)),
                     }
@@ -301,7 +462,10 @@ impl LanguageModel for OllamaLanguageModel {
             Ok(stream)
         });
 
-        async move { Ok(future.await?.boxed()) }.boxed()
+        interceptors.intercept(
+            self.in_flight
+                .track(async move { Ok(future.await?.boxed()) }.boxed()),
+        )
     }
 
     fn use_any_tool(
@@ -313,41 +477,60 @@ impl LanguageModel for OllamaLanguageModel {
         cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<serde_json::Value>> {
         use ollama::{OllamaFunctionTool, OllamaTool};
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        if let Err(error) = request.ensure_no_documents("Ollama") {
+            return interceptors.intercept_tool_use(futures::future::ready(Err(error)).boxed());
+        }
         let function = OllamaFunctionTool {
             name: tool_name.clone(),
             description: Some(tool_description),
             parameters: Some(schema),
         };
         let tools = vec![OllamaTool::Function { function }];
-        let request = self.to_ollama_request(request).with_tools(tools);
-        let response = self.request_completion(request, cx);
-        self.request_limiter
-            .run(async move {
-                let response = response.await?;
-                let ChatMessage::Assistant {
-                    tool_calls,
-                    content,
-                } = response.message
-                else {
-                    bail!("message does not have an assistant role");
-                };
-                if let Some(tool_calls) = tool_calls.filter(|calls| !calls.is_empty()) {
-                    for call in tool_calls {
-                        let OllamaToolCall::Function(function) = call;
-                        if function.name == tool_name {
-                            return Ok(function.arguments);
-                        }
-                    }
-                } else if let Ok(args) = serde_json::from_str::<Value>(&content) {
-                    // Parse content as arguments.
-                    return Ok(args);
-                } else {
-                    bail!("assistant message does not have any tool calls");
-                };
-
-                bail!("tool not used")
-            })
-            .boxed()
+        let priority = request.priority;
+        let request = self.to_ollama_request(request, cx);
+        let http_client = self.http_client.clone();
+        let Ok(api_url) = cx.update(|cx| {
+            let settings = &AllLanguageModelSettings::get_global(cx).ollama;
+            settings.api_url.clone()
+        }) else {
+            return interceptors.intercept_tool_use(
+                futures::future::ready(Err(anyhow!("App state dropped"))).boxed(),
+            );
+        };
+        interceptors.intercept_tool_use(
+            self.in_flight.track_future(
+                self.request_limiter
+                    .run(priority, async move {
+                        let request = request.await?.with_tools(tools);
+                        let response =
+                            ollama::complete(http_client.as_ref(), &api_url, request).await?;
+                        let ChatMessage::Assistant {
+                            tool_calls,
+                            content,
+                        } = response.message
+                        else {
+                            bail!("message does not have an assistant role");
+                        };
+                        if let Some(tool_calls) = tool_calls.filter(|calls| !calls.is_empty()) {
+                            for call in tool_calls {
+                                let OllamaToolCall::Function(function) = call;
+                                if function.name == tool_name {
+                                    return Ok(function.arguments);
+                                }
+                            }
+                        } else if let Ok(args) = serde_json::from_str::<Value>(&content) {
+                            // Parse content as arguments.
+                            return Ok(args);
+                        } else {
+                            bail!("assistant message does not have any tool calls");
+                        };
+
+                        bail!("tool not used")
+                    })
+                    .boxed(),
+            ),
+        )
     }
 }
 
@@ -386,6 +569,35 @@ impl ConfigurationView {
             .update(cx, |state, cx| state.fetch_models(cx))
             .detach_and_log_err(cx);
     }
+
+    fn render_models_last_refreshed(&self, cx: &mut ViewContext<Self>) -> Option<AnyElement> {
+        let models_last_refreshed = self.state.read(cx).models_last_refreshed?;
+        let local_offset = chrono::Local::now().offset().local_minus_utc();
+        let models_last_refreshed = time_format::format_localized_timestamp(
+            models_last_refreshed,
+            OffsetDateTime::now_utc(),
+            UtcOffset::from_whole_seconds(local_offset).unwrap_or(UtcOffset::UTC),
+            time_format::TimestampFormat::Relative,
+        );
+
+        Some(
+            h_flex()
+                .gap_1()
+                .child(
+                    Label::new(format!("Models last refreshed {models_last_refreshed}"))
+                        .size(LabelSize::Small)
+                        .color(Color::Muted),
+                )
+                .child(
+                    IconButton::new("refresh-ollama-models", IconName::ArrowCircle)
+                        .icon_size(IconSize::XSmall)
+                        .icon_color(Color::Muted)
+                        .tooltip(|cx| Tooltip::text("Refresh Models", cx))
+                        .on_click(cx.listener(move |this, _, cx| this.retry_connection(cx))),
+                )
+                .into_any_element(),
+        )
+    }
 }
 
 impl Render for ConfigurationView {
@@ -493,7 +705,222 @@ impl Render for ConfigurationView {
                                 .into_any_element()
                         }),
                 )
+                .children(
+                    is_authenticated
+                        .then(|| self.render_models_last_refreshed(cx))
+                        .flatten(),
+                )
                 .into_any()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpui::TestAppContext;
+    use http_client::{FakeHttpClient, Response};
+    use serde_json::json;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::SeqCst};
+
+    #[gpui::test]
+    async fn test_models_last_refreshed(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        let http_client = FakeHttpClient::create(|_| async move {
+            Ok(Response::new(
+                json!({
+                    "models": [{
+                        "name": "llama3.1:latest",
+                        "modified_at": "2024-08-01T00:00:00Z",
+                        "size": 0,
+                        "digest": "",
+                        "details": {
+                            "format": "gguf",
+                            "family": "llama",
+                            "families": null,
+                            "parameter_size": "8B",
+                            "quantization_level": "Q4_0"
+                        }
+                    }]
+                })
+                .to_string()
+                .into(),
+            ))
+        });
+
+        let provider = cx.update(|cx| OllamaLanguageModelProvider::new(http_client, cx));
+        cx.run_until_parked();
+
+        assert!(cx.update(|cx| provider.models_last_refreshed(cx)).is_some());
+        assert_eq!(cx.update(|cx| provider.provided_models(cx).len()), 1);
+
+        provider.state.update(cx, |state, _| {
+            state.models_last_refreshed = Some(OffsetDateTime::UNIX_EPOCH);
+        });
+        provider
+            .state
+            .update(cx, |state, cx| state.fetch_models(cx))
+            .await
+            .unwrap();
+
+        let last_refreshed = cx.update(|cx| provider.models_last_refreshed(cx)).unwrap();
+        assert!(last_refreshed > OffsetDateTime::UNIX_EPOCH);
+    }
+
+    #[gpui::test]
+    async fn test_authenticated_when_reachable(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        // A server without any models installed still counts as connected.
+        let is_running = Arc::new(AtomicBool::new(true));
+        let http_client = FakeHttpClient::create({
+            let is_running = is_running.clone();
+            move |_| {
+                let is_running = is_running.load(SeqCst);
+                async move {
+                    if is_running {
+                        Ok(Response::new(json!({ "models": [] }).to_string().into()))
+                    } else {
+                        Err(anyhow!("connection refused"))
+                    }
+                }
+            }
+        });
+        let provider = cx.update(|cx| OllamaLanguageModelProvider::new(http_client, cx));
+        cx.run_until_parked();
+        assert!(cx.update(|cx| provider.is_authenticated(cx)));
+        assert!(cx.update(|cx| provider.provided_models(cx)).is_empty());
+
+        is_running.store(false, SeqCst);
+        let refreshed = provider
+            .state
+            .update(cx, |state, cx| state.fetch_models(cx))
+            .await;
+        assert!(refreshed.is_err());
+        assert!(!cx.update(|cx| provider.is_authenticated(cx)));
+    }
+
+    #[gpui::test]
+    async fn test_reconnecting_while_model_loads(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        // Ollama refuses the first connection while it starts, and then
+        // reports that it's busy while it loads the model.
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let http_client = FakeHttpClient::create({
+            let attempts = attempts.clone();
+            move |_| {
+                let attempt = attempts.fetch_add(1, SeqCst);
+                async move {
+                    match attempt {
+                        0 => Err(anyhow!("connection refused")),
+                        1 => Ok(Response::builder()
+                            .status(503)
+                            .body("loading model".into())
+                            .unwrap()),
+                        _ => Ok(Response::new(
+                            [
+                                r#"{"model":"llama3.1","created_at":"2024-08-01T00:00:00Z","message":{"role":"assistant","content":"Hello"},"done":false}"#,
+                                r#"{"model":"llama3.1","created_at":"2024-08-01T00:00:00Z","message":{"role":"assistant","content":"!"},"done":true,"done_reason":"stop"}"#,
+                            ]
+                            .join("\n")
+                            .into(),
+                        )),
+                    }
+                }
+            }
+        });
+        let model = OllamaLanguageModel {
+            id: LanguageModelId::from("llama3.1".to_string()),
+            model: ollama::Model::new("llama3.1"),
+            http_client,
+            request_limiter: RateLimiter::new(4),
+            in_flight: InFlightCompletions::default(),
+        };
+
+        let events = model.stream_completion(LanguageModelRequest::default(), &cx.to_async());
+        let task = cx.executor().spawn(async move {
+            events
+                .await?
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>>>()
+        });
+        for _ in 0..10 {
+            cx.executor().advance_clock(CONNECT_RETRY_BASE_DELAY * 4);
+            cx.run_until_parked();
+        }
+
+        assert_eq!(
+            task.await.unwrap(),
+            [
+                LanguageModelCompletionEvent::ModelLoading,
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Text("!".into()),
+            ]
+        );
+        assert_eq!(attempts.load(SeqCst), 3);
+    }
+
+    #[gpui::test]
+    async fn test_output_reserves_room_for_system_prompt(cx: &mut TestAppContext) {
+        let model = OllamaLanguageModel {
+            id: LanguageModelId::from("llama3.1".to_string()),
+            model: ollama::Model::new("llama3.1"),
+            http_client: FakeHttpClient::with_404_response(),
+            request_limiter: RateLimiter::new(4),
+            in_flight: InFlightCompletions::default(),
+        };
+        let message = |role, tokens| LanguageModelRequestMessage {
+            role,
+            content: "word".repeat(tokens),
+            attachments: Vec::new(),
+            cache: false,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::System, 100), message(Role::User, 200)],
+            ..Default::default()
+        };
+
+        let request = model
+            .to_ollama_request(request, &cx.to_async())
+            .await
+            .unwrap();
+        assert_eq!(
+            request.options.unwrap().num_predict,
+            Some((2048 - 200 - 100 - crate::SYSTEM_PROMPT_RESERVATION_MARGIN) as isize)
+        );
+    }
+
+    #[gpui::test]
+    async fn test_other_errors_are_not_retried(cx: &mut TestAppContext) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connect = {
+            let attempts = attempts.clone();
+            move || {
+                attempts.fetch_add(1, SeqCst);
+                async move { Err(anyhow!("model 'llama3.1' not found")) }
+            }
+        };
+        let events = connect_with_backoff(connect, cx.executor())
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(events.len(), 1);
+        assert!(events[0].is_err());
+        assert_eq!(attempts.load(SeqCst), 1);
+    }
+}
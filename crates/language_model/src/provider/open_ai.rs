@@ -1,15 +1,13 @@
 use anyhow::{anyhow, bail, Result};
 use collections::BTreeMap;
 use editor::{Editor, EditorElement, EditorStyle};
-use futures::{future::BoxFuture, FutureExt, StreamExt};
+use futures::{future::BoxFuture, FutureExt, Stream, StreamExt};
 use gpui::{
     AnyView, AppContext, AsyncAppContext, FontStyle, ModelContext, Subscription, Task, TextStyle,
     View, WhiteSpace,
 };
 use http_client::HttpClient;
-use open_ai::{
-    stream_completion, FunctionDefinition, ResponseStreamEvent, ToolChoice, ToolDefinition,
-};
+use open_ai::{FunctionDefinition, ResponseStreamEvent, ToolChoice, ToolDefinition};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use settings::{Settings, SettingsStore};
@@ -20,9 +18,14 @@ use ui::{prelude::*, Icon, IconName};
 use util::ResultExt;
 
 use crate::{
-    settings::AllLanguageModelSettings, LanguageModel, LanguageModelId, LanguageModelName,
-    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
-    LanguageModelProviderState, LanguageModelRequest, RateLimiter, Role,
+    count_tiktoken_tokens, count_tiktoken_tokens_batch, diagnose_provider_settings,
+    intercept_request, number_after, open_ai_capabilities, parse_api_keys,
+    settings::AllLanguageModelSettings, warm_up_after_authentication, with_api_key_failover,
+    ApiKeyRotation, InFlightCompletions, LanguageModel, LanguageModelCapabilities,
+    LanguageModelCompletionEvent, LanguageModelError, LanguageModelId, LanguageModelName,
+    LanguageModelProvider, LanguageModelProviderDiagnostic, LanguageModelProviderId,
+    LanguageModelProviderName, LanguageModelProviderState, LanguageModelRequest, RateLimiter,
+    RequestPriority, RequestTransform, StreamingSchemaValidator, TransformingHttpClient,
 };
 
 const PROVIDER_ID: &str = "openai";
@@ -33,22 +36,41 @@ pub struct OpenAiSettings {
     pub api_url: String,
     pub low_speed_timeout: Option<Duration>,
     pub available_models: Vec<AvailableModel>,
+    /// Ids of built-in models to hide from the model picker.
+    pub disabled_models: Vec<String>,
+    /// Whether to ask for token usage at the end of each streamed completion,
+    /// or `None` to only ask when `api_url` is OpenAI's own.
+    pub include_stream_usage: Option<bool>,
     pub needs_setting_migration: bool,
 }
 
+impl OpenAiSettings {
+    fn include_stream_usage(&self) -> bool {
+        self.include_stream_usage.unwrap_or_else(|| {
+            http_client::Url::parse(&self.api_url)
+                .map_or(false, |url| url.host_str() == Some("api.openai.com"))
+        })
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub struct AvailableModel {
     pub name: String,
+    /// The name displayed in the model picker, defaulting to `name`.
+    pub display_name: Option<String>,
     pub max_tokens: usize,
 }
 
 pub struct OpenAiLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
     state: gpui::Model<State>,
+    in_flight: InFlightCompletions,
 }
 
 pub struct State {
     api_key: Option<String>,
+    api_key_rotation: ApiKeyRotation,
+    http_client: Arc<dyn HttpClient>,
     _subscription: Subscription,
 }
 
@@ -69,13 +91,21 @@ impl State {
         })
     }
 
+    /// Saves the API key, after checking that OpenAI doesn't reject it.
     fn set_api_key(&mut self, api_key: String, cx: &mut ModelContext<Self>) -> Task<Result<()>> {
-        let settings = &AllLanguageModelSettings::get_global(cx).openai;
-        let write_credentials =
-            cx.write_credentials(&settings.api_url, "Bearer", api_key.as_bytes());
+        let api_url = AllLanguageModelSettings::get_global(cx)
+            .openai
+            .api_url
+            .clone();
+        let http_client = self.http_client.clone();
 
         cx.spawn(|this, mut cx| async move {
-            write_credentials.await?;
+            for key in parse_api_keys(&api_key) {
+                open_ai::validate_api_key(http_client.as_ref(), &api_url, &key).await?;
+            }
+
+            cx.update(|cx| cx.write_credentials(&api_url, "Bearer", api_key.as_bytes()))?
+                .await?;
             this.update(&mut cx, |this, cx| {
                 this.api_key = Some(api_key);
                 cx.notify();
@@ -114,12 +144,25 @@ impl OpenAiLanguageModelProvider {
     pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut AppContext) -> Self {
         let state = cx.new_model(|cx| State {
             api_key: None,
+            api_key_rotation: ApiKeyRotation::default(),
+            http_client: http_client.clone(),
             _subscription: cx.observe_global::<SettingsStore>(|_this: &mut State, cx| {
                 cx.notify();
             }),
         });
 
-        Self { http_client, state }
+        Self {
+            http_client,
+            state,
+            in_flight: InFlightCompletions::default(),
+        }
+    }
+
+    /// Applies `transform` to the body of every request the models send to
+    /// OpenAI, e.g. to adapt requests for a gateway in front of it.
+    pub fn with_request_transform(mut self, transform: RequestTransform) -> Self {
+        self.http_client = Arc::new(TransformingHttpClient::new(self.http_client, transform));
+        self
     }
 }
 
@@ -163,11 +206,17 @@ impl LanguageModelProvider for OpenAiLanguageModelProvider {
                 model.name.clone(),
                 open_ai::Model::Custom {
                     name: model.name.clone(),
+                    display_name: model.display_name.clone(),
                     max_tokens: model.max_tokens,
                 },
             );
         }
 
+        let disabled_models = &AllLanguageModelSettings::get_global(cx)
+            .openai
+            .disabled_models;
+        models.retain(|id, _| !disabled_models.contains(id));
+
         models
             .into_values()
             .map(|model| {
@@ -176,18 +225,41 @@ impl LanguageModelProvider for OpenAiLanguageModelProvider {
                     model,
                     state: self.state.clone(),
                     http_client: self.http_client.clone(),
-                    request_limiter: RateLimiter::new(4),
+                    request_limiter: RateLimiter::new(4).with_configured_max_queue_wait(cx),
+                    in_flight: self.in_flight.clone(),
                 }) as Arc<dyn LanguageModel>
             })
             .collect()
     }
 
+    fn diagnostics(&self, cx: &AppContext) -> Vec<LanguageModelProviderDiagnostic> {
+        let settings = &AllLanguageModelSettings::get_global(cx).openai;
+        diagnose_provider_settings(
+            "openai",
+            &settings.api_url,
+            settings
+                .available_models
+                .iter()
+                .map(|model| (model.name.as_str(), model.max_tokens)),
+            !self.provided_models(cx).is_empty(),
+        )
+    }
+
     fn is_authenticated(&self, cx: &AppContext) -> bool {
         self.state.read(cx).is_authenticated()
     }
 
     fn authenticate(&self, cx: &mut AppContext) -> Task<Result<()>> {
-        self.state.update(cx, |state, cx| state.authenticate(cx))
+        let was_authenticated = self.state.read(cx).is_authenticated();
+        let authenticate = self.state.update(cx, |state, cx| state.authenticate(cx));
+        if was_authenticated {
+            return authenticate;
+        }
+        let api_url = AllLanguageModelSettings::get_global(cx)
+            .openai
+            .api_url
+            .clone();
+        warm_up_after_authentication(authenticate, self.http_client.clone(), api_url, cx)
     }
 
     fn configuration_view(&self, cx: &mut WindowContext) -> AnyView {
@@ -198,6 +270,10 @@ impl LanguageModelProvider for OpenAiLanguageModelProvider {
     fn reset_credentials(&self, cx: &mut AppContext) -> Task<Result<()>> {
         self.state.update(cx, |state, cx| state.reset_api_key(cx))
     }
+
+    fn cancel_all(&self, _cx: &mut AppContext) {
+        self.in_flight.abort_all();
+    }
 }
 
 pub struct OpenAiLanguageModel {
@@ -206,38 +282,81 @@ pub struct OpenAiLanguageModel {
     state: gpui::Model<State>,
     http_client: Arc<dyn HttpClient>,
     request_limiter: RateLimiter,
+    in_flight: InFlightCompletions,
 }
 
 impl OpenAiLanguageModel {
     fn stream_completion(
         &self,
         request: open_ai::Request,
+        priority: RequestPriority,
         cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<futures::stream::BoxStream<'static, Result<ResponseStreamEvent>>>>
+    {
+        let events = self.stream_raw_completion(request, priority, cx);
+        async move {
+            Ok(events
+                .await?
+                .map(|event| open_ai::parse_response_stream_event(event?))
+                .boxed())
+        }
+        .boxed()
+    }
+
+    fn stream_raw_completion(
+        &self,
+        mut request: open_ai::Request,
+        priority: RequestPriority,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<futures::stream::BoxStream<'static, Result<serde_json::Value>>>>
     {
         let http_client = self.http_client.clone();
-        let Ok((api_key, api_url, low_speed_timeout)) = cx.read_model(&self.state, |state, cx| {
+        let Ok((
+            api_key,
+            api_key_rotation,
+            api_url,
+            low_speed_timeout,
+            include_stream_usage,
+            known_model_ids,
+        )) = cx.read_model(&self.state, |state, cx| {
             let settings = &AllLanguageModelSettings::get_global(cx).openai;
             (
                 state.api_key.clone(),
+                state.api_key_rotation.clone(),
                 settings.api_url.clone(),
                 settings.low_speed_timeout,
+                settings.include_stream_usage(),
+                known_model_ids(settings),
             )
-        }) else {
+        })
+        else {
             return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
         };
+        if include_stream_usage {
+            request.stream_options = Some(open_ai::StreamOptions {
+                include_usage: true,
+            });
+        }
 
-        let future = self.request_limiter.stream(async move {
-            let api_key = api_key.ok_or_else(|| anyhow!("missing api key"))?;
-            let request = stream_completion(
-                http_client.as_ref(),
-                &api_url,
-                &api_key,
-                request,
-                low_speed_timeout,
-            );
-            let response = request.await?;
-            Ok(response)
+        let future = self.request_limiter.stream(priority, async move {
+            let api_keys = api_key_rotation.keys_for_next_request(api_key.as_deref())?;
+            let (http_client, api_url, request) = (&http_client, &api_url, &request);
+            let is_rate_limit_error = |error: &anyhow::Error| error.is::<open_ai::RateLimitError>();
+            let response =
+                with_api_key_failover(api_keys, is_rate_limit_error, |api_key| async move {
+                    open_ai::stream_raw_completion(
+                        http_client.as_ref(),
+                        api_url,
+                        &api_key,
+                        request.clone(),
+                        low_speed_timeout,
+                    )
+                    .await
+                });
+            response
+                .await
+                .map_err(map_open_ai_error)
+                .map_err(|error| map_model_not_found(error, &request.model, &known_model_ids))
         });
 
         async move { Ok(future.await?.boxed()) }.boxed()
@@ -268,6 +387,10 @@ impl LanguageModel for OpenAiLanguageModel {
         self.model.max_token_count()
     }
 
+    fn capabilities(&self) -> LanguageModelCapabilities {
+        open_ai_capabilities(&self.model)
+    }
+
     fn count_tokens(
         &self,
         request: LanguageModelRequest,
@@ -276,14 +399,52 @@ impl LanguageModel for OpenAiLanguageModel {
         count_open_ai_tokens(request, self.model.clone(), cx)
     }
 
+    fn count_tokens_batch(
+        &self,
+        requests: Vec<LanguageModelRequest>,
+        cx: &AppContext,
+    ) -> BoxFuture<'static, Result<Vec<usize>>> {
+        count_open_ai_tokens_batch(requests, self.model.clone(), cx)
+    }
+
     fn stream_completion(
         &self,
         request: LanguageModelRequest,
         cx: &AsyncAppContext,
-    ) -> BoxFuture<'static, Result<futures::stream::BoxStream<'static, Result<String>>>> {
-        let request = request.into_open_ai(self.model.id().into());
-        let completions = self.stream_completion(request, cx);
-        async move { Ok(open_ai::extract_text_from_events(completions.await?).boxed()) }.boxed()
+    ) -> BoxFuture<
+        'static,
+        Result<futures::stream::BoxStream<'static, Result<LanguageModelCompletionEvent>>>,
+    > {
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        let priority = request.priority;
+        let request = match request.into_open_ai(self.model.id().into(), None) {
+            Ok(request) => request,
+            Err(error) => {
+                return interceptors.intercept(futures::future::ready(Err(error)).boxed())
+            }
+        };
+        let completions = self.stream_completion(request, priority, cx);
+        interceptors.intercept(self.in_flight.track(
+            async move { Ok(map_to_language_model_completion_events(completions.await?).boxed()) }
+                .boxed(),
+        ))
+    }
+
+    fn stream_raw(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<futures::stream::BoxStream<'static, Result<serde_json::Value>>>>
+    {
+        // Raw chunks aren't completion events, so interceptors only see the request.
+        let (request, _) = intercept_request(request, self.provider_id(), self.id(), cx);
+        let priority = request.priority;
+        let request = match request.into_open_ai(self.model.id().into(), None) {
+            Ok(request) => request,
+            Err(error) => return futures::future::ready(Err(error)).boxed(),
+        };
+        self.in_flight
+            .track(self.stream_raw_completion(request, priority, cx))
     }
 
     fn use_any_tool(
@@ -294,7 +455,14 @@ impl LanguageModel for OpenAiLanguageModel {
         schema: serde_json::Value,
         cx: &AsyncAppContext,
     ) -> BoxFuture<'static, Result<serde_json::Value>> {
-        let mut request = request.into_open_ai(self.model.id().into());
+        let (request, interceptors) = intercept_request(request, self.provider_id(), self.id(), cx);
+        let priority = request.priority;
+        let mut request = match request.into_open_ai(self.model.id().into(), None) {
+            Ok(request) => request,
+            Err(error) => {
+                return interceptors.intercept_tool_use(futures::future::ready(Err(error)).boxed())
+            }
+        };
         let mut function = FunctionDefinition {
             name: tool_name.clone(),
             description: None,
@@ -306,82 +474,209 @@ impl LanguageModel for OpenAiLanguageModel {
         request.tool_choice = Some(ToolChoice::Other(func.clone()));
         // Fill in description and params separately, as they're not needed for tool_choice field.
         function.description = Some(tool_description);
+        let mut schema_validator = StreamingSchemaValidator::new(schema.clone());
         function.parameters = Some(schema);
         request.tools = vec![ToolDefinition::Function { function }];
-        let response = self.stream_completion(request, cx);
-        self.request_limiter
-            .run(async move {
-                let mut response = response.await?;
-
-                // Call arguments are gonna be streamed in over multiple chunks.
-                let mut load_state = None;
-                while let Some(Ok(part)) = response.next().await {
-                    for choice in part.choices {
-                        let Some(tool_calls) = choice.delta.tool_calls else {
-                            continue;
-                        };
-
-                        for call in tool_calls {
-                            if let Some(func) = call.function {
-                                if func.name.as_deref() == Some(tool_name.as_str()) {
-                                    load_state = Some((String::default(), call.index));
-                                }
-                                if let Some((arguments, (output, index))) =
-                                    func.arguments.zip(load_state.as_mut())
-                                {
-                                    if call.index == *index {
-                                        output.push_str(&arguments);
+        let response = self.stream_completion(request, priority, cx);
+        interceptors.intercept_tool_use(
+            self.in_flight.track_future(
+                self.request_limiter
+                    .run(priority, async move {
+                        let mut response = response.await?;
+
+                        // Call arguments are gonna be streamed in over multiple chunks.
+                        let mut load_state = None;
+                        while let Some(Ok(part)) = response.next().await {
+                            for choice in part.choices {
+                                let Some(tool_calls) = choice.delta.tool_calls else {
+                                    continue;
+                                };
+
+                                for call in tool_calls {
+                                    if let Some(func) = call.function {
+                                        if func.name.as_deref() == Some(tool_name.as_str()) {
+                                            load_state = Some((String::default(), call.index));
+                                        }
+                                        if let Some((arguments, (output, index))) =
+                                            func.arguments.zip(load_state.as_mut())
+                                        {
+                                            if call.index == *index {
+                                                schema_validator.push(&arguments)?;
+                                                output.push_str(&arguments);
+                                            }
+                                        }
                                     }
                                 }
                             }
                         }
-                    }
+                        if let Some((arguments, _)) = load_state {
+                            return Ok(serde_json::from_str(&arguments)?);
+                        } else {
+                            bail!("tool not used");
+                        }
+                    })
+                    .boxed(),
+            ),
+        )
+    }
+}
+
+pub fn map_to_language_model_completion_events(
+    events: impl Stream<Item = Result<ResponseStreamEvent>>,
+) -> impl Stream<Item = Result<LanguageModelCompletionEvent>> {
+    // Every chunk echoes the model, so only report it once. Usage is cumulative
+    // and only sent when `include_usage` is set, so remember the last total.
+    let mut reported_model = false;
+    let mut reported_input_tokens = 0;
+    let mut reported_output_tokens = 0;
+    events.flat_map(move |event| {
+        let mut completion_events = Vec::new();
+        match event {
+            Ok(mut event) => {
+                if !reported_model && !event.model.is_empty() {
+                    reported_model = true;
+                    completion_events
+                        .push(Ok(LanguageModelCompletionEvent::ReportedModel(event.model)));
                 }
-                if let Some((arguments, _)) = load_state {
-                    return Ok(serde_json::from_str(&arguments)?);
-                } else {
-                    bail!("tool not used");
+                if let Some(text) = event.choices.pop().and_then(|choice| choice.delta.content) {
+                    completion_events.push(Ok(LanguageModelCompletionEvent::Text(text)));
                 }
-            })
-            .boxed()
+                if let Some(usage) = event.usage {
+                    let input_tokens = usage.prompt_tokens.saturating_sub(reported_input_tokens);
+                    let output_tokens = usage
+                        .completion_tokens
+                        .saturating_sub(reported_output_tokens);
+                    if input_tokens > 0 || output_tokens > 0 {
+                        completion_events.push(Ok(LanguageModelCompletionEvent::UsageUpdate {
+                            input_tokens: input_tokens as usize,
+                            output_tokens: output_tokens as usize,
+                        }));
+                        reported_input_tokens = reported_input_tokens.max(usage.prompt_tokens);
+                        reported_output_tokens =
+                            reported_output_tokens.max(usage.completion_tokens);
+                    }
+                }
+            }
+            Err(error) => completion_events.push(Err(error)),
+        }
+        futures::stream::iter(completion_events)
+    })
+}
+
+/// Converts the error OpenAI reports for prompts that don't fit in the model's
+/// context window into [`LanguageModelError::ContextWindowExceeded`], leaving
+/// other errors untouched.
+fn map_open_ai_error(error: anyhow::Error) -> anyhow::Error {
+    match error.downcast_ref::<open_ai::ContextLengthExceededError>() {
+        // e.g. "This model's maximum context length is 8192 tokens. However, ..."
+        Some(context_length_exceeded) => anyhow!(LanguageModelError::ContextWindowExceeded {
+            limit: number_after(
+                &context_length_exceeded.message,
+                "maximum context length is"
+            ),
+        }),
+        None => error,
     }
 }
 
+/// Converts the error OpenAI reports for a model it doesn't know into
+/// [`LanguageModelError::ModelNotFound`], leaving other errors untouched.
+fn map_model_not_found(
+    error: anyhow::Error,
+    requested: &str,
+    known_model_ids: &[String],
+) -> anyhow::Error {
+    if error.is::<open_ai::ModelNotFoundError>() {
+        anyhow!(LanguageModelError::model_not_found(
+            requested,
+            known_model_ids.iter().map(String::as_str)
+        ))
+    } else {
+        error
+    }
+}
+
+/// The ids of the built-in and configured models, which a model id OpenAI
+/// doesn't know is compared against.
+fn known_model_ids(settings: &OpenAiSettings) -> Vec<String> {
+    open_ai::Model::iter()
+        .filter(|model| !matches!(model, open_ai::Model::Custom { .. }))
+        .map(|model| model.id().to_string())
+        .chain(
+            settings
+                .available_models
+                .iter()
+                .map(|model| model.name.clone()),
+        )
+        .collect()
+}
+
 pub fn count_open_ai_tokens(
     request: LanguageModelRequest,
     model: open_ai::Model,
     cx: &AppContext,
 ) -> BoxFuture<'static, Result<usize>> {
+    let assets_dir = AllLanguageModelSettings::get_global(cx)
+        .tokenizer_assets_dir
+        .clone();
     cx.background_executor()
         .spawn(async move {
-            let messages = request
-                .messages
-                .into_iter()
-                .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
-                    role: match message.role {
-                        Role::User => "user".into(),
-                        Role::Assistant => "assistant".into(),
-                        Role::System => "system".into(),
-                    },
-                    content: Some(message.content),
-                    name: None,
-                    function_call: None,
-                })
-                .collect::<Vec<_>>();
+            count_tiktoken_tokens(tiktoken_model_id(&model), request, assets_dir.as_deref())
+        })
+        .boxed()
+}
 
-            if let open_ai::Model::Custom { .. } = model {
-                tiktoken_rs::num_tokens_from_messages("gpt-4", &messages)
-            } else {
-                tiktoken_rs::num_tokens_from_messages(model.id(), &messages)
-            }
+/// Counts the tokens in each of `requests` in one pass on the background
+/// executor, sharing the model's tokenizer between them.
+pub fn count_open_ai_tokens_batch(
+    requests: Vec<LanguageModelRequest>,
+    model: open_ai::Model,
+    cx: &AppContext,
+) -> BoxFuture<'static, Result<Vec<usize>>> {
+    let assets_dir = AllLanguageModelSettings::get_global(cx)
+        .tokenizer_assets_dir
+        .clone();
+    cx.background_executor()
+        .spawn(async move {
+            count_tiktoken_tokens_batch(tiktoken_model_id(&model), requests, assets_dir.as_deref())
         })
         .boxed()
 }
 
+fn tiktoken_model_id(model: &open_ai::Model) -> &str {
+    if let open_ai::Model::Custom { .. } = model {
+        "gpt-4"
+    } else {
+        model.id()
+    }
+}
+
+/// Returns whether `api_url` points at a gateway that serves Anthropic models
+/// through an OpenAI-compatible API, where the native Anthropic provider would
+/// offer features like tool use and prompt caching.
+fn is_anthropic_compatible_gateway(api_url: &str) -> bool {
+    let api_url = api_url.trim().to_ascii_lowercase();
+    let without_scheme = api_url
+        .split_once("://")
+        .map_or(api_url.as_str(), |(_, rest)| rest);
+    let (authority, path) = without_scheme
+        .split_once('/')
+        .unwrap_or((without_scheme, ""));
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    let host = host.split(':').next().unwrap_or(host);
+
+    host == "anthropic.com"
+        || host.ends_with(".anthropic.com")
+        || path
+            .split('/')
+            .any(|segment| segment == "anthropic" || segment == "claude")
+}
+
 struct ConfigurationView {
     api_key_editor: View<Editor>,
     state: gpui::Model<State>,
     load_credentials_task: Option<Task<()>>,
+    api_key_error: Option<SharedString>,
 }
 
 impl ConfigurationView {
@@ -420,6 +715,7 @@ impl ConfigurationView {
             api_key_editor,
             state,
             load_credentials_task,
+            api_key_error: None,
         }
     }
 
@@ -429,11 +725,19 @@ impl ConfigurationView {
             return;
         }
 
+        self.api_key_error = None;
         let state = self.state.clone();
-        cx.spawn(|_, mut cx| async move {
-            state
+        cx.spawn(|this, mut cx| async move {
+            let result = state
                 .update(&mut cx, |state, cx| state.set_api_key(api_key, cx))?
-                .await
+                .await;
+            if let Err(error) = &result {
+                this.update(&mut cx, |this, cx| {
+                    this.api_key_error = Some(error.to_string().into());
+                    cx.notify();
+                })?;
+            }
+            result
         })
         .detach_and_log_err(cx);
 
@@ -489,15 +793,31 @@ impl ConfigurationView {
 
 impl Render for ConfigurationView {
     fn render(&mut self, cx: &mut ViewContext<Self>) -> impl IntoElement {
-        const INSTRUCTIONS: [&str; 6] = [
+        const INSTRUCTIONS: [&str; 7] = [
             "To use the assistant panel or inline assistant, you need to add your OpenAI API key.",
             " - You can create an API key at: platform.openai.com/api-keys",
             " - Make sure your OpenAI account has credits",
             " - Having a subscription for another service like GitHub Copilot won't work.",
+            " - To spread requests across several keys, separate them with commas.",
             "",
             "Paste your OpenAI API key below and hit enter to use the assistant:",
         ];
 
+        let gateway_warning = is_anthropic_compatible_gateway(
+            &AllLanguageModelSettings::get_global(cx).openai.api_url,
+        )
+        .then(|| {
+            h_flex()
+                .gap_1()
+                .child(Icon::new(IconName::ExclamationTriangle).color(Color::Warning))
+                .child(
+                    Label::new(
+                        "This API URL looks like an Anthropic gateway. Use the Anthropic provider for native features like tool use and prompt caching.",
+                    )
+                    .size(LabelSize::Small),
+                )
+        });
+
         if self.load_credentials_task.is_some() {
             div().child(Label::new("Loading credentials...")).into_any()
         } else if self.should_render_editor(cx) {
@@ -517,31 +837,530 @@ impl Render for ConfigurationView {
                         .rounded_md()
                         .child(self.render_api_key_editor(cx)),
                 )
+                .children(self.api_key_error.clone().map(|error| {
+                    h_flex()
+                        .gap_1()
+                        .mb_2()
+                        .child(Icon::new(IconName::ExclamationTriangle).color(Color::Error))
+                        .child(Label::new(error).size(LabelSize::Small).color(Color::Error))
+                }))
                 .child(
                     Label::new(
                         "You can also assign the OPENAI_API_KEY environment variable and restart Zed.",
                     )
                     .size(LabelSize::Small),
                 )
+                .children(gateway_warning)
                 .into_any()
         } else {
-            h_flex()
+            v_flex()
                 .size_full()
-                .justify_between()
+                .gap_2()
                 .child(
                     h_flex()
-                        .gap_1()
-                        .child(Icon::new(IconName::Check).color(Color::Success))
-                        .child(Label::new("API key configured.")),
-                )
-                .child(
-                    Button::new("reset-key", "Reset key")
-                        .icon(Some(IconName::Trash))
-                        .icon_size(IconSize::Small)
-                        .icon_position(IconPosition::Start)
-                        .on_click(cx.listener(|this, _, cx| this.reset_api_key(cx))),
+                        .w_full()
+                        .justify_between()
+                        .child(
+                            h_flex()
+                                .gap_1()
+                                .child(Icon::new(IconName::Check).color(Color::Success))
+                                .child(Label::new("API key configured.")),
+                        )
+                        .child(
+                            Button::new("reset-key", "Reset key")
+                                .icon(Some(IconName::Trash))
+                                .icon_size(IconSize::Small)
+                                .icon_position(IconPosition::Start)
+                                .on_click(cx.listener(|this, _, cx| this.reset_api_key(cx))),
+                        ),
                 )
+                .children(gateway_warning)
                 .into_any()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{LanguageModelRequestMessage, Role};
+    use gpui::TestAppContext;
+    use http_client::{
+        fault_injection::{Fault, FaultInjectingHttpClient},
+        FakeHttpClient,
+    };
+    use open_ai::stream_completion;
+
+    #[gpui::test]
+    async fn test_completion_events() {
+        let events = [
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o-2024-05-13","choices":[{"index":0,"delta":{"role":"assistant","content":""},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o-2024-05-13","choices":[{"index":0,"delta":{"content":"Hello!"},"finish_reason":null}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o-2024-05-13","choices":[{"index":0,"delta":{},"finish_reason":"stop"}]}"#,
+            r#"{"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o-2024-05-13","choices":[],"usage":{"prompt_tokens":9,"completion_tokens":3,"total_tokens":12}}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<ResponseStreamEvent>(event).unwrap()));
+
+        let events = map_to_language_model_completion_events(futures::stream::iter(events))
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::ReportedModel("gpt-4o-2024-05-13".into()),
+                LanguageModelCompletionEvent::Text(String::new()),
+                LanguageModelCompletionEvent::Text("Hello!".into()),
+                LanguageModelCompletionEvent::UsageUpdate {
+                    input_tokens: 9,
+                    output_tokens: 3,
+                },
+            ]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_replay_recorded_completion() {
+        let http_client = http_client::cassette::ReplayHttpClient::load(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/test_data/cassettes/open_ai_stream_completion.json"
+        ))
+        .unwrap();
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Say hello to everyone in French".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        }
+        .into_open_ai("gpt-4o-mini".into(), None)
+        .unwrap();
+
+        let response =
+            stream_completion(&http_client, open_ai::OPEN_AI_API_URL, "key", request, None)
+                .await
+                .unwrap();
+        let events = map_to_language_model_completion_events(response)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(
+            events,
+            vec![
+                LanguageModelCompletionEvent::ReportedModel("gpt-4o-mini-2024-07-18".into()),
+                LanguageModelCompletionEvent::Text(String::new()),
+                LanguageModelCompletionEvent::Text("Bonjour".into()),
+                LanguageModelCompletionEvent::Text(" à".into()),
+                LanguageModelCompletionEvent::Text(" tous".into()),
+                LanguageModelCompletionEvent::Text(" 👋".into()),
+                LanguageModelCompletionEvent::UsageUpdate {
+                    input_tokens: 14,
+                    output_tokens: 5,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_include_stream_usage() {
+        let settings = |api_url: &str, include_stream_usage| OpenAiSettings {
+            api_url: api_url.into(),
+            include_stream_usage,
+            ..Default::default()
+        };
+        assert!(settings(open_ai::OPEN_AI_API_URL, None).include_stream_usage());
+        assert!(!settings("http://localhost:8000/v1", None).include_stream_usage());
+        assert!(settings("http://localhost:8000/v1", Some(true)).include_stream_usage());
+        assert!(!settings(open_ai::OPEN_AI_API_URL, Some(false)).include_stream_usage());
+    }
+
+    #[gpui::test]
+    async fn test_rate_limited_key_fails_over() {
+        let authorizations = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let authorizations = authorizations.clone();
+            move |request| {
+                authorizations.lock().push(
+                    request.headers()["Authorization"]
+                        .to_str()
+                        .unwrap()
+                        .to_string(),
+                );
+                async move {
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(
+                            concat!(
+                                r#"data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o","choices":[{"index":0,"delta":{"content":"Hello!"},"finish_reason":"stop"}]}"#,
+                                "\n\ndata: [DONE]\n\n"
+                            )
+                            .into(),
+                        )
+                        .unwrap())
+                }
+            }
+        });
+        // The first request is rate limited before it reaches the API.
+        let http_client = FaultInjectingHttpClient::new(
+            http_client,
+            [Fault::Status(http_client::StatusCode::TOO_MANY_REQUESTS)],
+        );
+
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Hello".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        }
+        .into_open_ai("gpt-4o".into(), None)
+        .unwrap();
+        let (http_client, request) = (&http_client, &request);
+        let is_rate_limit_error = |error: &anyhow::Error| error.is::<open_ai::RateLimitError>();
+        let response = with_api_key_failover(
+            vec!["key-a".into(), "key-b".into()],
+            is_rate_limit_error,
+            |api_key| async move {
+                stream_completion(
+                    http_client,
+                    open_ai::OPEN_AI_API_URL,
+                    &api_key,
+                    request.clone(),
+                    None,
+                )
+                .await
+            },
+        )
+        .await
+        .unwrap();
+
+        let events = map_to_language_model_completion_events(response)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert!(events.contains(&LanguageModelCompletionEvent::Text("Hello!".into())));
+        assert_eq!(*authorizations.lock(), ["Bearer key-b"]);
+    }
+
+    #[gpui::test]
+    async fn test_raw_events() {
+        let http_client = FakeHttpClient::create(|_| async move {
+            Ok(http_client::Response::builder()
+                .status(200)
+                .body(
+                    concat!(
+                        r#"data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1720000000,"model":"gpt-4o","system_fingerprint":"fp_44709d6fcb","choices":[{"index":0,"delta":{"content":"Hi"},"logprobs":{"content":[]},"finish_reason":"stop"}]}"#,
+                        "\n\ndata: [DONE]\n\n"
+                    )
+                    .into(),
+                )
+                .unwrap())
+        });
+        let request = LanguageModelRequest::default()
+            .into_open_ai("gpt-4o".into(), None)
+            .unwrap();
+
+        let events = open_ai::stream_raw_completion(
+            http_client.as_ref(),
+            open_ai::OPEN_AI_API_URL,
+            "key",
+            request,
+            None,
+        )
+        .await
+        .unwrap()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>()
+        .await;
+        assert_eq!(events.len(), 1);
+        // Fields that `ResponseStreamEvent` doesn't model are kept.
+        assert_eq!(events[0]["system_fingerprint"], "fp_44709d6fcb");
+        assert_eq!(
+            events[0]["choices"][0]["logprobs"],
+            serde_json::json!({ "content": [] })
+        );
+
+        let event = open_ai::parse_response_stream_event(events[0].clone()).unwrap();
+        assert_eq!(event.choices[0].delta.content.as_deref(), Some("Hi"));
+    }
+
+    #[gpui::test]
+    async fn test_context_window_exceeded() {
+        let http_client = FakeHttpClient::create(|_| async move {
+            Ok(http_client::Response::builder()
+                .status(400)
+                .body(
+                    r#"{"error":{"message":"This model's maximum context length is 8192 tokens. However, your messages resulted in 9120 tokens. Please reduce the length of the messages.","type":"invalid_request_error","param":"messages","code":"context_length_exceeded"}}"#
+                        .into(),
+                )
+                .unwrap())
+        });
+
+        let request = LanguageModelRequest::default()
+            .into_open_ai("gpt-4".into(), None)
+            .unwrap();
+        let error = stream_completion(
+            http_client.as_ref(),
+            open_ai::OPEN_AI_API_URL,
+            "key",
+            request,
+            None,
+        )
+        .await
+        .map(|_| ())
+        .map_err(map_open_ai_error)
+        .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::ContextWindowExceeded { limit: Some(8192) })
+        );
+
+        let error = map_open_ai_error(anyhow!("Failed to connect to OpenAI API: 500"));
+        assert!(error.downcast_ref::<LanguageModelError>().is_none());
+    }
+
+    #[gpui::test]
+    async fn test_model_not_found() {
+        let http_client = FakeHttpClient::create(|_| async move {
+            Ok(http_client::Response::builder()
+                .status(404)
+                .body(
+                    r#"{"error":{"message":"The model `gpt-4o-mnii` does not exist or you do not have access to it.","type":"invalid_request_error","param":null,"code":"model_not_found"}}"#
+                        .into(),
+                )
+                .unwrap())
+        });
+
+        let request = LanguageModelRequest::default()
+            .into_open_ai("gpt-4o-mnii".into(), None)
+            .unwrap();
+        let known_model_ids = known_model_ids(&OpenAiSettings::default());
+        let error = stream_completion(
+            http_client.as_ref(),
+            open_ai::OPEN_AI_API_URL,
+            "key",
+            request,
+            None,
+        )
+        .await
+        .map(|_| ())
+        .map_err(map_open_ai_error)
+        .map_err(|error| map_model_not_found(error, "gpt-4o-mnii", &known_model_ids))
+        .unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::ModelNotFound {
+                requested: "gpt-4o-mnii".into(),
+                available: vec!["gpt-4o-mini".into()],
+            })
+        );
+        assert_eq!(
+            error.to_string(),
+            "The model \"gpt-4o-mnii\" wasn't found. Did you mean \"gpt-4o-mini\"?"
+        );
+    }
+
+    #[test]
+    fn test_is_anthropic_compatible_gateway() {
+        assert!(is_anthropic_compatible_gateway(
+            "https://api.anthropic.com/v1"
+        ));
+        assert!(is_anthropic_compatible_gateway(
+            "https://gateway.ai.cloudflare.com/v1/account/gateway/anthropic"
+        ));
+        assert!(is_anthropic_compatible_gateway(
+            "HTTPS://proxy.example.com:8443/Claude/v1/"
+        ));
+
+        assert!(!is_anthropic_compatible_gateway(
+            "https://api.openai.com/v1"
+        ));
+        assert!(!is_anthropic_compatible_gateway("http://localhost:4000/v1"));
+        assert!(!is_anthropic_compatible_gateway(
+            "https://notanthropic.com/v1"
+        ));
+        assert!(!is_anthropic_compatible_gateway(
+            "https://example.com/anthropic-docs/v1"
+        ));
+    }
+
+    #[gpui::test]
+    fn test_disabled_models(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        let provider = cx
+            .update(|cx| OpenAiLanguageModelProvider::new(FakeHttpClient::with_404_response(), cx));
+        let model_ids = |cx: &mut TestAppContext| {
+            cx.update(|cx| {
+                provider
+                    .provided_models(cx)
+                    .iter()
+                    .map(|model| model.id().0.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+        assert!(model_ids(cx).contains(&"gpt-3.5-turbo".to_string()));
+
+        cx.update(|cx| {
+            cx.update_global(|store: &mut SettingsStore, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "openai": {
+                                    "version": "1",
+                                    "disabled_models": ["gpt-3.5-turbo"]
+                                }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+        });
+
+        let remaining_model_ids = model_ids(cx);
+        assert!(!remaining_model_ids.contains(&"gpt-3.5-turbo".to_string()));
+        assert!(remaining_model_ids.contains(&"gpt-4o".to_string()));
+    }
+
+    #[gpui::test]
+    fn test_custom_model_display_name(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+            cx.update_global(|store: &mut SettingsStore, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "openai": {
+                                    "version": "1",
+                                    "available_models": [
+                                        {
+                                            "name": "gateway/openai/gpt-4o-2024-08-06",
+                                            "display_name": "GPT-4o (Gateway)",
+                                            "max_tokens": 128000
+                                        },
+                                        {
+                                            "name": "gateway/openai/o1-mini",
+                                            "max_tokens": 128000
+                                        }
+                                    ]
+                                }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+        });
+
+        let provider = cx
+            .update(|cx| OpenAiLanguageModelProvider::new(FakeHttpClient::with_404_response(), cx));
+        let names = cx.update(|cx| {
+            provider
+                .provided_models(cx)
+                .iter()
+                .map(|model| (model.id().0.to_string(), model.name().0.to_string()))
+                .collect::<collections::HashMap<_, _>>()
+        });
+        assert_eq!(
+            names["gateway/openai/gpt-4o-2024-08-06"],
+            "GPT-4o (Gateway)"
+        );
+        assert_eq!(names["gateway/openai/o1-mini"], "gateway/openai/o1-mini");
+    }
+
+    #[gpui::test]
+    async fn test_reject_api_key_without_permissions(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        // A restricted key that can't list models.
+        let http_client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/v1/models");
+            Ok(http_client::Response::builder()
+                .status(403)
+                .body(Default::default())
+                .unwrap())
+        });
+        let provider = cx.update(|cx| OpenAiLanguageModelProvider::new(http_client, cx));
+
+        let error = provider
+            .state
+            .update(cx, |state, cx| {
+                state.set_api_key("sk-restricted".into(), cx)
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The API key doesn't have permission to list models. Use a key with at least read access to models."
+        );
+        assert!(!cx.read(|cx| provider.is_authenticated(cx)));
+    }
+
+    #[gpui::test]
+    fn test_misconfigured_provider_diagnostics(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        let provider = cx
+            .update(|cx| OpenAiLanguageModelProvider::new(FakeHttpClient::with_404_response(), cx));
+        assert!(cx.update(|cx| provider.diagnostics(cx)).is_empty());
+
+        cx.update(|cx| {
+            cx.update_global(|store: &mut SettingsStore, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "openai": {
+                                    "version": "1",
+                                    "api_url": "localhost:4000/v1",
+                                    "available_models": [
+                                        { "name": "llama-3.1-70b", "max_tokens": 0 }
+                                    ]
+                                }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+        });
+
+        let messages = cx.update(|cx| {
+            provider
+                .diagnostics(cx)
+                .into_iter()
+                .map(|diagnostic| diagnostic.message.to_string())
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(
+            messages,
+            [
+                "`language_models.openai.api_url` is not a valid URL: \"localhost:4000/v1\". \
+                 Set it to an http:// or https:// URL, or remove it to use the default.",
+                "Model \"llama-3.1-70b\" in `language_models.openai.available_models` has a \
+                 `max_tokens` of 0. Set it to the size of the model's context window.",
+            ]
+        );
+    }
+}
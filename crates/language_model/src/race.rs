@@ -0,0 +1,85 @@
+use crate::{LanguageModel, LanguageModelCompletionEvent, LanguageModelRequest};
+use anyhow::{bail, Result};
+use futures::{
+    future::{self, BoxFuture},
+    stream::{self, BoxStream},
+    FutureExt, StreamExt,
+};
+use gpui::AsyncAppContext;
+use std::sync::Arc;
+
+/// Sends the same request to each of `models`, typically from different
+/// providers, and streams the completion of whichever produces an event first.
+///
+/// The other completions are dropped, which cancels them, as soon as there's a
+/// winner. Models whose completion fails are out of the race, and if they all
+/// fail, the last error is returned.
+pub fn race_completion(
+    request: LanguageModelRequest,
+    models: &[Arc<dyn LanguageModel>],
+    cx: &AsyncAppContext,
+) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+    let contenders = models
+        .iter()
+        .map(|model| {
+            let completion = model.stream_completion(request.clone(), cx);
+            async move {
+                let mut events = completion.await?;
+                match events.next().await {
+                    Some(Ok(first_event)) => Ok(stream::once(future::ready(Ok(first_event)))
+                        .chain(events)
+                        .boxed()),
+                    Some(Err(error)) => Err(error),
+                    None => Ok(stream::empty().boxed()),
+                }
+            }
+            .boxed()
+        })
+        .collect::<Vec<_>>();
+
+    async move {
+        if contenders.is_empty() {
+            bail!("no models to race");
+        }
+        let (events, _losers) = future::select_ok(contenders).await?;
+        Ok(events)
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::fake::FakeLanguageModel;
+    use gpui::TestAppContext;
+
+    #[gpui::test]
+    async fn test_fastest_model_wins_the_race(cx: &mut TestAppContext) {
+        let slow = Arc::new(FakeLanguageModel::default());
+        let fast = Arc::new(FakeLanguageModel::default());
+        let models: Vec<Arc<dyn LanguageModel>> = vec![slow.clone(), fast.clone()];
+        let request = LanguageModelRequest::default();
+
+        let race = cx
+            .executor()
+            .spawn(race_completion(request.clone(), &models, &cx.to_async()));
+        cx.run_until_parked();
+        assert_eq!(slow.completion_count(), 1);
+        assert_eq!(fast.completion_count(), 1);
+
+        fast.stream_last_completion_response("Hello".into());
+        let mut events = race.await.unwrap();
+        assert!(slow.is_completion_stream_closed(&request));
+        assert!(!fast.is_completion_stream_closed(&request));
+
+        fast.stream_last_completion_response(" world".into());
+        fast.end_last_completion_stream();
+        let mut text = String::new();
+        while let Some(event) = events.next().await {
+            if let LanguageModelCompletionEvent::Text(chunk) = event.unwrap() {
+                text.push_str(&chunk);
+            }
+        }
+        assert_eq!(text, "Hello world");
+    }
+}
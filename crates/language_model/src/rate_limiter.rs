@@ -1,21 +1,100 @@
-use anyhow::Result;
-use futures::Stream;
-use smol::lock::{Semaphore, SemaphoreGuardArc};
+use crate::{settings::AllLanguageModelSettings, LanguageModelError, RequestPriority};
+use anyhow::{anyhow, Result};
+use futures::{
+    future::{self, Either},
+    Stream,
+};
+use gpui::{AppContext, BackgroundExecutor};
+use parking_lot::Mutex;
+use settings::Settings;
 use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
     future::Future,
     pin::Pin,
     sync::Arc,
-    task::{Context, Poll},
+    task::{Context, Poll, Waker},
+    time::Duration,
 };
 
+/// Waiting requests are ordered by priority, then by when they were made.
+type Ticket = (Reverse<RequestPriority>, usize);
+
 #[derive(Clone)]
 pub struct RateLimiter {
-    semaphore: Arc<Semaphore>,
+    state: Arc<Mutex<State>>,
+    max_queue_wait: Option<(Duration, BackgroundExecutor)>,
+}
+
+struct State {
+    available_slots: usize,
+    next_ticket: usize,
+    waiting: BTreeMap<Ticket, Option<Waker>>,
+}
+
+impl State {
+    fn wake_next(&mut self) {
+        if self.available_slots > 0 {
+            if let Some(waker) = self.waiting.values_mut().next().and_then(Option::take) {
+                waker.wake();
+            }
+        }
+    }
+}
+
+struct Slot {
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = self.state.lock();
+        state.available_slots += 1;
+        state.wake_next();
+    }
+}
+
+struct AcquireSlot {
+    state: Arc<Mutex<State>>,
+    ticket: Ticket,
+    acquired: bool,
+}
+
+impl Future for AcquireSlot {
+    type Output = Slot;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.state.lock();
+        let is_next = state.waiting.keys().next() == Some(&this.ticket);
+        if is_next && state.available_slots > 0 {
+            state.waiting.remove(&this.ticket);
+            state.available_slots -= 1;
+            state.wake_next();
+            this.acquired = true;
+            Poll::Ready(Slot {
+                state: this.state.clone(),
+            })
+        } else {
+            state.waiting.insert(this.ticket, Some(cx.waker().clone()));
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for AcquireSlot {
+    fn drop(&mut self) {
+        if !self.acquired {
+            let mut state = self.state.lock();
+            state.waiting.remove(&self.ticket);
+            state.wake_next();
+        }
+    }
 }
 
 pub struct RateLimitGuard<T> {
     inner: T,
-    _guard: SemaphoreGuardArc,
+    _slot: Slot,
 }
 
 impl<T> Stream for RateLimitGuard<T>
@@ -32,39 +111,181 @@ where
 impl RateLimiter {
     pub fn new(limit: usize) -> Self {
         Self {
-            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Arc::new(Mutex::new(State {
+                available_slots: limit,
+                next_ticket: 0,
+                waiting: BTreeMap::new(),
+            })),
+            max_queue_wait: None,
+        }
+    }
+
+    /// Returns a limiter sharing this one's slots, whose requests fail with
+    /// [`LanguageModelError::QueueTimeout`] if they aren't admitted within
+    /// `max_wait`.
+    pub fn with_max_queue_wait(&self, max_wait: Duration, executor: BackgroundExecutor) -> Self {
+        Self {
+            state: self.state.clone(),
+            max_queue_wait: Some((max_wait, executor)),
+        }
+    }
+
+    /// Applies the `language_models.max_queue_wait_in_seconds` setting, if
+    /// it's set.
+    pub fn with_configured_max_queue_wait(self, cx: &AppContext) -> Self {
+        match AllLanguageModelSettings::get_global(cx).max_queue_wait {
+            Some(max_wait) => self.with_max_queue_wait(max_wait, cx.background_executor().clone()),
+            None => self,
+        }
+    }
+
+    /// Queues for a slot right away, so that requests of the same priority are
+    /// admitted in the order they were made.
+    fn acquire(&self, priority: RequestPriority) -> impl Future<Output = Result<Slot>> {
+        let slot = {
+            let mut state = self.state.lock();
+            let ticket = (Reverse(priority), state.next_ticket);
+            state.next_ticket += 1;
+            state.waiting.insert(ticket, None);
+            AcquireSlot {
+                state: self.state.clone(),
+                ticket,
+                acquired: false,
+            }
+        };
+        let timeout = self
+            .max_queue_wait
+            .as_ref()
+            .map(|(max_wait, executor)| (*max_wait, executor.timer(*max_wait)));
+
+        async move {
+            let Some((max_wait, timer)) = timeout else {
+                return Ok(slot.await);
+            };
+            match future::select(slot, timer).await {
+                Either::Left((slot, _)) => Ok(slot),
+                Either::Right(_) => Err(anyhow!(LanguageModelError::QueueTimeout {
+                    waited: max_wait
+                })),
+            }
         }
     }
 
-    pub fn run<'a, Fut, T>(&self, future: Fut) -> impl 'a + Future<Output = Result<T>>
+    pub fn run<'a, Fut, T>(
+        &self,
+        priority: RequestPriority,
+        future: Fut,
+    ) -> impl 'a + Future<Output = Result<T>>
     where
         Fut: 'a + Future<Output = Result<T>>,
     {
-        let guard = self.semaphore.acquire_arc();
+        let slot = self.acquire(priority);
         async move {
-            let guard = guard.await;
+            let slot = slot.await?;
             let result = future.await?;
-            drop(guard);
+            drop(slot);
             Ok(result)
         }
     }
 
     pub fn stream<'a, Fut, T>(
         &self,
+        priority: RequestPriority,
         future: Fut,
     ) -> impl 'a + Future<Output = Result<impl Stream<Item = T::Item>>>
     where
         Fut: 'a + Future<Output = Result<T>>,
         T: Stream,
     {
-        let guard = self.semaphore.acquire_arc();
+        let slot = self.acquire(priority);
         async move {
-            let guard = guard.await;
+            let slot = slot.await?;
             let inner = future.await?;
-            Ok(RateLimitGuard {
-                inner,
-                _guard: guard,
-            })
+            Ok(RateLimitGuard { inner, _slot: slot })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::channel::oneshot;
+    use gpui::TestAppContext;
+
+    #[gpui::test]
+    async fn test_high_priority_request_jumps_the_queue(cx: &mut TestAppContext) {
+        let limiter = RateLimiter::new(1);
+        let admitted = Arc::new(Mutex::new(Vec::new()));
+
+        let (finish_first, first_finished) = oneshot::channel::<()>();
+        let mut requests = vec![cx.executor().spawn(limiter.run(RequestPriority::Normal, {
+            let admitted = admitted.clone();
+            async move {
+                admitted.lock().push("first");
+                first_finished.await.ok();
+                Ok(())
+            }
+        }))];
+        cx.run_until_parked();
+
+        for (name, priority) in [
+            ("low", RequestPriority::Low),
+            ("normal", RequestPriority::Normal),
+            ("high", RequestPriority::High),
+        ] {
+            let admitted = admitted.clone();
+            requests.push(cx.executor().spawn(limiter.run(priority, async move {
+                admitted.lock().push(name);
+                Ok(())
+            })));
+        }
+        cx.run_until_parked();
+        assert_eq!(*admitted.lock(), ["first"]);
+
+        finish_first.send(()).unwrap();
+        for request in requests {
+            request.await.unwrap();
+        }
+        assert_eq!(*admitted.lock(), ["first", "high", "normal", "low"]);
+    }
+
+    #[gpui::test]
+    async fn test_queued_request_times_out(cx: &mut TestAppContext) {
+        let limiter = RateLimiter::new(1);
+        let (finish_first, first_finished) = oneshot::channel::<()>();
+        let first = cx
+            .executor()
+            .spawn(limiter.run(RequestPriority::Normal, async move {
+                first_finished.await.ok();
+                Ok(())
+            }));
+        cx.run_until_parked();
+
+        let max_wait = Duration::from_secs(30);
+        let impatient = limiter.with_max_queue_wait(max_wait, cx.executor());
+        let admitted = Arc::new(Mutex::new(false));
+        let waiter = cx.executor().spawn(impatient.run(RequestPriority::Normal, {
+            let admitted = admitted.clone();
+            async move {
+                *admitted.lock() = true;
+                Ok(())
+            }
+        }));
+        let patient = cx
+            .executor()
+            .spawn(limiter.run(RequestPriority::Low, async { Ok(()) }));
+
+        cx.executor().advance_clock(max_wait);
+        let error = waiter.await.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<LanguageModelError>(),
+            Some(&LanguageModelError::QueueTimeout { waited: max_wait })
+        );
+        assert!(!*admitted.lock());
+
+        // The timed out request gives up its place in the queue.
+        finish_first.send(()).unwrap();
+        first.await.unwrap();
+        patient.await.unwrap();
+    }
+}
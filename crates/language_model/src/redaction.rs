@@ -0,0 +1,141 @@
+use crate::{
+    settings::AllLanguageModelSettings, CompletionInterceptor, LanguageModelId,
+    LanguageModelProviderId, LanguageModelRequest,
+};
+use collections::BTreeMap;
+use gpui::AppContext;
+use regex::Regex;
+use settings::Settings;
+use std::borrow::Cow;
+
+/// Replaces text matching the `pii_patterns` setting in each request's
+/// messages with a placeholder naming the pattern, so that it never reaches
+/// the provider.
+pub(crate) struct PiiRedactor {
+    patterns: Vec<(String, Regex)>,
+}
+
+impl CompletionInterceptor for PiiRedactor {
+    fn on_request(
+        &self,
+        _provider: &LanguageModelProviderId,
+        _model: &LanguageModelId,
+        request: &mut LanguageModelRequest,
+    ) {
+        for (name, count) in self.redact(request) {
+            log::info!("redacted {count} {name} match(es) from a language model request");
+        }
+    }
+}
+
+impl PiiRedactor {
+    /// Returns a redactor for the `pii_patterns` setting, if `redact_pii` is
+    /// enabled.
+    pub fn from_settings(cx: &AppContext) -> Option<Self> {
+        let settings = AllLanguageModelSettings::get_global(cx);
+        settings
+            .redact_pii
+            .then(|| Self::new(&settings.pii_patterns))
+    }
+
+    /// Compiles the given patterns, keyed by name. Patterns that aren't valid
+    /// regexes are logged and skipped.
+    pub fn new(patterns: &BTreeMap<String, String>) -> Self {
+        Self {
+            patterns: patterns
+                .iter()
+                .filter_map(|(name, pattern)| match Regex::new(pattern) {
+                    Ok(regex) => Some((name.clone(), regex)),
+                    Err(error) => {
+                        log::error!("invalid PII pattern {name:?}: {error}");
+                        None
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Redacts every match in the request's messages, returning how many
+    /// matches each pattern had.
+    pub fn redact(&self, request: &mut LanguageModelRequest) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::default();
+        for message in &mut request.messages {
+            for (name, regex) in &self.patterns {
+                let mut count = 0;
+                let redacted = regex.replace_all(&message.content, |_: &regex::Captures| {
+                    count += 1;
+                    format!("[redacted {name}]")
+                });
+                if let Cow::Owned(redacted) = redacted {
+                    message.content = redacted;
+                    *counts.entry(name.clone()).or_default() += count;
+                }
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{intercept_request, LanguageModelRequestMessage, Role};
+    use gpui::TestAppContext;
+    use settings::SettingsStore;
+
+    #[gpui::test]
+    fn test_redact_pii(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        let code = "fn main() {\n    let port = 8080;\n    println!(\"{}\", port);\n}";
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: format!(
+                    "Email jane.doe@example.com about card 4111 1111 1111 1111.\n{code}"
+                ),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        // Redaction is opt-in.
+        assert!(cx.update(PiiRedactor::from_settings).is_none());
+
+        cx.update(|cx| {
+            SettingsStore::update_global(cx, |store, cx| {
+                store.update_user_settings::<AllLanguageModelSettings>(cx, |settings| {
+                    settings.redact_pii = Some(true);
+                });
+            });
+        });
+        let (redacted, _) = intercept_request(
+            request.clone(),
+            LanguageModelProviderId::from("fake".to_string()),
+            LanguageModelId::from("fake".to_string()),
+            &cx.to_async(),
+        );
+        assert_eq!(
+            redacted.messages[0].content,
+            format!("Email [redacted email] about card [redacted credit card number].\n{code}")
+        );
+
+        let patterns = BTreeMap::from_iter([
+            ("email".to_string(), r"\S+@\S+".to_string()),
+            ("invalid".to_string(), "(".to_string()),
+        ]);
+        let mut request = request;
+        request.messages[0].content = "a@b.c wrote to d@e.f".into();
+        let counts = PiiRedactor::new(&patterns).redact(&mut request);
+        assert_eq!(counts, BTreeMap::from_iter([("email".to_string(), 2)]));
+        assert_eq!(
+            request.messages[0].content,
+            "[redacted email] wrote to [redacted email]"
+        );
+    }
+}
@@ -1,21 +1,26 @@
 use crate::{
     provider::{
-        anthropic::AnthropicLanguageModelProvider, cloud::CloudLanguageModelProvider,
-        copilot_chat::CopilotChatLanguageModelProvider, google::GoogleLanguageModelProvider,
-        ollama::OllamaLanguageModelProvider, open_ai::OpenAiLanguageModelProvider,
+        anthropic::AnthropicLanguageModelProvider, bedrock::BedrockLanguageModelProvider,
+        cloud::CloudLanguageModelProvider, copilot_chat::CopilotChatLanguageModelProvider,
+        google::GoogleLanguageModelProvider, ollama::OllamaLanguageModelProvider,
+        open_ai::OpenAiLanguageModelProvider,
     },
-    LanguageModel, LanguageModelId, LanguageModelProvider, LanguageModelProviderId,
-    LanguageModelProviderState,
+    settings::AllLanguageModelSettings,
+    CompletionInterceptor, LanguageModel, LanguageModelId, LanguageModelProvider,
+    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState,
+    LoggingInterceptor,
 };
 use client::{Client, UserStore};
 use collections::BTreeMap;
 use gpui::{AppContext, EventEmitter, Global, Model, ModelContext};
-use std::sync::Arc;
+use settings::{Settings, SettingsStore};
+use std::{fmt, sync::Arc};
 use ui::Context;
 
 pub fn init(user_store: Model<UserStore>, client: Arc<Client>, cx: &mut AppContext) {
     let registry = cx.new_model(|cx| {
         let mut registry = LanguageModelRegistry::default();
+        registry.add_interceptor(Arc::new(LoggingInterceptor));
         register_language_model_providers(&mut registry, user_store, client, cx);
         registry
     });
@@ -46,28 +51,51 @@ fn register_language_model_providers(
         GoogleLanguageModelProvider::new(client.http_client(), cx),
         cx,
     );
+    registry.register_provider(
+        BedrockLanguageModelProvider::new(client.http_client(), cx),
+        cx,
+    );
     registry.register_provider(CopilotChatLanguageModelProvider::new(cx), cx);
 
-    cx.observe_flag::<feature_flags::LanguageModels, _>(move |enabled, cx| {
+    cx.observe_flag::<feature_flags::LanguageModels, _>({
         let user_store = user_store.clone();
         let client = client.clone();
-        LanguageModelRegistry::global(cx).update(cx, move |registry, cx| {
-            if enabled {
-                registry.register_provider(
-                    CloudLanguageModelProvider::new(user_store.clone(), client.clone(), cx),
-                    cx,
-                );
-            } else {
-                registry.unregister_provider(
-                    LanguageModelProviderId::from(crate::provider::cloud::PROVIDER_ID.to_string()),
-                    cx,
-                );
-            }
-        });
+        move |_, cx| {
+            LanguageModelRegistry::global(cx).update(cx, |registry, cx| {
+                update_cloud_provider(registry, &user_store, &client, cx);
+            });
+        }
+    })
+    .detach();
+    cx.observe_global::<SettingsStore>(move |registry, cx| {
+        update_cloud_provider(registry, &user_store, &client, cx);
     })
     .detach();
 }
 
+/// Registers the zed.dev provider when both the feature flag and the
+/// `zed.dev.enabled` setting allow it, and unregisters it otherwise.
+fn update_cloud_provider(
+    registry: &mut LanguageModelRegistry,
+    user_store: &Model<UserStore>,
+    client: &Arc<Client>,
+    cx: &mut ModelContext<LanguageModelRegistry>,
+) {
+    use feature_flags::FeatureFlagAppExt;
+
+    let id = LanguageModelProviderId::from(crate::provider::cloud::PROVIDER_ID.to_string());
+    let enabled = cx.has_flag::<feature_flags::LanguageModels>()
+        && AllLanguageModelSettings::get_global(cx).zed_dot_dev.enabled;
+    if !enabled {
+        registry.unregister_provider(id, cx);
+    } else if !registry.providers.contains_key(&id) {
+        registry.register_provider(
+            CloudLanguageModelProvider::new(user_store.clone(), client.clone(), cx),
+            cx,
+        );
+    }
+}
+
 struct GlobalLanguageModelRegistry(Model<LanguageModelRegistry>);
 
 impl Global for GlobalLanguageModelRegistry {}
@@ -76,6 +104,7 @@ impl Global for GlobalLanguageModelRegistry {}
 pub struct LanguageModelRegistry {
     active_model: Option<ActiveModel>,
     providers: BTreeMap<LanguageModelProviderId, Arc<dyn LanguageModelProvider>>,
+    interceptors: Vec<Arc<dyn CompletionInterceptor>>,
 }
 
 pub struct ActiveModel {
@@ -92,6 +121,40 @@ pub enum Event {
 
 impl EventEmitter<Event> for LanguageModelRegistry {}
 
+/// Returned when a provider is authenticated but does not offer any models.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoModelsAvailableError {
+    pub provider: LanguageModelProviderName,
+}
+
+impl fmt::Display for NoModelsAvailableError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "No models available for {}", self.provider.0)
+    }
+}
+
+impl std::error::Error for NoModelsAvailableError {}
+
+/// Returned when the `language_models.default_model` setting names a model
+/// that none of the registered providers offer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidDefaultModelError {
+    pub provider: LanguageModelProviderId,
+    pub model: LanguageModelId,
+}
+
+impl fmt::Display for InvalidDefaultModelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The default model {:?} from provider {:?} is not available",
+            self.model.0, self.provider.0
+        )
+    }
+}
+
+impl std::error::Error for InvalidDefaultModelError {}
+
 impl LanguageModelRegistry {
     pub fn global(cx: &AppContext) -> Model<Self> {
         cx.global::<GlobalLanguageModelRegistry>().0.clone()
@@ -103,7 +166,7 @@ impl LanguageModelRegistry {
 
     #[cfg(any(test, feature = "test-support"))]
     pub fn test(cx: &mut AppContext) -> crate::provider::fake::FakeLanguageModelProvider {
-        let fake_provider = crate::provider::fake::FakeLanguageModelProvider;
+        let fake_provider = crate::provider::fake::FakeLanguageModelProvider::default();
         let registry = cx.new_model(|cx| {
             let mut registry = Self::default();
             registry.register_provider(fake_provider.clone(), cx);
@@ -139,10 +202,40 @@ impl LanguageModelRegistry {
         cx: &mut ModelContext<Self>,
     ) {
         if self.providers.remove(&id).is_some() {
+            if self
+                .active_provider()
+                .map_or(false, |provider| provider.id() == id)
+            {
+                self.set_active_model(None, cx);
+            }
             cx.emit(Event::RemovedProvider(id));
         }
     }
 
+    /// Adds an interceptor that every subsequent completion, from any
+    /// provider, is passed through.
+    pub fn add_interceptor(&mut self, interceptor: Arc<dyn CompletionInterceptor>) {
+        self.interceptors.push(interceptor);
+    }
+
+    pub fn interceptors(&self) -> &[Arc<dyn CompletionInterceptor>] {
+        &self.interceptors
+    }
+
+    /// Returns the global registry's interceptors, if it has been initialized.
+    pub(crate) fn global_interceptors(cx: &AppContext) -> Vec<Arc<dyn CompletionInterceptor>> {
+        cx.try_global::<GlobalLanguageModelRegistry>()
+            .map(|registry| registry.0.read(cx).interceptors.clone())
+            .unwrap_or_default()
+    }
+
+    /// Aborts every completion that's streaming from any provider.
+    pub fn cancel_all_completions(&self, cx: &mut ModelContext<Self>) {
+        for provider in self.providers.values() {
+            provider.cancel_all(cx);
+        }
+    }
+
     pub fn providers(&self) -> Vec<Arc<dyn LanguageModelProvider>> {
         let zed_provider_id = LanguageModelProviderId(crate::provider::cloud::PROVIDER_ID.into());
         let mut providers = Vec::with_capacity(self.providers.len());
@@ -166,6 +259,26 @@ impl LanguageModelRegistry {
             .collect()
     }
 
+    /// Returns the models offered by the given provider.
+    ///
+    /// Fails with [`NoModelsAvailableError`] when the provider is authenticated
+    /// but its catalog is empty, so callers can tell that apart from a provider
+    /// that simply hasn't been configured yet.
+    pub fn provider_models(
+        &self,
+        provider: &dyn LanguageModelProvider,
+        cx: &AppContext,
+    ) -> Result<Vec<Arc<dyn LanguageModel>>, NoModelsAvailableError> {
+        let models = provider.provided_models(cx);
+        if models.is_empty() && provider.is_authenticated(cx) {
+            Err(NoModelsAvailableError {
+                provider: provider.name(),
+            })
+        } else {
+            Ok(models)
+        }
+    }
+
     pub fn provider(&self, id: &LanguageModelProviderId) -> Option<Arc<dyn LanguageModelProvider>> {
         self.providers.get(id).cloned()
     }
@@ -204,6 +317,18 @@ impl LanguageModelRegistry {
         cx: &mut ModelContext<Self>,
     ) {
         if let Some(model) = model {
+            if model.is_deprecated() {
+                let retirement = model
+                    .deprecation_date()
+                    .map(|date| format!(" and will stop being served after {date}"))
+                    .unwrap_or_default();
+                log::warn!(
+                    "model {:?} from provider {:?} is deprecated{retirement}",
+                    model.id().0,
+                    model.provider_id().0,
+                );
+            }
+
             let provider_id = model.provider_id();
             if let Some(provider) = self.providers.get(&provider_id).cloned() {
                 self.active_model = Some(ActiveModel {
@@ -227,19 +352,45 @@ impl LanguageModelRegistry {
     pub fn active_model(&self) -> Option<Arc<dyn LanguageModel>> {
         self.active_model.as_ref()?.model.clone()
     }
+
+    /// Returns the model to use for features that don't let the user pick
+    /// one, which is the `language_models.default_model` setting when it's
+    /// set, falling back to the active model otherwise.
+    pub fn default_model(
+        &self,
+        cx: &AppContext,
+    ) -> Result<Option<Arc<dyn LanguageModel>>, InvalidDefaultModelError> {
+        let Some(default_model) = &AllLanguageModelSettings::get_global(cx).default_model else {
+            return Ok(self.active_model());
+        };
+
+        let provider = LanguageModelProviderId::from(default_model.provider.clone());
+        let model = LanguageModelId::from(default_model.model.clone());
+        self.providers
+            .get(&provider)
+            .and_then(|provider| {
+                provider
+                    .provided_models(cx)
+                    .into_iter()
+                    .find(|provided_model| provided_model.id() == model)
+            })
+            .map(Some)
+            .ok_or(InvalidDefaultModelError { provider, model })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::provider::fake::FakeLanguageModelProvider;
+    use futures::StreamExt as _;
 
     #[gpui::test]
     fn test_register_providers(cx: &mut AppContext) {
         let registry = cx.new_model(|_| LanguageModelRegistry::default());
 
         registry.update(cx, |registry, cx| {
-            registry.register_provider(FakeLanguageModelProvider, cx);
+            registry.register_provider(FakeLanguageModelProvider::default(), cx);
         });
 
         let providers = registry.read(cx).providers();
@@ -253,4 +404,217 @@ mod tests {
         let providers = registry.read(cx).providers();
         assert!(providers.is_empty());
     }
+
+    #[gpui::test]
+    fn test_provider_with_no_models(cx: &mut AppContext) {
+        let registry = cx.new_model(|_| LanguageModelRegistry::default());
+
+        registry.update(cx, |registry, cx| {
+            registry.register_provider(EmptyLanguageModelProvider, cx);
+            registry.register_provider(FakeLanguageModelProvider::default(), cx);
+        });
+
+        let registry = registry.read(cx);
+        let empty_provider = registry.provider(&EmptyLanguageModelProvider.id()).unwrap();
+        let error = registry
+            .provider_models(empty_provider.as_ref(), cx)
+            .err()
+            .unwrap();
+        assert_eq!(error.provider, EmptyLanguageModelProvider.name());
+        assert_eq!(error.to_string(), "No models available for Empty");
+
+        let fake_provider = registry
+            .provider(&crate::provider::fake::provider_id())
+            .unwrap();
+        let models = registry
+            .provider_models(fake_provider.as_ref(), cx)
+            .unwrap();
+        assert_eq!(models.len(), 1);
+    }
+
+    #[gpui::test]
+    fn test_default_model(cx: &mut AppContext) {
+        let settings_store = settings::SettingsStore::test(cx);
+        cx.set_global(settings_store);
+        AllLanguageModelSettings::register(cx);
+
+        let registry = cx.new_model(|cx| {
+            let mut registry = LanguageModelRegistry::default();
+            registry.register_provider(FakeLanguageModelProvider::default(), cx);
+            registry
+        });
+        assert!(registry.read(cx).default_model(cx).unwrap().is_none());
+
+        let set_default_model = |provider: &str, model: &str, cx: &mut AppContext| {
+            let settings = serde_json::json!({
+                "language_models": {
+                    "default_model": { "provider": provider, "model": model }
+                }
+            });
+            cx.update_global(|store: &mut settings::SettingsStore, cx| {
+                store.set_user_settings(&settings.to_string(), cx).unwrap();
+            });
+        };
+
+        set_default_model("fake", "fake", cx);
+        let model = registry.read(cx).default_model(cx).unwrap().unwrap();
+        assert_eq!(model.id(), crate::provider::fake::language_model_id());
+
+        set_default_model("fake", "missing", cx);
+        let error = registry.read(cx).default_model(cx).err().unwrap();
+        assert_eq!(
+            error.to_string(),
+            "The default model \"missing\" from provider \"fake\" is not available"
+        );
+
+        set_default_model("missing", "fake", cx);
+        let error = registry.read(cx).default_model(cx).err().unwrap();
+        assert_eq!(
+            error.provider,
+            LanguageModelProviderId::from("missing".to_string())
+        );
+    }
+
+    #[gpui::test]
+    fn test_disabling_cloud_provider(cx: &mut gpui::TestAppContext) {
+        use feature_flags::FeatureFlagAppExt;
+
+        let cloud_provider_id =
+            LanguageModelProviderId::from(crate::provider::cloud::PROVIDER_ID.to_string());
+        cx.update(|cx| {
+            let settings_store = settings::SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+
+            let clock = Arc::new(clock::FakeSystemClock::default());
+            let http_client = http_client::FakeHttpClient::with_404_response();
+            let client = Client::new(clock, http_client, cx);
+            let user_store = cx.new_model(|cx| UserStore::new(client.clone(), cx));
+            init(user_store, client, cx);
+        });
+
+        cx.update(|cx| cx.update_flags(false, vec!["language-models".to_string()]));
+        cx.update(|cx| {
+            let registry = LanguageModelRegistry::read_global(cx);
+            assert!(registry.provider(&cloud_provider_id).is_some());
+        });
+
+        cx.update(|cx| {
+            let settings = r#"{"language_models": {"zed.dev": {"enabled": false}}}"#;
+            cx.update_global(|store: &mut settings::SettingsStore, cx| {
+                store.set_user_settings(settings, cx).unwrap();
+            });
+        });
+        cx.update(|cx| {
+            let registry = LanguageModelRegistry::read_global(cx);
+            assert!(registry.provider(&cloud_provider_id).is_none());
+            assert!(registry
+                .providers()
+                .iter()
+                .all(|provider| provider.id() != cloud_provider_id));
+            assert!(registry
+                .available_models(cx)
+                .iter()
+                .all(|model| model.provider_id() != cloud_provider_id));
+            assert!(registry
+                .provider(&LanguageModelProviderId::from("openai".to_string()))
+                .is_some());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_cancel_all_completions(cx: &mut gpui::TestAppContext) {
+        let fake_provider = FakeLanguageModelProvider::default();
+        let registry = cx.new_model(|cx| {
+            let mut registry = LanguageModelRegistry::default();
+            registry.register_provider(EmptyLanguageModelProvider, cx);
+            registry.register_provider(fake_provider.clone(), cx);
+            registry
+        });
+
+        let first_model = fake_provider.test_model();
+        let second_model = fake_provider.test_model();
+        let request = |content: &str| crate::LanguageModelRequest {
+            messages: vec![crate::LanguageModelRequestMessage {
+                role: crate::Role::User,
+                content: content.into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let mut first_stream = first_model
+            .stream_completion(request("first"), &cx.to_async())
+            .await
+            .unwrap();
+        let mut second_stream = second_model
+            .stream_completion(request("second"), &cx.to_async())
+            .await
+            .unwrap();
+        let tool_use = second_model.use_any_tool(
+            request("tool"),
+            "search".into(),
+            "Searches the web".into(),
+            serde_json::json!({ "type": "object" }),
+            &cx.to_async(),
+        );
+
+        first_model.stream_last_completion_response("Hello".into());
+        assert!(matches!(
+            first_stream.next().await,
+            Some(Ok(crate::LanguageModelCompletionEvent::Text(text))) if text == "Hello"
+        ));
+
+        registry.update(cx, |registry, cx| registry.cancel_all_completions(cx));
+        assert!(first_stream.next().await.is_none());
+        assert!(second_stream.next().await.is_none());
+        assert_eq!(
+            tool_use.await.unwrap_err().to_string(),
+            "completion was cancelled"
+        );
+        assert!(first_model.is_completion_stream_closed(&request("first")));
+        assert!(second_model.is_completion_stream_closed(&request("second")));
+    }
+
+    struct EmptyLanguageModelProvider;
+
+    impl LanguageModelProviderState for EmptyLanguageModelProvider {
+        type ObservableEntity = ();
+
+        fn observable_entity(&self) -> Option<Model<Self::ObservableEntity>> {
+            None
+        }
+    }
+
+    impl LanguageModelProvider for EmptyLanguageModelProvider {
+        fn id(&self) -> LanguageModelProviderId {
+            LanguageModelProviderId::from("empty".to_string())
+        }
+
+        fn name(&self) -> LanguageModelProviderName {
+            LanguageModelProviderName::from("Empty".to_string())
+        }
+
+        fn provided_models(&self, _: &AppContext) -> Vec<Arc<dyn LanguageModel>> {
+            Vec::new()
+        }
+
+        fn is_authenticated(&self, _: &AppContext) -> bool {
+            true
+        }
+
+        fn authenticate(&self, _: &mut AppContext) -> gpui::Task<anyhow::Result<()>> {
+            gpui::Task::ready(Ok(()))
+        }
+
+        fn configuration_view(&self, _: &mut ui::WindowContext) -> gpui::AnyView {
+            unimplemented!()
+        }
+
+        fn reset_credentials(&self, _: &mut AppContext) -> gpui::Task<anyhow::Result<()>> {
+            gpui::Task::ready(Ok(()))
+        }
+
+        fn cancel_all(&self, _: &mut AppContext) {}
+    }
 }
@@ -1,10 +1,81 @@
-use crate::role::Role;
+use crate::{role::Role, LanguageModelError};
+use anyhow::{bail, Context as _, Result};
+use collections::BTreeMap;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 
+/// Non-text content attached to a [`LanguageModelRequestMessage`].
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+pub enum MessageContent {
+    /// A base64-encoded document, such as a PDF.
+    ///
+    /// Only supported by Anthropic models.
+    Document { mime_type: String, data: String },
+}
+
+/// How urgently a request should be sent when a provider's concurrent request
+/// limit has been reached.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum RequestPriority {
+    /// Requests the user isn't waiting on, such as generating a title.
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// The format a completion has to be in.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    /// Any JSON value.
+    Json,
+    /// JSON that matches the given JSON schema.
+    JsonSchema { schema: serde_json::Value },
+}
+
+/// Whether the model has to call one of the tools offered in a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolChoice {
+    /// The model decides whether to call a tool.
+    Auto,
+    /// The model has to call at least one of the tools.
+    Required,
+}
+
+/// What to do with assistant messages that have no content, such as the
+/// response to a cancelled generation, which some providers reject.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EmptyAssistantMessages {
+    /// Leave them out of the request.
+    #[default]
+    Drop,
+    /// Send a placeholder in their place, so that the conversation keeps its turns.
+    Placeholder,
+}
+
+/// Opens conversations that would otherwise start with an assistant message,
+/// which Anthropic and Bedrock don't accept.
+const PLACEHOLDER_USER_MESSAGE: &str = "(continue)";
+
+/// Replaces the content of empty assistant messages when they're kept.
+const PLACEHOLDER_ASSISTANT_MESSAGE: &str = "(no response)";
+
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Hash)]
 pub struct LanguageModelRequestMessage {
     pub role: Role,
     pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<MessageContent>,
+    /// Whether providers that support prompt caching should cache the request
+    /// up to and including this message.
+    #[serde(default)]
+    pub cache: bool,
 }
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
@@ -12,11 +83,202 @@ pub struct LanguageModelRequest {
     pub messages: Vec<LanguageModelRequestMessage>,
     pub stop: Vec<String>,
     pub temperature: f32,
+    /// Only samples each token from this many of the most likely options.
+    ///
+    /// Only supported by Anthropic models; ignored by other providers.
+    pub top_k: Option<u32>,
+    /// Biases the likelihood of specific token IDs appearing in the completion.
+    ///
+    /// Only supported by OpenAI models; ignored by other providers.
+    pub logit_bias: Option<BTreeMap<u32, f32>>,
+    /// The maximum number of messages to send, keeping only the most recent ones.
+    ///
+    /// System messages are always kept and don't count towards this limit.
+    pub max_messages: Option<usize>,
+    /// Ends the completion as soon as the generated text matches this regex,
+    /// excluding the match itself.
+    ///
+    /// Unlike `stop`, this is applied on the client, so it works with every provider.
+    pub stop_regex: Option<String>,
+    /// Identifies the conversation this request belongs to, such as an
+    /// assistant context, so that the server can group related requests.
+    pub conversation_id: Option<String>,
+    /// The experiment bucket this request belongs to, which zed.dev records
+    /// and may use to route the request to a different model version.
+    pub experiment: Option<String>,
+    /// Asks zed.dev to use exactly the model version in the model's id, such
+    /// as `claude-3-5-sonnet-20240620`, rather than the version it currently
+    /// serves for that model.
+    ///
+    /// Ignored by other providers.
+    pub pin_model_version: bool,
+    /// Which requests to send first when the provider's concurrent request
+    /// limit has been reached.
+    pub priority: RequestPriority,
+    /// Overrides the provider's default for whether a tool has to be called,
+    /// for requests that offer tools.
+    pub tool_choice: Option<ToolChoice>,
+    /// Constrains the completion to JSON, optionally matching a schema.
+    ///
+    /// Only supported by Google models; ignored by other providers.
+    pub response_format: Option<ResponseFormat>,
+    /// How assistant messages without any content are sent.
+    pub empty_assistant_messages: EmptyAssistantMessages,
+    /// Top-level parameters to add to the provider's request body, for
+    /// parameters that aren't modeled here yet.
+    ///
+    /// Keys that the provider's request already uses are ignored, so these
+    /// can't override the parameters above.
+    pub extra_body: Option<serde_json::Map<String, serde_json::Value>>,
+    /// Labels that attribute the request's cost, such as to a project or team,
+    /// which zed.dev records with its usage.
+    ///
+    /// Sent as metadata to providers that support it, which is only OpenAI.
+    pub tags: BTreeMap<String, String>,
 }
 
 impl LanguageModelRequest {
-    pub fn into_open_ai(self, model: String) -> open_ai::Request {
-        open_ai::Request {
+    /// Compiles [`Self::stop_regex`], if there is one.
+    pub fn compile_stop_regex(&self) -> Result<Option<Regex>> {
+        self.stop_regex
+            .as_deref()
+            .map(|stop_regex| Regex::new(stop_regex).context("invalid stop regex"))
+            .transpose()
+    }
+
+    /// Drops the oldest non-system messages so that at most `max_messages` of them remain.
+    pub fn apply_max_messages(&mut self) {
+        let Some(max_messages) = self.max_messages else {
+            return;
+        };
+
+        let non_system_messages = self
+            .messages
+            .iter()
+            .filter(|message| message.role != Role::System)
+            .count();
+        let mut messages_to_drop = non_system_messages.saturating_sub(max_messages);
+        self.messages.retain(|message| {
+            if message.role == Role::System || messages_to_drop == 0 {
+                true
+            } else {
+                messages_to_drop -= 1;
+                false
+            }
+        });
+    }
+
+    /// Drops or fills in assistant messages that have neither text nor
+    /// attachments, according to [`Self::empty_assistant_messages`].
+    pub fn apply_empty_assistant_messages(&mut self) {
+        let is_empty = |message: &LanguageModelRequestMessage| {
+            message.role == Role::Assistant
+                && message.content.trim().is_empty()
+                && message.attachments.is_empty()
+        };
+        match self.empty_assistant_messages {
+            EmptyAssistantMessages::Drop => self.messages.retain(|message| !is_empty(message)),
+            EmptyAssistantMessages::Placeholder => {
+                for message in &mut self.messages {
+                    if is_empty(message) {
+                        message.content = PLACEHOLDER_ASSISTANT_MESSAGE.into();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns whether any message has a document attached.
+    pub fn has_documents(&self) -> bool {
+        self.messages.iter().any(|message| {
+            message
+                .attachments
+                .iter()
+                .any(|attachment| matches!(attachment, MessageContent::Document { .. }))
+        })
+    }
+
+    /// Returns an error if any message has a document attached, for providers
+    /// that can't accept them.
+    pub fn ensure_no_documents(&self, provider_name: &str) -> Result<()> {
+        if self.has_documents() {
+            bail!("{provider_name} does not support document content");
+        }
+        Ok(())
+    }
+
+    /// Takes [`Self::extra_body`], leaving out the keys that the provider's
+    /// request reserves for its own parameters.
+    fn take_extra_body(
+        &mut self,
+        reserved_keys: &[&str],
+    ) -> serde_json::Map<String, serde_json::Value> {
+        let mut extra_body = self.extra_body.take().unwrap_or_default();
+        extra_body.retain(|key, _| {
+            let is_reserved = reserved_keys.contains(&key.as_str());
+            if is_reserved {
+                log::warn!("ignoring {key:?} in extra_body, as it's already part of the request");
+            }
+            !is_reserved
+        });
+        extra_body
+    }
+
+    /// Roughly estimates the number of tokens in the request's messages,
+    /// assuming four characters per token, for checks that can't wait for
+    /// the provider to count them.
+    pub fn estimated_token_count(&self) -> usize {
+        self.messages
+            .iter()
+            .map(|message| message.content.chars().count())
+            .sum::<usize>()
+            / 4
+    }
+
+    /// Returns a [`LanguageModelError::InvalidParameter`] for each parameter
+    /// that no provider would accept.
+    pub fn parameter_errors(&self) -> Vec<LanguageModelError> {
+        let mut errors = Vec::new();
+        let mut invalid = |name: &str, reason: String| {
+            errors.push(LanguageModelError::InvalidParameter {
+                name: name.into(),
+                reason,
+            })
+        };
+
+        if !(0.0..=2.0).contains(&self.temperature) {
+            invalid(
+                "temperature",
+                format!("{} is outside of the range 0 to 2", self.temperature),
+            );
+        }
+        if self.stop.iter().any(String::is_empty) {
+            invalid("stop", "stop sequences can't be empty".into());
+        }
+        if self.max_messages == Some(0) {
+            invalid("max_messages", "at least one message has to be sent".into());
+        }
+        if self.compile_stop_regex().is_err() {
+            invalid("stop_regex", "it isn't a valid regular expression".into());
+        }
+        errors
+    }
+
+    pub fn into_open_ai(
+        mut self,
+        model: String,
+        max_output_tokens: Option<usize>,
+    ) -> Result<open_ai::Request> {
+        self.ensure_no_documents("OpenAI")?;
+        self.apply_max_messages();
+        self.apply_empty_assistant_messages();
+        let extra_body = self.take_extra_body(open_ai::Request::RESERVED_KEYS);
+        let (max_tokens, max_completion_tokens) = if open_ai::uses_max_completion_tokens(&model) {
+            (None, max_output_tokens)
+        } else {
+            (max_output_tokens, None)
+        };
+        Ok(open_ai::Request {
             model,
             messages: self
                 .messages
@@ -35,16 +297,44 @@ impl LanguageModelRequest {
                 })
                 .collect(),
             stream: true,
+            // Left to providers, which know whether the server supports it.
+            stream_options: None,
             stop: self.stop,
             temperature: self.temperature,
-            max_tokens: None,
+            logit_bias: self.logit_bias,
+            max_tokens,
+            max_completion_tokens,
             tools: Vec::new(),
-            tool_choice: None,
-        }
+            tool_choice: self.tool_choice.map(|tool_choice| match tool_choice {
+                ToolChoice::Auto => open_ai::ToolChoice::Auto,
+                ToolChoice::Required => open_ai::ToolChoice::Required,
+            }),
+            metadata: self.tags,
+            extra_body,
+        })
     }
 
-    pub fn into_google(self, model: String) -> google_ai::GenerateContentRequest {
-        google_ai::GenerateContentRequest {
+    /// Converts this request for Gemini, which blocks content in each harm
+    /// category at the given threshold, or at Gemini's default thresholds for
+    /// categories that aren't listed in `safety_settings`.
+    pub fn into_google(
+        mut self,
+        model: String,
+        safety_settings: &BTreeMap<google_ai::HarmCategory, google_ai::HarmBlockThreshold>,
+    ) -> Result<google_ai::GenerateContentRequest> {
+        self.ensure_no_documents("Google AI")?;
+        self.apply_max_messages();
+        self.apply_empty_assistant_messages();
+        let extra_body = self.take_extra_body(google_ai::GenerateContentRequest::RESERVED_KEYS);
+        let (response_mime_type, response_schema) = match self.response_format {
+            Some(ResponseFormat::Json) => (Some("application/json".into()), None),
+            Some(ResponseFormat::JsonSchema { mut schema }) => {
+                remove_unsupported_gemini_schema_keys(&mut schema);
+                (Some("application/json".into()), Some(schema))
+            }
+            None => (None, None),
+        };
+        Ok(google_ai::GenerateContentRequest {
             model,
             contents: self
                 .messages
@@ -67,17 +357,34 @@ impl LanguageModelRequest {
                 temperature: Some(self.temperature as f64),
                 top_p: None,
                 top_k: None,
+                response_mime_type,
+                response_schema,
             }),
-            safety_settings: None,
-        }
+            safety_settings: (!safety_settings.is_empty()).then(|| {
+                safety_settings
+                    .iter()
+                    .map(|(&category, &threshold)| google_ai::SafetySetting {
+                        category,
+                        threshold,
+                    })
+                    .collect()
+            }),
+            system_instruction: None,
+            tools: None,
+            tool_config: None,
+            extra_body,
+        })
     }
 
-    pub fn into_anthropic(self, model: String) -> anthropic::Request {
+    pub fn into_anthropic(mut self, model: String) -> anthropic::Request {
+        self.apply_max_messages();
+        self.apply_empty_assistant_messages();
+        let extra_body = self.take_extra_body(anthropic::Request::RESERVED_KEYS);
         let mut new_messages: Vec<LanguageModelRequestMessage> = Vec::new();
         let mut system_message = String::new();
 
         for message in self.messages {
-            if message.content.is_empty() {
+            if message.content.is_empty() && message.attachments.is_empty() {
                 continue;
             }
 
@@ -85,8 +392,14 @@ impl LanguageModelRequest {
                 Role::User | Role::Assistant => {
                     if let Some(last_message) = new_messages.last_mut() {
                         if last_message.role == message.role {
-                            last_message.content.push_str("\n\n");
-                            last_message.content.push_str(&message.content);
+                            if !message.content.is_empty() {
+                                if !last_message.content.is_empty() {
+                                    last_message.content.push_str("\n\n");
+                                }
+                                last_message.content.push_str(&message.content);
+                            }
+                            last_message.attachments.extend(message.attachments);
+                            last_message.cache |= message.cache;
                             continue;
                         }
                     }
@@ -102,33 +415,274 @@ impl LanguageModelRequest {
             }
         }
 
+        // Merging consecutive messages guarantees that roles alternate, but
+        // Anthropic also rejects conversations that don't open with a user turn.
+        if new_messages
+            .first()
+            .map_or(false, |message| message.role == Role::Assistant)
+        {
+            new_messages.insert(
+                0,
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: PLACEHOLDER_USER_MESSAGE.into(),
+                    attachments: Vec::new(),
+                    cache: false,
+                },
+            );
+        }
+
+        // Anthropic only accepts a few cache breakpoints per request, so when
+        // more messages are flagged, the latest ones win: they mark the longest
+        // prefixes, which also cover the earlier ones.
+        let mut cache_breakpoints = anthropic::MAX_CACHE_BREAKPOINTS;
+        for message in new_messages.iter_mut().rev() {
+            if message.cache {
+                if cache_breakpoints == 0 {
+                    message.cache = false;
+                } else {
+                    cache_breakpoints -= 1;
+                }
+            }
+        }
+
+        // Anthropic rejects requests that don't set `max_tokens`.
+        let max_tokens = anthropic::Model::from_id(&model)
+            .map_or(anthropic::DEFAULT_MAX_OUTPUT_TOKENS, |model| {
+                model.max_output_tokens()
+            });
+
         anthropic::Request {
             model,
             messages: new_messages
                 .into_iter()
                 .filter_map(|message| {
-                    Some(anthropic::Message {
-                        role: match message.role {
-                            Role::User => anthropic::Role::User,
-                            Role::Assistant => anthropic::Role::Assistant,
-                            Role::System => return None,
-                        },
-                        content: vec![anthropic::Content::Text {
+                    let role = match message.role {
+                        Role::User => anthropic::Role::User,
+                        Role::Assistant => anthropic::Role::Assistant,
+                        Role::System => return None,
+                    };
+
+                    // Anthropic recommends placing documents before any text that refers to them.
+                    let mut content = message
+                        .attachments
+                        .into_iter()
+                        .map(|attachment| match attachment {
+                            MessageContent::Document { mime_type, data } => {
+                                anthropic::Content::Document {
+                                    source: anthropic::DocumentSource {
+                                        source_type: "base64".into(),
+                                        media_type: mime_type,
+                                        data,
+                                    },
+                                    cache_control: None,
+                                }
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    if !message.content.is_empty() {
+                        content.push(anthropic::Content::Text {
                             text: message.content,
-                        }],
-                    })
+                            cache_control: None,
+                        });
+                    }
+                    if message.cache {
+                        if let Some(
+                            anthropic::Content::Text { cache_control, .. }
+                            | anthropic::Content::Document { cache_control, .. },
+                        ) = content.last_mut()
+                        {
+                            *cache_control = Some(anthropic::CacheControl {
+                                cache_type: anthropic::CacheControlType::Ephemeral,
+                            });
+                        }
+                    }
+
+                    Some(anthropic::Message { role, content })
                 })
                 .collect(),
-            max_tokens: 4092,
+            max_tokens,
             system: Some(system_message),
             tools: Vec::new(),
-            tool_choice: None,
+            tool_choice: self.tool_choice.map(|tool_choice| match tool_choice {
+                ToolChoice::Auto => anthropic::ToolChoice::Auto,
+                ToolChoice::Required => anthropic::ToolChoice::Any,
+            }),
             metadata: None,
             stop_sequences: Vec::new(),
             temperature: None,
-            top_k: None,
+            top_k: self.top_k,
             top_p: None,
+            extra_body,
+        }
+    }
+
+    /// Converts this request for Bedrock's Converse API, which serves every
+    /// model on Bedrock but, like Anthropic, requires roles to alternate,
+    /// starting with a user turn.
+    pub fn into_bedrock(
+        mut self,
+        model: String,
+        max_output_tokens: Option<u32>,
+    ) -> Result<bedrock::Request> {
+        self.ensure_no_documents("Bedrock")?;
+        self.apply_max_messages();
+        self.apply_empty_assistant_messages();
+        let mut system = Vec::new();
+        let mut messages: Vec<bedrock::Message> = Vec::new();
+        for message in self.messages {
+            if message.content.is_empty() {
+                continue;
+            }
+
+            let content = bedrock::ContentBlock::Text(message.content);
+            let role = match message.role {
+                Role::User => bedrock::Role::User,
+                Role::Assistant => bedrock::Role::Assistant,
+                Role::System => {
+                    system.push(content);
+                    continue;
+                }
+            };
+            match messages.last_mut() {
+                Some(last_message) if last_message.role == role => {
+                    last_message.content.push(content)
+                }
+                _ => messages.push(bedrock::Message {
+                    role,
+                    content: vec![content],
+                }),
+            }
+        }
+
+        if messages
+            .first()
+            .map_or(false, |message| message.role == bedrock::Role::Assistant)
+        {
+            messages.insert(
+                0,
+                bedrock::Message {
+                    role: bedrock::Role::User,
+                    content: vec![bedrock::ContentBlock::Text(PLACEHOLDER_USER_MESSAGE.into())],
+                },
+            );
+        }
+
+        Ok(bedrock::Request {
+            model,
+            messages,
+            system,
+            inference_config: Some(bedrock::InferenceConfig {
+                max_tokens: max_output_tokens,
+                temperature: Some(self.temperature),
+                stop_sequences: self.stop,
+            }),
+        })
+    }
+
+    /// Returns a copy of this request with the data of attached documents
+    /// replaced by their size, for logging and debugging.
+    pub fn redacted(&self) -> Self {
+        let mut request = self.clone();
+        for message in &mut request.messages {
+            for attachment in &mut message.attachments {
+                match attachment {
+                    MessageContent::Document { data, .. } => {
+                        *data = format!("<redacted {} bytes>", data.len());
+                    }
+                }
+            }
+        }
+        request
+    }
+
+    /// Converts this request into each provider's native request, keyed by
+    /// provider id, so that a debug view can compare how they differ.
+    ///
+    /// Each provider's default model is used, and the data of attached
+    /// documents is redacted.
+    pub fn to_provider_requests(&self) -> BTreeMap<&'static str, Result<serde_json::Value>> {
+        let request = self.redacted();
+
+        BTreeMap::from_iter([
+            (
+                "anthropic",
+                serde_json::to_value(
+                    request
+                        .clone()
+                        .into_anthropic(anthropic::Model::default().id().into()),
+                )
+                .map_err(Into::into),
+            ),
+            (
+                "openai",
+                request
+                    .clone()
+                    .into_open_ai(open_ai::Model::default().id().into(), None)
+                    .and_then(|request| Ok(serde_json::to_value(request)?)),
+            ),
+            (
+                "google",
+                request
+                    .into_google(
+                        google_ai::Model::Gemini15Pro.id().into(),
+                        &BTreeMap::default(),
+                    )
+                    .and_then(|request| Ok(serde_json::to_value(request)?)),
+            ),
+        ])
+    }
+}
+
+/// The number of tokens kept free beyond the system prompt by
+/// [`max_output_tokens_reserving_system_prompt`].
+pub const SYSTEM_PROMPT_RESERVATION_MARGIN: usize = 256;
+
+/// The fewest output tokens [`max_output_tokens_reserving_system_prompt`]
+/// allows, so that a conversation that already fills the context window still
+/// gets a response.
+pub const MIN_RESERVED_OUTPUT_TOKENS: usize = 256;
+
+/// Limits a completion's output for models that would otherwise fill the rest
+/// of their context window, so that the system prompt, plus a margin, still
+/// fits when the conversation continues. `conversation_tokens` counts every
+/// message but the system prompt.
+pub fn max_output_tokens_reserving_system_prompt(
+    max_token_count: usize,
+    conversation_tokens: usize,
+    system_prompt_tokens: usize,
+) -> usize {
+    max_token_count
+        .saturating_sub(conversation_tokens)
+        .saturating_sub(system_prompt_tokens + SYSTEM_PROMPT_RESERVATION_MARGIN)
+        .max(MIN_RESERVED_OUTPUT_TOKENS)
+}
+
+/// Removes the keywords Gemini rejects from a JSON schema, since its
+/// `responseSchema` only accepts a subset of JSON schema.
+pub(crate) fn remove_unsupported_gemini_schema_keys(schema: &mut serde_json::Value) {
+    match schema {
+        serde_json::Value::Object(object) => {
+            object.remove("$schema");
+            object.remove("additionalProperties");
+            for (key, value) in object.iter_mut() {
+                // The names of properties can be anything, including the
+                // keywords removed above.
+                if key == "properties" {
+                    if let serde_json::Value::Object(properties) = value {
+                        properties
+                            .values_mut()
+                            .for_each(remove_unsupported_gemini_schema_keys);
+                    }
+                } else {
+                    remove_unsupported_gemini_schema_keys(value);
+                }
+            }
         }
+        serde_json::Value::Array(values) => values
+            .iter_mut()
+            .for_each(remove_unsupported_gemini_schema_keys),
+        _ => {}
     }
 }
 
@@ -137,3 +691,829 @@ pub struct LanguageModelResponseMessage {
     pub role: Option<Role>,
     pub content: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_open_ai_logit_bias() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Hello".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            stop: Vec::new(),
+            temperature: 1.0,
+            top_k: None,
+            logit_bias: Some(BTreeMap::from_iter([(50256, -100.0), (1234, 5.5)])),
+            max_messages: None,
+            stop_regex: None,
+            conversation_id: None,
+            experiment: None,
+            pin_model_version: false,
+            priority: RequestPriority::Normal,
+            tool_choice: None,
+            response_format: None,
+            empty_assistant_messages: EmptyAssistantMessages::Drop,
+            extra_body: None,
+            tags: Default::default(),
+        };
+
+        let open_ai_request = request.clone().into_open_ai("gpt-4o".into(), None).unwrap();
+        assert!(open_ai_request.validate().is_ok());
+        let json = serde_json::to_value(&open_ai_request).unwrap();
+        assert_eq!(
+            json["logit_bias"],
+            serde_json::json!({ "1234": 5.5, "50256": -100.0 })
+        );
+
+        let google_request = request
+            .clone()
+            .into_google("gemini-1.5-pro".into(), &BTreeMap::default())
+            .unwrap();
+        let json = serde_json::to_value(&google_request).unwrap();
+        assert!(json.get("logit_bias").is_none());
+
+        let request = LanguageModelRequest {
+            logit_bias: Some(BTreeMap::from_iter([(50256, -101.0)])),
+            ..request
+        };
+        assert!(request
+            .into_open_ai("gpt-4o".into(), None)
+            .unwrap()
+            .validate()
+            .is_err());
+
+        let request = LanguageModelRequest {
+            logit_bias: None,
+            ..Default::default()
+        };
+        let json =
+            serde_json::to_value(request.into_open_ai("gpt-4o".into(), None).unwrap()).unwrap();
+        assert!(json.get("logit_bias").is_none());
+    }
+
+    #[test]
+    fn test_into_open_ai_stop() {
+        let request = LanguageModelRequest {
+            stop: vec!["</rewritten>".into()],
+            ..Default::default()
+        };
+        let open_ai_request = request.into_open_ai("gpt-4o".into(), None).unwrap();
+        let json = serde_json::to_value(&open_ai_request).unwrap();
+        assert_eq!(json["stop"], serde_json::json!("</rewritten>"));
+        let round_trip: open_ai::Request = serde_json::from_value(json).unwrap();
+        assert_eq!(round_trip.stop, ["</rewritten>"]);
+
+        let request = LanguageModelRequest {
+            stop: vec!["</rewritten>".into(), "\n\n".into()],
+            ..Default::default()
+        };
+        let open_ai_request = request.into_open_ai("gpt-4o".into(), None).unwrap();
+        let json = serde_json::to_value(&open_ai_request).unwrap();
+        assert_eq!(json["stop"], serde_json::json!(["</rewritten>", "\n\n"]));
+        let round_trip: open_ai::Request = serde_json::from_value(json).unwrap();
+        assert_eq!(round_trip.stop, ["</rewritten>", "\n\n"]);
+    }
+
+    #[test]
+    fn test_tags_as_open_ai_metadata() {
+        let request = LanguageModelRequest {
+            tags: BTreeMap::from_iter([("project".into(), "zed".into())]),
+            ..Default::default()
+        };
+        let json =
+            serde_json::to_value(request.into_open_ai("gpt-4o".into(), None).unwrap()).unwrap();
+        assert_eq!(json["metadata"], serde_json::json!({ "project": "zed" }));
+
+        let json = serde_json::to_value(
+            LanguageModelRequest::default()
+                .into_open_ai("gpt-4o".into(), None)
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(json.get("metadata").is_none());
+    }
+
+    #[test]
+    fn test_document_content() {
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: String::new(),
+                    attachments: vec![MessageContent::Document {
+                        mime_type: "application/pdf".into(),
+                        data: "JVBERi0xLjQK".into(),
+                    }],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: "Summarize this document.".into(),
+                    attachments: Vec::new(),
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let anthropic_request = request
+            .clone()
+            .into_anthropic("claude-3-5-sonnet-20240620".into());
+        let json = serde_json::to_value(&anthropic_request).unwrap();
+        assert_eq!(
+            json["messages"],
+            serde_json::json!([{
+                "role": "user",
+                "content": [
+                    {
+                        "type": "document",
+                        "source": {
+                            "type": "base64",
+                            "media_type": "application/pdf",
+                            "data": "JVBERi0xLjQK"
+                        }
+                    },
+                    { "type": "text", "text": "Summarize this document." }
+                ]
+            }])
+        );
+
+        let error = request.into_open_ai("gpt-4o".into(), None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "OpenAI does not support document content"
+        );
+    }
+
+    #[test]
+    fn test_max_messages() {
+        let message = |role, content: &str| LanguageModelRequestMessage {
+            role,
+            content: content.into(),
+            attachments: Vec::new(),
+            cache: false,
+        };
+        let mut request = LanguageModelRequest {
+            messages: vec![
+                message(Role::System, "You are a helpful assistant."),
+                message(Role::User, "one"),
+                message(Role::Assistant, "two"),
+                message(Role::User, "three"),
+                message(Role::Assistant, "four"),
+                message(Role::User, "five"),
+            ],
+            max_messages: Some(3),
+            ..Default::default()
+        };
+
+        request.apply_max_messages();
+        assert_eq!(
+            request.messages,
+            vec![
+                message(Role::System, "You are a helpful assistant."),
+                message(Role::User, "three"),
+                message(Role::Assistant, "four"),
+                message(Role::User, "five"),
+            ]
+        );
+
+        let anthropic_request = LanguageModelRequest {
+            max_messages: Some(1),
+            ..request
+        }
+        .into_anthropic("claude-3-5-sonnet-20240620".into());
+        assert_eq!(
+            anthropic_request.system.as_deref(),
+            Some("You are a helpful assistant.")
+        );
+        assert_eq!(anthropic_request.messages.len(), 1);
+    }
+
+    #[test]
+    fn test_anthropic_alternation() {
+        let message = |role, content: &str| LanguageModelRequestMessage {
+            role,
+            content: content.into(),
+            attachments: Vec::new(),
+            cache: false,
+        };
+        let roles_and_text = |request: anthropic::Request| {
+            request
+                .messages
+                .into_iter()
+                .map(|message| {
+                    let text = message
+                        .content
+                        .into_iter()
+                        .filter_map(|content| match content {
+                            anthropic::Content::Text { text, .. } => Some(text),
+                            _ => None,
+                        })
+                        .collect::<String>();
+                    let role = match message.role {
+                        anthropic::Role::User => Role::User,
+                        anthropic::Role::Assistant => Role::Assistant,
+                    };
+                    (role, text)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // A conversation that opens with the assistant gets a placeholder user turn.
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::System, "Be brief."),
+                message(Role::Assistant, "How can I help?"),
+                message(Role::User, "Say hi."),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            roles_and_text(request.into_anthropic("claude-3-5-sonnet-20240620".into())),
+            vec![
+                (Role::User, PLACEHOLDER_USER_MESSAGE.to_string()),
+                (Role::Assistant, "How can I help?".into()),
+                (Role::User, "Say hi.".into()),
+            ]
+        );
+
+        // Runs of the same role, including ones split up by system or empty
+        // messages, are merged so that roles strictly alternate.
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::User, "one"),
+                message(Role::System, "Be brief."),
+                message(Role::User, "two"),
+                message(Role::Assistant, "three"),
+                message(Role::User, ""),
+                message(Role::Assistant, "four"),
+                message(Role::User, "five"),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            roles_and_text(request.into_anthropic("claude-3-5-sonnet-20240620".into())),
+            vec![
+                (Role::User, "one\n\ntwo".into()),
+                (Role::Assistant, "three\n\nfour".into()),
+                (Role::User, "five".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_assistant_messages() {
+        let message = |role, content: &str| LanguageModelRequestMessage {
+            role,
+            content: content.into(),
+            attachments: Vec::new(),
+            cache: false,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::User, "one"),
+                message(Role::Assistant, ""),
+                message(Role::User, "two"),
+                message(Role::Assistant, "three"),
+                message(Role::User, "four"),
+                message(Role::Assistant, " \n"),
+            ],
+            ..Default::default()
+        };
+
+        let open_ai_messages = |request: LanguageModelRequest| {
+            let json =
+                serde_json::to_value(request.into_open_ai("gpt-4o".into(), None).unwrap()).unwrap();
+            json["messages"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|message| {
+                    (
+                        message["role"].as_str().unwrap().to_string(),
+                        message["content"].as_str().unwrap().to_string(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let turn = |role: &str, content: &str| (role.to_string(), content.to_string());
+
+        assert_eq!(
+            open_ai_messages(request.clone()),
+            vec![
+                turn("user", "one"),
+                turn("user", "two"),
+                turn("assistant", "three"),
+                turn("user", "four"),
+            ]
+        );
+
+        let request = LanguageModelRequest {
+            empty_assistant_messages: EmptyAssistantMessages::Placeholder,
+            ..request
+        };
+        assert_eq!(
+            open_ai_messages(request.clone()),
+            vec![
+                turn("user", "one"),
+                turn("assistant", PLACEHOLDER_ASSISTANT_MESSAGE),
+                turn("user", "two"),
+                turn("assistant", "three"),
+                turn("user", "four"),
+                turn("assistant", PLACEHOLDER_ASSISTANT_MESSAGE),
+            ]
+        );
+
+        // Placeholders keep the turns that Anthropic would otherwise merge.
+        let anthropic_request = request.into_anthropic("claude-3-5-sonnet-20240620".into());
+        assert_eq!(anthropic_request.messages.len(), 6);
+        assert_eq!(
+            serde_json::to_value(&anthropic_request.messages[1].content).unwrap(),
+            serde_json::json!([{ "type": "text", "text": PLACEHOLDER_ASSISTANT_MESSAGE }])
+        );
+    }
+
+    #[test]
+    fn test_anthropic_cache_control() {
+        let message = |role, content: &str, cache| LanguageModelRequestMessage {
+            role,
+            content: content.into(),
+            attachments: Vec::new(),
+            cache,
+        };
+        let cached_texts = |request: anthropic::Request| {
+            request
+                .messages
+                .into_iter()
+                .flat_map(|message| message.content)
+                .filter_map(|content| match content {
+                    anthropic::Content::Text {
+                        text,
+                        cache_control: Some(_),
+                    } => Some(text),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Only flagged messages are marked for caching.
+        let request = LanguageModelRequest {
+            messages: vec![
+                message(Role::User, "one", true),
+                message(Role::Assistant, "two", false),
+                message(Role::User, "three", false),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            cached_texts(request.into_anthropic("claude-3-5-sonnet-20240620".into())),
+            ["one"]
+        );
+
+        // Beyond Anthropic's limit, only the latest flagged messages are kept.
+        let request = LanguageModelRequest {
+            messages: ["one", "two", "three", "four", "five", "six"]
+                .into_iter()
+                .enumerate()
+                .map(|(ix, text)| {
+                    let role = if ix % 2 == 0 {
+                        Role::User
+                    } else {
+                        Role::Assistant
+                    };
+                    message(role, text, true)
+                })
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            cached_texts(request.into_anthropic("claude-3-5-sonnet-20240620".into())),
+            ["three", "four", "five", "six"]
+        );
+    }
+
+    #[test]
+    fn test_extra_body() {
+        let request = LanguageModelRequest {
+            temperature: 0.5,
+            extra_body: Some(
+                serde_json::json!({
+                    "reasoning_effort": "low",
+                    "model": "gpt-3.5-turbo",
+                    "temperature": 2.0,
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+            ..Default::default()
+        };
+        let json =
+            serde_json::to_value(request.into_open_ai("gpt-4o".into(), None).unwrap()).unwrap();
+        assert_eq!(json["reasoning_effort"], "low");
+        assert_eq!(json["model"], "gpt-4o");
+        assert_eq!(json["temperature"], 0.5);
+
+        // Keys are reserved even when the provider's request leaves them out.
+        let request = LanguageModelRequest {
+            extra_body: Some(
+                serde_json::json!({
+                    "stream": false,
+                    "top_k": 5,
+                    "thinking": { "type": "enabled", "budget_tokens": 1024 },
+                })
+                .as_object()
+                .unwrap()
+                .clone(),
+            ),
+            ..Default::default()
+        };
+        let json =
+            serde_json::to_value(request.into_anthropic("claude-3-5-sonnet-20240620".into()))
+                .unwrap();
+        assert_eq!(
+            json["thinking"],
+            serde_json::json!({ "type": "enabled", "budget_tokens": 1024 })
+        );
+        assert!(json.get("stream").is_none());
+        assert!(json.get("top_k").is_none());
+    }
+
+    #[test]
+    fn test_into_google_response_format() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "List three colors.".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            response_format: Some(ResponseFormat::JsonSchema {
+                schema: serde_json::json!({
+                    "$schema": "http://json-schema.org/draft-07/schema#",
+                    "type": "object",
+                    "properties": {
+                        "colors": { "type": "array", "items": { "type": "string" } }
+                    },
+                    "required": ["colors"],
+                    "additionalProperties": false
+                }),
+            }),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(
+            request
+                .into_google("gemini-1.5-pro".into(), &BTreeMap::default())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            json["generationConfig"]["responseMimeType"],
+            "application/json"
+        );
+        assert_eq!(
+            json["generationConfig"]["responseSchema"],
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "colors": { "type": "array", "items": { "type": "string" } }
+                },
+                "required": ["colors"]
+            })
+        );
+
+        let request = LanguageModelRequest {
+            response_format: Some(ResponseFormat::Json),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(
+            request
+                .into_google("gemini-1.5-pro".into(), &BTreeMap::default())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            json["generationConfig"]["responseMimeType"],
+            "application/json"
+        );
+        assert!(json["generationConfig"].get("responseSchema").is_none());
+    }
+
+    #[test]
+    fn test_into_google_safety_settings() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "How does this exploit work?".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        // Gemini's defaults apply unless some categories are relaxed.
+        let json = serde_json::to_value(
+            request
+                .clone()
+                .into_google("gemini-1.5-pro".into(), &BTreeMap::default())
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(json["safetySettings"].is_null());
+
+        let safety_settings = BTreeMap::from_iter([
+            (
+                google_ai::HarmCategory::DangerousContent,
+                google_ai::HarmBlockThreshold::BlockOnlyHigh,
+            ),
+            (
+                google_ai::HarmCategory::Harassment,
+                google_ai::HarmBlockThreshold::BlockNone,
+            ),
+        ]);
+        let json = serde_json::to_value(
+            request
+                .into_google("gemini-1.5-pro".into(), &safety_settings)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            json["safetySettings"],
+            serde_json::json!([
+                {
+                    "category": "HARM_CATEGORY_HARASSMENT",
+                    "threshold": "BLOCK_NONE"
+                },
+                {
+                    "category": "HARM_CATEGORY_DANGEROUS_CONTENT",
+                    "threshold": "BLOCK_ONLY_HIGH"
+                }
+            ])
+        );
+    }
+
+    #[test]
+    fn test_to_provider_requests() {
+        let mut request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::System,
+                    content: "Be brief.".into(),
+                    attachments: Vec::new(),
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: "Hello".into(),
+                    attachments: Vec::new(),
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+
+        let requests = request.to_provider_requests();
+        assert_eq!(
+            requests.keys().copied().collect::<Vec<_>>(),
+            ["anthropic", "google", "openai"]
+        );
+        assert_eq!(
+            requests["anthropic"].as_ref().unwrap()["system"],
+            "Be brief."
+        );
+        assert_eq!(
+            requests["openai"].as_ref().unwrap()["messages"][0]["role"],
+            "system"
+        );
+        assert_eq!(
+            requests["google"].as_ref().unwrap()["contents"][1]["parts"][0]["text"],
+            "Hello"
+        );
+
+        // Document data is redacted, and providers that can't accept documents fail.
+        request.messages[1]
+            .attachments
+            .push(MessageContent::Document {
+                mime_type: "application/pdf".into(),
+                data: "c2VjcmV0".into(),
+            });
+        let requests = request.to_provider_requests();
+        assert_eq!(
+            requests["anthropic"].as_ref().unwrap()["messages"][0]["content"][0]["source"]["data"],
+            "<redacted 8 bytes>"
+        );
+        assert!(requests["openai"].is_err());
+        assert!(requests["google"].is_err());
+    }
+
+    #[test]
+    fn test_into_open_ai_max_output_tokens() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Hello".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        for model in ["gpt-3.5-turbo", "gpt-4", "gpt-4o", "gpt-4o-mini", "o1x"] {
+            let json = serde_json::to_value(
+                request
+                    .clone()
+                    .into_open_ai(model.into(), Some(4000))
+                    .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(json["max_tokens"], 4000, "{model}");
+            assert!(json.get("max_completion_tokens").is_none(), "{model}");
+        }
+
+        for model in ["o1", "o1-preview", "o1-mini-2024-09-12", "o3-mini"] {
+            let json = serde_json::to_value(
+                request
+                    .clone()
+                    .into_open_ai(model.into(), Some(4000))
+                    .unwrap(),
+            )
+            .unwrap();
+            assert_eq!(json["max_completion_tokens"], 4000, "{model}");
+            assert!(json.get("max_tokens").is_none(), "{model}");
+        }
+    }
+
+    #[test]
+    fn test_tool_choice() {
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Hello".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            tool_choice: Some(ToolChoice::Required),
+            ..Default::default()
+        };
+        let open_ai_json =
+            serde_json::to_value(request.clone().into_open_ai("gpt-4o".into(), None).unwrap())
+                .unwrap();
+        assert_eq!(open_ai_json["tool_choice"], "required");
+        let anthropic_json =
+            serde_json::to_value(request.into_anthropic("claude-3-5-sonnet".into())).unwrap();
+        assert_eq!(
+            anthropic_json["tool_choice"],
+            serde_json::json!({ "type": "any" })
+        );
+
+        let request = LanguageModelRequest {
+            tool_choice: Some(ToolChoice::Auto),
+            ..Default::default()
+        };
+        let open_ai_json =
+            serde_json::to_value(request.clone().into_open_ai("gpt-4o".into(), None).unwrap())
+                .unwrap();
+        assert_eq!(open_ai_json["tool_choice"], "auto");
+        let anthropic_json =
+            serde_json::to_value(request.into_anthropic("claude-3-5-sonnet".into())).unwrap();
+        assert_eq!(
+            anthropic_json["tool_choice"],
+            serde_json::json!({ "type": "auto" })
+        );
+
+        // Without a tool choice, each provider's default applies.
+        let request = LanguageModelRequest::default();
+        let open_ai_json =
+            serde_json::to_value(request.clone().into_open_ai("gpt-4o".into(), None).unwrap())
+                .unwrap();
+        assert!(open_ai_json.get("tool_choice").is_none());
+        let anthropic_json =
+            serde_json::to_value(request.into_anthropic("claude-3-5-sonnet".into())).unwrap();
+        assert!(anthropic_json.get("tool_choice").is_none());
+    }
+
+    #[test]
+    fn test_into_bedrock() {
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::System,
+                    content: "Be concise.".into(),
+                    attachments: Vec::new(),
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: "How can I help?".into(),
+                    attachments: Vec::new(),
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: "Hello".into(),
+                    attachments: Vec::new(),
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: "Are you there?".into(),
+                    attachments: Vec::new(),
+                    cache: false,
+                },
+            ],
+            stop: vec!["\n\n".into()],
+            temperature: 0.5,
+            ..Default::default()
+        };
+
+        let bedrock_request = request
+            .into_bedrock("amazon.nova-lite-v1:0".into(), Some(1000))
+            .unwrap();
+        assert_eq!(bedrock_request.model, "amazon.nova-lite-v1:0");
+        assert_eq!(
+            serde_json::to_value(&bedrock_request).unwrap(),
+            serde_json::json!({
+                "messages": [
+                    { "role": "user", "content": [{ "text": PLACEHOLDER_USER_MESSAGE }] },
+                    { "role": "assistant", "content": [{ "text": "How can I help?" }] },
+                    {
+                        "role": "user",
+                        "content": [{ "text": "Hello" }, { "text": "Are you there?" }]
+                    },
+                ],
+                "system": [{ "text": "Be concise." }],
+                "inferenceConfig": {
+                    "maxTokens": 1000,
+                    "temperature": 0.5,
+                    "stopSequences": ["\n\n"],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn test_into_anthropic_max_tokens() {
+        for (model, max_tokens) in [
+            ("claude-3-5-sonnet-20240620", 8192),
+            ("claude-3-haiku", 4096),
+            ("my-custom-claude", anthropic::DEFAULT_MAX_OUTPUT_TOKENS),
+        ] {
+            let json =
+                serde_json::to_value(LanguageModelRequest::default().into_anthropic(model.into()))
+                    .unwrap();
+            assert_eq!(json["max_tokens"], max_tokens, "{model}");
+        }
+    }
+
+    #[test]
+    fn test_into_anthropic_top_k() {
+        let request = LanguageModelRequest {
+            top_k: Some(40),
+            ..Default::default()
+        };
+        let json =
+            serde_json::to_value(request.clone().into_anthropic("claude-3-haiku".into())).unwrap();
+        assert_eq!(json["top_k"], 40);
+
+        let json = serde_json::to_value(
+            LanguageModelRequest::default().into_anthropic("claude-3-haiku".into()),
+        )
+        .unwrap();
+        assert!(json.get("top_k").is_none());
+
+        // Other providers don't support it.
+        let json =
+            serde_json::to_value(request.into_open_ai("gpt-4o".into(), None).unwrap()).unwrap();
+        assert!(json.get("top_k").is_none());
+    }
+
+    #[test]
+    fn test_max_output_tokens_reserving_system_prompt() {
+        let max_token_count = 8192;
+        let conversation_tokens = 3000;
+
+        // Without a system prompt, only the margin is held back.
+        assert_eq!(
+            max_output_tokens_reserving_system_prompt(max_token_count, conversation_tokens, 0),
+            max_token_count - conversation_tokens - SYSTEM_PROMPT_RESERVATION_MARGIN
+        );
+
+        // A larger system prompt reduces the output limit by as much.
+        assert_eq!(
+            max_output_tokens_reserving_system_prompt(max_token_count, conversation_tokens, 1000),
+            max_token_count - conversation_tokens - 1000 - SYSTEM_PROMPT_RESERVATION_MARGIN
+        );
+
+        // Conversations that already fill the context window still leave room
+        // for a short response.
+        assert_eq!(
+            max_output_tokens_reserving_system_prompt(max_token_count, 8000, 1000),
+            MIN_RESERVED_OUTPUT_TOKENS
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use futures::{future::BoxFuture, AsyncReadExt as _, FutureExt as _};
+use http_client::{AsyncBody, Error, HttpClient, Request, Response, Uri};
+use std::sync::Arc;
+
+/// Rewrites the JSON body of a provider's requests before they're sent, for
+/// gateways that expect small deviations from the provider's API, such as
+/// renamed fields or extra parameters.
+pub type RequestTransform = fn(&mut serde_json::Value);
+
+/// An [`HttpClient`] that applies a [`RequestTransform`] to the body of each
+/// request it sends. Bodies that aren't JSON are sent unchanged.
+pub(crate) struct TransformingHttpClient {
+    client: Arc<dyn HttpClient>,
+    transform: RequestTransform,
+}
+
+impl TransformingHttpClient {
+    pub fn new(client: Arc<dyn HttpClient>, transform: RequestTransform) -> Self {
+        Self { client, transform }
+    }
+}
+
+impl HttpClient for TransformingHttpClient {
+    fn send(
+        &self,
+        req: Request<AsyncBody>,
+    ) -> BoxFuture<'static, Result<Response<AsyncBody>, Error>> {
+        let client = self.client.clone();
+        let transform = self.transform;
+        async move {
+            let (parts, mut body) = req.into_parts();
+            let mut bytes = Vec::new();
+            body.read_to_end(&mut bytes).await?;
+            let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+                Ok(mut value) => {
+                    transform(&mut value);
+                    serde_json::to_vec(&value).unwrap_or(bytes)
+                }
+                Err(_) => bytes,
+            };
+            client
+                .send(Request::from_parts(parts, AsyncBody::from(body)))
+                .await
+        }
+        .boxed()
+    }
+
+    fn proxy(&self) -> Option<&Uri> {
+        self.client.proxy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::block_on;
+    use http_client::FakeHttpClient;
+
+    #[test]
+    fn test_request_transform() {
+        // Echo the request body back, so that the test can see what was sent.
+        let http_client = FakeHttpClient::create(|mut request| async move {
+            let mut body = Vec::new();
+            request.body_mut().read_to_end(&mut body).await?;
+            Ok(Response::builder()
+                .status(200)
+                .body(AsyncBody::from(body))
+                .unwrap())
+        });
+        let client = TransformingHttpClient::new(http_client, |body| {
+            if let Some(body) = body.as_object_mut() {
+                if let Some(max_tokens) = body.remove("max_tokens") {
+                    body.insert("max_completion_tokens".into(), max_tokens);
+                }
+                body.insert("gateway_route".into(), "fast".into());
+            }
+        });
+
+        let mut response = block_on(client.post_json(
+            "http://test.example/chat/completions",
+            AsyncBody::from(r#"{"model":"gpt-4o","max_tokens":64}"#),
+        ))
+        .unwrap();
+        let mut body = Vec::new();
+        block_on(response.body_mut().read_to_end(&mut body)).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!({
+                "model": "gpt-4o",
+                "max_completion_tokens": 64,
+                "gateway_route": "fast",
+            })
+        );
+    }
+}
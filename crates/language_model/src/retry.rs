@@ -0,0 +1,137 @@
+use gpui::BackgroundExecutor;
+use rand::Rng;
+use std::{future::Future, time::Duration};
+
+/// How many times a request is retried after the provider reports that it's
+/// overloaded, before the error is returned to the caller.
+pub const MAX_RETRIES: u32 = 3;
+
+/// How long to wait before the first retry. Each later retry waits twice as
+/// long as the one before it.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+
+/// The longest a server may ask a request to wait before it's retried.
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// How often and how soon [`with_configured_backoff`] retries a failed request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    pub max_retries: u32,
+    /// How long to wait before the first retry. Each later retry waits twice
+    /// as long as the one before it.
+    pub base_delay: Duration,
+    /// The most random time added to each wait, so that clients that failed
+    /// together don't all retry at once.
+    pub max_jitter: Duration,
+    /// The longest delay a server may ask for before a retry. Requests that
+    /// it asks to wait for longer fail straight away, rather than leaving the
+    /// user waiting.
+    pub max_retry_after: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            max_retries: MAX_RETRIES,
+            base_delay: INITIAL_BACKOFF,
+            max_jitter: Duration::ZERO,
+            max_retry_after: MAX_RETRY_AFTER,
+        }
+    }
+}
+
+impl Backoff {
+    /// How long to wait before the given retry, counting from zero.
+    pub fn delay(&self, retry: u32, rng: &mut impl Rng) -> Duration {
+        let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(retry));
+        let jitter = self.max_jitter.mul_f64(rng.gen_range(0.0..=1.0));
+        backoff.saturating_add(jitter)
+    }
+}
+
+/// Whether, and when, a failed request should be retried.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retry {
+    Never,
+    AfterBackoff,
+    /// After the given delay instead of the backoff, such as when the server
+    /// asked for one with a `Retry-After` header.
+    After(Duration),
+}
+
+/// Makes a request, retrying it with exponential backoff while it fails with
+/// errors that `is_retryable` accepts, up to [`MAX_RETRIES`] times.
+pub async fn with_backoff<T, E, Fut>(
+    executor: &BackgroundExecutor,
+    mut is_retryable: impl FnMut(&E) -> bool,
+    request: impl FnMut() -> Fut,
+) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    let should_retry = |error: &E| {
+        if is_retryable(error) {
+            Retry::AfterBackoff
+        } else {
+            Retry::Never
+        }
+    };
+    with_configured_backoff(executor, Backoff::default(), should_retry, request).await
+}
+
+/// Like [`with_backoff`], but retrying as `backoff` describes, when
+/// `should_retry` says to.
+///
+/// `should_retry` is only asked about errors that there are retries left
+/// for, so it's also where the caller can react to a retry being made.
+pub async fn with_configured_backoff<T, E, Fut>(
+    executor: &BackgroundExecutor,
+    backoff: Backoff,
+    mut should_retry: impl FnMut(&E) -> Retry,
+    mut request: impl FnMut() -> Fut,
+) -> Result<T, E>
+where
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut retries = 0;
+    loop {
+        match request().await {
+            Err(error) if retries < backoff.max_retries => {
+                let delay = match should_retry(&error) {
+                    Retry::Never => return Err(error),
+                    Retry::AfterBackoff => backoff.delay(retries, &mut rand::thread_rng()),
+                    Retry::After(delay) if delay > backoff.max_retry_after => return Err(error),
+                    Retry::After(delay) => delay,
+                };
+                log::warn!("request failed with a transient error, retrying in {delay:?}");
+                executor.timer(delay).await;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_delay() {
+        let backoff = Backoff {
+            max_retries: 3,
+            base_delay: Duration::from_secs(1),
+            max_jitter: Duration::from_millis(500),
+            max_retry_after: MAX_RETRY_AFTER,
+        };
+        let mut rng = rand::thread_rng();
+        for (retry, expected) in [1, 2, 4].into_iter().enumerate() {
+            let delay = backoff.delay(retry as u32, &mut rng);
+            let expected = Duration::from_secs(expected);
+            assert!(
+                delay >= expected && delay <= expected + backoff.max_jitter,
+                "{delay:?}"
+            );
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use std::{sync::Arc, time::Duration};
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
+use collections::BTreeMap;
 use gpui::AppContext;
 use project::Fs;
 use schemars::JsonSchema;
@@ -10,6 +11,7 @@ use settings::{update_settings_file, Settings, SettingsSources};
 use crate::provider::{
     self,
     anthropic::AnthropicSettings,
+    bedrock::BedrockSettings,
     cloud::{self, ZedDotDevSettings},
     copilot_chat::CopilotChatSettings,
     google::GoogleSettings,
@@ -57,7 +59,26 @@ pub struct AllLanguageModelSettings {
     pub openai: OpenAiSettings,
     pub zed_dot_dev: ZedDotDevSettings,
     pub google: GoogleSettings,
+    pub bedrock: BedrockSettings,
     pub copilot_chat: CopilotChatSettings,
+    pub default_model: Option<DefaultModelSettings>,
+    pub max_queue_wait: Option<Duration>,
+    pub record_transcripts: bool,
+    pub warm_up_connections: bool,
+    pub tokenizer_assets_dir: Option<PathBuf>,
+    pub assumed_output_tokens: usize,
+    pub assumed_output_tokens_per_model: BTreeMap<String, usize>,
+    pub redact_pii: bool,
+    pub pii_patterns: BTreeMap<String, String>,
+}
+
+/// The model used by features that don't let the user pick one.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+pub struct DefaultModelSettings {
+    /// The id of the provider offering the model, such as "anthropic" or "openai".
+    pub provider: String,
+    /// The id of the model within the provider.
+    pub model: String,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -68,7 +89,33 @@ pub struct AllLanguageModelSettingsContent {
     #[serde(rename = "zed.dev")]
     pub zed_dot_dev: Option<ZedDotDevSettingsContent>,
     pub google: Option<GoogleSettingsContent>,
+    pub bedrock: Option<BedrockSettingsContent>,
     pub copilot_chat: Option<CopilotChatSettingsContent>,
+    pub default_model: Option<DefaultModelSettings>,
+    /// How long a request may wait for one of its provider's concurrent
+    /// request slots before failing, rather than waiting indefinitely.
+    pub max_queue_wait_in_seconds: Option<u64>,
+    /// Whether to keep the last few requests and responses of each provider in
+    /// memory, for the `assistant: debug transcripts` command.
+    pub record_transcripts: Option<bool>,
+    /// Whether to open a connection to Anthropic and OpenAI as soon as their
+    /// API keys are loaded, so that the first completion starts sooner.
+    pub warm_up_connections: Option<bool>,
+    /// A directory containing tiktoken's BPE files, such as
+    /// `cl100k_base.tiktoken`, to count tokens with instead of the copies
+    /// bundled with Zed. Token counts are estimated if a file is missing.
+    pub tokenizer_assets_dir: Option<PathBuf>,
+    /// How many tokens to assume a completion will produce when estimating
+    /// its cost before it's sent, since its length isn't known until then.
+    pub assumed_output_tokens: Option<usize>,
+    /// Overrides `assumed_output_tokens` for specific models, by model id.
+    pub assumed_output_tokens_per_model: Option<BTreeMap<String, usize>>,
+    /// Whether to replace text matching `pii_patterns` in requests before
+    /// they're sent to any provider.
+    pub redact_pii: Option<bool>,
+    /// Regexes matching personal information to redact when `redact_pii` is
+    /// enabled, keyed by a name that replaces each match.
+    pub pii_patterns: Option<BTreeMap<String, String>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -91,10 +138,12 @@ impl AnthropicSettingsContent {
                             .filter_map(|model| match model {
                                 anthropic::Model::Custom {
                                     name,
+                                    display_name,
                                     max_tokens,
                                     tool_override,
                                 } => Some(provider::anthropic::AvailableModel {
                                     name,
+                                    display_name,
                                     max_tokens,
                                     tool_override,
                                 }),
@@ -102,6 +151,7 @@ impl AnthropicSettingsContent {
                             })
                             .collect()
                     }),
+                    disabled_models: content.disabled_models,
                 },
                 true,
             ),
@@ -117,6 +167,7 @@ pub struct LegacyAnthropicSettingsContent {
     pub api_url: Option<String>,
     pub low_speed_timeout_in_seconds: Option<u64>,
     pub available_models: Option<Vec<anthropic::Model>>,
+    pub disabled_models: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -131,6 +182,7 @@ pub struct AnthropicSettingsContentV1 {
     pub api_url: Option<String>,
     pub low_speed_timeout_in_seconds: Option<u64>,
     pub available_models: Option<Vec<provider::anthropic::AvailableModel>>,
+    pub disabled_models: Option<Vec<String>>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -157,13 +209,21 @@ impl OpenAiSettingsContent {
                         models
                             .into_iter()
                             .filter_map(|model| match model {
-                                open_ai::Model::Custom { name, max_tokens } => {
-                                    Some(provider::open_ai::AvailableModel { name, max_tokens })
-                                }
+                                open_ai::Model::Custom {
+                                    name,
+                                    display_name,
+                                    max_tokens,
+                                } => Some(provider::open_ai::AvailableModel {
+                                    name,
+                                    display_name,
+                                    max_tokens,
+                                }),
                                 _ => None,
                             })
                             .collect()
                     }),
+                    disabled_models: content.disabled_models,
+                    include_stream_usage: None,
                 },
                 true,
             ),
@@ -179,6 +239,7 @@ pub struct LegacyOpenAiSettingsContent {
     pub api_url: Option<String>,
     pub low_speed_timeout_in_seconds: Option<u64>,
     pub available_models: Option<Vec<open_ai::Model>>,
+    pub disabled_models: Option<Vec<String>>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -193,6 +254,11 @@ pub struct OpenAiSettingsContentV1 {
     pub api_url: Option<String>,
     pub low_speed_timeout_in_seconds: Option<u64>,
     pub available_models: Option<Vec<provider::open_ai::AvailableModel>>,
+    pub disabled_models: Option<Vec<String>>,
+    /// Whether to ask for token usage at the end of each streamed completion.
+    /// Defaults to only asking when `api_url` is OpenAI's own, since some
+    /// OpenAI-compatible servers reject the `stream_options` parameter.
+    pub include_stream_usage: Option<bool>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -200,16 +266,45 @@ pub struct GoogleSettingsContent {
     pub api_url: Option<String>,
     pub low_speed_timeout_in_seconds: Option<u64>,
     pub available_models: Option<Vec<provider::google::AvailableModel>>,
+    pub disabled_models: Option<Vec<String>>,
+    pub max_concurrent_requests: Option<usize>,
+    pub max_concurrent_token_counts: Option<usize>,
+    pub safety_settings: Option<BTreeMap<google_ai::HarmCategory, google_ai::HarmBlockThreshold>>,
+}
+
+#[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
+pub struct BedrockSettingsContent {
+    /// The AWS region whose Bedrock runtime endpoint requests are sent to,
+    /// such as `us-east-1`.
+    pub region: Option<String>,
+    pub low_speed_timeout_in_seconds: Option<u64>,
+    pub available_models: Option<Vec<provider::bedrock::AvailableModel>>,
+    pub disabled_models: Option<Vec<String>>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct ZedDotDevSettingsContent {
+    enabled: Option<bool>,
     available_models: Option<Vec<cloud::AvailableModel>>,
+    compress_requests: Option<bool>,
+    disabled_models: Option<Vec<String>>,
+    /// How many times to retry a completion that fails with a transient
+    /// error, such as the server being overloaded.
+    max_retries: Option<u32>,
+    /// How long to wait before the first retry, doubling for each later one,
+    /// unless the server says how long to wait.
+    retry_base_delay_in_milliseconds: Option<u64>,
+    /// The most random time added to each wait before a retry.
+    retry_max_jitter_in_milliseconds: Option<u64>,
+    /// The longest the server may ask to wait before a retry. Completions
+    /// it asks to wait for longer fail straight away.
+    max_retry_after_in_seconds: Option<u64>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct CopilotChatSettingsContent {
     low_speed_timeout_in_seconds: Option<u64>,
+    disabled_models: Option<Vec<String>>,
 }
 
 impl settings::Settings for AllLanguageModelSettings {
@@ -254,6 +349,10 @@ impl settings::Settings for AllLanguageModelSettings {
                 &mut settings.anthropic.available_models,
                 anthropic.as_ref().and_then(|s| s.available_models.clone()),
             );
+            merge(
+                &mut settings.anthropic.disabled_models,
+                anthropic.as_ref().and_then(|s| s.disabled_models.clone()),
+            );
 
             merge(
                 &mut settings.ollama.api_url,
@@ -292,7 +391,19 @@ impl settings::Settings for AllLanguageModelSettings {
                 &mut settings.openai.available_models,
                 openai.as_ref().and_then(|s| s.available_models.clone()),
             );
+            merge(
+                &mut settings.openai.disabled_models,
+                openai.as_ref().and_then(|s| s.disabled_models.clone()),
+            );
+            if let Some(include_stream_usage) = openai.as_ref().and_then(|s| s.include_stream_usage)
+            {
+                settings.openai.include_stream_usage = Some(include_stream_usage);
+            }
 
+            merge(
+                &mut settings.zed_dot_dev.enabled,
+                value.zed_dot_dev.as_ref().and_then(|s| s.enabled),
+            );
             merge(
                 &mut settings.zed_dot_dev.available_models,
                 value
@@ -300,6 +411,45 @@ impl settings::Settings for AllLanguageModelSettings {
                     .as_ref()
                     .and_then(|s| s.available_models.clone()),
             );
+            merge(
+                &mut settings.zed_dot_dev.compress_requests,
+                value.zed_dot_dev.as_ref().and_then(|s| s.compress_requests),
+            );
+            merge(
+                &mut settings.zed_dot_dev.disabled_models,
+                value
+                    .zed_dot_dev
+                    .as_ref()
+                    .and_then(|s| s.disabled_models.clone()),
+            );
+            merge(
+                &mut settings.zed_dot_dev.retry.max_retries,
+                value.zed_dot_dev.as_ref().and_then(|s| s.max_retries),
+            );
+            merge(
+                &mut settings.zed_dot_dev.retry.base_delay,
+                value
+                    .zed_dot_dev
+                    .as_ref()
+                    .and_then(|s| s.retry_base_delay_in_milliseconds)
+                    .map(Duration::from_millis),
+            );
+            merge(
+                &mut settings.zed_dot_dev.retry.max_jitter,
+                value
+                    .zed_dot_dev
+                    .as_ref()
+                    .and_then(|s| s.retry_max_jitter_in_milliseconds)
+                    .map(Duration::from_millis),
+            );
+            merge(
+                &mut settings.zed_dot_dev.retry.max_retry_after,
+                value
+                    .zed_dot_dev
+                    .as_ref()
+                    .and_then(|s| s.max_retry_after_in_seconds)
+                    .map(Duration::from_secs),
+            );
 
             merge(
                 &mut settings.google.api_url,
@@ -320,6 +470,61 @@ impl settings::Settings for AllLanguageModelSettings {
                     .as_ref()
                     .and_then(|s| s.available_models.clone()),
             );
+            merge(
+                &mut settings.google.disabled_models,
+                value
+                    .google
+                    .as_ref()
+                    .and_then(|s| s.disabled_models.clone()),
+            );
+            if let Some(max_concurrent_requests) = value
+                .google
+                .as_ref()
+                .and_then(|s| s.max_concurrent_requests)
+            {
+                settings.google.max_concurrent_requests = Some(max_concurrent_requests);
+            }
+            if let Some(max_concurrent_token_counts) = value
+                .google
+                .as_ref()
+                .and_then(|s| s.max_concurrent_token_counts)
+            {
+                settings.google.max_concurrent_token_counts = Some(max_concurrent_token_counts);
+            }
+            merge(
+                &mut settings.google.safety_settings,
+                value
+                    .google
+                    .as_ref()
+                    .and_then(|s| s.safety_settings.clone()),
+            );
+
+            merge(
+                &mut settings.bedrock.region,
+                value.bedrock.as_ref().and_then(|s| s.region.clone()),
+            );
+            if let Some(low_speed_timeout_in_seconds) = value
+                .bedrock
+                .as_ref()
+                .and_then(|s| s.low_speed_timeout_in_seconds)
+            {
+                settings.bedrock.low_speed_timeout =
+                    Some(Duration::from_secs(low_speed_timeout_in_seconds));
+            }
+            merge(
+                &mut settings.bedrock.available_models,
+                value
+                    .bedrock
+                    .as_ref()
+                    .and_then(|s| s.available_models.clone()),
+            );
+            merge(
+                &mut settings.bedrock.disabled_models,
+                value
+                    .bedrock
+                    .as_ref()
+                    .and_then(|s| s.disabled_models.clone()),
+            );
 
             if let Some(low_speed_timeout) = value
                 .copilot_chat
@@ -329,6 +534,44 @@ impl settings::Settings for AllLanguageModelSettings {
                 settings.copilot_chat.low_speed_timeout =
                     Some(Duration::from_secs(low_speed_timeout));
             }
+            merge(
+                &mut settings.copilot_chat.disabled_models,
+                value
+                    .copilot_chat
+                    .as_ref()
+                    .and_then(|s| s.disabled_models.clone()),
+            );
+
+            if let Some(default_model) = value.default_model.clone() {
+                settings.default_model = Some(default_model);
+            }
+            if let Some(max_queue_wait_in_seconds) = value.max_queue_wait_in_seconds {
+                settings.max_queue_wait = Some(Duration::from_secs(max_queue_wait_in_seconds));
+            }
+            merge(&mut settings.record_transcripts, value.record_transcripts);
+            merge(&mut settings.warm_up_connections, value.warm_up_connections);
+            if let Some(tokenizer_assets_dir) = value.tokenizer_assets_dir.clone() {
+                settings.tokenizer_assets_dir = Some(tokenizer_assets_dir);
+            }
+            merge(
+                &mut settings.assumed_output_tokens,
+                value.assumed_output_tokens,
+            );
+            if let Some(per_model) = value.assumed_output_tokens_per_model.as_ref() {
+                settings.assumed_output_tokens_per_model.extend(
+                    per_model
+                        .iter()
+                        .map(|(model, tokens)| (model.clone(), *tokens)),
+                );
+            }
+            merge(&mut settings.redact_pii, value.redact_pii);
+            if let Some(pii_patterns) = value.pii_patterns.as_ref() {
+                settings.pii_patterns.extend(
+                    pii_patterns
+                        .iter()
+                        .map(|(name, pattern)| (name.clone(), pattern.clone())),
+                );
+            }
         }
 
         Ok(settings)
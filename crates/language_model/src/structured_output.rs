@@ -0,0 +1,489 @@
+use anyhow::{anyhow, Result};
+use collections::HashSet;
+use serde_json::Value;
+
+/// How many `$ref`/`allOf` indirections to follow before giving up on a schema.
+const MAX_SCHEMA_INDIRECTIONS: usize = 32;
+
+/// Checks a JSON document against a JSON schema while it is still being
+/// streamed, so that generation can be abandoned as soon as the output can no
+/// longer conform to the schema.
+///
+/// Only violations that can't be recovered from are reported: a value of the
+/// wrong type, a property the schema forbids, or an object closed without one
+/// of its required properties. Anything the validator doesn't understand is
+/// accepted, leaving the final say to deserialization.
+pub struct StreamingSchemaValidator {
+    root_schema: Value,
+    stack: Vec<Frame>,
+    token: Token,
+    started: bool,
+    finished: bool,
+}
+
+enum Frame {
+    Object {
+        schema: Value,
+        seen_keys: HashSet<String>,
+        key: Option<String>,
+        expecting: ObjectExpectation,
+    },
+    Array {
+        items: Value,
+        index: usize,
+        expecting_value: bool,
+    },
+}
+
+enum ObjectExpectation {
+    KeyOrEnd,
+    Colon(Value),
+    Value(Value),
+    CommaOrEnd,
+}
+
+enum Token {
+    None,
+    String { key: Option<String>, escaped: bool },
+    Literal,
+}
+
+impl StreamingSchemaValidator {
+    pub fn new(schema: Value) -> Self {
+        Self {
+            root_schema: schema,
+            stack: Vec::new(),
+            token: Token::None,
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Feeds the next chunk of streamed JSON into the validator, returning an
+    /// error as soon as the document can no longer match the schema.
+    pub fn push(&mut self, chunk: &str) -> Result<()> {
+        for ch in chunk.chars() {
+            self.push_char(ch)?;
+        }
+        Ok(())
+    }
+
+    fn push_char(&mut self, ch: char) -> Result<()> {
+        match &mut self.token {
+            Token::String { key, escaped } => {
+                if *escaped {
+                    *escaped = false;
+                } else if ch == '\\' {
+                    *escaped = true;
+                } else if ch == '"' {
+                    let key = key.take();
+                    self.token = Token::None;
+                    if let Some(key) = key {
+                        self.end_key(key)?;
+                    } else {
+                        self.end_value()?;
+                    }
+                    return Ok(());
+                }
+
+                if let Some(key) = key {
+                    key.push(ch);
+                }
+                Ok(())
+            }
+            Token::Literal => {
+                if ch.is_whitespace() || matches!(ch, ',' | '}' | ']') {
+                    self.token = Token::None;
+                    self.end_value()?;
+                    self.push_structural(ch)
+                } else {
+                    Ok(())
+                }
+            }
+            Token::None => self.push_structural(ch),
+        }
+    }
+
+    fn push_structural(&mut self, ch: char) -> Result<()> {
+        if ch.is_whitespace() {
+            return Ok(());
+        }
+
+        let Some(frame) = self.stack.last_mut() else {
+            if self.started {
+                return Err(anyhow!("unexpected '{ch}' after the end of the JSON value"));
+            }
+            self.started = true;
+            let schema = self.root_schema.clone();
+            return self.begin_value(ch, &schema);
+        };
+
+        match frame {
+            Frame::Object { key, expecting, .. } => {
+                match std::mem::replace(expecting, ObjectExpectation::CommaOrEnd) {
+                    ObjectExpectation::KeyOrEnd if ch == '"' => {
+                        *key = None;
+                        self.token = Token::String {
+                            key: Some(String::new()),
+                            escaped: false,
+                        };
+                        Ok(())
+                    }
+                    ObjectExpectation::KeyOrEnd | ObjectExpectation::CommaOrEnd if ch == '}' => {
+                        self.end_object()
+                    }
+                    ObjectExpectation::CommaOrEnd if ch == ',' => {
+                        *expecting = ObjectExpectation::KeyOrEnd;
+                        Ok(())
+                    }
+                    ObjectExpectation::Colon(schema) if ch == ':' => {
+                        *expecting = ObjectExpectation::Value(schema);
+                        Ok(())
+                    }
+                    ObjectExpectation::Value(schema) => self.begin_value(ch, &schema),
+                    _ => Err(anyhow!("unexpected '{ch}' in JSON object")),
+                }
+            }
+            Frame::Array {
+                items,
+                index,
+                expecting_value,
+            } => {
+                if ch == ']' {
+                    self.stack.pop();
+                    self.end_value()
+                } else if *expecting_value {
+                    *expecting_value = false;
+                    let items = items.clone();
+                    self.begin_value(ch, &items)
+                } else if ch == ',' {
+                    *index += 1;
+                    *expecting_value = true;
+                    Ok(())
+                } else {
+                    Err(anyhow!("unexpected '{ch}' in JSON array"))
+                }
+            }
+        }
+    }
+
+    fn begin_value(&mut self, ch: char, schema: &Value) -> Result<()> {
+        let schema = self.resolve(schema);
+        let kind = match ch {
+            '{' => "object",
+            '[' => "array",
+            '"' => "string",
+            't' | 'f' => "boolean",
+            'n' => "null",
+            '-' | '0'..='9' => "number",
+            _ => return Err(anyhow!("unexpected '{ch}' at {}", self.path())),
+        };
+
+        if !schema_allows_kind(&schema, kind) {
+            return Err(anyhow!(
+                "output does not match the schema: expected {} at {}, found {kind}",
+                schema_type_description(&schema),
+                self.path(),
+            ));
+        }
+
+        match kind {
+            "object" => self.stack.push(Frame::Object {
+                schema,
+                seen_keys: HashSet::default(),
+                key: None,
+                expecting: ObjectExpectation::KeyOrEnd,
+            }),
+            "array" => self.stack.push(Frame::Array {
+                items: schema.get("items").cloned().unwrap_or(Value::Bool(true)),
+                index: 0,
+                expecting_value: true,
+            }),
+            "string" => {
+                self.token = Token::String {
+                    key: None,
+                    escaped: false,
+                }
+            }
+            _ => self.token = Token::Literal,
+        }
+        Ok(())
+    }
+
+    fn end_key(&mut self, new_key: String) -> Result<()> {
+        let Some(Frame::Object {
+            schema,
+            seen_keys,
+            key,
+            expecting,
+        }) = self.stack.last_mut()
+        else {
+            return Ok(());
+        };
+
+        let property_schema = property_schema(schema, &new_key)?;
+        seen_keys.insert(new_key.clone());
+        *key = Some(new_key);
+        *expecting = ObjectExpectation::Colon(property_schema);
+        Ok(())
+    }
+
+    fn end_object(&mut self) -> Result<()> {
+        if let Some(Frame::Object {
+            schema, seen_keys, ..
+        }) = self.stack.pop()
+        {
+            let required = schema.get("required").and_then(Value::as_array);
+            for required_key in required.into_iter().flatten().filter_map(Value::as_str) {
+                if !seen_keys.contains(required_key) {
+                    return Err(anyhow!(
+                        "output does not match the schema: missing required property `{required_key}` at {}",
+                        self.path(),
+                    ));
+                }
+            }
+        }
+
+        self.end_value()
+    }
+
+    fn end_value(&mut self) -> Result<()> {
+        if self.stack.is_empty() {
+            self.finished = true;
+        }
+        Ok(())
+    }
+
+    /// Follows `$ref`s into the root schema and unwraps single-element `allOf`s,
+    /// which is how `schemars` describes nested types.
+    fn resolve(&self, schema: &Value) -> Value {
+        let mut schema = schema;
+        for _ in 0..MAX_SCHEMA_INDIRECTIONS {
+            if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+                match reference
+                    .strip_prefix('#')
+                    .and_then(|pointer| self.root_schema.pointer(pointer))
+                {
+                    Some(referenced) => schema = referenced,
+                    None => break,
+                }
+            } else if let Some([inner]) = schema
+                .get("allOf")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+            {
+                schema = inner;
+            } else {
+                break;
+            }
+        }
+        schema.clone()
+    }
+
+    fn path(&self) -> String {
+        let mut path = String::from("$");
+        for frame in &self.stack {
+            match frame {
+                Frame::Object { key: Some(key), .. } => {
+                    path.push('.');
+                    path.push_str(key);
+                }
+                Frame::Object { key: None, .. } => {}
+                Frame::Array { index, .. } => path.push_str(&format!("[{index}]")),
+            }
+        }
+        path
+    }
+
+    /// Returns whether a complete JSON value has been received.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Checks a value that may still be added to against the schema, such as
+    /// tool input that's sent as several objects to be merged. Like a value
+    /// that's still streaming, it isn't required to have every required
+    /// property yet.
+    pub fn validate_partial(&self, value: &Value) -> Result<()> {
+        self.validate_partial_at(value, &self.root_schema, "$".into())
+    }
+
+    fn validate_partial_at(&self, value: &Value, schema: &Value, path: String) -> Result<()> {
+        let schema = self.resolve(schema);
+        let kind = match value {
+            Value::Null => "null",
+            Value::Bool(_) => "boolean",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        };
+        if !schema_allows_kind(&schema, kind) {
+            return Err(anyhow!(
+                "output does not match the schema: expected {} at {path}, found {kind}",
+                schema_type_description(&schema),
+            ));
+        }
+
+        match value {
+            Value::Object(properties) => {
+                for (key, value) in properties {
+                    let property_schema = property_schema(&schema, key)?;
+                    self.validate_partial_at(value, &property_schema, format!("{path}.{key}"))?;
+                }
+            }
+            Value::Array(items) => {
+                let items_schema = schema.get("items").cloned().unwrap_or(Value::Bool(true));
+                for (index, item) in items.iter().enumerate() {
+                    self.validate_partial_at(item, &items_schema, format!("{path}[{index}]"))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Returns the schema for an object's `key`, or an error if the object's
+/// schema doesn't allow it.
+fn property_schema(schema: &Value, key: &str) -> Result<Value> {
+    if let Some(property_schema) = schema
+        .get("properties")
+        .and_then(|properties| properties.get(key))
+    {
+        return Ok(property_schema.clone());
+    }
+    match schema.get("additionalProperties") {
+        Some(Value::Bool(false)) => Err(anyhow!(
+            "output does not match the schema: property `{key}` is not allowed",
+        )),
+        Some(additional_properties) => Ok(additional_properties.clone()),
+        None => Ok(Value::Bool(true)),
+    }
+}
+
+fn schema_types(schema: &Value) -> Option<Vec<&str>> {
+    match schema.get("type")? {
+        Value::String(ty) => Some(vec![ty.as_str()]),
+        Value::Array(types) => Some(types.iter().filter_map(Value::as_str).collect()),
+        _ => None,
+    }
+}
+
+fn schema_allows_kind(schema: &Value, kind: &str) -> bool {
+    if schema == &Value::Bool(false) {
+        return false;
+    }
+
+    schema_types(schema).map_or(true, |types| {
+        types
+            .iter()
+            .any(|ty| *ty == kind || (kind == "number" && *ty == "integer"))
+    })
+}
+
+fn schema_type_description(schema: &Value) -> String {
+    schema_types(schema).map_or_else(|| "nothing".into(), |types| types.join(" or "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn schema() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "age": { "type": "integer" },
+                "tags": { "type": "array", "items": { "$ref": "#/definitions/Tag" } }
+            },
+            "required": ["name"],
+            "additionalProperties": false,
+            "definitions": {
+                "Tag": { "type": "string" }
+            }
+        })
+    }
+
+    #[test]
+    fn test_streaming_schema_validator() {
+        let mut validator = StreamingSchemaValidator::new(schema());
+        for chunk in [
+            "{\"na",
+            "me\": \"Ada \\\"",
+            "Lovelace\\\"\", \"age\": 3",
+            "6, \"tags\": [\"math\", ",
+            "\"poetry\"]}",
+        ] {
+            validator.push(chunk).unwrap();
+        }
+        assert!(validator.is_finished());
+
+        let mut validator = StreamingSchemaValidator::new(schema());
+        let error = validator.push("{\"tags\": [\"math\", 42").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "output does not match the schema: expected string at $.tags[1], found number"
+        );
+
+        let mut validator = StreamingSchemaValidator::new(schema());
+        let error = validator.push("{\"age\": 1}").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "output does not match the schema: missing required property `name` at $"
+        );
+    }
+
+    #[test]
+    fn test_streaming_schema_violation_stops_early() {
+        let chunks = [
+            "{\"name\": \"Ada\", ",
+            "\"nickname\"",
+            ": \"The Enchantress of Numbers\"",
+            ", \"age\": 36",
+            "}",
+        ];
+
+        let mut validator = StreamingSchemaValidator::new(schema());
+        let mut consumed_chunks = 0;
+        let result = chunks.iter().try_for_each(|chunk| {
+            consumed_chunks += 1;
+            validator.push(chunk)
+        });
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "output does not match the schema: property `nickname` is not allowed"
+        );
+        assert_eq!(consumed_chunks, 2);
+        assert!(!validator.is_finished());
+    }
+
+    #[test]
+    fn test_validate_partial() {
+        let validator = StreamingSchemaValidator::new(schema());
+
+        // Required properties may still be added.
+        validator.validate_partial(&json!({ "age": 36 })).unwrap();
+        validator
+            .validate_partial(&json!({ "name": "Ada", "tags": ["math"] }))
+            .unwrap();
+
+        assert_eq!(
+            validator
+                .validate_partial(&json!({ "tags": ["math", 42] }))
+                .unwrap_err()
+                .to_string(),
+            "output does not match the schema: expected string at $.tags[1], found number"
+        );
+        assert_eq!(
+            validator
+                .validate_partial(&json!({ "nickname": "Ada" }))
+                .unwrap_err()
+                .to_string(),
+            "output does not match the schema: property `nickname` is not allowed"
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{LanguageModel, LanguageModelRequest, LanguageModelRequestMessage, Role};
+use anyhow::{bail, Result};
+use futures::{future::BoxFuture, FutureExt, StreamExt};
+use gpui::AsyncAppContext;
+use std::sync::Arc;
+
+/// Starts the system message that stands in for the turns that were summarized.
+const SUMMARY_PREFIX: &str = "Summary of the earlier conversation:\n\n";
+
+const SUMMARIZATION_PROMPT: &str = "\
+Summarize the following conversation between a user and an assistant. Keep every \
+fact, decision, name, and piece of code that later turns may rely on, and leave out \
+pleasantries. Respond with the summary only.";
+
+impl LanguageModelRequest {
+    /// Has `model` summarize the oldest turns of the conversation into a
+    /// system note, repeating until the request's estimated token count is at
+    /// most `max_tokens`, as an alternative to dropping those turns.
+    ///
+    /// System messages and the latest turn are always kept as they are, and
+    /// the attachments of summarized turns are dropped. Fails if the request
+    /// still doesn't fit once every other turn has been summarized.
+    pub fn summarize_to_fit(
+        mut self,
+        model: Arc<dyn LanguageModel>,
+        max_tokens: usize,
+        cx: &AsyncAppContext,
+    ) -> BoxFuture<'static, Result<Self>> {
+        let cx = cx.clone();
+        async move {
+            if self.estimated_token_count() <= max_tokens {
+                return Ok(self);
+            }
+
+            // Summarize the oldest half of the turns before the latest one,
+            // along with any earlier summary, so that each pass shrinks the
+            // conversation while the summary doesn't grow without bound.
+            let turns = self
+                .messages
+                .iter()
+                .enumerate()
+                .filter(|(_, message)| message.role != Role::System)
+                .map(|(ix, _)| ix)
+                .collect::<Vec<_>>();
+            let summarizable_turns = turns.len().saturating_sub(1);
+            if summarizable_turns == 0 {
+                bail!("the request doesn't fit in {max_tokens} tokens, even when summarized");
+            }
+            let summarized = turns[..summarizable_turns.div_ceil(2)]
+                .iter()
+                .copied()
+                .chain(
+                    self.messages
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, message)| is_summary(message))
+                        .map(|(ix, _)| ix),
+                )
+                .collect::<Vec<_>>();
+
+            let transcript = self
+                .messages
+                .iter()
+                .enumerate()
+                .filter(|(ix, _)| summarized.contains(ix))
+                .map(
+                    |(_, message)| match message.content.strip_prefix(SUMMARY_PREFIX) {
+                        Some(summary) => format!("(summary of earlier turns): {summary}"),
+                        None => format!("{}: {}", message.role, message.content),
+                    },
+                )
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            let summary = summarize(&model, transcript, &cx).await?;
+
+            let insertion_ix = summarized.iter().copied().min().unwrap_or_default();
+            let mut messages = Vec::with_capacity(self.messages.len());
+            for (ix, message) in self.messages.into_iter().enumerate() {
+                if ix == insertion_ix {
+                    messages.push(LanguageModelRequestMessage {
+                        role: Role::System,
+                        content: format!("{SUMMARY_PREFIX}{summary}"),
+                        attachments: Vec::new(),
+                        cache: false,
+                    });
+                }
+                if !summarized.contains(&ix) {
+                    messages.push(message);
+                }
+            }
+            self.messages = messages;
+
+            self.summarize_to_fit(model, max_tokens, &cx).await
+        }
+        .boxed()
+    }
+}
+
+fn is_summary(message: &LanguageModelRequestMessage) -> bool {
+    message.role == Role::System && message.content.starts_with(SUMMARY_PREFIX)
+}
+
+async fn summarize(
+    model: &Arc<dyn LanguageModel>,
+    transcript: String,
+    cx: &AsyncAppContext,
+) -> Result<String> {
+    let request = LanguageModelRequest {
+        messages: vec![
+            LanguageModelRequestMessage {
+                role: Role::System,
+                content: SUMMARIZATION_PROMPT.into(),
+                attachments: Vec::new(),
+                cache: false,
+            },
+            LanguageModelRequestMessage {
+                role: Role::User,
+                content: transcript,
+                attachments: Vec::new(),
+                cache: false,
+            },
+        ],
+        temperature: 0.,
+        ..Default::default()
+    };
+    let mut chunks = model.stream_completion_text(request, cx).await?;
+    let mut summary = String::new();
+    while let Some(chunk) = chunks.next().await {
+        summary.push_str(&chunk?);
+    }
+    Ok(summary.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::fake::FakeLanguageModel;
+    use gpui::TestAppContext;
+
+    fn message(role: Role, content: String) -> LanguageModelRequestMessage {
+        LanguageModelRequestMessage {
+            role,
+            content,
+            attachments: Vec::new(),
+            cache: false,
+        }
+    }
+
+    #[gpui::test]
+    async fn test_summarize_to_fit(cx: &mut TestAppContext) {
+        // Twenty turns of about a hundred tokens each, after a system prompt.
+        let mut messages = vec![message(Role::System, "You are a helpful assistant.".into())];
+        for turn in 0..20 {
+            let role = if turn % 2 == 0 {
+                Role::User
+            } else {
+                Role::Assistant
+            };
+            messages.push(message(
+                role,
+                format!("Turn {turn}. {}", "lorem ".repeat(66)),
+            ));
+        }
+        let latest_turn = messages.last().unwrap().clone();
+        let request = LanguageModelRequest {
+            messages,
+            ..Default::default()
+        };
+        assert!(request.estimated_token_count() > 2000);
+
+        let model = Arc::new(FakeLanguageModel::default());
+        let summarized =
+            cx.executor()
+                .spawn(request.summarize_to_fit(model.clone(), 500, &cx.to_async()));
+
+        let mut summaries = 0;
+        loop {
+            cx.run_until_parked();
+            let Some(summarization) = model.pending_completions().pop() else {
+                break;
+            };
+            summaries += 1;
+            // Earlier summaries are folded into the next one.
+            if summaries > 1 {
+                assert!(summarization.messages[1].content.starts_with(&format!(
+                    "(summary of earlier turns): Summary {}",
+                    summaries - 1
+                )));
+            }
+            model.stream_last_completion_response(format!("Summary {summaries}."));
+            model.end_last_completion_stream();
+        }
+
+        let request = summarized.await.unwrap();
+        assert!(summaries > 1);
+        assert!(request.estimated_token_count() <= 500);
+        assert_eq!(request.messages[0].content, "You are a helpful assistant.");
+        assert_eq!(
+            request.messages[1].content,
+            format!("{SUMMARY_PREFIX}Summary {summaries}.")
+        );
+        assert_eq!(request.messages.last(), Some(&latest_turn));
+        assert_eq!(
+            request
+                .messages
+                .iter()
+                .filter(|message| is_summary(message))
+                .count(),
+            1
+        );
+    }
+
+    #[gpui::test]
+    async fn test_summarize_to_fit_when_latest_turn_is_too_long(cx: &mut TestAppContext) {
+        let request = LanguageModelRequest {
+            messages: vec![message(Role::User, "lorem ".repeat(1000))],
+            ..Default::default()
+        };
+        let model = Arc::new(FakeLanguageModel::default());
+        let result = request
+            .summarize_to_fit(model.clone(), 500, &cx.to_async())
+            .await;
+        assert!(result.is_err());
+        assert_eq!(model.completion_count(), 0);
+    }
+}
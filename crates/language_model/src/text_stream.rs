@@ -0,0 +1,616 @@
+use anyhow::Result;
+use futures::{
+    future::{self, Either},
+    stream, Stream, StreamExt,
+};
+use gpui::{BackgroundExecutor, Task};
+use regex::Regex;
+use regex_automata::{hybrid, Anchored, Input};
+use std::{mem, pin::Pin, time::Duration};
+use util::ResultExt as _;
+
+/// Cuts a stream of completion text short at the first match of `regex`.
+///
+/// Text is passed through as soon as it's known not to be part of a match, so
+/// the end of a chunk that could be the start of one is held back until more
+/// text arrives. The upstream stream is dropped as soon as the regex matches,
+/// cancelling the underlying request.
+pub fn stop_on_regex(
+    chunks: impl Stream<Item = Result<String>>,
+    regex: Regex,
+) -> impl Stream<Item = Result<String>> {
+    let state = StopOnRegex {
+        chunks: Some(Box::pin(chunks)),
+        text: String::new(),
+        emitted_len: 0,
+        match_starts: MatchStarts::new(&regex),
+        regex,
+    };
+    stream::unfold(state, |mut state| async move {
+        let mut chunks = state.chunks.take()?;
+        loop {
+            match chunks.next().await {
+                Some(Ok(chunk)) => state.text.push_str(&chunk),
+                Some(Err(error)) => {
+                    state.chunks = Some(chunks);
+                    return Some((Err(error), state));
+                }
+                None => {
+                    // Nothing more can arrive to complete a match.
+                    let rest = state.text[state.emitted_len..].to_string();
+                    state.emitted_len = state.text.len();
+                    return (!rest.is_empty()).then_some((Ok(rest), state));
+                }
+            }
+
+            // Text before `emitted_len` can't start a match, so there's no
+            // need to search it again.
+            if let Some(stop) = state.regex.find_at(&state.text, state.emitted_len) {
+                let rest = state.text[state.emitted_len..stop.start()].to_string();
+                return (!rest.is_empty()).then_some((Ok(rest), state));
+            }
+            let hold_back_from = state
+                .match_starts
+                .first_possible(&state.text, state.emitted_len);
+            if hold_back_from > state.emitted_len {
+                let chunk = state.text[state.emitted_len..hold_back_from].to_string();
+                state.emitted_len = hold_back_from;
+                state.chunks = Some(chunks);
+                return Some((Ok(chunk), state));
+            }
+        }
+    })
+}
+
+struct StopOnRegex<S> {
+    chunks: Option<Pin<Box<S>>>,
+    text: String,
+    emitted_len: usize,
+    regex: Regex,
+    match_starts: MatchStarts,
+}
+
+/// Finds where a match of a regex could start in text that's still being
+/// streamed, by running the regex's DFA from each position until it either
+/// can't match or runs out of text.
+struct MatchStarts(Option<(hybrid::dfa::DFA, hybrid::dfa::Cache)>);
+
+impl MatchStarts {
+    fn new(regex: &Regex) -> Self {
+        let dfa = hybrid::dfa::DFA::builder()
+            .configure(hybrid::dfa::DFA::config().unicode_word_boundary(true))
+            .build(regex.as_str())
+            .log_err();
+        Self(dfa.map(|dfa| {
+            let cache = dfa.create_cache();
+            (dfa, cache)
+        }))
+    }
+
+    /// Returns the first position in `text`, from `start` on, at which a match
+    /// could begin once more text arrives, or `text.len()` if there is none.
+    fn first_possible(&mut self, text: &str, start: usize) -> usize {
+        let Some((dfa, cache)) = &mut self.0 else {
+            return text.len();
+        };
+        (start..text.len())
+            .filter(|&position| text.is_char_boundary(position))
+            .find(|&position| {
+                // Anything the DFA can't decide, such as a Unicode word
+                // boundary next to non-ASCII text, is assumed not to match.
+                let input = Input::new(text).range(position..).anchored(Anchored::Yes);
+                let Ok(mut state) = dfa.start_state_forward(cache, &input) else {
+                    return false;
+                };
+                for &byte in &text.as_bytes()[position..] {
+                    match dfa.next_state(cache, state, byte) {
+                        Ok(next_state) if !next_state.is_dead() && !next_state.is_quit() => {
+                            state = next_state
+                        }
+                        _ => return false,
+                    }
+                }
+                true
+            })
+            .unwrap_or(text.len())
+    }
+}
+
+/// Removes whitespace that some providers emit around a response: leading
+/// whitespace is dropped, and the whitespace at the very end is replaced by at
+/// most `max_trailing_newlines` newlines.
+///
+/// Whitespace in the middle of the response, such as blank lines or indentation
+/// in code blocks, is passed through unchanged. Because it's only known to be in
+/// the middle once more text follows it, trailing whitespace is held back until
+/// the next chunk that isn't entirely whitespace arrives.
+pub fn normalize_response_whitespace(
+    chunks: impl Stream<Item = Result<String>>,
+    max_trailing_newlines: usize,
+) -> impl Stream<Item = Result<String>> {
+    let state = (Some(Box::pin(chunks)), false, String::new());
+    stream::unfold(
+        state,
+        move |(chunks, mut started, mut pending)| async move {
+            let mut chunks = chunks?;
+            loop {
+                let chunk = match chunks.next().await {
+                    Some(Ok(chunk)) => chunk,
+                    Some(Err(error)) => {
+                        return Some((Err(error), (Some(chunks), started, pending)))
+                    }
+                    None => {
+                        let newlines = pending.matches('\n').count().min(max_trailing_newlines);
+                        return (newlines > 0)
+                            .then(|| (Ok("\n".repeat(newlines)), (None, started, pending)));
+                    }
+                };
+
+                pending.push_str(if started { &chunk } else { chunk.trim_start() });
+                let content_len = pending.trim_end().len();
+                if content_len > 0 {
+                    started = true;
+                    let trailing_whitespace = pending.split_off(content_len);
+                    let text = mem::replace(&mut pending, trailing_whitespace);
+                    return Some((Ok(text), (Some(chunks), started, pending)));
+                }
+            }
+        },
+    )
+}
+
+/// Where [`group_text_chunks`] may split completion text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChunkBoundary {
+    /// After whitespace.
+    Word,
+    /// After a line break, or whitespace following `.`, `!` or `?`, unless
+    /// the `.` ends an abbreviation or an initial.
+    Sentence,
+    /// After whitespace, once every code fence, inline code span, link and
+    /// emphasis opened so far has been closed, so that partial output never
+    /// renders as broken markdown.
+    Markdown,
+}
+
+impl ChunkBoundary {
+    /// Returns the byte offset just past the last boundary in `text`.
+    fn last_boundary_end(self, text: &str) -> Option<usize> {
+        match self {
+            ChunkBoundary::Word => text
+                .char_indices()
+                .rev()
+                .find(|(_, ch)| ch.is_whitespace())
+                .map(|(ix, ch)| ix + ch.len_utf8()),
+            ChunkBoundary::Sentence => sentence_boundary_ends(text).last(),
+            ChunkBoundary::Markdown => last_balanced_markdown_end(text),
+        }
+    }
+}
+
+/// Words that are followed by a period without ending a sentence.
+const ABBREVIATIONS: &[&str] = &[
+    "dr", "e.g", "etc", "i.e", "jr", "mr", "mrs", "ms", "no", "prof", "sr", "st", "vs",
+];
+
+/// Returns the byte offsets just past each [`ChunkBoundary::Sentence`] in
+/// `text`.
+fn sentence_boundary_ends(text: &str) -> impl Iterator<Item = usize> + '_ {
+    const CLOSING_PUNCTUATION: [char; 5] = ['"', '\'', ')', '”', '’'];
+    const OPENING_PUNCTUATION: [char; 4] = ['"', '\'', '(', '“'];
+
+    text.char_indices().filter_map(move |(ix, ch)| {
+        let end = ix + ch.len_utf8();
+        if ch == '\n' {
+            return Some(end);
+        } else if !ch.is_whitespace() {
+            return None;
+        }
+
+        // Punctuation may be followed by a closing quote or parenthesis.
+        let preceding = text[..ix].trim_end_matches(CLOSING_PUNCTUATION);
+        let Some(preceding) = preceding.strip_suffix('.') else {
+            return preceding.ends_with(['!', '?']).then_some(end);
+        };
+        let word = preceding
+            .rsplit(char::is_whitespace)
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches(OPENING_PUNCTUATION);
+        let is_initial = word.chars().count() == 1 && word.chars().all(char::is_alphabetic);
+        let is_abbreviation = ABBREVIATIONS.contains(&word.to_lowercase().as_str());
+        (!is_initial && !is_abbreviation).then_some(end)
+    })
+}
+
+/// Returns the byte offset just past the last whitespace in `text` at which
+/// no markdown construct is left open.
+fn last_balanced_markdown_end(text: &str) -> Option<usize> {
+    let mut end = None;
+    let mut in_code_block = false;
+    let mut in_code_span = false;
+    let mut link_depth = 0usize;
+    let mut in_link_destination = false;
+    // Whether `*`/`_` emphasis (index 0) and `**`/`__` strong emphasis
+    // (index 1) are open.
+    let mut open_asterisks = [false; 2];
+    let mut open_underscores = [false; 2];
+    let mut prev_ch = None;
+
+    let mut chars = text.char_indices().peekable();
+    while let Some((ix, ch)) = chars.next() {
+        let mut run_len = 1;
+        if matches!(ch, '`' | '*' | '_') {
+            while chars.next_if(|(_, next_ch)| *next_ch == ch).is_some() {
+                run_len += 1;
+            }
+        }
+        let next_ch = chars.peek().map(|(_, ch)| *ch);
+
+        match ch {
+            '`' if run_len >= 3 => in_code_block = !in_code_block,
+            '`' if !in_code_block => in_code_span = !in_code_span,
+            _ if in_code_block || in_code_span => {}
+            '[' => link_depth += 1,
+            ']' if link_depth > 0 => {
+                link_depth -= 1;
+                in_link_destination = next_ch == Some('(');
+            }
+            ')' if in_link_destination => in_link_destination = false,
+            '*' | '_' => {
+                let is_whitespace = |ch: Option<char>| ch.map_or(true, char::is_whitespace);
+                let is_alphanumeric = |ch: Option<char>| ch.map_or(false, char::is_alphanumeric);
+                // List markers and `a * b` aren't emphasis, and neither are
+                // underscores within words like `snake_case`.
+                let is_delimiter = !(is_whitespace(prev_ch) && is_whitespace(next_ch))
+                    && !(ch == '_' && is_alphanumeric(prev_ch) && is_alphanumeric(next_ch));
+                if is_delimiter {
+                    let open = if ch == '*' {
+                        &mut open_asterisks
+                    } else {
+                        &mut open_underscores
+                    };
+                    if run_len % 2 == 1 {
+                        open[0] = !open[0];
+                    }
+                    if run_len >= 2 {
+                        open[1] = !open[1];
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        let is_balanced = !in_code_block
+            && !in_code_span
+            && link_depth == 0
+            && !in_link_destination
+            && open_asterisks == [false; 2]
+            && open_underscores == [false; 2];
+        if ch.is_whitespace() && is_balanced {
+            end = Some(ix + ch.len_utf8());
+        }
+        prev_ch = Some(ch);
+    }
+    end
+}
+
+/// Regroups a stream of completion text so that every chunk ends on a
+/// [`ChunkBoundary`], which renders more smoothly than arbitrary token fragments.
+///
+/// Text that hasn't reached a boundary is held back for at most `max_latency`
+/// before being flushed anyway, so a long word or sentence doesn't stall output.
+pub fn group_text_chunks(
+    chunks: impl Stream<Item = Result<String>> + Send + 'static,
+    boundary: ChunkBoundary,
+    max_latency: Duration,
+    executor: BackgroundExecutor,
+) -> impl Stream<Item = Result<String>> {
+    struct State {
+        chunks: Option<Pin<Box<dyn Stream<Item = Result<String>> + Send>>>,
+        buffer: String,
+        flush_timer: Option<Task<()>>,
+    }
+
+    let state = State {
+        chunks: Some(Box::pin(chunks)),
+        buffer: String::new(),
+        flush_timer: None,
+    };
+    stream::unfold(state, move |mut state| {
+        let executor = executor.clone();
+        async move {
+            loop {
+                if let Some(end) = boundary.last_boundary_end(&state.buffer) {
+                    let rest = state.buffer.split_off(end);
+                    let text = mem::replace(&mut state.buffer, rest);
+                    state.flush_timer =
+                        (!state.buffer.is_empty()).then(|| executor.timer(max_latency));
+                    return Some((Ok(text), state));
+                }
+
+                let Some(chunks) = state.chunks.as_mut() else {
+                    if state.buffer.is_empty() {
+                        return None;
+                    }
+                    return Some((Ok(mem::take(&mut state.buffer)), state));
+                };
+
+                // `None` if the flush timer fired before the next chunk arrived.
+                let next = match state.flush_timer.as_mut() {
+                    Some(flush_timer) => match future::select(chunks.next(), flush_timer).await {
+                        Either::Left((next, _)) => Some(next),
+                        Either::Right(_) => None,
+                    },
+                    None => Some(chunks.next().await),
+                };
+                let Some(next) = next else {
+                    state.flush_timer = None;
+                    return Some((Ok(mem::take(&mut state.buffer)), state));
+                };
+
+                match next {
+                    Some(Ok(chunk)) => {
+                        if state.buffer.is_empty() && !chunk.is_empty() {
+                            state.flush_timer = Some(executor.timer(max_latency));
+                        }
+                        state.buffer.push_str(&chunk);
+                    }
+                    Some(Err(error)) => return Some((Err(error), state)),
+                    None => state.chunks = None,
+                }
+            }
+        }
+    })
+}
+
+/// Regroups a stream of completion text into sentences, one per item, for
+/// consumers such as text-to-speech that need complete sentences rather than
+/// token fragments.
+///
+/// Sentences end as described by [`ChunkBoundary::Sentence`], and have their
+/// surrounding whitespace trimmed. As with [`group_text_chunks`], text that
+/// hasn't reached the end of a sentence is flushed after `max_latency`, so a
+/// slow sentence may be split in two.
+pub fn segment_sentences(
+    chunks: impl Stream<Item = Result<String>> + Send + 'static,
+    max_latency: Duration,
+    executor: BackgroundExecutor,
+) -> impl Stream<Item = Result<String>> {
+    group_text_chunks(chunks, ChunkBoundary::Sentence, max_latency, executor).flat_map(|text| {
+        let sentences = match text {
+            Ok(text) => {
+                let mut start = 0;
+                sentence_boundary_ends(&text)
+                    .chain([text.len()])
+                    .filter_map(|end| {
+                        let sentence = text[start..end].trim();
+                        start = end;
+                        (!sentence.is_empty()).then(|| Ok(sentence.to_string()))
+                    })
+                    .collect()
+            }
+            Err(error) => vec![Err(error)],
+        };
+        stream::iter(sentences)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LanguageModelRequest;
+
+    #[gpui::test]
+    async fn test_normalize_response_whitespace() {
+        let chunks = futures::stream::iter([
+            "\n  ",
+            " \nHere's the fix:\n\n```rust\nfn main() {\n",
+            "\n    println!(\"hi\");\n",
+            "}\n```\n\n\n",
+            "  \n",
+        ])
+        .map(|chunk| Ok(chunk.to_string()));
+        let output = normalize_response_whitespace(chunks, 1)
+            .map(Result::unwrap)
+            .collect::<String>()
+            .await;
+        assert_eq!(
+            output,
+            "Here's the fix:\n\n```rust\nfn main() {\n\n    println!(\"hi\");\n}\n```\n"
+        );
+
+        let chunks = futures::stream::iter(["  ", "\n"]).map(|chunk| Ok(chunk.to_string()));
+        let output = normalize_response_whitespace(chunks, 1)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert!(output.is_empty());
+    }
+
+    #[gpui::test]
+    async fn test_stop_on_regex() {
+        let polled_chunks = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let chunks = futures::stream::iter(["Hello", " wor", "ld.\nEND", " of text", "never sent"])
+            .inspect({
+                let polled_chunks = polled_chunks.clone();
+                move |_| {
+                    polled_chunks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                }
+            })
+            .map(|chunk| Ok(chunk.to_string()));
+
+        let request = LanguageModelRequest {
+            stop_regex: Some(r"(?m)^END\b".into()),
+            ..Default::default()
+        };
+        let regex = request.compile_stop_regex().unwrap().unwrap();
+        let output = stop_on_regex(chunks, regex)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(output, vec!["Hello", " wor", "ld.\n"]);
+        // The upstream isn't polled again once the regex has matched.
+        assert_eq!(polled_chunks.load(std::sync::atomic::Ordering::SeqCst), 3);
+
+        // Text that could be the start of a match is held back until it's
+        // known whether it is one.
+        let regex = Regex::new("STOP").unwrap();
+        for (chunks, expected_output) in [
+            (vec!["Go", " ST", "OP", " now"], vec!["Go", " "]),
+            (vec!["ST", "AR", " STOP"], vec!["STAR", " "]),
+            (vec!["Go", " ST"], vec!["Go", " ", "ST"]),
+        ] {
+            let chunks = futures::stream::iter(chunks).map(|chunk| Ok(chunk.to_string()));
+            let output = stop_on_regex(chunks, regex.clone())
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(output, expected_output);
+        }
+
+        let request = LanguageModelRequest {
+            stop_regex: Some("(".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            request.compile_stop_regex().unwrap_err().to_string(),
+            "invalid stop regex"
+        );
+    }
+
+    #[gpui::test]
+    async fn test_group_text_chunks(cx: &mut gpui::TestAppContext) {
+        use futures::FutureExt as _;
+
+        let max_latency = Duration::from_millis(100);
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut words =
+            group_text_chunks(rx, ChunkBoundary::Word, max_latency, cx.executor()).boxed();
+        tx.unbounded_send(Ok("Hel".into())).unwrap();
+        tx.unbounded_send(Ok("lo, wor".into())).unwrap();
+        tx.unbounded_send(Ok("ld! How".into())).unwrap();
+        assert_eq!(words.next().await.unwrap().unwrap(), "Hello, ");
+        assert_eq!(words.next().await.unwrap().unwrap(), "world! ");
+
+        // A partial word is held back until the latency flush.
+        assert!(words.next().now_or_never().is_none());
+        cx.executor().advance_clock(max_latency / 2);
+        cx.run_until_parked();
+        assert!(words.next().now_or_never().is_none());
+        cx.executor().advance_clock(max_latency);
+        cx.run_until_parked();
+        assert_eq!(
+            words.next().now_or_never().unwrap().unwrap().unwrap(),
+            "How"
+        );
+
+        tx.unbounded_send(Ok(" are".into())).unwrap();
+        drop(tx);
+        assert_eq!(words.next().await.unwrap().unwrap(), " ");
+        assert_eq!(words.next().await.unwrap().unwrap(), "are");
+        assert!(words.next().await.is_none());
+
+        let chunks = futures::stream::iter(["It's 3.5 wide. Is", " it?\nYes"])
+            .map(|chunk| Ok(chunk.to_string()));
+        let sentences =
+            group_text_chunks(chunks, ChunkBoundary::Sentence, max_latency, cx.executor())
+                .map(Result::unwrap)
+                .collect::<Vec<_>>()
+                .await;
+        assert_eq!(sentences, vec!["It's 3.5 wide. ", "Is it?\n", "Yes"]);
+    }
+
+    #[gpui::test]
+    async fn test_segment_sentences(cx: &mut gpui::TestAppContext) {
+        use futures::FutureExt as _;
+
+        let max_latency = Duration::from_millis(100);
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut sentences = segment_sentences(rx, max_latency, cx.executor()).boxed();
+        for chunk in [
+            "Dr. Smith met J. R. R",
+            ". Tolkien at 3.30 p",
+            "m. \"Was it",
+            " raining?\" she asked",
+            ". It was, e.g. in the",
+            " morning!\n\nThe rest",
+        ] {
+            tx.unbounded_send(Ok(chunk.into())).unwrap();
+        }
+        for expected in [
+            "Dr. Smith met J. R. R. Tolkien at 3.30 p.m.",
+            "\"Was it raining?\"",
+            "she asked.",
+            "It was, e.g. in the morning!",
+        ] {
+            assert_eq!(sentences.next().await.unwrap().unwrap(), expected);
+        }
+
+        // An unfinished sentence is flushed once the latency limit passes...
+        assert!(sentences.next().now_or_never().is_none());
+        cx.executor().advance_clock(max_latency);
+        cx.run_until_parked();
+        assert_eq!(
+            sentences.next().now_or_never().unwrap().unwrap().unwrap(),
+            "The rest"
+        );
+
+        // ...and whatever is left when the stream ends is flushed too.
+        tx.unbounded_send(Ok(" of it. Done".into())).unwrap();
+        drop(tx);
+        assert_eq!(sentences.next().await.unwrap().unwrap(), "of it.");
+        assert_eq!(sentences.next().await.unwrap().unwrap(), "Done");
+        assert!(sentences.next().await.is_none());
+    }
+
+    #[gpui::test]
+    async fn test_group_markdown_chunks(cx: &mut gpui::TestAppContext) {
+        use futures::FutureExt as _;
+
+        let max_latency = Duration::from_millis(100);
+
+        let (tx, rx) = futures::channel::mpsc::unbounded();
+        let mut chunks =
+            group_text_chunks(rx, ChunkBoundary::Markdown, max_latency, cx.executor()).boxed();
+        tx.unbounded_send(Ok("Try this:\n```rust\nfn main() {".into()))
+            .unwrap();
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "Try this:\n");
+
+        // The code block isn't emitted until its closing fence arrives.
+        tx.unbounded_send(Ok("\n    println!(\"hi\");\n}\n``".into()))
+            .unwrap();
+        cx.run_until_parked();
+        assert!(chunks.next().now_or_never().is_none());
+        tx.unbounded_send(Ok("`\nIt prints *hi*.\n".into()))
+            .unwrap();
+        assert_eq!(
+            chunks.next().await.unwrap().unwrap(),
+            "```rust\nfn main() {\n    println!(\"hi\");\n}\n```\nIt prints *hi*.\n"
+        );
+
+        // Unbalanced markdown is flushed anyway after `max_latency`.
+        tx.unbounded_send(Ok("[See the docs](https://zed".into()))
+            .unwrap();
+        cx.run_until_parked();
+        assert!(chunks.next().now_or_never().is_none());
+        cx.executor().advance_clock(max_latency);
+        cx.run_until_parked();
+        assert_eq!(
+            chunks.next().now_or_never().unwrap().unwrap().unwrap(),
+            "[See the docs](https://zed"
+        );
+
+        for (text, balanced_end) in [
+            ("**bold** and *", Some("**bold** and ".len())),
+            ("a `b c` d", Some("a `b c` ".len())),
+            ("* item one\n* item", Some("* item one\n* ".len())),
+            ("snake_case and _emph", Some("snake_case and ".len())),
+            ("[link text", None),
+        ] {
+            assert_eq!(last_balanced_markdown_end(text), balanced_end, "{text:?}");
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{LanguageModelRequest, Role};
+use anyhow::{anyhow, Context as _, Result};
+use base64::prelude::*;
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tiktoken_rs::CoreBPE;
+
+const CL100K_BASE_PATTERN: &str = concat!(
+    r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}",
+    r"| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+);
+const CL100K_BASE_SPECIAL_TOKENS: &[(&str, usize)] = &[
+    ("<|endoftext|>", 100257),
+    ("<|fim_prefix|>", 100258),
+    ("<|fim_middle|>", 100259),
+    ("<|fim_suffix|>", 100260),
+    ("<|endofprompt|>", 100276),
+];
+
+const O200K_BASE_PATTERN: &str = concat!(
+    r"[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]*[\p{Ll}\p{Lm}\p{Lo}\p{M}]+",
+    r"(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|[^\r\n\p{L}\p{N}]?[\p{Lu}\p{Lt}\p{Lm}\p{Lo}\p{M}]+[\p{Ll}\p{Lm}\p{Lo}\p{M}]*",
+    r"(?i:'s|'t|'re|'ve|'m|'ll|'d)?",
+    r"|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n/]*|\s*[\r\n]+|\s+(?!\S)|\s+",
+);
+const O200K_BASE_SPECIAL_TOKENS: &[(&str, usize)] =
+    &[("<|endoftext|>", 199999), ("<|endofprompt|>", 200018)];
+
+/// Tokenizers loaded from asset directories, keyed by the path of their BPE
+/// file. Files that couldn't be loaded are remembered as `None`, so that
+/// they're only reported once.
+static LOADED_TOKENIZERS: Mutex<BTreeMap<PathBuf, Option<Arc<CoreBPE>>>> =
+    Mutex::new(BTreeMap::new());
+
+/// Counts the tokens in `request`'s messages with the tokenizer of the
+/// OpenAI model `model`, including the tokens that frame each message.
+///
+/// The tokenizer's BPE data is loaded from `assets_dir` when it's set, for
+/// installs that can't rely on tiktoken's own copy. If the data isn't there,
+/// the count falls back to [`LanguageModelRequest::estimated_token_count`].
+pub(crate) fn count_tiktoken_tokens(
+    model: &str,
+    request: LanguageModelRequest,
+    assets_dir: Option<&Path>,
+) -> Result<usize> {
+    let mut counts = count_tiktoken_tokens_batch(model, vec![request], assets_dir)?;
+    Ok(counts.pop().unwrap_or_default())
+}
+
+/// Counts the tokens in each of `requests` like [`count_tiktoken_tokens`],
+/// loading the tokenizer from `assets_dir` only once for all of them.
+pub(crate) fn count_tiktoken_tokens_batch(
+    model: &str,
+    requests: Vec<LanguageModelRequest>,
+    assets_dir: Option<&Path>,
+) -> Result<Vec<usize>> {
+    let Some(assets_dir) = assets_dir else {
+        return requests
+            .into_iter()
+            .map(|request| {
+                let messages = request
+                    .messages
+                    .into_iter()
+                    .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
+                        role: role_name(message.role).into(),
+                        content: Some(message.content),
+                        name: None,
+                        function_call: None,
+                    })
+                    .collect::<Vec<_>>();
+                tiktoken_rs::num_tokens_from_messages(model, &messages)
+            })
+            .collect();
+    };
+
+    let Some(bpe) = load_tokenizer(model, assets_dir) else {
+        return Ok(requests
+            .iter()
+            .map(LanguageModelRequest::estimated_token_count)
+            .collect());
+    };
+    // Every message is framed by 3 tokens, and the reply is primed with 3
+    // more, as in `tiktoken_rs::num_tokens_from_messages`.
+    Ok(requests
+        .iter()
+        .map(|request| {
+            let message_tokens = request
+                .messages
+                .iter()
+                .map(|message| {
+                    3 + bpe
+                        .encode_with_special_tokens(role_name(message.role))
+                        .len()
+                        + bpe.encode_with_special_tokens(&message.content).len()
+                })
+                .sum::<usize>();
+            message_tokens + 3
+        })
+        .collect())
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::User => "user",
+        Role::Assistant => "assistant",
+        Role::System => "system",
+    }
+}
+
+fn load_tokenizer(model: &str, assets_dir: &Path) -> Option<Arc<CoreBPE>> {
+    let (encoding, pattern, special_tokens) = if model.starts_with("gpt-4o") {
+        ("o200k_base", O200K_BASE_PATTERN, O200K_BASE_SPECIAL_TOKENS)
+    } else {
+        (
+            "cl100k_base",
+            CL100K_BASE_PATTERN,
+            CL100K_BASE_SPECIAL_TOKENS,
+        )
+    };
+    let path = assets_dir.join(format!("{encoding}.tiktoken"));
+
+    let mut loaded_tokenizers = LOADED_TOKENIZERS.lock().unwrap();
+    loaded_tokenizers
+        .entry(path.clone())
+        .or_insert_with(|| match read_tokenizer(&path, pattern, special_tokens) {
+            Ok(bpe) => Some(Arc::new(bpe)),
+            Err(error) => {
+                log::error!("falling back to estimated token counts: {error:?}");
+                None
+            }
+        })
+        .clone()
+}
+
+/// Reads a `.tiktoken` file, which lists each token as its base64-encoded
+/// bytes followed by its rank.
+fn read_tokenizer(path: &Path, pattern: &str, special_tokens: &[(&str, usize)]) -> Result<CoreBPE> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read tokenizer from {path:?}"))?;
+    let encoder = contents
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (token, rank) = line
+                .split_once(' ')
+                .ok_or_else(|| anyhow!("invalid line in {path:?}: {line:?}"))?;
+            let token = BASE64_STANDARD
+                .decode(token)
+                .with_context(|| format!("invalid token in {path:?}: {token:?}"))?;
+            let rank = rank
+                .parse::<usize>()
+                .with_context(|| format!("invalid rank in {path:?}: {rank:?}"))?;
+            Ok((token, rank))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let special_tokens = special_tokens
+        .iter()
+        .map(|(token, rank)| (token.to_string(), *rank));
+    CoreBPE::new(
+        encoder.into_iter().collect(),
+        special_tokens.collect(),
+        pattern,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::LanguageModelRequestMessage;
+
+    #[test]
+    fn test_count_tokens_from_assets_dir() {
+        // A vocabulary of single bytes, so that every character of ASCII text
+        // is one token.
+        let assets_dir = tempfile::tempdir().unwrap();
+        let vocabulary = (0..=255u8)
+            .map(|byte| format!("{} {byte}\n", BASE64_STANDARD.encode([byte])))
+            .collect::<String>();
+        std::fs::write(assets_dir.path().join("cl100k_base.tiktoken"), vocabulary).unwrap();
+
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: "Hello, world!".into(),
+                attachments: Vec::new(),
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            count_tiktoken_tokens("gpt-4", request.clone(), Some(assets_dir.path())).unwrap(),
+            3 + "user".len() + "Hello, world!".len() + 3
+        );
+
+        // Without a BPE file for the model's encoding, the count is estimated.
+        assert_eq!(
+            count_tiktoken_tokens("gpt-4o", request.clone(), Some(assets_dir.path())).unwrap(),
+            request.estimated_token_count()
+        );
+    }
+
+    #[test]
+    fn test_count_tokens_batch() {
+        let assets_dir = tempfile::tempdir().unwrap();
+        let vocabulary = (0..=255u8)
+            .map(|byte| format!("{} {byte}\n", BASE64_STANDARD.encode([byte])))
+            .collect::<String>();
+        std::fs::write(assets_dir.path().join("cl100k_base.tiktoken"), vocabulary).unwrap();
+
+        let requests = ["fn main() {}", "", "Hello, world!\nHow are you?"]
+            .into_iter()
+            .map(|content| LanguageModelRequest {
+                messages: vec![
+                    LanguageModelRequestMessage {
+                        role: Role::System,
+                        content: "You are a helpful assistant.".into(),
+                        attachments: Vec::new(),
+                        cache: false,
+                    },
+                    LanguageModelRequestMessage {
+                        role: Role::User,
+                        content: content.into(),
+                        attachments: Vec::new(),
+                        cache: false,
+                    },
+                ],
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        for model in ["gpt-4", "gpt-4o"] {
+            let individual_counts = requests
+                .iter()
+                .map(|request| {
+                    count_tiktoken_tokens(model, request.clone(), Some(assets_dir.path())).unwrap()
+                })
+                .collect::<Vec<_>>();
+            let batch_counts =
+                count_tiktoken_tokens_batch(model, requests.clone(), Some(assets_dir.path()))
+                    .unwrap();
+            assert_eq!(batch_counts, individual_counts);
+        }
+        assert!(
+            count_tiktoken_tokens_batch("gpt-4", Vec::new(), Some(assets_dir.path()))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
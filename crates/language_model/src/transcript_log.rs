@@ -0,0 +1,215 @@
+use crate::{
+    settings::AllLanguageModelSettings, LanguageModelId, LanguageModelProviderId,
+    LanguageModelRequest,
+};
+use anyhow::Result;
+use collections::BTreeMap;
+use futures::{Stream, StreamExt as _};
+use gpui::{AppContext, Global};
+use parking_lot::Mutex;
+use settings::{Settings, SettingsStore};
+use std::{collections::VecDeque, fmt::Write as _, mem, sync::Arc};
+
+/// How many exchanges are kept for each provider.
+pub const TRANSCRIPTS_PER_PROVIDER: usize = 5;
+
+/// The longest request or response kept in a transcript, in bytes. Anything
+/// longer is truncated.
+const MAX_TRANSCRIPT_TEXT_LEN: usize = 16 * 1024;
+
+/// A request sent to a model and the text it responded with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transcript {
+    pub model: LanguageModelId,
+    /// The request as JSON, with attached documents redacted.
+    pub request: String,
+    pub response: String,
+}
+
+/// The last few exchanges with each provider, kept in memory when the
+/// `language_models.record_transcripts` setting is enabled, to help diagnose
+/// unexpected responses.
+pub struct TranscriptLog {
+    capacity: usize,
+    transcripts: Mutex<BTreeMap<LanguageModelProviderId, VecDeque<Transcript>>>,
+}
+
+struct GlobalTranscriptLog(Arc<TranscriptLog>);
+
+impl Global for GlobalTranscriptLog {}
+
+pub(crate) fn init(cx: &mut AppContext) {
+    cx.set_global(GlobalTranscriptLog(Arc::new(TranscriptLog::new(
+        TRANSCRIPTS_PER_PROVIDER,
+    ))));
+    cx.observe_global::<SettingsStore>(|cx| {
+        if !AllLanguageModelSettings::get_global(cx).record_transcripts {
+            TranscriptLog::global(cx).clear();
+        }
+    })
+    .detach();
+}
+
+impl TranscriptLog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            transcripts: Mutex::default(),
+        }
+    }
+
+    pub fn global(cx: &AppContext) -> Arc<Self> {
+        cx.global::<GlobalTranscriptLog>().0.clone()
+    }
+
+    /// Returns the log if transcripts should currently be recorded.
+    pub(crate) fn recording(cx: &AppContext) -> Option<Arc<Self>> {
+        let log = cx.try_global::<GlobalTranscriptLog>()?;
+        AllLanguageModelSettings::get_global(cx)
+            .record_transcripts
+            .then(|| log.0.clone())
+    }
+
+    pub(crate) fn record(
+        &self,
+        provider: LanguageModelProviderId,
+        model: LanguageModelId,
+        request: &LanguageModelRequest,
+        mut response: String,
+    ) {
+        let mut request = serde_json::to_string_pretty(&request.redacted()).unwrap_or_default();
+        truncate(&mut request);
+        truncate(&mut response);
+
+        let mut transcripts = self.transcripts.lock();
+        let transcripts = transcripts.entry(provider).or_default();
+        if transcripts.len() >= self.capacity {
+            transcripts.pop_front();
+        }
+        transcripts.push_back(Transcript {
+            model,
+            request,
+            response,
+        });
+    }
+
+    /// Returns the recorded transcripts for the given provider, oldest first.
+    pub fn transcripts(&self, provider: &LanguageModelProviderId) -> Vec<Transcript> {
+        self.transcripts
+            .lock()
+            .get(provider)
+            .map(|transcripts| transcripts.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn clear(&self) {
+        self.transcripts.lock().clear();
+    }
+
+    /// Renders every recorded transcript as Markdown, grouped by provider.
+    pub fn to_markdown(&self) -> String {
+        let mut output = String::new();
+        for (provider, transcripts) in self.transcripts.lock().iter() {
+            writeln!(output, "# {}\n", provider.0).unwrap();
+            for transcript in transcripts {
+                writeln!(output, "## {}\n", transcript.model.0).unwrap();
+                writeln!(output, "Request:\n```json\n{}\n```\n", transcript.request).unwrap();
+                writeln!(output, "Response:\n```\n{}\n```\n", transcript.response).unwrap();
+            }
+        }
+        output
+    }
+}
+
+/// Passes a completion's text through, recording it in `log` along with the
+/// request once the stream is dropped.
+pub(crate) fn record_transcript(
+    chunks: impl Stream<Item = Result<String>>,
+    log: Arc<TranscriptLog>,
+    provider: LanguageModelProviderId,
+    model: LanguageModelId,
+    request: LanguageModelRequest,
+) -> impl Stream<Item = Result<String>> {
+    let mut recorder = TranscriptRecorder {
+        log,
+        provider,
+        model,
+        request,
+        response: String::new(),
+    };
+    chunks.map(move |chunk| {
+        if let Ok(text) = &chunk {
+            recorder.response.push_str(text);
+        }
+        chunk
+    })
+}
+
+struct TranscriptRecorder {
+    log: Arc<TranscriptLog>,
+    provider: LanguageModelProviderId,
+    model: LanguageModelId,
+    request: LanguageModelRequest,
+    response: String,
+}
+
+impl Drop for TranscriptRecorder {
+    fn drop(&mut self) {
+        self.log.record(
+            self.provider.clone(),
+            self.model.clone(),
+            &self.request,
+            mem::take(&mut self.response),
+        );
+    }
+}
+
+fn truncate(text: &mut String) {
+    if text.len() > MAX_TRANSCRIPT_TEXT_LEN {
+        let mut end = MAX_TRANSCRIPT_TEXT_LEN;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+        text.push('…');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcript_log_keeps_last_exchanges() {
+        let log = TranscriptLog::new(2);
+        let provider = LanguageModelProviderId::from("openai".to_string());
+        for response in ["one", "two", "three"] {
+            log.record(
+                provider.clone(),
+                LanguageModelId::from("gpt-4o".to_string()),
+                &LanguageModelRequest::default(),
+                response.into(),
+            );
+        }
+
+        let responses = log
+            .transcripts(&provider)
+            .into_iter()
+            .map(|transcript| transcript.response)
+            .collect::<Vec<_>>();
+        assert_eq!(responses, ["two", "three"]);
+        assert!(log
+            .transcripts(&LanguageModelProviderId::from("anthropic".to_string()))
+            .is_empty());
+
+        log.record(
+            provider.clone(),
+            LanguageModelId::from("gpt-4o".to_string()),
+            &LanguageModelRequest::default(),
+            "x".repeat(MAX_TRANSCRIPT_TEXT_LEN * 2),
+        );
+        let transcripts = log.transcripts(&provider);
+        assert_eq!(transcripts.len(), 2);
+        assert!(transcripts[1].response.len() <= MAX_TRANSCRIPT_TEXT_LEN + '…'.len_utf8());
+    }
+}
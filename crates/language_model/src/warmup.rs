@@ -0,0 +1,131 @@
+use crate::settings::AllLanguageModelSettings;
+use anyhow::Result;
+use gpui::{AppContext, Task};
+use http_client::{AsyncBody, HttpClient, Method, Request};
+use settings::Settings;
+use std::sync::Arc;
+use util::ResultExt as _;
+
+/// Sends a `HEAD` request to `url`, which leaves a connection to the provider
+/// in the HTTP client's pool, with DNS resolution and the TLS handshake
+/// already done, for the first completion to reuse. Whatever status the
+/// provider responds with is ignored.
+pub(crate) async fn warm_up_connection(http_client: &dyn HttpClient, url: &str) -> Result<()> {
+    let request = Request::builder()
+        .method(Method::HEAD)
+        .uri(url)
+        .body(AsyncBody::empty())?;
+    http_client.send(request).await?;
+    Ok(())
+}
+
+/// Warms up a connection to `url` in the background once `authenticate`
+/// succeeds, if the `language_models.warm_up_connections` setting is enabled.
+pub(crate) fn warm_up_after_authentication(
+    authenticate: Task<Result<()>>,
+    http_client: Arc<dyn HttpClient>,
+    url: String,
+    cx: &mut AppContext,
+) -> Task<Result<()>> {
+    if !AllLanguageModelSettings::get_global(cx).warm_up_connections {
+        return authenticate;
+    }
+
+    cx.spawn(|cx| async move {
+        authenticate.await?;
+        cx.background_executor()
+            .spawn(async move {
+                warm_up_connection(http_client.as_ref(), &url)
+                    .await
+                    .log_err();
+            })
+            .detach();
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_client::{FakeHttpClient, Response};
+    use parking_lot::Mutex;
+    use settings::SettingsStore;
+
+    #[gpui::test]
+    async fn test_warm_up_after_authentication(cx: &mut gpui::TestAppContext) {
+        cx.update(|cx| {
+            let settings_store = SettingsStore::test(cx);
+            cx.set_global(settings_store);
+            AllLanguageModelSettings::register(cx);
+        });
+
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let http_client = FakeHttpClient::create({
+            let requests = requests.clone();
+            move |request| {
+                requests
+                    .lock()
+                    .push((request.method().clone(), request.uri().to_string()));
+                async move {
+                    Ok(Response::builder()
+                        .status(404)
+                        .body(AsyncBody::empty())
+                        .unwrap())
+                }
+            }
+        });
+
+        // Nothing is sent unless the setting is enabled...
+        cx.update(|cx| {
+            warm_up_after_authentication(
+                Task::ready(Ok(())),
+                http_client.clone(),
+                "https://api.anthropic.com".into(),
+                cx,
+            )
+        })
+        .await
+        .unwrap();
+        cx.run_until_parked();
+        assert!(requests.lock().is_empty());
+
+        // ...and then only a HEAD request, rather than a completion.
+        cx.update(|cx| {
+            SettingsStore::update_global(cx, |store, cx| {
+                store.update_user_settings::<AllLanguageModelSettings>(cx, |settings| {
+                    settings.warm_up_connections = Some(true);
+                });
+            });
+        });
+        cx.update(|cx| {
+            warm_up_after_authentication(
+                Task::ready(Ok(())),
+                http_client.clone(),
+                "https://api.anthropic.com".into(),
+                cx,
+            )
+        })
+        .await
+        .unwrap();
+        cx.run_until_parked();
+        assert_eq!(
+            *requests.lock(),
+            [(Method::HEAD, "https://api.anthropic.com/".to_string())]
+        );
+
+        // Connections aren't warmed up for failed authentication.
+        let result = cx
+            .update(|cx| {
+                warm_up_after_authentication(
+                    Task::ready(Err(anyhow::anyhow!("credentials not found"))),
+                    http_client,
+                    "https://api.anthropic.com".into(),
+                    cx,
+                )
+            })
+            .await;
+        assert!(result.is_err());
+        cx.run_until_parked();
+        assert_eq!(requests.lock().len(), 1);
+    }
+}
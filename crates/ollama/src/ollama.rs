@@ -1,6 +1,6 @@
 use anyhow::{anyhow, Context, Result};
 use futures::{io::BufReader, stream::BoxStream, AsyncBufReadExt, AsyncReadExt, StreamExt};
-use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest};
+use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest, StatusCode};
 use isahc::config::Configurable;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -92,7 +92,7 @@ impl Model {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "role", rename_all = "lowercase")]
 pub enum ChatMessage {
     Assistant {
@@ -107,32 +107,32 @@ pub enum ChatMessage {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum OllamaToolCall {
     Function(OllamaFunctionCall),
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct OllamaFunctionCall {
     pub name: String,
     pub arguments: Value,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct OllamaFunctionTool {
     pub name: String,
     pub description: Option<String>,
     pub parameters: Option<Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum OllamaTool {
     Function { function: OllamaFunctionTool },
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Clone, Serialize, Debug)]
 pub struct ChatRequest {
     pub model: String,
     pub messages: Vec<ChatMessage>,
@@ -151,7 +151,7 @@ impl ChatRequest {
 }
 
 // https://github.com/ollama/ollama/blob/main/docs/modelfile.md#valid-parameters-and-values
-#[derive(Serialize, Default, Debug)]
+#[derive(Clone, Serialize, Default, Debug)]
 pub struct ChatOptions {
     pub num_ctx: Option<usize>,
     pub num_predict: Option<isize>,
@@ -253,8 +253,16 @@ pub async fn stream_chat_completion(
     };
 
     let request = request_builder.body(AsyncBody::from(serde_json::to_string(&request)?))?;
-    let mut response = client.send(request).await?;
-    if response.status().is_success() {
+    let mut response = client.send(request).await.map_err(|error| {
+        anyhow!(OllamaUnavailable {
+            reason: error.to_string(),
+        })
+    })?;
+    if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+        let mut body = String::new();
+        response.body_mut().read_to_string(&mut body).await?;
+        Err(anyhow!(OllamaUnavailable { reason: body }))
+    } else if response.status().is_success() {
         let reader = BufReader::new(response.into_body());
 
         Ok(reader
@@ -280,6 +288,21 @@ pub async fn stream_chat_completion(
     }
 }
 
+/// Ollama couldn't be reached, or replied that it's busy, as it may while it
+/// starts up or loads the model, so the request may succeed if it's retried.
+#[derive(Debug)]
+pub struct OllamaUnavailable {
+    pub reason: String,
+}
+
+impl std::fmt::Display for OllamaUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ollama is unavailable: {}", self.reason)
+    }
+}
+
+impl std::error::Error for OllamaUnavailable {}
+
 pub async fn get_models(
     client: &dyn HttpClient,
     api_url: &str,
@@ -2,21 +2,50 @@ mod supported_countries;
 
 use anyhow::{anyhow, Context, Result};
 use futures::{io::BufReader, stream::BoxStream, AsyncBufReadExt, AsyncReadExt, Stream, StreamExt};
-use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest};
+use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest, StatusCode};
 use isahc::config::Configurable;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{convert::TryFrom, future::Future, time::Duration};
+use std::{
+    collections::BTreeMap, convert::TryFrom, fmt, future::Future, ops::RangeInclusive,
+    time::Duration,
+};
 use strum::EnumIter;
 
 pub use supported_countries::*;
 
 pub const OPEN_AI_API_URL: &str = "https://api.openai.com/v1";
 
+/// The range of values accepted for each token in [`Request::logit_bias`].
+pub const LOGIT_BIAS_RANGE: RangeInclusive<f32> = -100.0..=100.0;
+
 fn is_none_or_empty<T: AsRef<[U]>, U>(opt: &Option<T>) -> bool {
     opt.as_ref().map_or(true, |v| v.as_ref().is_empty())
 }
 
+fn serialize_stop<S: serde::Serializer>(stop: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    match stop {
+        [stop] => serializer.serialize_str(stop),
+        stop => stop.serialize(serializer),
+    }
+}
+
+fn deserialize_stop<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stop {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Stop::deserialize(deserializer)? {
+        Stop::One(stop) => vec![stop],
+        Stop::Many(stop) => stop,
+    })
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum Role {
@@ -66,7 +95,12 @@ pub enum Model {
     #[serde(rename = "gpt-4o-mini", alias = "gpt-4o-mini-2024-07-18")]
     FourOmniMini,
     #[serde(rename = "custom")]
-    Custom { name: String, max_tokens: usize },
+    Custom {
+        name: String,
+        /// The name displayed in the model picker, defaulting to the model's id.
+        display_name: Option<String>,
+        max_tokens: usize,
+    },
 }
 
 impl Model {
@@ -99,7 +133,9 @@ impl Model {
             Self::FourTurbo => "gpt-4-turbo",
             Self::FourOmni => "gpt-4o",
             Self::FourOmniMini => "gpt-4o-mini",
-            Self::Custom { name, .. } => name,
+            Self::Custom {
+                name, display_name, ..
+            } => display_name.as_ref().unwrap_or(name),
         }
     }
 
@@ -115,27 +151,100 @@ impl Model {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Returns whether the given model limits its output with `max_completion_tokens`
+/// instead of the deprecated `max_tokens`.
+pub fn uses_max_completion_tokens(model_id: &str) -> bool {
+    ["o1", "o3"]
+        .iter()
+        .any(|family| model_id == *family || model_id.starts_with(&format!("{family}-")))
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Request {
     pub model: String,
     pub messages: Vec<RequestMessage>,
     pub stream: bool,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub max_tokens: Option<usize>,
+    /// Replaces `max_tokens` for reasoning models, which reject the latter.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_completion_tokens: Option<usize>,
+    /// Sent as a bare string when there's a single stop sequence, since some
+    /// stricter gateways only accept an array for more than one.
+    #[serde(
+        serialize_with = "serialize_stop",
+        deserialize_with = "deserialize_stop"
+    )]
     pub stop: Vec<String>,
     pub temperature: f32,
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_options: Option<StreamOptions>,
+    /// Biases the likelihood of the given token IDs appearing in the completion.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logit_bias: Option<BTreeMap<u32, f32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tool_choice: Option<ToolChoice>,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub tools: Vec<ToolDefinition>,
+    /// Key-value pairs that are stored with the completion, such as for
+    /// attributing its cost.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// Top-level parameters that aren't modeled above, such as experimental
+    /// ones, sent as they are.
+    #[serde(flatten)]
+    pub extra_body: serde_json::Map<String, serde_json::Value>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(untagged)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StreamOptions {
+    /// Whether to report token usage while streaming the response.
+    pub include_usage: bool,
+}
+
+impl Request {
+    /// The top-level keys of a serialized request, which [`Self::extra_body`]
+    /// must not contain.
+    pub const RESERVED_KEYS: &'static [&'static str] = &[
+        "model",
+        "messages",
+        "stream",
+        "max_tokens",
+        "max_completion_tokens",
+        "stop",
+        "temperature",
+        "stream_options",
+        "logit_bias",
+        "tool_choice",
+        "tools",
+        "metadata",
+    ];
+
+    /// Returns an error if the request contains parameters that OpenAI would reject.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(logit_bias) = self.logit_bias.as_ref() {
+            for (token, bias) in logit_bias {
+                if !LOGIT_BIAS_RANGE.contains(bias) {
+                    return Err(anyhow!(
+                        "logit bias for token {token} must be between {} and {}, got {bias}",
+                        LOGIT_BIAS_RANGE.start(),
+                        LOGIT_BIAS_RANGE.end(),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ToolChoice {
     Auto,
     Required,
     None,
+    #[serde(untagged)]
     Other(ToolDefinition),
 }
 
@@ -153,7 +262,7 @@ pub struct FunctionDefinition {
     pub parameters: Option<Value>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "role", rename_all = "lowercase")]
 pub enum RequestMessage {
     Assistant {
@@ -173,20 +282,20 @@ pub enum RequestMessage {
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct ToolCall {
     pub id: String,
     #[serde(flatten)]
     pub content: ToolCallContent,
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ToolCallContent {
     Function { function: FunctionContent },
 }
 
-#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 pub struct FunctionContent {
     pub name: String,
     pub arguments: String,
@@ -222,6 +331,15 @@ pub struct Usage {
     pub prompt_tokens: u32,
     pub completion_tokens: u32,
     pub total_tokens: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens_details: Option<PromptTokensDetails>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PromptTokensDetails {
+    /// How many of the prompt tokens were read from the prompt cache.
+    #[serde(default)]
+    pub cached_tokens: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -246,6 +364,37 @@ pub struct ResponseStreamEvent {
     pub usage: Option<Usage>,
 }
 
+/// Checks that `api_key` can at least list models, to catch keys that are
+/// invalid or lack permissions before they're saved.
+///
+/// Only a clear rejection of the key is reported as an error. The key is
+/// assumed to be fine if the check itself fails, since some OpenAI-compatible
+/// APIs don't implement the models endpoint.
+pub async fn validate_api_key(client: &dyn HttpClient, api_url: &str, api_key: &str) -> Result<()> {
+    let request = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(format!("{api_url}/models"))
+        .header("Authorization", format!("Bearer {}", api_key))
+        .body(AsyncBody::default())?;
+    let response = match client.send(request).await {
+        Ok(response) => response,
+        Err(error) => {
+            log::warn!("failed to validate OpenAI API key: {error}");
+            return Ok(());
+        }
+    };
+
+    match response.status() {
+        StatusCode::UNAUTHORIZED => Err(anyhow!(
+            "The API key was rejected as invalid. Check that it was copied correctly and hasn't been revoked."
+        )),
+        StatusCode::FORBIDDEN => Err(anyhow!(
+            "The API key doesn't have permission to list models. Use a key with at least read access to models."
+        )),
+        _ => Ok(()),
+    }
+}
+
 pub async fn stream_completion(
     client: &dyn HttpClient,
     api_url: &str,
@@ -253,6 +402,32 @@ pub async fn stream_completion(
     request: Request,
     low_speed_timeout: Option<Duration>,
 ) -> Result<BoxStream<'static, Result<ResponseStreamEvent>>> {
+    let events =
+        stream_raw_completion(client, api_url, api_key, request, low_speed_timeout).await?;
+    Ok(events
+        .map(|event| parse_response_stream_event(event?))
+        .boxed())
+}
+
+/// Parses one of the events streamed by [`stream_raw_completion`].
+pub fn parse_response_stream_event(event: serde_json::Value) -> Result<ResponseStreamEvent> {
+    match serde_json::from_value(event)? {
+        ResponseStreamResult::Ok(response) => Ok(response),
+        ResponseStreamResult::Err { error } => Err(anyhow!(error)),
+    }
+}
+
+/// Streams a completion's events as JSON, including the fields that
+/// [`ResponseStreamEvent`] leaves out.
+pub async fn stream_raw_completion(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    request: Request,
+    low_speed_timeout: Option<Duration>,
+) -> Result<BoxStream<'static, Result<serde_json::Value>>> {
+    request.validate()?;
+
     let uri = format!("{api_url}/chat/completions");
     let mut request_builder = HttpRequest::builder()
         .method(Method::POST)
@@ -277,13 +452,7 @@ pub async fn stream_completion(
                         if line == "[DONE]" {
                             None
                         } else {
-                            match serde_json::from_str(line) {
-                                Ok(ResponseStreamResult::Ok(response)) => Some(Ok(response)),
-                                Ok(ResponseStreamResult::Err { error }) => {
-                                    Some(Err(anyhow!(error)))
-                                }
-                                Err(error) => Some(Err(anyhow!(error))),
-                            }
+                            Some(serde_json::from_str(line).map_err(|error| anyhow!(error)))
                         }
                     }
                     Err(error) => Some(Err(anyhow!(error))),
@@ -302,23 +471,72 @@ pub async fn stream_completion(
         #[derive(Deserialize)]
         struct OpenAiError {
             message: String,
+            #[serde(default)]
+            code: Option<String>,
         }
 
-        match serde_json::from_str::<OpenAiResponse>(&body) {
-            Ok(response) if !response.error.message.is_empty() => Err(anyhow!(
-                "Failed to connect to OpenAI API: {}",
-                response.error.message,
-            )),
-
-            _ => Err(anyhow!(
-                "Failed to connect to OpenAI API: {} {}",
-                response.status(),
-                body,
-            )),
+        let (message, code) = match serde_json::from_str::<OpenAiResponse>(&body) {
+            Ok(response) if !response.error.message.is_empty() => {
+                (response.error.message, response.error.code)
+            }
+            _ => (format!("{} {}", response.status(), body), None),
+        };
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            Err(RateLimitError { message }.into())
+        } else if code.as_deref() == Some("context_length_exceeded") {
+            Err(ContextLengthExceededError { message }.into())
+        } else if code.as_deref() == Some("model_not_found") {
+            Err(ModelNotFoundError { message }.into())
+        } else {
+            Err(anyhow!("Failed to connect to OpenAI API: {message}"))
         }
     }
 }
 
+/// The error returned when OpenAI rejects a request for exceeding a rate limit.
+#[derive(Debug)]
+pub struct RateLimitError {
+    pub message: String,
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to connect to OpenAI API: {}", self.message)
+    }
+}
+
+impl std::error::Error for RateLimitError {}
+
+/// The error returned when OpenAI rejects a request for not fitting in the
+/// model's context window.
+#[derive(Debug)]
+pub struct ContextLengthExceededError {
+    pub message: String,
+}
+
+impl fmt::Display for ContextLengthExceededError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to connect to OpenAI API: {}", self.message)
+    }
+}
+
+impl std::error::Error for ContextLengthExceededError {}
+
+/// The error returned when the requested model doesn't exist or the API key
+/// doesn't have access to it.
+#[derive(Debug)]
+pub struct ModelNotFoundError {
+    pub message: String,
+}
+
+impl fmt::Display for ModelNotFoundError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to connect to OpenAI API: {}", self.message)
+    }
+}
+
+impl std::error::Error for ModelNotFoundError {}
+
 #[derive(Copy, Clone, Serialize, Deserialize)]
 pub enum OpenAiEmbeddingModel {
     #[serde(rename = "text-embedding-3-small")]
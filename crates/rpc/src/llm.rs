@@ -1,7 +1,11 @@
+use collections::{BTreeMap, HashMap};
 use serde::{Deserialize, Serialize};
 use strum::{Display, EnumIter, EnumString};
 
 pub const EXPIRED_LLM_TOKEN_HEADER_NAME: &str = "x-zed-expired-token";
+/// Set on a completion's response when the user is close to one of their
+/// limits. The value is a [`UsageLimitWarning`], serialized as JSON.
+pub const USAGE_LIMIT_WARNING_HEADER_NAME: &str = "x-zed-usage-limit-warning";
 
 #[derive(
     Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize, EnumString, EnumIter, Display,
@@ -20,4 +24,154 @@ pub struct PerformCompletionParams {
     pub provider: LanguageModelProvider,
     pub model: String,
     pub provider_request: Box<serde_json::value::RawValue>,
+    /// The name of a prompt template defined on the server, which is expanded
+    /// into the system prompt of `provider_request` before it is forwarded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// The values substituted for the variables referenced by `template`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variables: HashMap<String, String>,
+    /// Identifies the conversation the request belongs to, so that a user's
+    /// turns can be grouped into sessions for analytics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation_id: Option<String>,
+    /// The experiment bucket the request belongs to, which the server records
+    /// in telemetry and may use to route the request to a different model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub experiment: Option<String>,
+    /// Labels that attribute the request's cost, such as to a project or
+    /// team, which the server records in telemetry.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Sends `model` upstream as is, instead of mapping it to the version of
+    /// the model that the server currently serves.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pin_model_version: bool,
+}
+
+/// What a model in the server's catalog supports. Capabilities the server
+/// doesn't know are left unset, so that clients fall back to their defaults.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModelCapabilities {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tools: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LanguageModelCatalogEntry {
+    pub provider: LanguageModelProvider,
+    /// The model's name, without a version suffix, e.g. `claude-3-5-sonnet`.
+    pub name: String,
+    pub capabilities: ModelCapabilities,
+}
+
+/// The response to `GET /models`, listing the models the server serves.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetLanguageModelsResponse {
+    pub models: Vec<LanguageModelCatalogEntry>,
+}
+
+/// Which of a user's rate limits a request exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum RateLimitScope {
+    #[serde(rename = "requests")]
+    RequestsPerMinute,
+    #[serde(rename = "tokens-minute")]
+    TokensPerMinute,
+    #[serde(rename = "tokens-day")]
+    TokensPerDay,
+}
+
+impl std::fmt::Display for RateLimitScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::RequestsPerMinute => write!(f, "requests per minute"),
+            Self::TokensPerMinute => write!(f, "tokens per minute"),
+            Self::TokensPerDay => write!(f, "tokens per day"),
+        }
+    }
+}
+
+/// The body of the `429 Too Many Requests` response sent when a request
+/// exceeds one of the user's rate limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimitExceeded {
+    pub scope: RateLimitScope,
+    /// The user's share of the limit that was exceeded.
+    pub limit: usize,
+    /// How many seconds until the limit's window moves forward, after which
+    /// the request may fit within the limit again.
+    pub reset_after_secs: u64,
+}
+
+/// Warns that the user has used most of one of their limits, so that they
+/// aren't caught off guard when they reach it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageLimitWarning {
+    pub scope: RateLimitScope,
+    /// The user's share of the limit.
+    pub limit: usize,
+    /// How much of the limit the user had used before the request.
+    pub used: usize,
+}
+
+/// Sent in place of the rest of a completion when the server cuts it short
+/// because the user ran out of tokens while it was streaming.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompletionTruncated {
+    /// The limit that was reached, such as "tokens per day".
+    pub truncated_by: String,
+}
+
+/// A provider-independent completion event, which the server sends in place
+/// of the provider's own chunks when the client asks for normalized events.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CompletionEvent {
+    /// The model the provider actually used, which can be more specific than
+    /// the one requested. Sent once per completion.
+    Model {
+        model: String,
+    },
+    Text {
+        text: String,
+    },
+    /// An image the model generated, as base64-encoded `data`.
+    Image {
+        mime_type: String,
+        data: String,
+    },
+    /// Part of a tool call. `id` and `name` are sent with the first part of
+    /// each call, and concatenating the `input_json` of every part with the
+    /// same `index` gives the call's input.
+    ///
+    /// Google sends a call's input as whole JSON objects instead, which are
+    /// each sent as a part with `name`. Later objects are merged into earlier
+    /// ones, with arrays concatenated.
+    ToolUse {
+        index: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        input_json: String,
+    },
+    /// Tokens counted towards the user's usage since the previous `usage`
+    /// event.
+    Usage {
+        input_tokens: usize,
+        output_tokens: usize,
+    },
+    /// Why the model stopped generating: `end_turn`, `max_tokens`,
+    /// `stop_sequence`, `tool_use`, or a provider-specific reason.
+    Stop {
+        reason: String,
+        /// The stop sequence the completion ended on, if any.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stop_sequence: Option<String>,
+    },
 }